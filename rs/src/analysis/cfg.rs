@@ -0,0 +1,259 @@
+use std::fmt::Write;
+
+use super::error::CfgError;
+use crate::binary::chunk::{LuaVersion, Prototype};
+use crate::vm::decoded::Decoded;
+
+/// 基本块，包含[start, end)范围内的指令，只能从第一条指令进入、从最后一条指令离开
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    // 第一条指令的pc
+    pub start: usize,
+    // 最后一条指令之后的pc
+    pub end: usize,
+    // 后继基本块的下标
+    pub succs: Vec<usize>,
+    // 前驱基本块的下标
+    pub preds: Vec<usize>,
+}
+
+/// 函数原型的控制流图，基本块按pc的顺序排列，第一个基本块是入口
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl Cfg {
+    // 把5.3的指令表划分为基本块，并连接前驱和后继
+    pub fn build(proto: &Prototype) -> Result<Cfg, CfgError> {
+        if proto.version != LuaVersion::Lua53 {
+            return Err(CfgError::UnsupportedVersion(proto.version));
+        }
+        let n = proto.code.len();
+        let mut succs = Vec::with_capacity(n);
+        for pc in 0..n {
+            succs.push(successors(proto, pc)?);
+        }
+
+        // 入口、跳转目标以及分支之后的指令都是基本块的开头
+        let mut leader = vec![false; n];
+        if n > 0 {
+            leader[0] = true;
+        }
+        for (pc, targets) in succs.iter().enumerate() {
+            if targets.as_slice() != [pc + 1] {
+                for &target in targets.iter().chain([pc + 1].iter()) {
+                    if target < n {
+                        leader[target] = true;
+                    }
+                }
+            }
+        }
+
+        // 每条指令所在的基本块
+        let mut block_of = vec![0; n];
+        let mut blocks: Vec<BasicBlock> = Vec::new();
+        for pc in 0..n {
+            if leader[pc] {
+                blocks.push(BasicBlock {
+                    start: pc,
+                    end: pc,
+                    succs: Vec::new(),
+                    preds: Vec::new(),
+                });
+            }
+            let last = blocks.len() - 1;
+            blocks[last].end = pc + 1;
+            block_of[pc] = last;
+        }
+
+        for b in 0..blocks.len() {
+            let last = blocks[b].end - 1;
+            for &target in &succs[last] {
+                let to = block_of[target];
+                if !blocks[b].succs.contains(&to) {
+                    blocks[b].succs.push(to);
+                    blocks[to].preds.push(b);
+                }
+            }
+        }
+        Ok(Cfg { blocks })
+    }
+
+    // pc所在的基本块的下标
+    pub fn block_of(&self, pc: usize) -> Option<usize> {
+        self.blocks.iter().position(|b| b.start <= pc && pc < b.end)
+    }
+}
+
+// pc处的指令执行后可能到达的指令，落到指令表之外的不算在内
+pub(crate) fn successors(proto: &Prototype, pc: usize) -> Result<Vec<usize>, CfgError> {
+    let n = proto.code.len();
+    let instruction = proto.code[pc];
+    let decoded = Decoded::decode(instruction).ok_or(CfgError::UnknownOpcode {
+        pc,
+        opcode: (instruction & 0x3F) as u8,
+    })?;
+    let targets = match decoded {
+        Decoded::Jmp { offset, .. } | Decoded::ForPrep { offset, .. } => {
+            vec![jump(n, pc, offset)?]
+        }
+        // 循环继续时跳回循环体，否则顺序执行
+        Decoded::ForLoop { offset, .. } | Decoded::TForLoop { offset, .. } => {
+            vec![pc + 1, jump(n, pc, offset)?]
+        }
+        Decoded::Return { .. } => vec![],
+        Decoded::LoadBool { skip: true, .. } => vec![pc + 2],
+        // 跳过下一条EXTRAARG
+        Decoded::LoadKX { .. } | Decoded::SetList { block: None, .. } => vec![pc + 2],
+        // test指令之后必须是JMP，条件成立时执行JMP，否则跳过它
+        _ if decoded.op().info().test_flag == 1 => vec![pc + 1, pc + 2],
+        _ => vec![pc + 1],
+    };
+    Ok(targets.into_iter().filter(|&target| target < n).collect())
+}
+
+fn jump(n: usize, pc: usize, offset: isize) -> Result<usize, CfgError> {
+    let target = pc as isize + 1 + offset;
+    if target < 0 || target as usize >= n {
+        return Err(CfgError::JumpOutOfRange { pc, target });
+    }
+    Ok(target as usize)
+}
+
+// 以Graphviz DOT格式输出函数原型及其所有子函数的控制流图，每个函数是一个子图
+pub fn to_dot(proto: &Prototype) -> Result<String, CfgError> {
+    let mut out = String::from("digraph cfg {\n\tnode [shape=box, fontname=monospace];\n");
+    let mut id = 0;
+    dot_function(&mut out, proto, &mut id)?;
+    out.push_str("}\n");
+    Ok(out)
+}
+
+fn dot_function(out: &mut String, proto: &Prototype, id: &mut usize) -> Result<(), CfgError> {
+    let cfg = Cfg::build(proto)?;
+    let f = *id;
+    *id += 1;
+    let name = if proto.line_defined == 0 {
+        "main".to_string()
+    } else {
+        format!(
+            "function <{}:{},{}>",
+            String::from_utf8_lossy(&proto.source),
+            proto.line_defined,
+            proto.last_line_defined
+        )
+    };
+    writeln!(out, "\tsubgraph cluster_{f} {{").unwrap();
+    writeln!(out, "\t\tlabel=\"{}\";", escape(&name)).unwrap();
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        let mut label = String::new();
+        for pc in block.start..block.end {
+            let decoded = Decoded::decode(proto.code[pc]).unwrap();
+            let text = format!("{:<4}{:<9} {}", pc + 1, decoded.op().name(), decoded);
+            label.push_str(&escape(&text));
+            label.push_str("\\l");
+        }
+        writeln!(out, "\t\tf{f}_b{b} [label=\"{label}\"];").unwrap();
+    }
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        for succ in &block.succs {
+            writeln!(out, "\t\tf{f}_b{b} -> f{f}_b{succ};").unwrap();
+        }
+    }
+    out.push_str("\t}\n");
+    for child in &proto.protos {
+        dot_function(out, child, id)?;
+    }
+    Ok(())
+}
+
+// DOT字符串中的双引号和反斜杠需要转义
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::cfg::{to_dot, Cfg};
+    use crate::analysis::error::CfgError;
+    use crate::compiler;
+    use crate::vm::decoded::Decoded;
+    use crate::vm::instruction::Instruction;
+    use crate::vm::opcode::Op;
+
+    #[test]
+    fn test_blocks() {
+        let source = b"local a = 1\nwhile a < 10 do\n  if a % 2 == 0 then a = a + 3 end\n  a = a + 1\nend\nreturn a";
+        let proto = compiler::compile(source, "@test.lua").unwrap();
+        let cfg = Cfg::build(&proto).unwrap();
+        // 前驱和后继互相对应，跳转目标总是基本块的开头
+        for (b, block) in cfg.blocks.iter().enumerate() {
+            for &succ in &block.succs {
+                assert!(cfg.blocks[succ].preds.contains(&b));
+            }
+        }
+        for (pc, &i) in proto.code.iter().enumerate() {
+            if let Some(target) = Decoded::decode(i).unwrap().jump_target(pc) {
+                let b = cfg.block_of(target).unwrap();
+                assert_eq!(cfg.blocks[b].start, target);
+            }
+        }
+        assert_eq!(cfg.blocks[0].start, 0);
+        assert!(cfg.blocks[0].preds.is_empty());
+        assert_eq!(cfg.blocks.last().unwrap().end, proto.code.len());
+        // 循环使得某个基本块有回边
+        assert!(cfg
+            .blocks
+            .iter()
+            .enumerate()
+            .any(|(b, block)| block.succs.iter().any(|&s| s <= b)));
+        // 没有后继的基本块都以RETURN结束(显式的return和末尾隐含的return)
+        let exits: Vec<_> = cfg.blocks.iter().filter(|b| b.succs.is_empty()).collect();
+        assert_eq!(exits.len(), 2);
+        for block in exits {
+            let last = Decoded::decode(proto.code[block.end - 1]).unwrap();
+            assert_eq!(last.op(), Op::Return);
+        }
+    }
+
+    #[test]
+    fn test_edges() {
+        let mut proto = compiler::compile(b"", "=test").unwrap();
+        proto.code = vec![
+            u32::encode_abc(Op::Eq, 1, 0, 1).unwrap(),
+            u32::encode_asbx(Op::Jmp, 0, 2).unwrap(),
+            u32::encode_abc(Op::LoadBool, 0, 1, 1).unwrap(),
+            u32::encode_abc(Op::LoadBool, 0, 0, 0).unwrap(),
+            u32::encode_abc(Op::Return, 0, 1, 0).unwrap(),
+        ];
+        let cfg = Cfg::build(&proto).unwrap();
+        let ranges: Vec<_> = cfg.blocks.iter().map(|b| (b.start, b.end)).collect();
+        assert_eq!(ranges, [(0, 1), (1, 2), (2, 3), (3, 4), (4, 5)]);
+        assert_eq!(cfg.blocks[0].succs, [1, 2]);
+        assert_eq!(cfg.blocks[1].succs, [4]);
+        assert_eq!(cfg.blocks[2].succs, [4]);
+        assert_eq!(cfg.blocks[3].succs, [4]);
+        assert_eq!(cfg.blocks[4].preds, [1, 2, 3]);
+        assert!(cfg.blocks[3].preds.is_empty());
+
+        proto.code[1] = u32::encode_asbx(Op::Jmp, 0, 5).unwrap();
+        assert_eq!(
+            Cfg::build(&proto),
+            Err(CfgError::JumpOutOfRange { pc: 1, target: 7 })
+        );
+    }
+
+    #[test]
+    fn test_dot() {
+        let source = b"local f = function(x) return x or \"a\\\"b\" end\nreturn f(1)";
+        let proto = compiler::compile(source, "@test.lua").unwrap();
+        let dot = to_dot(&proto).unwrap();
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains("subgraph cluster_0 {\n\t\tlabel=\"main\";"));
+        assert!(dot.contains("label=\"function <@test.lua:1,1>\";"));
+        assert!(dot.contains("f1_b0 -> f1_b"));
+        assert!(dot.contains("RETURN"));
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt;
+
+use crate::binary::chunk::LuaVersion;
+
+/// 构造控制流图时可能出现的错误，pc从0开始
+#[derive(Debug, Clone, PartialEq)]
+pub enum CfgError {
+    // 只支持5.3的指令
+    UnsupportedVersion(LuaVersion),
+    // 无法解码的指令
+    UnknownOpcode { pc: usize, opcode: u8 },
+    // 跳转目标不在指令表内
+    JumpOutOfRange { pc: usize, target: isize },
+}
+
+impl fmt::Display for CfgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CfgError::UnsupportedVersion(version) => {
+                write!(f, "cannot analyze Lua {version} code")
+            }
+            CfgError::UnknownOpcode { pc, opcode } => {
+                write!(f, "unknown opcode {} at pc {}", opcode, pc + 1)
+            }
+            CfgError::JumpOutOfRange { pc, target } => {
+                write!(f, "jump at pc {} to {} is out of range", pc + 1, target + 1)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CfgError {}
+
+/// 校验函数原型时发现的错误，pc从0开始
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    // 只支持5.3的指令
+    UnsupportedVersion(LuaVersion),
+    // 无法解码的指令
+    UnknownOpcode {
+        pc: usize,
+        opcode: u8,
+    },
+    // 寄存器超出max_stack_size
+    RegisterOutOfRange {
+        pc: usize,
+        register: usize,
+        max_stack_size: u8,
+    },
+    // 常量索引超出常量表
+    ConstantOutOfRange {
+        pc: usize,
+        index: usize,
+        count: usize,
+    },
+    // upvalue索引超出upvalue表
+    UpvalueOutOfRange {
+        pc: usize,
+        index: usize,
+        count: usize,
+    },
+    // 子函数索引超出子函数表
+    ProtoOutOfRange {
+        pc: usize,
+        index: usize,
+        count: usize,
+    },
+    // 跳转目标不在指令表内
+    JumpOutOfRange {
+        pc: usize,
+        target: isize,
+    },
+    // test指令之后不是JMP
+    MissingJump {
+        pc: usize,
+    },
+    // LOADKX或C为0的SETLIST之后不是EXTRAARG
+    MissingExtraArg {
+        pc: usize,
+    },
+    // B或C为0的CALL、TAILCALL、RETURN、SETLIST之前不是设置栈顶的指令
+    MissingOpenTop {
+        pc: usize,
+    },
+    // TFORCALL之后不是TFORLOOP
+    MissingForLoop {
+        pc: usize,
+    },
+    // 跳转到EXTRAARG
+    JumpToExtraArg {
+        pc: usize,
+        target: usize,
+    },
+    // 最后一条指令不是RETURN
+    MissingReturn,
+    // 行号表不为空时必须与指令表一样长
+    LineInfoMismatch {
+        lines: usize,
+        code: usize,
+    },
+    // 子函数的upvalue引用了不存在的寄存器或upvalue
+    BadUpvalue {
+        index: usize,
+    },
+    // 子函数中的错误，index是它在子函数表中的位置
+    InFunction {
+        index: usize,
+        line_defined: u32,
+        error: Box<VerifyError>,
+    },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::UnsupportedVersion(version) => {
+                write!(f, "cannot verify Lua {version} code")
+            }
+            VerifyError::UnknownOpcode { pc, opcode } => {
+                write!(f, "unknown opcode {} at pc {}", opcode, pc + 1)
+            }
+            VerifyError::RegisterOutOfRange {
+                pc,
+                register,
+                max_stack_size,
+            } => write!(
+                f,
+                "register {} out of range at pc {} (max stack size {})",
+                register,
+                pc + 1,
+                max_stack_size
+            ),
+            VerifyError::ConstantOutOfRange { pc, index, count } => write!(
+                f,
+                "constant {} out of range at pc {} ({} constants)",
+                index,
+                pc + 1,
+                count
+            ),
+            VerifyError::UpvalueOutOfRange { pc, index, count } => write!(
+                f,
+                "upvalue {} out of range at pc {} ({} upvalues)",
+                index,
+                pc + 1,
+                count
+            ),
+            VerifyError::ProtoOutOfRange { pc, index, count } => write!(
+                f,
+                "function {} out of range at pc {} ({} functions)",
+                index,
+                pc + 1,
+                count
+            ),
+            VerifyError::JumpOutOfRange { pc, target } => {
+                write!(f, "jump at pc {} to {} is out of range", pc + 1, target + 1)
+            }
+            VerifyError::MissingJump { pc } => {
+                write!(f, "test at pc {} is not followed by JMP", pc + 1)
+            }
+            VerifyError::MissingExtraArg { pc } => {
+                write!(
+                    f,
+                    "instruction at pc {} is not followed by EXTRAARG",
+                    pc + 1
+                )
+            }
+            VerifyError::MissingOpenTop { pc } => write!(
+                f,
+                "instruction at pc {} uses the stack top but does not follow \
+                 a CALL or VARARG that sets it",
+                pc + 1
+            ),
+            VerifyError::MissingForLoop { pc } => {
+                write!(f, "TFORCALL at pc {} is not followed by TFORLOOP", pc + 1)
+            }
+            VerifyError::JumpToExtraArg { pc, target } => write!(
+                f,
+                "jump at pc {} lands on EXTRAARG at pc {}",
+                pc + 1,
+                target + 1
+            ),
+            VerifyError::MissingReturn => write!(f, "code does not end with RETURN"),
+            VerifyError::LineInfoMismatch { lines, code } => {
+                write!(f, "line info has {lines} entries for {code} instructions")
+            }
+            VerifyError::BadUpvalue { index } => write!(f, "bad upvalue {index}"),
+            VerifyError::InFunction {
+                index,
+                line_defined,
+                error,
+            } => write!(
+                f,
+                "function {index} defined at line {line_defined}: {error}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
@@ -0,0 +1,7 @@
+pub mod cfg;
+pub mod error;
+pub mod stats;
+pub mod verify;
+
+pub use error::{CfgError, VerifyError};
+pub use verify::verify;
@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::binary::chunk::{LuaVersion, Prototype};
+use crate::binary::json::Json;
+use crate::vm::instruction::{Instruction, Instruction54};
+use crate::vm::opcode::{self, lua51, lua52};
+
+/// 函数原型树的统计信息
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    // 函数个数，包括主函数
+    pub functions: usize,
+    pub instructions: usize,
+    pub constants: usize,
+    pub upvalues: usize,
+    pub locals: usize,
+    // 所有函数中最大的寄存器数量
+    pub max_stack_size: u8,
+    // 各操作码出现的次数，不认识的操作码记为UNKNOWN
+    pub opcodes: BTreeMap<&'static str, usize>,
+}
+
+impl Stats {
+    // 统计函数原型，recurse为true时包括所有嵌套的子函数
+    pub fn collect(proto: &Prototype, recurse: bool) -> Stats {
+        let mut stats = Stats::default();
+        stats.add(proto, recurse);
+        stats
+    }
+
+    fn add(&mut self, proto: &Prototype, recurse: bool) {
+        self.functions += 1;
+        self.instructions += proto.code.len();
+        self.constants += proto.constants.len();
+        self.upvalues += proto.upvalues.len();
+        self.locals += proto.loc_vars.len();
+        self.max_stack_size = self.max_stack_size.max(proto.max_stack_size);
+        for &i in &proto.code {
+            *self.opcodes.entry(op_name(proto.version, i)).or_insert(0) += 1;
+        }
+        if recurse {
+            for p in &proto.protos {
+                self.add(p, recurse);
+            }
+        }
+    }
+
+    // 操作码按出现次数从多到少排列，次数相同的按名字排列
+    fn sorted_opcodes(&self) -> Vec<(&'static str, usize)> {
+        let mut opcodes: Vec<_> = self.opcodes.iter().map(|(k, v)| (*k, *v)).collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        opcodes
+    }
+
+    pub fn to_json(&self) -> Json {
+        let opcodes = self
+            .sorted_opcodes()
+            .into_iter()
+            .map(|(name, count)| {
+                Json::Obj(vec![
+                    ("name", Json::Str(name.to_string())),
+                    ("count", Json::Int(count as i64)),
+                ])
+            })
+            .collect();
+        Json::Obj(vec![
+            ("functions", Json::Int(self.functions as i64)),
+            ("instructions", Json::Int(self.instructions as i64)),
+            ("constants", Json::Int(self.constants as i64)),
+            ("upvalues", Json::Int(self.upvalues as i64)),
+            ("locals", Json::Int(self.locals as i64)),
+            ("max_stack_size", Json::Int(self.max_stack_size as i64)),
+            ("opcodes", Json::Arr(opcodes)),
+        ])
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "functions     {}", self.functions)?;
+        writeln!(f, "instructions  {}", self.instructions)?;
+        writeln!(f, "constants     {}", self.constants)?;
+        writeln!(f, "upvalues      {}", self.upvalues)?;
+        writeln!(f, "locals        {}", self.locals)?;
+        writeln!(f, "max stack     {}", self.max_stack_size)?;
+        writeln!(f, "opcodes:")?;
+        for (name, count) in self.sorted_opcodes() {
+            let percent = count as f64 * 100.0 / self.instructions as f64;
+            writeln!(f, "  {:<10}{:>6}  {:>5.1}%", name, count, percent)?;
+        }
+        Ok(())
+    }
+}
+
+// 按chunk的版本取指令的操作码名
+fn op_name(version: LuaVersion, instruction: u32) -> &'static str {
+    let op_codes = match version {
+        LuaVersion::Lua54 => return Instruction54(instruction).op_name().unwrap_or("UNKNOWN"),
+        LuaVersion::Lua51 => lua51::OP_CODES,
+        LuaVersion::Lua52 => lua52::OP_CODES,
+        LuaVersion::Lua53 => opcode::OP_CODES,
+    };
+    match op_codes.get(instruction.op_code() as usize) {
+        Some(op) => op.name.trim_end(),
+        None => "UNKNOWN",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::stats::Stats;
+    use crate::compiler;
+
+    #[test]
+    fn test_stats() {
+        let source = b"local a = 1\nlocal function f(x) return x + a end\nreturn f(2)";
+        let proto = compiler::compile(source, "@test.lua").unwrap();
+        let all = Stats::collect(&proto, true);
+        let main = Stats::collect(&proto, false);
+        assert_eq!(all.functions, 2);
+        assert_eq!(main.functions, 1);
+        assert_eq!(main.instructions, proto.code.len());
+        assert_eq!(
+            all.instructions,
+            proto.code.len() + proto.protos[0].code.len()
+        );
+        assert_eq!(all.upvalues, 2);
+        assert_eq!(all.locals, 3);
+        assert_eq!(all.opcodes["RETURN"], 4);
+        assert_eq!(all.opcodes.values().sum::<usize>(), all.instructions);
+
+        let text = all.to_string();
+        assert!(text.starts_with("functions     2\n"));
+        assert!(text.contains("  RETURN         4"));
+        let json = all.to_json().to_string();
+        assert!(json.starts_with("{\"functions\":2,\"instructions\":"));
+        assert!(json.contains("{\"name\":\"RETURN\",\"count\":4}"));
+    }
+}
@@ -0,0 +1,400 @@
+use super::error::VerifyError;
+use crate::binary::chunk::{LuaVersion, Prototype};
+use crate::vm::decoded::{Decoded, Rk};
+use crate::vm::instruction::Instruction;
+use crate::vm::opcode::{Op, OP_ARG_K, OP_ARG_R, OP_MODE_ABC};
+
+// 校验函数原型及其所有子函数，通过校验的5.3函数原型可以交给虚拟机执行
+// 检查寄存器、常量、upvalue和子函数的索引，跳转目标，以及指令之间的搭配
+pub fn verify(proto: &Prototype) -> Result<(), VerifyError> {
+    if proto.version != LuaVersion::Lua53 {
+        return Err(VerifyError::UnsupportedVersion(proto.version));
+    }
+    let n = proto.code.len();
+    if n == 0 || proto.code[n - 1].op() != Some(Op::Return) {
+        return Err(VerifyError::MissingReturn);
+    }
+    // strip之后行号表为空，否则每条指令都有行号
+    if !proto.line_info.is_empty() && proto.line_info.len() != n {
+        return Err(VerifyError::LineInfoMismatch {
+            lines: proto.line_info.len(),
+            code: n,
+        });
+    }
+    for pc in 0..n {
+        verify_instruction(proto, pc)?;
+    }
+    for (index, child) in proto.protos.iter().enumerate() {
+        verify_upvalues(proto, child)
+            .and_then(|_| verify(child))
+            .map_err(|error| VerifyError::InFunction {
+                index,
+                line_defined: child.line_defined,
+                error: Box::new(error),
+            })?;
+    }
+    Ok(())
+}
+
+fn verify_instruction(proto: &Prototype, pc: usize) -> Result<(), VerifyError> {
+    let instruction = proto.code[pc];
+    let decoded = Decoded::decode(instruction).ok_or(VerifyError::UnknownOpcode {
+        pc,
+        opcode: instruction.op_code(),
+    })?;
+    let op = decoded.op();
+    let info = op.info();
+    let next = proto.code.get(pc + 1).and_then(|i| i.op());
+
+    // 先根据OP_CODES中的参数模式检查A、B、C
+    if op == Op::LoadK {
+        let (_, bx) = instruction.abx();
+        check_constant(proto, pc, bx as usize)?;
+    }
+    if info.op_mode == OP_MODE_ABC {
+        let (_, b, c) = instruction.abc();
+        for (x, mode) in [(b, info.b_arg_mode), (c, info.c_arg_mode)] {
+            match Rk::from_arg(x, mode) {
+                Rk::Const(k) => check_constant(proto, pc, k)?,
+                Rk::Reg(r) if mode == OP_ARG_R || mode == OP_ARG_K => check_register(proto, pc, r)?,
+                Rk::Reg(_) => (),
+            }
+        }
+    }
+    if info.set_a_flag == 1 {
+        let (a, _, _) = instruction.abc();
+        check_register(proto, pc, a as usize)?;
+    }
+    if info.test_flag == 1 && next != Some(Op::Jmp) {
+        return Err(VerifyError::MissingJump { pc });
+    }
+
+    // B或C为0时从上一条指令设置的栈顶取值，上一条指令必须是C为0的CALL、TAILCALL或B为0的VARARG
+    let uses_top = match decoded {
+        Decoded::Call { args, .. } | Decoded::TailCall { args, .. } => args.is_none(),
+        Decoded::Return { n, .. } | Decoded::SetList { n, .. } => n.is_none(),
+        _ => false,
+    };
+    if uses_top {
+        let prev = pc.checked_sub(1).and_then(|p| Decoded::decode(proto.code[p]));
+        if !matches!(
+            prev,
+            Some(
+                Decoded::Call { results: None, .. }
+                    | Decoded::TailCall { .. }
+                    | Decoded::VarArg { n: None, .. }
+            )
+        ) {
+            return Err(VerifyError::MissingOpenTop { pc });
+        }
+    }
+
+    // 再检查参数之间的关系和各指令特有的约束
+    match decoded {
+        Decoded::LoadKX { .. } => match proto.code.get(pc + 1) {
+            Some(&i) if i.op() == Some(Op::ExtraArg) => check_constant(proto, pc, i.ax() as usize)?,
+            _ => return Err(VerifyError::MissingExtraArg { pc }),
+        },
+        Decoded::LoadNil { a, n } => check_range(proto, pc, a, n)?,
+        Decoded::GetUpval { upval, .. }
+        | Decoded::GetTabUp { upval, .. }
+        | Decoded::SetTabUp { upval, .. } => check_upvalue(proto, pc, upval)?,
+        Decoded::SetUpval { a, upval } => {
+            check_register(proto, pc, a)?;
+            check_upvalue(proto, pc, upval)?;
+        }
+        Decoded::SetTable { table: a, .. } | Decoded::Test { a, .. } => {
+            check_register(proto, pc, a)?
+        }
+        Decoded::Self_ { a, .. } => check_range(proto, pc, a, 2)?,
+        Decoded::Jmp { close, offset } => {
+            if let Some(close) = close {
+                check_register(proto, pc, close)?;
+            }
+            check_jump(proto, pc, offset)?;
+        }
+        Decoded::Call { a, args, results } => {
+            check_range(proto, pc, a, args.unwrap_or(0) + 1)?;
+            check_range(proto, pc, a, results.unwrap_or(0))?;
+        }
+        Decoded::TailCall { a, args } => check_range(proto, pc, a, args.unwrap_or(0) + 1)?,
+        Decoded::Return { a, n } => check_range(proto, pc, a, n.unwrap_or(0))?,
+        Decoded::ForLoop { a, offset } | Decoded::ForPrep { a, offset } => {
+            check_range(proto, pc, a, 4)?;
+            check_jump(proto, pc, offset)?;
+        }
+        Decoded::TForCall { a, results } => {
+            // 调用前先把R(A)..R(A+2)复制到R(A+3)..R(A+5)，结果再写回R(A+3)开始的寄存器
+            check_range(proto, pc, a, 3 + results.max(3))?;
+            if next != Some(Op::TForLoop) {
+                return Err(VerifyError::MissingForLoop { pc });
+            }
+        }
+        Decoded::TForLoop { a, offset } => {
+            check_range(proto, pc, a, 2)?;
+            check_jump(proto, pc, offset)?;
+        }
+        Decoded::SetList { a, n, block } => {
+            check_range(proto, pc, a, n.unwrap_or(0) + 1)?;
+            if block.is_none() && next != Some(Op::ExtraArg) {
+                return Err(VerifyError::MissingExtraArg { pc });
+            }
+        }
+        Decoded::Closure { proto: index, .. } if index >= proto.protos.len() => {
+            return Err(VerifyError::ProtoOutOfRange {
+                pc,
+                index,
+                count: proto.protos.len(),
+            });
+        }
+        Decoded::VarArg { a, n } => check_range(proto, pc, a, n.unwrap_or(0))?,
+        _ => (),
+    }
+    Ok(())
+}
+
+// 子函数的upvalue要么捕获父函数的寄存器，要么引用父函数的upvalue
+fn verify_upvalues(parent: &Prototype, child: &Prototype) -> Result<(), VerifyError> {
+    for (index, upval) in child.upvalues.iter().enumerate() {
+        let valid = if upval.instack == 1 {
+            upval.idx < parent.max_stack_size
+        } else {
+            (upval.idx as usize) < parent.upvalues.len()
+        };
+        if !valid {
+            return Err(VerifyError::BadUpvalue { index });
+        }
+    }
+    Ok(())
+}
+
+fn check_register(proto: &Prototype, pc: usize, register: usize) -> Result<(), VerifyError> {
+    if register >= proto.max_stack_size as usize {
+        return Err(VerifyError::RegisterOutOfRange {
+            pc,
+            register,
+            max_stack_size: proto.max_stack_size,
+        });
+    }
+    Ok(())
+}
+
+// 检查从a开始的n个寄存器
+fn check_range(proto: &Prototype, pc: usize, a: usize, n: usize) -> Result<(), VerifyError> {
+    match n {
+        0 => Ok(()),
+        _ => check_register(proto, pc, a + n - 1),
+    }
+}
+
+fn check_constant(proto: &Prototype, pc: usize, index: usize) -> Result<(), VerifyError> {
+    if index >= proto.constants.len() {
+        return Err(VerifyError::ConstantOutOfRange {
+            pc,
+            index,
+            count: proto.constants.len(),
+        });
+    }
+    Ok(())
+}
+
+fn check_upvalue(proto: &Prototype, pc: usize, index: usize) -> Result<(), VerifyError> {
+    if index >= proto.upvalues.len() {
+        return Err(VerifyError::UpvalueOutOfRange {
+            pc,
+            index,
+            count: proto.upvalues.len(),
+        });
+    }
+    Ok(())
+}
+
+fn check_jump(proto: &Prototype, pc: usize, offset: isize) -> Result<(), VerifyError> {
+    let target = pc as isize + 1 + offset;
+    if target < 0 || target as usize >= proto.code.len() {
+        return Err(VerifyError::JumpOutOfRange { pc, target });
+    }
+    // EXTRAARG是上一条指令的参数，不能单独执行
+    let target = target as usize;
+    if proto.code[target].op() == Some(Op::ExtraArg) {
+        return Err(VerifyError::JumpToExtraArg { pc, target });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::analysis::error::VerifyError;
+    use crate::analysis::verify::verify;
+    use crate::binary;
+    use crate::compiler;
+    use crate::vm::instruction::{rk_constant, Instruction};
+    use crate::vm::opcode::Op;
+
+    #[test]
+    fn test_compiled() {
+        let source = b"local t = {1, 2, 3, n = 'x'}\n\
+            for i, v in ipairs(t) do t[i] = v * 2 end\n\
+            for i = 1, #t do print(i, t[i]) end\n\
+            local function f(a, ...)\n  local b = {...}\n  return function() a = a + 1; return a, b end\nend\n\
+            while t[1] < 10 and not t.n do t[1] = t[1] + 1 end\n\
+            return f(1)(), select('#', ...)";
+        let proto = compiler::compile(source, "@test.lua").unwrap();
+        assert_eq!(verify(&proto), Ok(()));
+        let proto = binary::undump(binary::dump(&proto)).unwrap();
+        assert_eq!(verify(&proto), Ok(()));
+    }
+
+    #[test]
+    fn test_errors() {
+        let base = compiler::compile(b"local a = 1", "=test").unwrap();
+        let return0 = u32::encode_abc(Op::Return, 0, 1, 0).unwrap();
+        let check = |code: Vec<u32>, expected: VerifyError| {
+            let mut proto = compiler::compile(b"local a = 1", "=test").unwrap();
+            proto.line_info.clear();
+            proto.code = code;
+            assert_eq!(verify(&proto), Err(expected));
+        };
+        let max = base.max_stack_size as isize;
+
+        check(vec![], VerifyError::MissingReturn);
+        check(
+            vec![u32::encode_abc(Op::Move, 0, 0, 0).unwrap()],
+            VerifyError::MissingReturn,
+        );
+        check(
+            vec![u32::encode_abc(Op::Move, max, 0, 0).unwrap(), return0],
+            VerifyError::RegisterOutOfRange {
+                pc: 0,
+                register: max as usize,
+                max_stack_size: max as u8,
+            },
+        );
+        check(
+            vec![u32::encode_abx(Op::LoadK, 0, 5).unwrap(), return0],
+            VerifyError::ConstantOutOfRange {
+                pc: 0,
+                index: 5,
+                count: 1,
+            },
+        );
+        check(
+            vec![
+                u32::encode_abc(Op::Add, 0, 0, rk_constant(1).unwrap()).unwrap(),
+                return0,
+            ],
+            VerifyError::ConstantOutOfRange {
+                pc: 0,
+                index: 1,
+                count: 1,
+            },
+        );
+        check(
+            vec![u32::encode_abc(Op::GetUpval, 0, 1, 0).unwrap(), return0],
+            VerifyError::UpvalueOutOfRange {
+                pc: 0,
+                index: 1,
+                count: 1,
+            },
+        );
+        check(
+            vec![u32::encode_asbx(Op::Jmp, 0, -3).unwrap(), return0],
+            VerifyError::JumpOutOfRange { pc: 0, target: -2 },
+        );
+        check(
+            vec![u32::encode_abx(Op::Closure, 0, 0).unwrap(), return0],
+            VerifyError::ProtoOutOfRange {
+                pc: 0,
+                index: 0,
+                count: 0,
+            },
+        );
+        check(
+            vec![u32::encode_abc(Op::Test, 0, 0, 1).unwrap(), return0],
+            VerifyError::MissingJump { pc: 0 },
+        );
+        check(
+            vec![u32::encode_abx(Op::LoadKX, 0, 0).unwrap(), return0],
+            VerifyError::MissingExtraArg { pc: 0 },
+        );
+        check(
+            vec![u32::encode_abc(Op::Return, max - 1, 3, 0).unwrap()],
+            VerifyError::RegisterOutOfRange {
+                pc: 0,
+                register: max as usize,
+                max_stack_size: max as u8,
+            },
+        );
+        // B为0的SETLIST之前没有设置栈顶的指令，虚拟机会用过期的栈顶计算元素个数
+        check(
+            vec![
+                u32::encode_abc(Op::NewTable, 0, 0, 0).unwrap(),
+                u32::encode_abc(Op::SetList, 0, 0, 1).unwrap(),
+                return0,
+            ],
+            VerifyError::MissingOpenTop { pc: 1 },
+        );
+        check(
+            vec![
+                u32::encode_abc(Op::Call, 0, 1, 2).unwrap(),
+                u32::encode_abc(Op::Return, 0, 0, 0).unwrap(),
+            ],
+            VerifyError::MissingOpenTop { pc: 1 },
+        );
+        check(
+            vec![
+                u32::encode_asbx(Op::Jmp, 0, 1).unwrap(),
+                u32::encode_abx(Op::LoadKX, 0, 0).unwrap(),
+                u32::encode_ax(Op::ExtraArg, 0).unwrap(),
+                return0,
+            ],
+            VerifyError::JumpToExtraArg { pc: 0, target: 2 },
+        );
+        // 行号表与指令表长度不一致
+        let mut proto = compiler::compile(b"local a = 1", "=test").unwrap();
+        proto.line_info.push(1);
+        assert_eq!(
+            verify(&proto),
+            Err(VerifyError::LineInfoMismatch { lines: 3, code: 2 })
+        );
+        proto.line_info.clear();
+        assert_eq!(verify(&proto), Ok(()));
+
+        proto.max_stack_size = 6;
+        proto.code = vec![u32::encode_abc(Op::TForCall, 0, 0, 1).unwrap(), return0];
+        assert_eq!(verify(&proto), Err(VerifyError::MissingForLoop { pc: 0 }));
+        // 只有一个结果时TFORCALL仍然使用R(A+3)..R(A+5)作为调用的函数和参数
+        proto.max_stack_size = 4;
+        proto.code = vec![
+            u32::encode_abc(Op::GetTabUp, 0, 0, rk_constant(0).unwrap()).unwrap(),
+            u32::encode_abc(Op::TForCall, 0, 0, 1).unwrap(),
+            u32::encode_asbx(Op::TForLoop, 2, -2).unwrap(),
+            return0,
+        ];
+        assert_eq!(
+            verify(&proto),
+            Err(VerifyError::RegisterOutOfRange {
+                pc: 1,
+                register: 5,
+                max_stack_size: 4,
+            })
+        );
+
+        // 子函数中的错误带有它的位置
+        let mut proto =
+            compiler::compile(b"local a\nlocal f = function() return a end", "=test").unwrap();
+        let mut child = Rc::try_unwrap(proto.protos.pop().unwrap()).unwrap();
+        child.upvalues[0].idx = 200;
+        proto.protos.push(Rc::new(child));
+        assert_eq!(
+            verify(&proto),
+            Err(VerifyError::InFunction {
+                index: 0,
+                line_defined: 2,
+                error: Box::new(VerifyError::BadUpvalue { index: 0 }),
+            })
+        );
+    }
+}
@@ -0,0 +1,633 @@
+use std::rc::Rc;
+
+use super::chunk::{Constant, LocVar, LuaVersion, Prototype, UpValue};
+use super::error::AsmError;
+use crate::vm::instruction::{
+    rk_constant, Instruction, MAX_ARG_A, MAX_ARG_AX, MAX_ARG_B, MAX_ARG_BX, MAX_ARG_SBX,
+    MAX_INDEX_RK,
+};
+use crate::vm::opcode::{self, Op};
+
+type Result<T> = std::result::Result<T, AsmError>;
+
+/// 把.luasm文本汇编为Lua5.3的函数原型，是列出字节码(binary::listing)的逆过程
+/// 文本格式与luac -l -l的输出相同，例如
+/// main <@hello.lua:0, 0> (4 instructions)
+/// 0+ params, 2 slots, 1 upvalues, 0 locals, 2 constants, 0 functions
+///     1   [1] GETTABUP    0 0 -1
+///     2   [1] LOADK       1 -2
+///     3   [1] CALL        0 2 1
+///     4   [1] RETURN      0 1
+/// constants (2):
+///     1   "print"
+///     2   "Hello, World!"
+/// locals (0):
+/// upvalues (1):
+///     0   _ENV    1   0
+/// 指令前的序号和[行号]可以省略，';'之后是注释，空行被忽略；子函数按先序紧跟在父函数之后
+pub fn assemble(text: &str) -> Result<Prototype> {
+    let lines: Vec<(usize, &str)> = text
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l))
+        .filter(|(_, l)| {
+            let l = l.trim();
+            !l.is_empty() && !l.starts_with(';')
+        })
+        .collect();
+    let mut parser = Parser {
+        lines,
+        pos: 0,
+        last_line: text.lines().count(),
+    };
+    let proto = parser.function()?;
+    if let Some((line, _)) = parser.peek() {
+        return Err(error(line, "unexpected text after main function"));
+    }
+    Ok(proto)
+}
+
+struct Parser<'a> {
+    // 去掉空行和注释行后的(行号, 内容)
+    lines: Vec<(usize, &'a str)>,
+    pos: usize,
+    // 文本的最后一行，用于报告意外结束
+    last_line: usize,
+}
+
+// 函数头第二行中的各项数量
+struct Summary {
+    num_params: u8,
+    is_vararg: u8,
+    max_stack_size: u8,
+    upvalues: usize,
+    locals: usize,
+    constants: usize,
+    functions: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<(usize, &'a str)> {
+        self.lines.get(self.pos).copied()
+    }
+
+    fn next(&mut self, what: &str) -> Result<(usize, &'a str)> {
+        match self.peek() {
+            Some(line) => {
+                self.pos += 1;
+                Ok(line)
+            }
+            None => Err(error(
+                self.last_line,
+                format!("unexpected end of text, expected {what}"),
+            )),
+        }
+    }
+
+    fn function(&mut self) -> Result<Prototype> {
+        let (header_line, text) = self.next("function header")?;
+        let (source, line_defined, last_line_defined, num_code) = header(header_line, text)?;
+        let (summary_line, text) = self.next("function summary")?;
+        let summary = summary(summary_line, text)?;
+
+        let mut code = Vec::new();
+        let mut lines = Vec::new();
+        while let Some((line, text)) = self.peek() {
+            if text.trim_start().starts_with("constants") {
+                break;
+            }
+            self.pos += 1;
+            let (i, l) = instruction(line, text, code.len())?;
+            code.push(i);
+            lines.push((line, l));
+        }
+        check_count(header_line, "instructions", num_code, code.len())?;
+        let line_info = line_info(&lines)?;
+
+        let constants = self.section("constants", 1, constant)?;
+        check_count(
+            summary_line,
+            "constants",
+            summary.constants,
+            constants.len(),
+        )?;
+        let loc_vars = self.section("locals", 0, local)?;
+        check_count(summary_line, "locals", summary.locals, loc_vars.len())?;
+        let upvalues = self.section("upvalues", 0, upvalue)?;
+        check_count(summary_line, "upvalues", summary.upvalues, upvalues.len())?;
+        let upvalue_names = upvalue_names(&upvalues)?;
+
+        let mut protos = Vec::new();
+        for _ in 0..summary.functions {
+            protos.push(Rc::new(self.function()?));
+        }
+
+        Ok(Prototype {
+            version: LuaVersion::Lua53,
+            source,
+            line_defined,
+            last_line_defined,
+            num_params: summary.num_params,
+            is_vararg: summary.is_vararg,
+            max_stack_size: summary.max_stack_size,
+            code,
+            constants,
+            upvalues: upvalues.into_iter().map(|(_, _, u)| u).collect(),
+            protos,
+            line_info,
+            loc_vars,
+            upvalue_names,
+        })
+    }
+
+    // 解析"name (n):"开头的一节，之后以序号开头的行都是这一节的条目
+    fn section<T>(
+        &mut self,
+        name: &str,
+        first: usize,
+        entry: fn(usize, &str) -> Result<T>,
+    ) -> Result<Vec<T>> {
+        let (line, text) = self.next(name)?;
+        let count = text
+            .trim()
+            .strip_prefix(name)
+            .and_then(|s| s.trim().strip_prefix('('))
+            .and_then(|s| s.strip_suffix("):"))
+            .and_then(|s| s.trim().parse::<usize>().ok())
+            .ok_or_else(|| error(line, format!("expected '{name} (n):'")))?;
+
+        let mut entries = Vec::new();
+        while let Some((entry_line, text)) = self.peek() {
+            let (index, rest) = split_token(text);
+            let Ok(index) = index.parse::<usize>() else {
+                break;
+            };
+            self.pos += 1;
+            if index != entries.len() + first {
+                return Err(error(
+                    entry_line,
+                    format!("expected index {}, found {index}", entries.len() + first),
+                ));
+            }
+            entries.push(entry(entry_line, rest)?);
+        }
+        check_count(line, name, count, entries.len())?;
+        Ok(entries)
+    }
+}
+
+// main|function <source:line_defined, last_line_defined> (n instructions)
+fn header(line: usize, text: &str) -> Result<(Vec<u8>, u32, u32, usize)> {
+    let bad = || {
+        error(
+            line,
+            "expected 'main|function <source:line, line> (n instructions)'",
+        )
+    };
+    let text = text.trim();
+    let rest = text
+        .strip_prefix("main")
+        .or_else(|| text.strip_prefix("function"))
+        .ok_or_else(bad)?
+        .trim_start()
+        .strip_prefix('<')
+        .ok_or_else(bad)?;
+    let close = rest.rfind('>').ok_or_else(bad)?;
+    let (source, lines) = rest[..close].rsplit_once(':').ok_or_else(bad)?;
+    let (line_defined, last_line_defined) = lines.split_once(',').ok_or_else(bad)?;
+    let num_code = rest[close + 1..]
+        .trim()
+        .strip_prefix('(')
+        .and_then(|s| s.strip_suffix("instructions)"))
+        .ok_or_else(bad)?;
+    Ok((
+        source.as_bytes().to_vec(),
+        number(line, line_defined, "line")?,
+        number(line, last_line_defined, "line")?,
+        number(line, num_code, "instruction count")?,
+    ))
+}
+
+// Np[+] params, S slots, U upvalues, L locals, K constants, F functions
+fn summary(line: usize, text: &str) -> Result<Summary> {
+    let mut counts = Vec::new();
+    let names = [
+        "params",
+        "slots",
+        "upvalues",
+        "locals",
+        "constants",
+        "functions",
+    ];
+    let items: Vec<&str> = text.split(',').map(str::trim).collect();
+    if items.len() != names.len() {
+        return Err(error(
+            line,
+            "expected 'N params, N slots, N upvalues, N locals, N constants, N functions'",
+        ));
+    }
+    let mut is_vararg = 0;
+    for (item, name) in items.iter().zip(names) {
+        let mut count = item
+            .strip_suffix(name)
+            .ok_or_else(|| error(line, format!("expected '{name}' in '{item}'")))?
+            .trim();
+        // 参数个数后面的'+'表示有可变参数
+        if name == "params" {
+            if let Some(c) = count.strip_suffix('+') {
+                is_vararg = 1;
+                count = c;
+            }
+        }
+        counts.push(number::<usize>(line, count, name)?);
+    }
+    let byte = |count: usize, name: &str| {
+        u8::try_from(count).map_err(|_| error(line, format!("too many {name}: {count}")))
+    };
+    Ok(Summary {
+        num_params: byte(counts[0], "params")?,
+        is_vararg,
+        max_stack_size: byte(counts[1], "slots")?,
+        upvalues: counts[2],
+        locals: counts[3],
+        constants: counts[4],
+        functions: counts[5],
+    })
+}
+
+// [pc] [[line]] OPNAME operands
+fn instruction(line: usize, text: &str, pc: usize) -> Result<(u32, Option<u32>)> {
+    let text = text.split(';').next().unwrap_or("");
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    let mut rest = &tokens[..];
+
+    if let [first, tail @ ..] = rest {
+        if first.bytes().all(|b| b.is_ascii_digit()) {
+            if number::<usize>(line, first, "pc")? != pc + 1 {
+                return Err(error(
+                    line,
+                    format!("expected pc {}, found {first}", pc + 1),
+                ));
+            }
+            rest = tail;
+        }
+    }
+
+    let mut line_no = None;
+    if let [first, tail @ ..] = rest {
+        if let Some(l) = first.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            if l != "-" {
+                line_no = Some(number(line, l, "line")?);
+            }
+            rest = tail;
+        }
+    }
+
+    let [name, operands @ ..] = rest else {
+        return Err(error(line, "missing opcode"));
+    };
+    let code = Op::ALL
+        .into_iter()
+        .find(|op| op.name().eq_ignore_ascii_case(name))
+        .ok_or_else(|| error(line, format!("unknown opcode '{name}'")))?;
+    let op = code.info();
+    let args = operands
+        .iter()
+        .map(|arg| number::<isize>(line, arg, "operand"))
+        .collect::<Result<Vec<isize>>>()?;
+
+    let expected = match op.op_mode {
+        opcode::OP_MODE_ABC => {
+            1 + (op.b_arg_mode != opcode::OP_ARG_N) as usize
+                + (op.c_arg_mode != opcode::OP_ARG_N) as usize
+        }
+        opcode::OP_MODE_ABX => 1 + (op.b_arg_mode != opcode::OP_ARG_N) as usize,
+        opcode::OP_MODE_ASBX => 2,
+        _ => 1,
+    };
+    if args.len() != expected {
+        return Err(error(
+            line,
+            format!("{name} expects {expected} operand(s), found {}", args.len()),
+        ));
+    }
+
+    let i = match op.op_mode {
+        opcode::OP_MODE_ABC => {
+            let a = operand(line, "A", args[0], 0, MAX_ARG_A)?;
+            let mut rest = args[1..].iter();
+            let mut rk = |mode: u8, what: &str| -> Result<isize> {
+                if mode == opcode::OP_ARG_N {
+                    return Ok(0);
+                }
+                // 常量k显示为-1-k，OpArgU模式的参数可以使用整个字段
+                let v = operand(
+                    line,
+                    what,
+                    *rest.next().unwrap(),
+                    -1 - MAX_INDEX_RK,
+                    MAX_ARG_B,
+                )?;
+                Ok(if v < 0 {
+                    rk_constant(-1 - v).unwrap()
+                } else {
+                    v
+                })
+            };
+            let b = rk(op.b_arg_mode, "B")?;
+            let c = rk(op.c_arg_mode, "C")?;
+            u32::encode_abc(code, a, b, c)
+        }
+        opcode::OP_MODE_ABX => {
+            let a = operand(line, "A", args[0], 0, MAX_ARG_A)?;
+            let bx = match op.b_arg_mode {
+                // 常量索引显示为-1-Bx
+                opcode::OP_ARG_K => -1 - operand(line, "Bx", args[1], -1 - MAX_ARG_BX, -1)?,
+                opcode::OP_ARG_U => operand(line, "Bx", args[1], 0, MAX_ARG_BX)?,
+                _ => 0,
+            };
+            u32::encode_abx(code, a, bx)
+        }
+        opcode::OP_MODE_ASBX => {
+            let a = operand(line, "A", args[0], 0, MAX_ARG_A)?;
+            let sbx = operand(line, "sBx", args[1], -MAX_ARG_SBX, MAX_ARG_BX - MAX_ARG_SBX)?;
+            u32::encode_asbx(code, a, sbx)
+        }
+        _ => {
+            let ax = -1 - operand(line, "Ax", args[0], -1 - MAX_ARG_AX, -1)?;
+            u32::encode_ax(code, ax)
+        }
+    };
+    // 按参数模式检查，例如R模式的操作数不能是常量
+    let i = i.map_err(|err| error(line, err.to_string()))?;
+    Ok((i, line_no))
+}
+
+// 行号要么每条指令都有，要么都没有(去掉了调试信息)
+fn line_info(lines: &[(usize, Option<u32>)]) -> Result<Vec<u32>> {
+    if lines.iter().all(|(_, l)| l.is_none()) {
+        return Ok(Vec::new());
+    }
+    lines
+        .iter()
+        .map(|(line, l)| l.ok_or_else(|| error(*line, "missing line number")))
+        .collect()
+}
+
+fn constant(line: usize, text: &str) -> Result<Constant> {
+    let text = text.trim();
+    if text.starts_with('"') {
+        let (s, rest) = unquote(line, text)?;
+        let rest = rest.trim();
+        if !rest.is_empty() && !rest.starts_with(';') {
+            return Err(error(line, format!("unexpected '{rest}' after string")));
+        }
+        return Ok(Constant::Str(s));
+    }
+
+    let text = text.split(';').next().unwrap_or("").trim();
+    match text {
+        "nil" => Ok(Constant::Nil),
+        "true" => Ok(Constant::Boolean(true)),
+        "false" => Ok(Constant::Boolean(false)),
+        _ => {
+            if let Ok(i) = text.parse::<i64>() {
+                return Ok(Constant::Integer(i));
+            }
+            // 浮点数总是带有小数点或指数，或者是inf、nan
+            match text.parse::<f64>() {
+                Ok(n) if text.contains(['.', 'e', 'E', 'n', 'N']) => Ok(Constant::Number(n)),
+                _ => Err(error(line, format!("invalid constant '{text}'"))),
+            }
+        }
+    }
+}
+
+// 解析以双引号包围的字符串，转义规则与Lua源码相同，返回字符串和剩余的文本
+fn unquote(line: usize, text: &str) -> Result<(Vec<u8>, &str)> {
+    let mut bytes = Vec::new();
+    let mut chars = text.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((bytes, &text[i + 1..])),
+            '\\' => {
+                let Some((_, e)) = chars.next() else {
+                    break;
+                };
+                let b = match e {
+                    'a' => 0x07,
+                    'b' => 0x08,
+                    'f' => 0x0C,
+                    'n' => b'\n',
+                    'r' => b'\r',
+                    't' => b'\t',
+                    'v' => 0x0B,
+                    '\\' | '"' | '\'' => e as u8,
+                    '0'..='9' => {
+                        let mut n = e.to_digit(10).unwrap();
+                        for _ in 0..2 {
+                            match chars.peek().and_then(|(_, d)| d.to_digit(10)) {
+                                Some(d) => {
+                                    n = n * 10 + d;
+                                    chars.next();
+                                }
+                                None => break,
+                            }
+                        }
+                        u8::try_from(n).map_err(|_| error(line, "decimal escape too large"))?
+                    }
+                    _ => return Err(error(line, format!("invalid escape sequence '\\{e}'"))),
+                };
+                bytes.push(b);
+            }
+            c => {
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+    Err(error(line, "unfinished string"))
+}
+
+// name start end，start和end是从1开始的pc
+fn local(line: usize, text: &str) -> Result<LocVar> {
+    let (name, start_pc, end_pc) = split_entry(line, text, "'name start end'")?;
+    let pc = |s: &str| -> Result<u32> {
+        match number::<u32>(line, s, "pc")? {
+            0 => Err(error(line, "pc starts from 1")),
+            pc => Ok(pc - 1),
+        }
+    };
+    Ok(LocVar {
+        var_name: name.to_string(),
+        start_pc: pc(start_pc)?,
+        end_pc: pc(end_pc)?,
+    })
+}
+
+// name instack idx，没有名字时name为'-'
+fn upvalue(line: usize, text: &str) -> Result<(usize, Option<String>, UpValue)> {
+    let (name, instack, idx) = split_entry(line, text, "'name instack idx'")?;
+    let name = if name == "-" {
+        None
+    } else {
+        Some(name.to_string())
+    };
+    let upvalue = UpValue {
+        instack: number(line, instack, "instack")?,
+        idx: number(line, idx, "idx")?,
+        kind: 0,
+    };
+    Ok((line, name, upvalue))
+}
+
+// 只有最后若干个upvalue可以没有名字
+fn upvalue_names(upvalues: &[(usize, Option<String>, UpValue)]) -> Result<Vec<String>> {
+    let names: Vec<String> = upvalues
+        .iter()
+        .map_while(|(_, name, _)| name.clone())
+        .collect();
+    if let Some((line, _, _)) = upvalues[names.len()..].iter().find(|(_, n, _)| n.is_some()) {
+        return Err(error(*line, "named upvalue after an unnamed one"));
+    }
+    Ok(names)
+}
+
+// 条目中的名字可能包含空格(如"(for index)")，所以从右边拆出最后两个字段
+fn split_entry<'a>(
+    line: usize,
+    text: &'a str,
+    expected: &str,
+) -> Result<(&'a str, &'a str, &'a str)> {
+    let bad = || error(line, format!("expected {expected}"));
+    let (rest, last) = text
+        .trim()
+        .rsplit_once(char::is_whitespace)
+        .ok_or_else(bad)?;
+    let (name, middle) = rest
+        .trim_end()
+        .rsplit_once(char::is_whitespace)
+        .ok_or_else(bad)?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(bad());
+    }
+    Ok((name, middle, last))
+}
+
+// 拆出第一个空白分隔的词
+fn split_token(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    text.split_once(char::is_whitespace).unwrap_or((text, ""))
+}
+
+fn number<T: std::str::FromStr>(line: usize, s: &str, what: &str) -> Result<T> {
+    s.trim()
+        .parse()
+        .map_err(|_| error(line, format!("invalid {what} '{}'", s.trim())))
+}
+
+fn operand(line: usize, what: &str, v: isize, min: isize, max: isize) -> Result<isize> {
+    if v < min || v > max {
+        return Err(error(
+            line,
+            format!("operand {what} out of range: {v} (expected {min}..={max})"),
+        ));
+    }
+    Ok(v)
+}
+
+fn check_count(line: usize, what: &str, expected: usize, found: usize) -> Result<()> {
+    if expected != found {
+        return Err(error(
+            line,
+            format!("expected {expected} {what}, found {found}"),
+        ));
+    }
+    Ok(())
+}
+
+fn error(line: usize, msg: impl Into<String>) -> AsmError {
+    AsmError {
+        line,
+        msg: msg.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::binary::asm::assemble;
+    use crate::binary::error::AsmError;
+    use crate::binary::listing::Listing;
+    use crate::compiler;
+    use crate::vm::instruction::Instruction;
+
+    #[test]
+    fn test_round_trip() {
+        let source = b"local t = {1.5, 2e300, -0.25, \"a\\\"b\\\\c\\n\\0\", \"\\xff\xc3\xa9\"}\n\
+            for i = 1, 300 do t[i] = i * 0.5 end\n\
+            local function f(a, ...)\n  return function() return a, t end\nend\n\
+            print(f(1)(), #t, 1 << 62)";
+        let proto = compiler::compile(source, "@test.lua").unwrap();
+        let text = Listing(&proto).to_string();
+        assert_eq!(assemble(&text).unwrap(), proto);
+    }
+
+    #[test]
+    fn test_hand_written() {
+        let text = "; 返回两个数的和\n\
+            main <=asm:0, 0> (3 instructions)\n\
+            0+ params, 2 slots, 0 upvalues, 0 locals, 2 constants, 0 functions\n\
+            \tLOADK 0 -1\n\
+            \tADD 0 0 -2   ; R0 + K1\n\
+            \tRETURN 0 2\n\
+            constants (2):\n\
+            \t1\t1\n\
+            \t2\t\"x; y\"\n\
+            locals (0):\n\
+            upvalues (0):\n";
+        let proto = assemble(text).unwrap();
+        assert_eq!(proto.is_vararg, 1);
+        assert!(proto.line_info.is_empty());
+        assert_eq!(proto.code[0].abx(), (0, 0));
+        assert_eq!(proto.code[1].abc(), (0, 0, 0x101));
+        assert_eq!(proto.code[2].op_name().map(str::trim_end), Some("RETURN"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let header = "main <=asm:0, 0> (1 instructions)\n\
+            0 params, 2 slots, 0 upvalues, 0 locals, 0 constants, 0 functions\n";
+        let error = |code: &str| {
+            let text = format!("{header}{code}\nconstants (0):\nlocals (0):\nupvalues (0):\n");
+            assemble(&text).unwrap_err()
+        };
+        let err = |line, msg: &str| AsmError {
+            line,
+            msg: msg.to_string(),
+        };
+
+        assert_eq!(error("FOO 0"), err(3, "unknown opcode 'FOO'"));
+        assert_eq!(
+            error("MOVE 0"),
+            err(3, "MOVE expects 2 operand(s), found 1")
+        );
+        assert_eq!(
+            error("MOVE 256 0"),
+            err(3, "operand A out of range: 256 (expected 0..=255)")
+        );
+        assert_eq!(
+            error("LOADK 0 0"),
+            err(3, "operand Bx out of range: 0 (expected -262144..=-1)")
+        );
+        assert_eq!(error("2 [1] MOVE 0 1"), err(3, "expected pc 1, found 2"));
+        assert_eq!(
+            error("RETURN 0 1\nRETURN 0 1"),
+            err(1, "expected 1 instructions, found 2")
+        );
+        assert_eq!(
+            assemble(&header.replace("(1 instructions)", "(0 instructions)")).unwrap_err(),
+            err(2, "unexpected end of text, expected constants")
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::rc::Rc;
+
 /// Header 常量
 pub const LUA_SIGNATURE: [u8; 4] = [0x1B, b'L', b'u', b'a'];
 pub const LUAC_VERSION: u8 = 0x53;
@@ -19,6 +21,69 @@ pub const TAG_INTEGER: u8 = 0x13;
 pub const TAG_SHORT_STR: u8 = 0x04;
 pub const TAG_LONG_STR: u8 = 0x14;
 
+// Lua5.1/5.2 Header 常量，版本号之后记录字节序和各类型的大小，5.2在末尾追加了LUAC_TAIL(与LUAC_DATA相同)
+pub const LUAC_VERSION_51: u8 = 0x51;
+pub const LUAC_VERSION_52: u8 = 0x52;
+// 1表示小端
+pub const LUAC_ENDIANNESS: u8 = 0x01;
+// 0表示lua_Number是浮点数
+pub const LUAC_INTEGRAL: u8 = 0x00;
+
+// Lua5.1/5.2 Prototype constants，只有一种数字类型
+pub const TAG_STRING_51: u8 = 0x04;
+
+// Lua5.4 Header 常量，5.4 不再记录 C int 和 size_t 的大小
+pub const LUAC_VERSION_54: u8 = 0x54;
+
+// Lua5.4 Prototype constants，布尔值的真假分成两个标签，整数和浮点数的标签与5.3互换
+pub const TAG_FALSE_54: u8 = 0x01;
+pub const TAG_TRUE_54: u8 = 0x11;
+pub const TAG_INTEGER_54: u8 = 0x03;
+pub const TAG_NUMBER_54: u8 = 0x13;
+
+// Lua5.4 行号表中的特殊值，表示该指令的行号记录在绝对行号表中
+pub const ABS_LINE_INFO: i8 = -0x80;
+
+/// chunk对应的Lua版本
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LuaVersion {
+    Lua51,
+    Lua52,
+    #[default]
+    Lua53,
+    Lua54,
+}
+
+impl LuaVersion {
+    // 根据Header中的版本号获取版本
+    pub fn from_byte(version: u8) -> Option<LuaVersion> {
+        match version {
+            LUAC_VERSION_51 => Some(LuaVersion::Lua51),
+            LUAC_VERSION_52 => Some(LuaVersion::Lua52),
+            LUAC_VERSION => Some(LuaVersion::Lua53),
+            LUAC_VERSION_54 => Some(LuaVersion::Lua54),
+            _ => None,
+        }
+    }
+
+    // Header中的版本号
+    pub fn to_byte(self) -> u8 {
+        match self {
+            LuaVersion::Lua51 => LUAC_VERSION_51,
+            LuaVersion::Lua52 => LUAC_VERSION_52,
+            LuaVersion::Lua53 => LUAC_VERSION,
+            LuaVersion::Lua54 => LUAC_VERSION_54,
+        }
+    }
+}
+
+impl std::fmt::Display for LuaVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let version = self.to_byte();
+        write!(f, "{}.{}", version >> 4, version & 0x0F)
+    }
+}
+
 /// Lua chunk文件结构
 #[derive(Debug)]
 pub struct BinaryChunk {
@@ -56,23 +121,25 @@ pub struct Header {
 
 /// lua 函数原型，包括
 /// 1. 函数基本信息
-///     1.1 源文件名
-///     1.2 起止行号
-///     1.3 固定参数个数
-///     1.4 是否是vararg函数
-///     1.5 函数运行需要的寄存器数量
+///    1.1 源文件名
+///    1.2 起止行号
+///    1.3 固定参数个数
+///    1.4 是否是vararg函数
+///    1.5 函数运行需要的寄存器数量
 /// 2. 指令表
 /// 3. 常量表
 /// 4. upvalue表
 /// 5. 子函数原型表
 /// 6. 调试信息
-///     6.1 行号表
-///     6.2 局部变量表
-///     6.3 upvalue名列表
-#[derive(Debug)]
+///    6.1 行号表
+///    6.2 局部变量表
+///    6.3 upvalue名列表
+#[derive(Debug, Clone, PartialEq)]
 pub struct Prototype {
-    // 源文件名
-    pub source: String,
+    // chunk的Lua版本，决定了指令的编码方式
+    pub version: LuaVersion,
+    // 源文件名，与字符串常量一样是原始字节，不要求是UTF-8
+    pub source: Vec<u8>,
     // 开始行号
     pub line_defined: u32,
     // 结束行号
@@ -90,8 +157,9 @@ pub struct Prototype {
     // upvalue表
     pub upvalues: Vec<UpValue>,
     // 函数原型表
-    pub protos: Vec<Prototype>,
-    // 行号表
+    pub protos: Vec<Rc<Prototype>>,
+    // 行号表，记录每条指令对应的行号
+    // 5.4 chunk中的相对行号在加载时已换算为绝对行号
     pub line_info: Vec<u32>,
     // 局部变量表
     pub loc_vars: Vec<LocVar>,
@@ -100,26 +168,50 @@ pub struct Prototype {
 }
 
 /// 常量,
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Constant {
     Nil,
     Boolean(bool),
     Number(f64),
     Integer(i64),
-    Str(String),
+    // Lua字符串可以包含任意字节
+    Str(Vec<u8>),
 }
 
 /// 类似闭包中的变量
-#[derive(Debug)]
+/// 5.1的chunk中没有upvalue描述，加载时根据父函数中CLOSURE之后的伪指令还原
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct UpValue {
     pub instack: u8,
     pub idx: u8,
+    // 5.4新增的变量类型: 0普通变量, 1常量, 2待关闭变量, 3编译期常量；其他版本中总是0
+    pub kind: u8,
 }
 
 /// 局部变量
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct LocVar {
     pub var_name: String,
     pub start_pc: u32,
     pub end_pc: u32,
-}
\ No newline at end of file
+}
+
+// Lua源文件名的显示形式(luaO_chunkid)
+// "@file" 表示文件名，"=name" 表示原样显示，其余情况是源码字符串本身
+pub fn short_source(source: &str) -> String {
+    if let Some(name) = source.strip_prefix('@').or_else(|| source.strip_prefix('=')) {
+        return name.to_string();
+    }
+    let first_line = source.lines().next().unwrap_or("");
+    if first_line.len() < source.len() || first_line.len() > 40 {
+        let end = first_line
+            .char_indices()
+            .map(|(i, _)| i)
+            .take_while(|i| *i <= 40)
+            .last()
+            .unwrap_or(0);
+        format!("[string \"{}...\"]", &first_line[..end])
+    } else {
+        format!("[string \"{first_line}\"]")
+    }
+}
@@ -0,0 +1,138 @@
+use std::fmt;
+
+/// 解析chunk时可能出现的错误
+/// 每种错误都携带出错位置在chunk中的字节偏移量，方便定位损坏的数据
+#[derive(Debug, Clone, PartialEq)]
+pub enum UndumpError {
+    // 签名不是 ESC Lua
+    BadSignature { offset: usize },
+    // 不支持的版本号
+    UnsupportedVersion { offset: usize, found: u8 },
+    // 格式号不匹配
+    FormatMismatch { offset: usize, expected: u8, found: u8 },
+    // LUAC_DATA 校验失败，通常说明文件在传输过程中被改写过(如换行符转换)
+    Corrupted { offset: usize },
+    // Instruction 等占用字节数不匹配
+    SizeMismatch {
+        offset: usize,
+        what: &'static str,
+        expected: u8,
+        found: u8,
+    },
+    // int、size_t、Lua整数、Lua浮点数等占用字节数不受支持
+    UnsupportedSize {
+        offset: usize,
+        what: &'static str,
+        found: u8,
+    },
+    // LUAC_INT 校验失败，通常说明整数格式不受支持
+    IntegerFormatMismatch { offset: usize },
+    // LUAC_NUM 校验失败，通常说明浮点数格式不匹配
+    NumberFormatMismatch { offset: usize },
+    // 数据被截断，需要从offset处读取needed个字节
+    Truncated { offset: usize, needed: usize },
+    // 局部变量名或upvalue名不是合法的UTF-8
+    InvalidUtf8 { offset: usize },
+    // 未知的常量类型
+    UnknownConstantTag { offset: usize, tag: u8 },
+    // 函数原型嵌套过深
+    NestingTooDeep { offset: usize },
+    // 5.4中的变长整数溢出
+    IntegerOverflow { offset: usize },
+}
+
+impl UndumpError {
+    /// 出错位置在chunk中的字节偏移量
+    pub fn offset(&self) -> usize {
+        match *self {
+            UndumpError::BadSignature { offset }
+            | UndumpError::UnsupportedVersion { offset, .. }
+            | UndumpError::FormatMismatch { offset, .. }
+            | UndumpError::Corrupted { offset }
+            | UndumpError::SizeMismatch { offset, .. }
+            | UndumpError::UnsupportedSize { offset, .. }
+            | UndumpError::IntegerFormatMismatch { offset }
+            | UndumpError::NumberFormatMismatch { offset }
+            | UndumpError::Truncated { offset, .. }
+            | UndumpError::InvalidUtf8 { offset }
+            | UndumpError::UnknownConstantTag { offset, .. }
+            | UndumpError::NestingTooDeep { offset }
+            | UndumpError::IntegerOverflow { offset } => offset,
+        }
+    }
+}
+
+impl fmt::Display for UndumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UndumpError::BadSignature { offset } => {
+                write!(f, "not a precompiled chunk (at offset {offset})")
+            }
+            UndumpError::UnsupportedVersion { offset, found } => write!(
+                f,
+                "unsupported version 0x{found:02X} (at offset {offset})"
+            ),
+            UndumpError::FormatMismatch {
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "format mismatched: expected {expected}, found {found} (at offset {offset})"
+            ),
+            UndumpError::Corrupted { offset } => write!(f, "corrupted (at offset {offset})"),
+            UndumpError::SizeMismatch {
+                offset,
+                what,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{what} size mismatched: expected {expected}, found {found} (at offset {offset})"
+            ),
+            UndumpError::UnsupportedSize { offset, what, found } => write!(
+                f,
+                "unsupported {what} size {found} (at offset {offset})"
+            ),
+            UndumpError::IntegerFormatMismatch { offset } => {
+                write!(f, "luac_int mismatched (at offset {offset})")
+            }
+            UndumpError::NumberFormatMismatch { offset } => {
+                write!(f, "luac_num mismatched (at offset {offset})")
+            }
+            UndumpError::Truncated { offset, needed } => write!(
+                f,
+                "truncated chunk: need {needed} more byte(s) at offset {offset}"
+            ),
+            UndumpError::InvalidUtf8 { offset } => {
+                write!(f, "invalid UTF-8 string (at offset {offset})")
+            }
+            UndumpError::UnknownConstantTag { offset, tag } => {
+                write!(f, "unknown constant tag 0x{tag:02X} (at offset {offset})")
+            }
+            UndumpError::NestingTooDeep { offset } => {
+                write!(f, "function prototypes nested too deep (at offset {offset})")
+            }
+            UndumpError::IntegerOverflow { offset } => {
+                write!(f, "integer overflow (at offset {offset})")
+            }
+        }
+    }
+}
+
+impl std::error::Error for UndumpError {}
+
+/// 汇编.luasm文本时出现的错误，携带出错的行号(从1开始)
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl std::error::Error for AsmError {}
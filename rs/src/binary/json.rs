@@ -0,0 +1,289 @@
+use std::fmt;
+
+use super::chunk::{Constant, LuaVersion, Prototype};
+use crate::vm::instruction::{Instruction, Instruction54};
+use crate::vm::opcode::{self, lua51, lua52, lua54, OpCode};
+
+/// JSON值，只实现了输出
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    // 保持字段顺序，保证输出稳定
+    Obj(Vec<(&'static str, Json)>),
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{b}"),
+            Json::Int(i) => write!(f, "{i}"),
+            // JSON不能表示inf和nan，以字符串输出
+            Json::Num(n) if !n.is_finite() => write!(f, "\"{n}\""),
+            Json::Num(n) if n.fract() == 0.0 && n.abs() < 1e16 => write!(f, "{n:.1}"),
+            Json::Num(n) => write!(f, "{n}"),
+            Json::Str(s) => write_str(f, s),
+            Json::Arr(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+            Json::Obj(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// 把函数原型树转换为JSON，字段如下
+/// {"version": "5.3", "main": function}
+/// function: source, line_defined, last_line_defined, num_params, is_vararg, max_stack_size,
+///           instructions, constants, locals, upvalues, protos
+/// 所有pc和下标都从0开始
+pub fn to_json(proto: &Prototype) -> Json {
+    Json::Obj(vec![
+        ("version", Json::Str(proto.version.to_string())),
+        ("main", function(proto)),
+    ])
+}
+
+fn function(proto: &Prototype) -> Json {
+    Json::Obj(vec![
+        ("source", Json::Str(String::from_utf8_lossy(&proto.source).into_owned())),
+        ("line_defined", Json::Int(proto.line_defined as i64)),
+        ("last_line_defined", Json::Int(proto.last_line_defined as i64)),
+        ("num_params", Json::Int(proto.num_params as i64)),
+        ("is_vararg", Json::Bool(proto.is_vararg != 0)),
+        ("max_stack_size", Json::Int(proto.max_stack_size as i64)),
+        (
+            "instructions",
+            Json::Arr(
+                proto
+                    .code
+                    .iter()
+                    .enumerate()
+                    .map(|(pc, i)| instruction(proto, pc, *i))
+                    .collect(),
+            ),
+        ),
+        (
+            "constants",
+            Json::Arr(proto.constants.iter().map(constant).collect()),
+        ),
+        (
+            "locals",
+            Json::Arr(
+                proto
+                    .loc_vars
+                    .iter()
+                    .map(|l| {
+                        Json::Obj(vec![
+                            ("name", Json::Str(l.var_name.clone())),
+                            ("start_pc", Json::Int(l.start_pc as i64)),
+                            ("end_pc", Json::Int(l.end_pc as i64)),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ),
+        (
+            "upvalues",
+            Json::Arr(
+                proto
+                    .upvalues
+                    .iter()
+                    .enumerate()
+                    .map(|(i, u)| {
+                        let name = match proto.upvalue_names.get(i) {
+                            Some(name) => Json::Str(name.clone()),
+                            None => Json::Null,
+                        };
+                        Json::Obj(vec![
+                            ("name", name),
+                            ("instack", Json::Bool(u.instack != 0)),
+                            ("idx", Json::Int(u.idx as i64)),
+                            ("kind", Json::Int(u.kind as i64)),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ),
+        (
+            "protos",
+            Json::Arr(proto.protos.iter().map(|p| function(p)).collect()),
+        ),
+    ])
+}
+
+// 指令: pc, line(没有行号信息时为null), opcode, mode, raw, operands
+// operands的字段由编码模式决定: iABC为a/b/c(5.4还有k)，iABx为a/bx，iAsBx为a/sbx，iAx为ax，isJ为sj
+fn instruction(proto: &Prototype, pc: usize, i: u32) -> Json {
+    let line = match proto.line_info.get(pc) {
+        Some(line) => Json::Int(*line as i64),
+        None => Json::Null,
+    };
+    let (name, mode, operands) = match proto.version {
+        LuaVersion::Lua54 => decode_54(Instruction54(i)),
+        version => {
+            let op_codes = match version {
+                LuaVersion::Lua51 => lua51::OP_CODES,
+                LuaVersion::Lua52 => lua52::OP_CODES,
+                _ => opcode::OP_CODES,
+            };
+            decode(i, op_codes.get(i.op_code() as usize))
+        }
+    };
+    Json::Obj(vec![
+        ("pc", Json::Int(pc as i64)),
+        ("line", line),
+        ("opcode", name.map_or(Json::Null, |name| Json::Str(name.trim_end().to_string()))),
+        ("mode", Json::Str(mode.to_string())),
+        ("raw", Json::Int(i as i64)),
+        ("operands", Json::Obj(operands)),
+    ])
+}
+
+type Decoded = (Option<&'static str>, &'static str, Vec<(&'static str, Json)>);
+
+fn decode(i: u32, op: Option<&OpCode>) -> Decoded {
+    let Some(op) = op else {
+        return (None, "unknown", vec![]);
+    };
+    let (mode, operands) = match op.op_mode {
+        opcode::OP_MODE_ABX => {
+            let (a, bx) = i.abx();
+            ("iABx", vec![("a", Json::Int(a as i64)), ("bx", Json::Int(bx as i64))])
+        }
+        opcode::OP_MODE_ASBX => {
+            let (a, sbx) = i.asbx();
+            ("iAsBx", vec![("a", Json::Int(a as i64)), ("sbx", Json::Int(sbx as i64))])
+        }
+        opcode::OP_MODE_AX => ("iAx", vec![("ax", Json::Int(i.ax() as i64))]),
+        _ => {
+            let (a, b, c) = i.abc();
+            (
+                "iABC",
+                vec![
+                    ("a", Json::Int(a as i64)),
+                    ("b", Json::Int(b as i64)),
+                    ("c", Json::Int(c as i64)),
+                ],
+            )
+        }
+    };
+    (Some(op.name), mode, operands)
+}
+
+fn decode_54(i: Instruction54) -> Decoded {
+    let Some(mode) = i.op_mode() else {
+        return (None, "unknown", vec![]);
+    };
+    let (mode, operands) = match mode {
+        opcode::OP_MODE_ABX => {
+            let (a, bx) = i.abx();
+            ("iABx", vec![("a", Json::Int(a as i64)), ("bx", Json::Int(bx as i64))])
+        }
+        opcode::OP_MODE_ASBX => {
+            let (a, sbx) = i.asbx();
+            ("iAsBx", vec![("a", Json::Int(a as i64)), ("sbx", Json::Int(sbx as i64))])
+        }
+        opcode::OP_MODE_AX => ("iAx", vec![("ax", Json::Int(i.ax() as i64))]),
+        lua54::OP_MODE_SJ => ("isJ", vec![("sj", Json::Int(i.sj() as i64))]),
+        _ => {
+            let (a, b, c, k) = i.abck();
+            (
+                "iABC",
+                vec![
+                    ("a", Json::Int(a as i64)),
+                    ("b", Json::Int(b as i64)),
+                    ("c", Json::Int(c as i64)),
+                    ("k", Json::Bool(k)),
+                ],
+            )
+        }
+    };
+    (i.op_name(), mode, operands)
+}
+
+// 常量: type(nil/boolean/integer/number/string), value
+// 不是合法UTF-8的字符串常量的value是字节数组
+fn constant(constant: &Constant) -> Json {
+    let (ty, value) = match constant {
+        Constant::Nil => ("nil", Json::Null),
+        Constant::Boolean(b) => ("boolean", Json::Bool(*b)),
+        Constant::Integer(i) => ("integer", Json::Int(*i)),
+        Constant::Number(n) => ("number", Json::Num(*n)),
+        Constant::Str(s) => match std::str::from_utf8(s) {
+            Ok(s) => ("string", Json::Str(s.to_string())),
+            Err(_) => ("string", Json::Arr(s.iter().map(|b| Json::Int(*b as i64)).collect())),
+        },
+    };
+    Json::Obj(vec![("type", Json::Str(ty.to_string())), ("value", value)])
+}
+
+#[cfg(test)]
+mod test {
+    use crate::binary::json::{to_json, Json};
+    use crate::compiler;
+
+    #[test]
+    fn test_display() {
+        let json = Json::Obj(vec![
+            ("s", Json::Str("a\"b\\c\n\u{1}".to_string())),
+            ("n", Json::Arr(vec![Json::Num(1.0), Json::Num(0.5), Json::Num(f64::INFINITY)])),
+            ("i", Json::Int(-3)),
+            ("x", Json::Null),
+        ]);
+        assert_eq!(
+            json.to_string(),
+            r#"{"s":"a\"b\\c\n\u0001","n":[1.0,0.5,"inf"],"i":-3,"x":null}"#
+        );
+    }
+
+    #[test]
+    fn test_to_json() {
+        let proto = compiler::compile(b"local x = 1\nprint(x, 'hi')", "@test.lua").unwrap();
+        let json = to_json(&proto).to_string();
+        assert!(json.starts_with(r#"{"version":"5.3","main":{"source":"@test.lua","line_defined":0,"#));
+        assert!(json.contains(
+            r#"{"pc":0,"line":1,"opcode":"LOADK","mode":"iABx","raw":1,"operands":{"a":0,"bx":0}}"#
+        ));
+        assert!(json.contains(r#"{"type":"integer","value":1}"#));
+        assert!(json.contains(r#""locals":[{"name":"x","start_pc":1,"end_pc":6}]"#));
+        assert!(json.contains(r#""upvalues":[{"name":"_ENV","instack":true,"idx":0,"kind":0}]"#));
+    }
+}
@@ -0,0 +1,423 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use super::chunk::{Constant, LuaVersion, Prototype};
+use crate::vm::decoded::{Decoded, Rk};
+use crate::vm::instruction::{Instruction, Instruction54};
+use crate::vm::lua_value::number_to_string;
+use crate::vm::opcode::{self, lua51, lua52, lua54, OpCode};
+
+/// 与luac -l -l格式相同的文本列表，嵌套的函数按先序依次列出
+/// 5.3的指令后带有注释，注明用到的常量、upvalue的名字和跳转的目标
+pub struct Listing<'a>(pub &'a Prototype);
+
+impl fmt::Display for Listing<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        list(f, self.0)
+    }
+}
+
+/// 只列出一个函数，不包括嵌套的子函数
+pub struct FunctionListing<'a>(pub &'a Prototype);
+
+impl fmt::Display for FunctionListing<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        list_function(f, self.0)
+    }
+}
+
+fn list(f: &mut fmt::Formatter<'_>, proto: &Prototype) -> fmt::Result {
+    list_function(f, proto)?;
+    for p in proto.protos.iter() {
+        list(f, p)?;
+    }
+    Ok(())
+}
+
+fn list_function(f: &mut fmt::Formatter<'_>, proto: &Prototype) -> fmt::Result {
+    print_header(f, proto)?;
+    print_code(f, proto)?;
+    print_detail(f, proto)
+}
+
+fn print_header(f: &mut fmt::Formatter<'_>, proto: &Prototype) -> fmt::Result {
+    let func_type = if proto.line_defined == 0 {
+        "main"
+    } else {
+        "function"
+    };
+
+    let vararg_flag = if proto.is_vararg > 0 { "+" } else { "" };
+
+    writeln!(
+        f,
+        "{func_type} <{}:{}, {}> ({} instructions)",
+        source_name(proto),
+        proto.line_defined,
+        proto.last_line_defined,
+        proto.code.len()
+    )?;
+
+    write!(
+        f,
+        "{}{} params, {} slots, {} upvalues, ",
+        proto.num_params,
+        vararg_flag,
+        proto.max_stack_size,
+        proto.upvalues.len()
+    )?;
+    writeln!(
+        f,
+        "{} locals, {} constants, {} functions",
+        proto.loc_vars.len(),
+        proto.constants.len(),
+        proto.protos.len()
+    )?;
+    Ok(())
+}
+
+fn print_code(f: &mut fmt::Formatter<'_>, proto: &Prototype) -> fmt::Result {
+    for (i, c) in proto.code.iter().enumerate() {
+        let line = match proto.line_info.get(i) {
+            Some(line) => line.to_string(),
+            None => "-".to_string(),
+        };
+
+        let instruction = *c;
+        match proto.version {
+            LuaVersion::Lua53 => match Decoded::decode(instruction) {
+                Some(decoded) => {
+                    let name = decoded.op().info().name;
+                    write!(f, "\t{}\t[{}]\t{} \t{}", i + 1, line, name, decoded)?;
+                    print_comment(f, proto, i, decoded)?;
+                }
+                None => write!(f, "\t{}\t[{}]\tUNKNOWN  \t", i + 1, line)?,
+            },
+            LuaVersion::Lua51 | LuaVersion::Lua52 => {
+                // 5.1、5.2的指令布局与5.3相同，只是操作码表不同
+                let op_codes = match proto.version {
+                    LuaVersion::Lua51 => lua51::OP_CODES,
+                    _ => lua52::OP_CODES,
+                };
+                match op_codes.get(instruction.op_code() as usize) {
+                    Some(op) => {
+                        write!(f, "\t{}\t[{}]\t{} \t", i + 1, line, op.name)?;
+                        print_operands(f, instruction, op)?;
+                    }
+                    None => write!(f, "\t{}\t[{}]\tUNKNOWN  \t", i + 1, line)?,
+                }
+            }
+            LuaVersion::Lua54 => {
+                let instruction = Instruction54(instruction);
+                let name = instruction.op_name().unwrap_or("UNKNOWN");
+                write!(f, "\t{}\t[{}]\t{:<9}\t", i + 1, line, name)?;
+                print_operands_54(f, instruction)?;
+            }
+        }
+        writeln!(f)?;
+    }
+    Ok(())
+}
+
+// 与luac5.3的PrintCode一致，在指令后注释常量的值、upvalue的名字和跳转的目标(从1开始)
+fn print_comment(
+    f: &mut fmt::Formatter<'_>,
+    proto: &Prototype,
+    pc: usize,
+    decoded: Decoded,
+) -> fmt::Result {
+    let k = |k: usize| match proto.constants.get(k) {
+        Some(c) => constant_to_string(c),
+        None => "?".to_string(),
+    };
+    // 常量显示为它的值，寄存器显示为"-"
+    let rk = |x: Rk| match x {
+        Rk::Const(i) => k(i),
+        Rk::Reg(_) => "-".to_string(),
+    };
+    match decoded {
+        Decoded::LoadK { k: i, .. } => write!(f, "\t; {}", k(i))?,
+        Decoded::GetUpval { upval, .. } | Decoded::SetUpval { upval, .. } => {
+            write!(f, "\t; {}", upvalue_name(proto, upval))?
+        }
+        Decoded::GetTabUp { upval, key, .. } => {
+            write!(f, "\t; {}", upvalue_name(proto, upval))?;
+            if let Rk::Const(i) = key {
+                write!(f, " {}", k(i))?;
+            }
+        }
+        Decoded::SetTabUp { upval, key, value } => {
+            write!(f, "\t; {}", upvalue_name(proto, upval))?;
+            for x in [key, value] {
+                if let Rk::Const(i) = x {
+                    write!(f, " {}", k(i))?;
+                }
+            }
+        }
+        Decoded::GetTable { key: Rk::Const(i), .. } | Decoded::Self_ { key: Rk::Const(i), .. } => {
+            write!(f, "\t; {}", k(i))?
+        }
+        Decoded::SetTable { key: b, value: c, .. }
+        | Decoded::Arith { b, c, .. }
+        | Decoded::Eq { b, c, .. }
+        | Decoded::Lt { b, c, .. }
+        | Decoded::Le { b, c, .. }
+            if matches!(b, Rk::Const(_)) || matches!(c, Rk::Const(_)) =>
+        {
+            write!(f, "\t; {} {}", rk(b), rk(c))?
+        }
+        Decoded::Jmp { .. }
+        | Decoded::ForLoop { .. }
+        | Decoded::ForPrep { .. }
+        | Decoded::TForLoop { .. } => {
+            let target = decoded.jump_target(pc).unwrap();
+            write!(f, "\t; to {}", target + 1)?
+        }
+        // luac在这里输出子函数的地址，这里输出子函数的位置
+        Decoded::Closure { proto: i, .. } => match proto.protos.get(i) {
+            Some(p) => write!(f, "\t; function <{}:{}>", source_name(p), p.line_defined)?,
+            None => write!(f, "\t; ?")?,
+        },
+        Decoded::SetList { block, .. } => match block {
+            Some(block) => write!(f, "\t; {}", block)?,
+            None => match proto.code.get(pc + 1) {
+                Some(next) => write!(f, "\t; {}", next.ax())?,
+                None => write!(f, "\t; ?")?,
+            },
+        },
+        Decoded::ExtraArg { ax } => write!(f, "\t; {}", k(ax))?,
+        _ => (),
+    }
+    Ok(())
+}
+
+fn print_operands(f: &mut fmt::Formatter<'_>, instruction: u32, op: &OpCode) -> fmt::Result {
+    match op.op_mode {
+        opcode::OP_MODE_ABC => {
+            let (a, b, c) = instruction.abc();
+            write!(f, "{}", a)?;
+            if op.b_arg_mode != opcode::OP_ARG_N {
+                write!(f, " {}", Rk::from_arg(b, op.b_arg_mode))?;
+            }
+            if op.c_arg_mode != opcode::OP_ARG_N {
+                write!(f, " {}", Rk::from_arg(c, op.c_arg_mode))?;
+            }
+        }
+        opcode::OP_MODE_ABX => {
+            let (a, bx) = instruction.abx();
+            write!(f, "{}", a)?;
+            if op.b_arg_mode == opcode::OP_ARG_K {
+                write!(f, " {}", -1 - bx)?;
+            } else if op.b_arg_mode == opcode::OP_ARG_U {
+                write!(f, " {}", bx)?;
+            }
+        }
+        opcode::OP_MODE_ASBX => {
+            let (a, sbx) = instruction.asbx();
+            write!(f, "{} {}", a, sbx)?;
+        }
+        opcode::OP_MODE_AX => {
+            let ax = instruction.ax();
+            write!(f, "{}", -1 - ax)?;
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+// 与luac5.4的PrintCode一致，每条指令按自己的语义打印操作数
+fn print_operands_54(f: &mut fmt::Formatter<'_>, instruction: Instruction54) -> fmt::Result {
+    let (a, b, c, k) = instruction.abck();
+    let (_, bx) = instruction.abx();
+    let sb = Instruction54::signed(b);
+    let sc = Instruction54::signed(c);
+    let isk = if k { "k" } else { "" };
+    match instruction.op_code() {
+        lua54::OP_LOADI | lua54::OP_LOADF => write!(f, "{} {}", a, instruction.asbx().1)?,
+        lua54::OP_LOADK
+        | lua54::OP_FORLOOP
+        | lua54::OP_FORPREP
+        | lua54::OP_TFORPREP
+        | lua54::OP_TFORLOOP
+        | lua54::OP_CLOSURE => write!(f, "{} {}", a, bx)?,
+        lua54::OP_LOADKX
+        | lua54::OP_LOADFALSE
+        | lua54::OP_LFALSESKIP
+        | lua54::OP_LOADTRUE
+        | lua54::OP_CLOSE
+        | lua54::OP_TBC
+        | lua54::OP_RETURN1
+        | lua54::OP_VARARGPREP => write!(f, "{}", a)?,
+        lua54::OP_MOVE
+        | lua54::OP_LOADNIL
+        | lua54::OP_GETUPVAL
+        | lua54::OP_SETUPVAL
+        | lua54::OP_UNM
+        | lua54::OP_BNOT
+        | lua54::OP_NOT
+        | lua54::OP_LEN
+        | lua54::OP_CONCAT => write!(f, "{} {}", a, b)?,
+        lua54::OP_SETTABUP
+        | lua54::OP_SETTABLE
+        | lua54::OP_SETI
+        | lua54::OP_SETFIELD
+        | lua54::OP_SELF => write!(f, "{} {} {}{}", a, b, c, isk)?,
+        lua54::OP_ADDI | lua54::OP_SHRI | lua54::OP_SHLI => write!(f, "{} {} {}", a, b, sc)?,
+        lua54::OP_MMBINI => write!(f, "{} {} {} {}", a, sb, c, k as u8)?,
+        lua54::OP_MMBINK => write!(f, "{} {} {} {}", a, b, c, k as u8)?,
+        lua54::OP_JMP => write!(f, "{}", instruction.sj())?,
+        lua54::OP_EQ | lua54::OP_LT | lua54::OP_LE | lua54::OP_EQK | lua54::OP_TESTSET => {
+            write!(f, "{} {} {}", a, b, k as u8)?
+        }
+        lua54::OP_EQI | lua54::OP_LTI | lua54::OP_LEI | lua54::OP_GTI | lua54::OP_GEI => {
+            write!(f, "{} {} {}", a, sb, k as u8)?
+        }
+        lua54::OP_TEST => write!(f, "{} {}", a, k as u8)?,
+        lua54::OP_TAILCALL | lua54::OP_RETURN => write!(f, "{} {} {}{}", a, b, c, isk)?,
+        lua54::OP_RETURN0 => (),
+        lua54::OP_TFORCALL | lua54::OP_VARARG => write!(f, "{} {}", a, c)?,
+        lua54::OP_EXTRAARG => write!(f, "{}", instruction.ax())?,
+        _ => write!(f, "{} {} {}", a, b, c)?,
+    }
+    Ok(())
+}
+
+fn print_detail(f: &mut fmt::Formatter<'_>, proto: &Prototype) -> fmt::Result {
+    // 5.4之前的常量从1开始编号，5.4从0开始
+    let first_constant = match proto.version {
+        LuaVersion::Lua54 => 0,
+        _ => 1,
+    };
+    writeln!(f, "constants ({}):", proto.constants.len())?;
+    for (i, c) in proto.constants.iter().enumerate() {
+        writeln!(f, "\t{}\t{}", i + first_constant, constant_to_string(c))?;
+    }
+
+    writeln!(f, "locals ({}):", proto.loc_vars.len())?;
+    for (i, l) in proto.loc_vars.iter().enumerate() {
+        writeln!(
+            f,
+            "\t{}\t{}\t{}\t{}",
+            i,
+            l.var_name,
+            l.start_pc + 1,
+            l.end_pc + 1
+        )?;
+    }
+
+    writeln!(f, "upvalues ({}):", proto.upvalues.len())?;
+    for (i, u) in proto.upvalues.iter().enumerate() {
+        write!(
+            f,
+            "\t{}\t{}\t{}\t{}",
+            i,
+            upvalue_name(proto, i),
+            u.instack,
+            u.idx
+        )?;
+        if proto.version == LuaVersion::Lua54 {
+            write!(f, "\t{}", u.kind)?;
+        }
+        writeln!(f)?;
+    }
+    Ok(())
+}
+
+fn constant_to_string(constant: &Constant) -> String {
+    match constant {
+        Constant::Nil => "nil".to_string(),
+        Constant::Boolean(b) => b.to_string(),
+        Constant::Number(n) => number_to_string(*n),
+        Constant::Integer(i) => i.to_string(),
+        Constant::Str(s) => quote_string(s),
+    }
+}
+
+// 与luac的PrintString一致，转义引号、反斜杠和控制字符，其他不可打印的字节(包括非ASCII字节)写成\ddd
+fn quote_string(s: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for &c in s {
+        match c {
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            0x0C => quoted.push_str("\\f"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            0x0B => quoted.push_str("\\v"),
+            0x20..=0x7E => quoted.push(c as char),
+            c => quoted.push_str(&format!("\\{c:03}")),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// 去掉调试信息的函数原型没有源文件名，与luac一样显示为"=?"
+fn source_name(proto: &Prototype) -> Cow<'_, str> {
+    if proto.source.is_empty() {
+        Cow::Borrowed("=?")
+    } else {
+        String::from_utf8_lossy(&proto.source)
+    }
+}
+
+fn upvalue_name(proto: &Prototype, index: usize) -> String {
+    if proto.upvalue_names.len() > index {
+        proto.upvalue_names[index].clone()
+    } else {
+        "-".to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::binary::listing::{FunctionListing, Listing};
+    use crate::compiler;
+
+    #[test]
+    fn test_comments() {
+        let source = b"for i = 1, 2 do print(\"hi\", i + 1) end\nx = {n = 1}";
+        let proto = compiler::compile(source, "@test.lua").unwrap();
+        let text = Listing(&proto).to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        let find = |name: &str| {
+            lines
+                .iter()
+                .find(|line| line.contains(name))
+                .unwrap_or_else(|| panic!("no {name} in\n{text}"))
+                .to_string()
+        };
+        assert!(find("LOADK").ends_with("\t; 1"));
+        assert!(find("FORPREP").ends_with("\t; to 9"));
+        assert!(find("FORLOOP").ends_with("\t; to 5"));
+        assert!(find("GETTABUP").ends_with("\t; _ENV \"print\""));
+        assert!(find("ADD").ends_with("\t; - 1"));
+        assert!(find("SETTABLE").ends_with("\t; \"n\" 1"));
+        assert!(find("SETTABUP").ends_with("\t; _ENV \"x\""));
+        assert!(!find("CALL").contains(';'));
+    }
+
+    #[test]
+    fn test_binary_string() {
+        // 与luac一样，不可打印的字节(包括UTF-8编码的字节)写成\ddd
+        let source = b"local s = \"\\xff\\0\xc3\xa9\\a\\\"~\"";
+        let proto = compiler::compile(source, "@test.lua").unwrap();
+        let text = Listing(&proto).to_string();
+        assert!(text.contains("\t1\t\"\\255\\000\\195\\169\\a\\\"~\"\n"), "{text}");
+    }
+
+    #[test]
+    fn test_function_listing() {
+        let source = b"local function f() return function() end end";
+        let proto = compiler::compile(source, "@test.lua").unwrap();
+        let all = Listing(&proto).to_string();
+        let main = FunctionListing(&proto).to_string();
+        assert_eq!(all.matches("\nfunction <@test.lua:").count(), 2);
+        assert!(main.starts_with("main <@test.lua:0, 0>"));
+        assert!(!main.contains("\nfunction <"));
+        assert!(all.starts_with(&main));
+    }
+}
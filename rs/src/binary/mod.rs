@@ -1,9 +1,31 @@
 mod reader;
+mod writer;
+pub mod asm;
 pub mod chunk;
+pub mod error;
+pub mod json;
+pub mod listing;
+pub mod strip;
 
-pub fn undump(data: Vec<u8>) -> chunk::Prototype {
+pub use error::{AsmError, UndumpError};
+
+pub fn undump(data: Vec<u8>) -> Result<chunk::Prototype, UndumpError> {
     let mut reader = reader::Reader::new(data.as_ref());
-    reader.check_header();
-    reader.read_u8();
-    reader.read_proto("".to_string())
-}
\ No newline at end of file
+    reader.check_header()?;
+    if reader.has_upvalue_size() {
+        reader.read_u8()?;
+    }
+    reader.read_proto(Vec::new())
+}
+
+/// 将函数原型按其版本序列化为Lua chunk，是undump的逆过程
+/// 总是按小端、8字节size_t和lua_Integer/lua_Number的布局写入
+pub fn dump(proto: &chunk::Prototype) -> Vec<u8> {
+    let mut writer = writer::Writer::new(proto.version);
+    writer.write_header();
+    if !matches!(proto.version, chunk::LuaVersion::Lua51 | chunk::LuaVersion::Lua52) {
+        writer.write_u8(proto.upvalues.len() as u8);
+    }
+    writer.write_proto(proto, b"");
+    writer.into_bytes()
+}
@@ -1,283 +1,1002 @@
+use std::rc::Rc;
+
 use bytes::{Buf, BytesMut};
 
-use super::chunk::{self, Constant, LocVar, Prototype, UpValue};
+use super::chunk::{self, Constant, LocVar, LuaVersion, Prototype, UpValue};
+use super::error::UndumpError;
+use crate::vm::instruction::Instruction;
+use crate::vm::opcode::lua51;
+
+// 函数原型最大嵌套深度，与Lua的LUAI_MAXCCALLS保持一致
+const MAX_NESTING_DEPTH: usize = 200;
+
+type Result<T> = std::result::Result<T, UndumpError>;
+
+/// Header中声明的数据布局，交叉编译到其他平台的chunk可能与本机不同
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    // 字节序，由LUAC_INT(5.3/5.4)或字节序标志(5.1/5.2)确定
+    pub big_endian: bool,
+    // C int 占用字节数
+    pub int_size: u8,
+    // C size_t 占用字节数
+    pub size_t_size: u8,
+    // Lua 整型占用字节数
+    pub integer_size: u8,
+    // Lua 浮点型占用字节数
+    pub number_size: u8,
+    // 5.1/5.2中lua_Number可以被编译为整数类型
+    pub integral: bool,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            big_endian: false,
+            int_size: chunk::CINT_SIZE,
+            size_t_size: chunk::C_SIZE_T_SIZE,
+            integer_size: chunk::LUA_INTEGER_SIZE,
+            number_size: chunk::LUA_NUMBER_SIZE,
+            integral: false,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Reader {
     data: BytesMut,
+    // chunk总长度，用于计算当前读取位置
+    len: usize,
+    // 当前函数原型的嵌套深度
+    depth: usize,
+    // 由Header中的版本号决定，影响后续数据的解析方式
+    version: LuaVersion,
+    // 由Header中的字节序和各类型大小决定
+    layout: Layout,
 }
 
 #[allow(dead_code)]
 impl Reader {
     pub fn new(data: &[u8]) -> Self {
-        Self { data: BytesMut::from(data) }
+        Self {
+            data: BytesMut::from(data),
+            len: data.len(),
+            depth: 0,
+            version: LuaVersion::default(),
+            layout: Layout::default(),
+        }
+    }
+
+    // 解析出的数据布局
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    // 解析出的chunk版本
+    pub fn version(&self) -> LuaVersion {
+        self.version
+    }
+
+    // 当前读取位置在chunk中的字节偏移量
+    pub fn offset(&self) -> usize {
+        self.len - self.data.len()
+    }
+
+    // 确保剩余数据至少有n个字节
+    fn ensure(&self, n: usize) -> Result<()> {
+        if self.data.remaining() < n {
+            Err(UndumpError::Truncated {
+                offset: self.offset(),
+                needed: n,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        self.ensure(1)?;
+        Ok(self.data.get_u8())
+    }
+
+    // 按chunk的字节序读取size个字节的无符号整数
+    fn read_uint(&mut self, size: u8) -> Result<u64> {
+        self.ensure(size as usize)?;
+        if self.layout.big_endian {
+            Ok(self.data.get_uint(size as usize))
+        } else {
+            Ok(self.data.get_uint_le(size as usize))
+        }
     }
 
-    pub fn read_u8(&mut self) -> u8 {
-        self.data.get_u8()
+    // 指令固定占4字节
+    fn read_u32(&mut self) -> Result<u32> {
+        Ok(self.read_uint(4)? as u32)
     }
 
-    fn read_u32(&mut self) -> u32 {
-        self.data.get_u32_le()
+    // C int，5.1到5.3中用于记录行号、列表长度等
+    fn read_c_int(&mut self) -> Result<u32> {
+        let offset = self.offset();
+        let n = self.read_uint(self.layout.int_size)?;
+        u32::try_from(n).map_err(|_| UndumpError::IntegerOverflow { offset })
     }
 
-    fn read_u64(&mut self) -> u64 {
-        self.data.get_u64_le()
+    fn read_size_t(&mut self) -> Result<u64> {
+        self.read_uint(self.layout.size_t_size)
     }
 
-    fn read_lua_int(&mut self) -> i64 {
-        self.data.get_i64_le()
+    // 按lua_Integer的大小读取并做符号扩展
+    fn read_lua_int(&mut self) -> Result<i64> {
+        let size = self.layout.integer_size;
+        let n = self.read_uint(size)?;
+        let shift = 64 - size as u32 * 8;
+        Ok(((n << shift) as i64) >> shift)
     }
 
-    fn read_lua_num(&mut self) -> f64 {
-        self.data.get_f64_le()
+    fn read_lua_num(&mut self) -> Result<f64> {
+        let size = self.layout.number_size;
+        if self.layout.integral {
+            let n = self.read_uint(size)?;
+            let shift = 64 - size as u32 * 8;
+            return Ok((((n << shift) as i64) >> shift) as f64);
+        }
+        match size {
+            4 => Ok(f32::from_bits(self.read_uint(4)? as u32) as f64),
+            _ => Ok(f64::from_bits(self.read_uint(8)?)),
+        }
     }
 
     // 字符串分为短字符串和长字符串
     // 对于NULL字符串，长度为0x00
     // 对于短字符串, 长度 <= 253(0xFD), 先用一个字节记录长度+1, 然后是字节数组
     // 对于长字符串, 长度 >= 254(0xFE), 第一个字节是0xFF, 然后加一个size_t记录长度+1, 最后是字节数组
-    fn read_string(&mut self) -> String {
-        let size = self.data.get_u8();
-        if size == 0x00 {
-            return String::new();
+    // 5.4中长度+1统一用变长整数记录
+    // 5.1/5.2中先用size_t记录长度+1，字节数组末尾带有'\0'
+    // Lua字符串可以包含任意字节，按原样返回
+    fn read_string(&mut self) -> Result<Vec<u8>> {
+        Ok(self.read_string_at()?.1)
+    }
+
+    // 返回字符串内容在chunk中的位置和内容
+    fn read_string_at(&mut self) -> Result<(usize, Vec<u8>)> {
+        let size = match self.version {
+            LuaVersion::Lua51 | LuaVersion::Lua52 => self.read_size_t()?,
+            LuaVersion::Lua53 => match self.read_u8()? {
+                0xFF => self.read_size_t()?,
+                size => size as u64,
+            },
+            LuaVersion::Lua54 => self.read_varint(u64::MAX)?,
+        };
+        let offset = self.offset();
+        if size == 0 {
+            return Ok((offset, Vec::new()));
         }
 
-        let buf: BytesMut;
-        if size == 0xFF {
-            let size = self.data.get_u64();
-            buf = self.data.split_to((size - 1) as usize);
-        } else {
-            buf = self.data.split_to((size - 1) as usize);
+        let buf = self.read_bytes(usize::try_from(size - 1).unwrap_or(usize::MAX))?;
+        if matches!(self.version, LuaVersion::Lua51 | LuaVersion::Lua52) {
+            self.read_u8()?;
         }
+        Ok((offset, buf.to_vec()))
+    }
+
+    // 局部变量名和upvalue名是标识符，必须是合法的UTF-8
+    fn read_name(&mut self) -> Result<String> {
+        let (offset, buf) = self.read_string_at()?;
+        String::from_utf8(buf).map_err(|_| UndumpError::InvalidUtf8 { offset })
+    }
 
-        let str = std::str::from_utf8(&buf).unwrap();
+    fn read_bytes(&mut self, n_bytes: usize) -> Result<BytesMut> {
+        self.ensure(n_bytes)?;
+        Ok(self.data.split_to(n_bytes))
+    }
 
-        str.to_string()
+    // 读取列表长度，并根据剩余数据量限制预分配的容量，避免恶意数据导致分配过大的内存
+    fn read_len(&mut self) -> Result<(u32, usize)> {
+        let size = self.read_int()?;
+        Ok((size, (size as usize).min(self.data.remaining())))
+    }
+
+    // 5.4使用变长整数(loadUnsigned)，每个字节记录7位，高位在前，最后一个字节的最高位为1
+    fn read_varint(&mut self, limit: u64) -> Result<u64> {
+        let offset = self.offset();
+        let limit = limit >> 7;
+        let mut x: u64 = 0;
+        loop {
+            let b = self.read_u8()?;
+            if x >= limit {
+                return Err(UndumpError::IntegerOverflow { offset });
+            }
+            x = (x << 7) | (b & 0x7F) as u64;
+            if b & 0x80 != 0 {
+                return Ok(x);
+            }
+        }
     }
 
-    fn read_bytes(&mut self, n_bytes: u32) -> BytesMut {
-        self.data.split_to(n_bytes as usize)
+    // 5.4中的int类型(loadInt)
+    fn read_varint_u32(&mut self) -> Result<u32> {
+        Ok(self.read_varint(i32::MAX as u64)? as u32)
     }
 }
 
 impl Reader {
-    pub fn check_header(&mut self) {
-        if self.read_bytes(4).as_ref() != &chunk::LUA_SIGNATURE {
-            panic!("not a precompiled chunk");
-        } else if self.read_u8() != chunk::LUAC_VERSION {
-            panic!("version mismatched");
-        } else if self.read_u8() != chunk::LUAC_FORMAT {
-            panic!("format mismatched");
-        } else if self.read_bytes(6).as_ref() != &chunk::LUAC_DATA {
-            panic!("corrupted");
-        } else if self.read_u8() != chunk::CINT_SIZE {
-            panic!("int size mismatched");
-        } else if self.read_u8() != chunk::C_SIZE_T_SIZE {
-            panic!("size_t size mismatched");
-        } else if self.read_u8() != chunk::INSTRUCTION_SIZE {
-            panic!("instruction size mismatched");
-        } else if self.read_u8() != chunk::LUA_INTEGER_SIZE {
-            panic!("lua integer size mismatched");
-        } else if self.read_u8() != chunk::LUA_NUMBER_SIZE {
-            panic!("lua number size mismatched");
-        } else if self.read_lua_int() != chunk::LUAC_INT {
-            panic!("luac_int mismatched");
-        } else if self.read_lua_num() != chunk::LUAC_NUM {
-            panic!("lua_num mismatched");
-        }
-    }
-
-    fn read_code(&mut self) -> Vec<u32> {
-        let size = self.read_u32();
-        let mut codes = Vec::with_capacity(size as usize);
+    // 校验Header，并根据版本号决定后续数据的解析方式
+    pub fn check_header(&mut self) -> Result<()> {
+        let offset = self.offset();
+        if self.read_bytes(4)?.as_ref() != chunk::LUA_SIGNATURE {
+            return Err(UndumpError::BadSignature { offset });
+        }
+
+        let offset = self.offset();
+        let version = self.read_u8()?;
+        self.version = match LuaVersion::from_byte(version) {
+            Some(version) => version,
+            None => {
+                return Err(UndumpError::UnsupportedVersion {
+                    offset,
+                    found: version,
+                })
+            }
+        };
+
+        let offset = self.offset();
+        let format = self.read_u8()?;
+        if format != chunk::LUAC_FORMAT {
+            return Err(UndumpError::FormatMismatch {
+                offset,
+                expected: chunk::LUAC_FORMAT,
+                found: format,
+            });
+        }
+
+        if matches!(self.version, LuaVersion::Lua51 | LuaVersion::Lua52) {
+            return self.check_header_51();
+        }
+
+        let offset = self.offset();
+        if self.read_bytes(6)?.as_ref() != chunk::LUAC_DATA {
+            return Err(UndumpError::Corrupted { offset });
+        }
+
+        if self.version == LuaVersion::Lua53 {
+            self.layout.int_size = self.read_size("int", &[2, 4, 8])?;
+            self.layout.size_t_size = self.read_size("size_t", &[4, 8])?;
+        }
+        self.check_size("instruction", chunk::INSTRUCTION_SIZE)?;
+        self.layout.integer_size = self.read_size("lua integer", &[4, 8])?;
+        self.layout.number_size = self.read_size("lua number", &[4, 8])?;
+
+        // 先按小端解析LUAC_INT，不匹配时认为是大端
+        let offset = self.offset();
+        let size = self.layout.integer_size as usize;
+        self.ensure(size)?;
+        self.layout.big_endian = (&self.data[..size]).get_int_le(size) != chunk::LUAC_INT;
+        if self.read_lua_int()? != chunk::LUAC_INT {
+            return Err(UndumpError::IntegerFormatMismatch { offset });
+        }
+
+        let offset = self.offset();
+        if self.read_lua_num()? != chunk::LUAC_NUM {
+            return Err(UndumpError::NumberFormatMismatch { offset });
+        }
+
+        Ok(())
+    }
+
+    // 5.1/5.2的Header在版本号和格式号之后依次是字节序、int、size_t、Instruction、lua_Number的大小和lua_Number是否为整数
+    // 5.2在最后追加了LUAC_TAIL
+    fn check_header_51(&mut self) -> Result<()> {
+        let offset = self.offset();
+        self.layout.big_endian = match self.read_u8()? {
+            0 => true,
+            chunk::LUAC_ENDIANNESS => false,
+            _ => return Err(UndumpError::IntegerFormatMismatch { offset }),
+        };
+
+        self.layout.int_size = self.read_size("int", &[2, 4, 8])?;
+        self.layout.size_t_size = self.read_size("size_t", &[4, 8])?;
+        self.check_size("instruction", chunk::INSTRUCTION_SIZE)?;
+        self.layout.number_size = self.read_size("lua number", &[4, 8])?;
+
+        let offset = self.offset();
+        self.layout.integral = match self.read_u8()? {
+            chunk::LUAC_INTEGRAL => false,
+            1 => true,
+            _ => return Err(UndumpError::NumberFormatMismatch { offset }),
+        };
+
+        if self.version == LuaVersion::Lua52 {
+            let offset = self.offset();
+            if self.read_bytes(6)?.as_ref() != chunk::LUAC_DATA {
+                return Err(UndumpError::Corrupted { offset });
+            }
+        }
+
+        Ok(())
+    }
+
+    // 5.3/5.4在Header之后记录主函数的upvalue数量
+    pub fn has_upvalue_size(&self) -> bool {
+        matches!(self.version, LuaVersion::Lua53 | LuaVersion::Lua54)
+    }
+
+    // 读取Header中可变的类型大小，只接受allowed中的值
+    fn read_size(&mut self, what: &'static str, allowed: &[u8]) -> Result<u8> {
+        let offset = self.offset();
+        let found = self.read_u8()?;
+        if !allowed.contains(&found) {
+            return Err(UndumpError::UnsupportedSize {
+                offset,
+                what,
+                found,
+            });
+        }
+        Ok(found)
+    }
+
+    fn check_size(&mut self, what: &'static str, expected: u8) -> Result<()> {
+        let offset = self.offset();
+        let found = self.read_u8()?;
+        if found != expected {
+            return Err(UndumpError::SizeMismatch {
+                offset,
+                what,
+                expected,
+                found,
+            });
+        }
+        Ok(())
+    }
+
+    fn read_code(&mut self) -> Result<Vec<u32>> {
+        let (size, capacity) = self.read_len()?;
+        let mut codes = Vec::with_capacity(capacity);
         for _ in 0..size {
-            codes.push(self.read_u32());
+            codes.push(self.read_u32()?);
         }
 
-        codes
+        Ok(codes)
     }
 
-    fn read_constants(&mut self) -> Vec<Constant> {
-        let size = self.read_u32();
-        let mut constants = Vec::with_capacity(size as usize);
+    fn read_constants(&mut self) -> Result<Vec<Constant>> {
+        let (size, capacity) = self.read_len()?;
+        let mut constants = Vec::with_capacity(capacity);
         for _ in 0..size {
-            constants.push(self.read_constant());
+            constants.push(self.read_constant()?);
         }
-        constants
+        Ok(constants)
     }
 
-    fn read_constant(&mut self) -> Constant {
-        match self.read_u8() {
-            chunk::TAG_NIL => Constant::Nil,
-            chunk::TAG_BOOLEAN => Constant::Boolean(self.read_u8() != 0),
-            chunk::TAG_INTEGER => Constant::Integer(self.read_lua_int()),
-            chunk::TAG_NUMBER => Constant::Number(self.read_lua_num()),
-            chunk::TAG_SHORT_STR => Constant::Str(self.read_string()),
-            chunk::TAG_LONG_STR => Constant::Str(self.read_string()),
-            _ => Constant::Nil
+    fn read_constant(&mut self) -> Result<Constant> {
+        match self.version {
+            LuaVersion::Lua51 | LuaVersion::Lua52 => return self.read_constant_51(),
+            LuaVersion::Lua54 => return self.read_constant_54(),
+            LuaVersion::Lua53 => (),
         }
+
+        let offset = self.offset();
+        let constant = match self.read_u8()? {
+            chunk::TAG_NIL => Constant::Nil,
+            chunk::TAG_BOOLEAN => Constant::Boolean(self.read_u8()? != 0),
+            chunk::TAG_INTEGER => Constant::Integer(self.read_lua_int()?),
+            chunk::TAG_NUMBER => Constant::Number(self.read_lua_num()?),
+            chunk::TAG_SHORT_STR => Constant::Str(self.read_string()?),
+            chunk::TAG_LONG_STR => Constant::Str(self.read_string()?),
+            tag => return Err(UndumpError::UnknownConstantTag { offset, tag }),
+        };
+        Ok(constant)
     }
 
-    fn read_upvalues(&mut self) -> Vec<UpValue> {
-        let size = self.read_u32();
-        let mut upvalues = Vec::with_capacity(size as usize);
+    // 5.1/5.2中数字只有浮点数一种
+    fn read_constant_51(&mut self) -> Result<Constant> {
+        let offset = self.offset();
+        let constant = match self.read_u8()? {
+            chunk::TAG_NIL => Constant::Nil,
+            chunk::TAG_BOOLEAN => Constant::Boolean(self.read_u8()? != 0),
+            chunk::TAG_NUMBER => Constant::Number(self.read_lua_num()?),
+            chunk::TAG_STRING_51 => Constant::Str(self.read_string()?),
+            tag => return Err(UndumpError::UnknownConstantTag { offset, tag }),
+        };
+        Ok(constant)
+    }
+
+    fn read_constant_54(&mut self) -> Result<Constant> {
+        let offset = self.offset();
+        let constant = match self.read_u8()? {
+            chunk::TAG_NIL => Constant::Nil,
+            chunk::TAG_FALSE_54 => Constant::Boolean(false),
+            chunk::TAG_TRUE_54 => Constant::Boolean(true),
+            chunk::TAG_INTEGER_54 => Constant::Integer(self.read_lua_int()?),
+            chunk::TAG_NUMBER_54 => Constant::Number(self.read_lua_num()?),
+            chunk::TAG_SHORT_STR => Constant::Str(self.read_string()?),
+            chunk::TAG_LONG_STR => Constant::Str(self.read_string()?),
+            tag => return Err(UndumpError::UnknownConstantTag { offset, tag }),
+        };
+        Ok(constant)
+    }
+
+    fn read_upvalues(&mut self) -> Result<Vec<UpValue>> {
+        let (size, capacity) = self.read_len()?;
+        let mut upvalues = Vec::with_capacity(capacity);
         for _ in 0..size {
             upvalues.push(
                 UpValue {
-                    instack: self.read_u8(),
-                    idx: self.read_u8(),
+                    instack: self.read_u8()?,
+                    idx: self.read_u8()?,
+                    kind: match self.version {
+                        LuaVersion::Lua54 => self.read_u8()?,
+                        _ => 0,
+                    },
                 }
             );
         }
 
-        upvalues
+        Ok(upvalues)
     }
 
-    fn read_upvalue_names(&mut self) -> Vec<String> {
-        let size = self.read_u32();
-        let mut upvalue_names = Vec::with_capacity(size as usize);
+    fn read_upvalue_names(&mut self) -> Result<Vec<String>> {
+        let (size, capacity) = self.read_len()?;
+        let mut upvalue_names = Vec::with_capacity(capacity);
         for _ in 0..size {
-            upvalue_names.push(self.read_string());
+            upvalue_names.push(self.read_name()?);
         }
 
-        upvalue_names
+        Ok(upvalue_names)
     }
 
-    fn read_loc_vars(&mut self) -> Vec<LocVar> {
-        let size = self.read_u32();
-        let mut loc_vars = Vec::with_capacity(size as usize);
+    fn read_loc_vars(&mut self) -> Result<Vec<LocVar>> {
+        let (size, capacity) = self.read_len()?;
+        let mut loc_vars = Vec::with_capacity(capacity);
         for _ in 0..size {
             loc_vars.push(
                 LocVar {
-                    var_name: self.read_string(),
-                    start_pc: self.read_u32(),
-                    end_pc: self.read_u32(),
+                    var_name: self.read_name()?,
+                    start_pc: self.read_int()?,
+                    end_pc: self.read_int()?,
                 }
             );
         }
 
-        loc_vars
+        Ok(loc_vars)
     }
 
-    fn read_line_info(&mut self) -> Vec<u32> {
-        let size = self.read_u32();
-        let mut line_infos = Vec::with_capacity(size as usize);
+    // 5.1到5.3中int的大小由头部的int_size决定(read_c_int)，5.4中是变长整数
+    fn read_int(&mut self) -> Result<u32> {
+        match self.version {
+            LuaVersion::Lua54 => self.read_varint_u32(),
+            _ => self.read_c_int(),
+        }
+    }
+
+    fn read_line_info(&mut self) -> Result<Vec<u32>> {
+        let (size, capacity) = self.read_len()?;
+        let mut line_infos = Vec::with_capacity(capacity);
         for _ in 0..size {
-            line_infos.push(self.read_u32());
+            line_infos.push(self.read_int()?);
         }
 
-        line_infos
+        Ok(line_infos)
     }
 
-    fn read_protos(&mut self, parent_source: String) -> Vec<Prototype> {
-        let size = self.read_u32();
-        let mut protos = Vec::with_capacity(size as usize);
+    // 5.4的行号表记录与上一条指令的行号差(有符号字节)，
+    // 差值过大或每隔一定数量的指令会在绝对行号表中记录(pc, 行号)，此时行号差为ABS_LINE_INFO
+    // 这里将两张表合并换算为每条指令的绝对行号
+    fn read_line_info_54(&mut self, line_defined: u32) -> Result<Vec<u32>> {
+        let (size, capacity) = self.read_len()?;
+        let mut deltas = Vec::with_capacity(capacity);
         for _ in 0..size {
-            protos.push(self.read_proto(parent_source.clone()))
+            deltas.push(self.read_u8()? as i8);
         }
 
-        protos
+        let (size, capacity) = self.read_len()?;
+        let mut abs_line_info = Vec::with_capacity(capacity);
+        for _ in 0..size {
+            let pc = self.read_varint_u32()?;
+            let line = self.read_varint_u32()?;
+            abs_line_info.push((pc, line));
+        }
+
+        let mut abs_line_info = abs_line_info.into_iter();
+        let mut line = line_defined as i64;
+        let line_infos = deltas
+            .into_iter()
+            .map(|delta| {
+                if delta == chunk::ABS_LINE_INFO {
+                    if let Some((_, abs_line)) = abs_line_info.next() {
+                        line = abs_line as i64;
+                    }
+                } else {
+                    line += delta as i64;
+                }
+                line as u32
+            })
+            .collect();
+        Ok(line_infos)
     }
 
-    pub fn read_proto(&mut self, parent_source: String) -> Prototype {
-        let mut source = self.read_string();
+    fn read_protos(&mut self, parent_source: Vec<u8>) -> Result<Vec<Rc<Prototype>>> {
+        let (size, capacity) = self.read_len()?;
+        let mut protos = Vec::with_capacity(capacity);
+        for _ in 0..size {
+            protos.push(Rc::new(self.read_proto(parent_source.clone())?))
+        }
+
+        Ok(protos)
+    }
+
+    pub fn read_proto(&mut self, parent_source: Vec<u8>) -> Result<Prototype> {
+        if self.depth >= MAX_NESTING_DEPTH {
+            return Err(UndumpError::NestingTooDeep {
+                offset: self.offset(),
+            });
+        }
+        self.depth += 1;
+
+        // 5.2的源文件名记录在调试信息中
+        let proto = if self.version == LuaVersion::Lua52 {
+            self.read_proto_52(parent_source)?
+        } else {
+            let mut source = self.read_string()?;
+            if source.is_empty() {
+                source = parent_source;
+            }
+
+            match self.version {
+                LuaVersion::Lua51 => self.read_proto_51(source)?,
+                LuaVersion::Lua52 => unreachable!(),
+                LuaVersion::Lua53 => self.read_proto_53(source)?,
+                LuaVersion::Lua54 => self.read_proto_54(source)?,
+            }
+        };
+
+        self.depth -= 1;
+        Ok(proto)
+    }
+
+    fn read_proto_53(&mut self, source: Vec<u8>) -> Result<Prototype> {
+        Ok(Prototype {
+            version: LuaVersion::Lua53,
+            line_defined: self.read_int()?,
+            last_line_defined: self.read_int()?,
+            num_params: self.read_u8()?,
+            is_vararg: self.read_u8()?,
+            max_stack_size: self.read_u8()?,
+            code: self.read_code()?,
+            constants: self.read_constants()?,
+            upvalues: self.read_upvalues()?,
+            protos: self.read_protos(source.clone())?,
+            line_info: self.read_line_info()?,
+            loc_vars: self.read_loc_vars()?,
+            upvalue_names: self.read_upvalue_names()?,
+            source,
+        })
+    }
+
+    // 5.1的函数原型只记录upvalue数量，upvalue的来源由父函数中CLOSURE之后的伪指令描述
+    fn read_proto_51(&mut self, source: Vec<u8>) -> Result<Prototype> {
+        let line_defined = self.read_int()?;
+        let last_line_defined = self.read_int()?;
+        let num_upvalues = self.read_u8()?;
+        let num_params = self.read_u8()?;
+        let is_vararg = self.read_u8()?;
+        let max_stack_size = self.read_u8()?;
+        let code = self.read_code()?;
+        let constants = self.read_constants()?;
+        let mut protos = self.read_protos(source.clone())?;
+        fix_upvalues_51(&code, &mut protos);
+        Ok(Prototype {
+            version: LuaVersion::Lua51,
+            source,
+            line_defined,
+            last_line_defined,
+            num_params,
+            is_vararg,
+            max_stack_size,
+            code,
+            constants,
+            upvalues: vec![UpValue::default(); num_upvalues as usize],
+            protos,
+            line_info: self.read_line_info()?,
+            loc_vars: self.read_loc_vars()?,
+            upvalue_names: self.read_upvalue_names()?,
+        })
+    }
+
+    // 5.2的函数原型中，源文件名在子函数之后，子函数的NULL源文件名需要在读到父函数的源文件名之后再补上
+    fn read_proto_52(&mut self, parent_source: Vec<u8>) -> Result<Prototype> {
+        let line_defined = self.read_int()?;
+        let last_line_defined = self.read_int()?;
+        let num_params = self.read_u8()?;
+        let is_vararg = self.read_u8()?;
+        let max_stack_size = self.read_u8()?;
+        let code = self.read_code()?;
+        let constants = self.read_constants()?;
+        let mut protos = self.read_protos(Vec::new())?;
+        let upvalues = self.read_upvalues()?;
+        let mut source = self.read_string()?;
         if source.is_empty() {
             source = parent_source;
         }
+        inherit_source(&mut protos, &source);
+        Ok(Prototype {
+            version: LuaVersion::Lua52,
+            line_defined,
+            last_line_defined,
+            num_params,
+            is_vararg,
+            max_stack_size,
+            code,
+            constants,
+            upvalues,
+            protos,
+            line_info: self.read_line_info()?,
+            loc_vars: self.read_loc_vars()?,
+            upvalue_names: self.read_upvalue_names()?,
+            source,
+        })
+    }
 
-        Prototype {
-            line_defined: self.read_u32(),
-            last_line_defined: self.read_u32(),
-            num_params: self.read_u8(),
-            is_vararg: self.read_u8(),
-            max_stack_size: self.read_u8(),
-            code: self.read_code(),
-            constants: self.read_constants(),
-            upvalues: self.read_upvalues(),
-            protos: self.read_protos(source.clone()),
-            line_info: self.read_line_info(),
-            loc_vars: self.read_loc_vars(),
-            upvalue_names: self.read_upvalue_names(),
+    // 5.4的函数原型，整数都以变长整数记录，调试信息中多了绝对行号表
+    fn read_proto_54(&mut self, source: Vec<u8>) -> Result<Prototype> {
+        let line_defined = self.read_varint_u32()?;
+        Ok(Prototype {
+            version: LuaVersion::Lua54,
+            line_defined,
+            last_line_defined: self.read_varint_u32()?,
+            num_params: self.read_u8()?,
+            is_vararg: self.read_u8()?,
+            max_stack_size: self.read_u8()?,
+            code: self.read_code()?,
+            constants: self.read_constants()?,
+            upvalues: self.read_upvalues()?,
+            protos: self.read_protos(source.clone())?,
+            line_info: self.read_line_info_54(line_defined)?,
+            loc_vars: self.read_loc_vars()?,
+            upvalue_names: self.read_upvalue_names()?,
             source,
+        })
+    }
+}
+
+// 根据CLOSURE之后的MOVE/GETUPVAL伪指令还原5.1子函数的upvalue描述
+// MOVE 0 B表示捕获父函数的局部变量R(B)，GETUPVAL 0 B表示捕获父函数的upvalue[B]
+fn fix_upvalues_51(code: &[u32], protos: &mut [Rc<Prototype>]) {
+    for (pc, i) in code.iter().enumerate() {
+        if i.op_code() != lua51::OP_CLOSURE {
+            continue;
+        }
+        let (_, bx) = i.abx();
+        let Some(proto) = protos.get_mut(bx as usize).and_then(Rc::get_mut) else {
+            continue;
+        };
+        for (n, upvalue) in proto.upvalues.iter_mut().enumerate() {
+            let Some(pseudo) = code.get(pc + 1 + n) else {
+                break;
+            };
+            let (_, b, _) = pseudo.abc();
+            upvalue.instack = (pseudo.op_code() == lua51::OP_MOVE) as u8;
+            upvalue.idx = b as u8;
+        }
+    }
+}
+
+// 把源文件名为NULL的子函数(及其子函数)的源文件名设置为source
+fn inherit_source(protos: &mut [Rc<Prototype>], source: &[u8]) {
+    for proto in protos.iter_mut() {
+        if let Some(proto) = Rc::get_mut(proto) {
+            if proto.source.is_empty() {
+                proto.source = source.to_vec();
+                inherit_source(&mut proto.protos, source);
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::binary::chunk;
+    use std::rc::Rc;
+
+    use crate::binary::chunk::{self, LuaVersion, Prototype, UpValue};
+    use crate::binary::error::UndumpError;
     use crate::binary::reader::Reader;
+    use crate::vm::opcode::lua51;
+
+    fn header() -> Vec<u8> {
+        let mut string: Vec<u8> = vec![
+            0x1B, b'L', b'u', b'a', 0x53, 0x00,
+            0x19, 0x93, b'\r', b'\n', 0x1A, b'\n',
+            0x04, 0x08, 0x04, 0x08, 0x08,
+        ];
+        // mac use little endian
+        string.extend(0x5678_i64.to_le_bytes());
+        string.extend(370.5_f64.to_le_bytes());
+        string
+    }
     
     #[test]
     fn test_read_u8() {
         let mut reader = Reader::new(&chunk::LUA_SIGNATURE);
-        let b = reader.read_u8();
+        let b = reader.read_u8().unwrap();
         assert_eq!(b, 0x1B);
-        let b = reader.read_u8();
+        let b = reader.read_u8().unwrap();
         assert_eq!(b, b'L');
-        let b = reader.read_u8();
+        let b = reader.read_u8().unwrap();
         assert_eq!(b, b'u');
-        let b = reader.read_u8();
+        let b = reader.read_u8().unwrap();
         assert_eq!(b, b'a');
+        assert_eq!(
+            reader.read_u8(),
+            Err(UndumpError::Truncated { offset: 4, needed: 1 })
+        );
     }
 
     #[test]
     fn test_read_string_null() {
         let string = [0x00];
         let mut reader = Reader::new(&string);
-        let result = reader.read_string();
-        assert_eq!(result, "".to_string());
+        let result = reader.read_string().unwrap();
+        assert_eq!(result, b"");
     }
 
     #[test]
     fn test_read_string_lte_0xfd() {
         let string = [0x0B, b'h', b'e', b'l', b'l', b'o', b'w', b'o', b'r', b'l', b'd'];
         let mut reader = Reader::new(&string);
-        let result = reader.read_string();
-        assert_eq!(result, "helloworld".to_string());
+        let result = reader.read_string().unwrap();
+        assert_eq!(result, b"helloworld");
     }
 
     #[test]
     fn test_read_string_gte_0xff() {
-        let string = [0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0B, b'h', b'e', b'l', b'l', b'o', b'w', b'o', b'r', b'l', b'd'];
+        let string = [0xFF, 0x0B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', b'w', b'o', b'r', b'l', b'd'];
+        let mut reader = Reader::new(&string);
+        let result = reader.read_string().unwrap();
+        assert_eq!(result, b"helloworld");
+    }
+
+    #[test]
+    fn test_read_string_truncated() {
+        let string = [0x0B, b'h', b'e', b'l', b'l', b'o'];
+        let mut reader = Reader::new(&string);
+        assert_eq!(
+            reader.read_string(),
+            Err(UndumpError::Truncated { offset: 1, needed: 10 })
+        );
+    }
+
+    #[test]
+    fn test_read_string_invalid_utf8() {
+        // 字符串常量可以包含任意字节，变量名必须是合法的UTF-8
+        let string = [0x03, 0xFF, 0xFE];
+        let mut reader = Reader::new(&string);
+        assert_eq!(reader.read_string(), Ok(vec![0xFF, 0xFE]));
+        let mut reader = Reader::new(&string);
+        assert_eq!(
+            reader.read_name(),
+            Err(UndumpError::InvalidUtf8 { offset: 1 })
+        );
+    }
+
+    #[test]
+    fn test_read_constant_unknown_tag() {
+        let string = [0x01, 0x01, 0x07];
         let mut reader = Reader::new(&string);
-        let result = reader.read_string();
-        assert_eq!(result, "helloworld".to_string());
+        assert_eq!(reader.read_constant().unwrap(), chunk::Constant::Boolean(true));
+        assert_eq!(
+            reader.read_constant(),
+            Err(UndumpError::UnknownConstantTag { offset: 2, tag: 0x07 })
+        );
     }
 
     #[test]
     fn test_check_header() {
-        let mut string: Vec<u8> = Vec::new();
-        string.push(0x1B);
-        string.push(b'L');
-        string.push(b'u');
-        string.push(b'a');
-        string.push(0x53);
-        string.push(0x00);
-        string.push(0x19);
-        string.push(0x93);
-        string.push(b'\r');
-        string.push(b'\n');
-        string.push(0x1A);
-        string.push(b'\n');
-        string.push(0x04);
-        string.push(0x08);
-        string.push(0x04);
-        string.push(0x08);
-        string.push(0x08);
-        // mac use little endian
-        string.extend((0x5678 as i64).to_le_bytes());
-        string.extend((370.5 as f64).to_le_bytes());
+        let string = header();
         let mut reader = Reader::new(&string);
-        reader.check_header();
+        reader.check_header().unwrap();
     }
 
     #[test]
-    #[should_panic]
-    fn test_check_header_panic() {
+    fn test_check_header_bad_signature() {
         let string = [0x1B, b'L', b'u', b'1'];
         let mut reader = Reader::new(&string);
-        reader.check_header();
+        assert_eq!(
+            reader.check_header(),
+            Err(UndumpError::BadSignature { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn test_check_header_mismatch() {
+        let mut string = header();
+        string[4] = 0x50;
+        let mut reader = Reader::new(&string);
+        assert_eq!(
+            reader.check_header(),
+            Err(UndumpError::UnsupportedVersion { offset: 4, found: 0x50 })
+        );
+
+        let mut string = header();
+        string[13] = 0x03;
+        let mut reader = Reader::new(&string);
+        assert_eq!(
+            reader.check_header(),
+            Err(UndumpError::UnsupportedSize { offset: 13, what: "size_t", found: 3 })
+        );
+
+        let mut string = header();
+        string[17] = 0x00;
+        let mut reader = Reader::new(&string);
+        assert_eq!(
+            reader.check_header(),
+            Err(UndumpError::IntegerFormatMismatch { offset: 17 })
+        );
+
+        let string = &header()[..20];
+        let mut reader = Reader::new(string);
+        assert_eq!(
+            reader.check_header(),
+            Err(UndumpError::Truncated { offset: 17, needed: 8 })
+        );
+    }
+
+    #[test]
+    fn test_undump_32bit_big_endian() {
+        // 4字节int、size_t、lua_Integer，lua_Number为float，大端
+        let mut string: Vec<u8> = vec![
+            0x1B, b'L', b'u', b'a', 0x53, 0x00,
+            0x19, 0x93, b'\r', b'\n', 0x1A, b'\n',
+            0x04, 0x04, 0x04, 0x04, 0x04,
+        ];
+        string.extend(0x5678_i32.to_be_bytes());
+        string.extend(370.5_f32.to_be_bytes());
+        // size_upvalues
+        string.push(0x01);
+        // source
+        string.push(0x0A);
+        string.extend(b"@test.lua");
+        // line_defined, last_line_defined
+        string.extend(0u32.to_be_bytes());
+        string.extend(0u32.to_be_bytes());
+        // num_params, is_vararg, max_stack_size
+        string.extend([0x00, 0x01, 0x02]);
+        // code
+        string.extend(1u32.to_be_bytes());
+        string.extend(0x00800026u32.to_be_bytes());
+        // constants
+        string.extend(3u32.to_be_bytes());
+        string.push(chunk::TAG_INTEGER);
+        string.extend((-2_i32).to_be_bytes());
+        string.push(chunk::TAG_NUMBER);
+        string.extend(0.5_f32.to_be_bytes());
+        string.extend([chunk::TAG_LONG_STR, 0xFF]);
+        string.extend(301u32.to_be_bytes());
+        string.extend([b'x'; 300]);
+        // upvalues, protos
+        string.extend(1u32.to_be_bytes());
+        string.extend([0x01, 0x00]);
+        string.extend(0u32.to_be_bytes());
+        // line_info
+        string.extend(1u32.to_be_bytes());
+        string.extend(7u32.to_be_bytes());
+        // loc_vars, upvalue_names
+        string.extend(0u32.to_be_bytes());
+        string.extend(0u32.to_be_bytes());
+
+        let proto = crate::binary::undump(string).unwrap();
+        assert_eq!(proto.code, [0x00800026]);
+        assert_eq!(
+            proto.constants,
+            [
+                chunk::Constant::Integer(-2),
+                chunk::Constant::Number(0.5),
+                chunk::Constant::Str(b"x".repeat(300)),
+            ]
+        );
+        assert_eq!(proto.line_info, [7]);
+    }
+
+    #[test]
+    fn test_check_header_51_layout() {
+        // 大端，2字节int，4字节size_t，lua_Number为4字节整数
+        let string = [
+            0x1B, b'L', b'u', b'a', 0x51, 0x00,
+            0x00, 0x02, 0x04, 0x04, 0x04, 0x01,
+        ];
+        let mut reader = Reader::new(&string);
+        reader.check_header().unwrap();
+        let layout = reader.layout();
+        assert!(layout.big_endian);
+        assert!(layout.integral);
+        assert_eq!((layout.int_size, layout.size_t_size, layout.number_size), (2, 4, 4));
+
+        let mut reader = Reader::new(&[0x00, 0x2A, 0xFF, 0xFF, 0xFF, 0xFE]);
+        reader.version = LuaVersion::Lua51;
+        reader.layout = layout;
+        assert_eq!(reader.read_c_int(), Ok(42));
+        assert_eq!(reader.read_lua_num(), Ok(-2.0));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_check_header_54() {
+        let mut string = vec![
+            0x1B, b'L', b'u', b'a', 0x54, 0x00,
+            0x19, 0x93, b'\r', b'\n', 0x1A, b'\n',
+            0x04, 0x08, 0x08,
+        ];
+        string.extend(0x5678_i64.to_le_bytes());
+        string.extend(370.5_f64.to_le_bytes());
+        let mut reader = Reader::new(&string);
+        reader.check_header().unwrap();
+        assert_eq!(reader.version(), LuaVersion::Lua54);
+    }
+
+    #[test]
+    fn test_read_varint() {
+        let string = [0x85, 0x01, 0x80, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x80];
+        let mut reader = Reader::new(&string);
+        assert_eq!(reader.read_varint(u64::MAX), Ok(5));
+        assert_eq!(reader.read_varint(u64::MAX), Ok(128));
+        assert_eq!(
+            reader.read_varint_u32(),
+            Err(UndumpError::IntegerOverflow { offset: 3 })
+        );
+    }
+
+    #[test]
+    fn test_read_line_info_54() {
+        let mut string = vec![0x84, 0x01, 0x80, 0x7F, 0xFE];
+        // abs_line_info: (1, 300)
+        string.extend([0x81, 0x81, 0x02, 0xAC]);
+        let mut reader = Reader::new(&string);
+        reader.version = LuaVersion::Lua54;
+        assert_eq!(reader.read_line_info_54(10), Ok(vec![11, 300, 427, 425]));
+    }
+
+    #[test]
+    fn test_fix_upvalues_51() {
+        fn proto(code: Vec<u32>, num_upvalues: usize, protos: Vec<Rc<Prototype>>) -> Prototype {
+            Prototype {
+                version: LuaVersion::Lua51,
+                source: b"@test.lua".to_vec(),
+                line_defined: 0,
+                last_line_defined: 0,
+                num_params: 0,
+                is_vararg: 2,
+                max_stack_size: 2,
+                code,
+                constants: vec![],
+                upvalues: vec![UpValue::default(); num_upvalues],
+                protos,
+                line_info: vec![],
+                loc_vars: vec![],
+                upvalue_names: vec![],
+            }
+        }
+
+        // CLOSURE 0 0; MOVE 0 1; GETUPVAL 0 2
+        let code = vec![lua51::OP_CLOSURE as u32, 1 << 23, lua51::OP_GETUPVAL as u32 | 2 << 23];
+        let main = proto(code, 0, vec![Rc::new(proto(vec![], 2, vec![]))]);
+        let main = crate::binary::undump(crate::binary::dump(&main)).unwrap();
+        assert_eq!(
+            main.protos[0].upvalues,
+            [
+                UpValue { instack: 1, idx: 1, kind: 0 },
+                UpValue { instack: 0, idx: 2, kind: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_undump_truncated() {
+        let mut string = header();
+        // size_upvalues
+        string.push(0x01);
+        // source
+        string.extend([0x02, b'@']);
+        // line_defined
+        string.extend(0u32.to_le_bytes());
+        let err = crate::binary::undump(string).unwrap_err();
+        assert_eq!(err, UndumpError::Truncated { offset: 40, needed: 4 });
+        assert_eq!(err.offset(), 40);
+    }
+}
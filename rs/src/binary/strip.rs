@@ -0,0 +1,66 @@
+use std::rc::Rc;
+
+use super::chunk::Prototype;
+
+// 去掉函数原型及其所有子函数的调试信息，与luac -s相同
+// keep_lines为true时保留源文件名和行号表，出错时仍能报告位置
+pub fn strip(proto: &Prototype, keep_lines: bool) -> Prototype {
+    let mut stripped = proto.clone();
+    if !keep_lines {
+        stripped.source = Vec::new();
+        stripped.line_info = Vec::new();
+    }
+    stripped.loc_vars = Vec::new();
+    stripped.upvalue_names = Vec::new();
+    stripped.protos = proto
+        .protos
+        .iter()
+        .map(|p| Rc::new(strip(p, keep_lines)))
+        .collect();
+    stripped
+}
+
+#[cfg(test)]
+mod test {
+    use crate::binary::chunk::Prototype;
+    use crate::binary::strip::strip;
+    use crate::binary::{dump, undump};
+    use crate::compiler;
+    use crate::stdlib;
+    use crate::vm::lua_state::LuaState;
+    use crate::vm::lua_value::LuaValue;
+
+    fn assert_stripped(proto: &Prototype, keep_lines: bool) {
+        assert!(proto.loc_vars.is_empty());
+        assert!(proto.upvalue_names.is_empty());
+        assert_eq!(proto.line_info.is_empty(), !keep_lines);
+        assert_eq!(proto.source.is_empty(), !keep_lines);
+        for p in &proto.protos {
+            assert_stripped(p, keep_lines);
+        }
+    }
+
+    #[test]
+    fn test_strip() {
+        let source = b"local a = 1\nlocal function f(x)\n  return function() return x + a end\nend\nreturn f(2)()";
+        let proto = compiler::compile(source, "@test.lua").unwrap();
+        let size = dump(&proto).len();
+        for keep_lines in [false, true] {
+            let stripped = strip(&proto, keep_lines);
+            assert_stripped(&stripped, keep_lines);
+            assert_eq!(stripped.code, proto.code);
+            assert_eq!(stripped.protos[0].code, proto.protos[0].code);
+
+            // 去掉调试信息后chunk变小，加载后仍然可以执行
+            let data = dump(&stripped);
+            assert!(data.len() < size);
+            let loaded = undump(data).unwrap();
+            assert_eq!(loaded, stripped);
+            let mut state = LuaState::new();
+            stdlib::open_libs(&mut state);
+            let main = state.load(loaded).unwrap();
+            assert_eq!(state.call(main, Vec::new()), Ok(vec![LuaValue::Integer(3)]));
+        }
+        assert!(dump(&strip(&proto, false)).len() < dump(&strip(&proto, true)).len());
+    }
+}
@@ -0,0 +1,750 @@
+use std::rc::Rc;
+
+use bytes::{BufMut, BytesMut};
+
+use super::chunk::{self, Constant, LocVar, LuaVersion, Prototype, UpValue};
+
+// 短字符串的最大长度，与Lua的LUAI_MAXSHORTLEN保持一致
+const MAX_SHORT_STR_LEN: usize = 40;
+
+// 5.4行号表中相对行号的上限(LIMLINEDIFF)
+const LIM_LINE_DIFF: i64 = 0x80;
+
+// 5.4中两条绝对行号之间最多间隔的指令数(MAXIWTHABS)
+const MAX_IWTH_ABS: u32 = 128;
+
+/// chunk写入器，是Reader的逆过程
+#[derive(Debug, Default)]
+pub struct Writer {
+    data: BytesMut,
+    // 按哪个版本的格式写入
+    version: LuaVersion,
+}
+
+impl Writer {
+    pub fn new(version: LuaVersion) -> Self {
+        Self {
+            data: BytesMut::new(),
+            version,
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data.to_vec()
+    }
+
+    pub fn write_u8(&mut self, b: u8) {
+        self.data.put_u8(b);
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.data.put_u32_le(n);
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.data.put_u64_le(n);
+    }
+
+    fn write_lua_int(&mut self, n: i64) {
+        self.data.put_i64_le(n);
+    }
+
+    fn write_lua_num(&mut self, n: f64) {
+        self.data.put_f64_le(n);
+    }
+
+    // 与Reader::read_string对应
+    // NULL字符串写入0x00
+    // 长度+1 < 0xFF 的字符串, 先用一个字节记录长度+1, 然后是字节数组
+    // 其他字符串, 先写入0xFF, 然后用size_t记录长度+1, 最后是字节数组
+    // 5.4中长度+1统一用变长整数记录，NULL字符串记为变长整数0
+    // 5.1/5.2中先用size_t记录长度+1，字节数组末尾补'\0'
+    fn write_string(&mut self, s: &[u8]) {
+        if matches!(self.version, LuaVersion::Lua51 | LuaVersion::Lua52) {
+            if s.is_empty() {
+                self.write_u64(0);
+            } else {
+                self.write_u64(s.len() as u64 + 1);
+                self.write_bytes(s);
+                self.write_u8(0x00);
+            }
+            return;
+        }
+
+        if self.version == LuaVersion::Lua54 {
+            let size = if s.is_empty() { 0 } else { s.len() + 1 };
+            self.write_varint(size as u64);
+            self.write_bytes(s);
+            return;
+        }
+
+        if s.is_empty() {
+            self.write_u8(0x00);
+            return;
+        }
+
+        let size = s.len() + 1;
+        if size < 0xFF {
+            self.write_u8(size as u8);
+        } else {
+            self.write_u8(0xFF);
+            self.write_u64(size as u64);
+        }
+        self.write_bytes(s);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.put_slice(bytes);
+    }
+
+    fn write_len(&mut self, len: usize) {
+        self.write_int(len as u32);
+    }
+
+    // 5.1到5.3中的int固定4字节，5.4中是变长整数
+    fn write_int(&mut self, n: u32) {
+        match self.version {
+            LuaVersion::Lua54 => self.write_varint(n as u64),
+            _ => self.write_u32(n),
+        }
+    }
+
+    // 与Reader::read_varint对应
+    fn write_varint(&mut self, mut n: u64) {
+        let mut buf = vec![(n & 0x7F) as u8 | 0x80];
+        n >>= 7;
+        while n != 0 {
+            buf.push((n & 0x7F) as u8);
+            n >>= 7;
+        }
+        buf.reverse();
+        self.write_bytes(&buf);
+    }
+}
+
+impl Writer {
+    pub fn write_header(&mut self) {
+        self.write_bytes(&chunk::LUA_SIGNATURE);
+        self.write_u8(self.version.to_byte());
+        self.write_u8(chunk::LUAC_FORMAT);
+        if matches!(self.version, LuaVersion::Lua51 | LuaVersion::Lua52) {
+            self.write_u8(chunk::LUAC_ENDIANNESS);
+            self.write_u8(chunk::CINT_SIZE);
+            self.write_u8(chunk::C_SIZE_T_SIZE);
+            self.write_u8(chunk::INSTRUCTION_SIZE);
+            self.write_u8(chunk::LUA_NUMBER_SIZE);
+            self.write_u8(chunk::LUAC_INTEGRAL);
+            if self.version == LuaVersion::Lua52 {
+                self.write_bytes(&chunk::LUAC_DATA);
+            }
+            return;
+        }
+        self.write_bytes(&chunk::LUAC_DATA);
+        if self.version == LuaVersion::Lua53 {
+            self.write_u8(chunk::CINT_SIZE);
+            self.write_u8(chunk::C_SIZE_T_SIZE);
+        }
+        self.write_u8(chunk::INSTRUCTION_SIZE);
+        self.write_u8(chunk::LUA_INTEGER_SIZE);
+        self.write_u8(chunk::LUA_NUMBER_SIZE);
+        self.write_lua_int(chunk::LUAC_INT);
+        self.write_lua_num(chunk::LUAC_NUM);
+    }
+
+    fn write_code(&mut self, code: &[u32]) {
+        self.write_len(code.len());
+        for c in code {
+            self.write_u32(*c);
+        }
+    }
+
+    fn write_constants(&mut self, constants: &[Constant]) {
+        self.write_len(constants.len());
+        for c in constants {
+            self.write_constant(c);
+        }
+    }
+
+    fn write_constant(&mut self, constant: &Constant) {
+        match self.version {
+            LuaVersion::Lua51 | LuaVersion::Lua52 => return self.write_constant_51(constant),
+            LuaVersion::Lua54 => return self.write_constant_54(constant),
+            LuaVersion::Lua53 => (),
+        }
+
+        match constant {
+            Constant::Nil => self.write_u8(chunk::TAG_NIL),
+            Constant::Boolean(b) => {
+                self.write_u8(chunk::TAG_BOOLEAN);
+                self.write_u8(*b as u8);
+            }
+            Constant::Integer(i) => {
+                self.write_u8(chunk::TAG_INTEGER);
+                self.write_lua_int(*i);
+            }
+            Constant::Number(n) => {
+                self.write_u8(chunk::TAG_NUMBER);
+                self.write_lua_num(*n);
+            }
+            Constant::Str(s) => {
+                if s.len() <= MAX_SHORT_STR_LEN {
+                    self.write_u8(chunk::TAG_SHORT_STR);
+                } else {
+                    self.write_u8(chunk::TAG_LONG_STR);
+                }
+                self.write_string(s);
+            }
+        }
+    }
+
+    // 5.1/5.2中数字只有浮点数一种，整数常量按浮点数写入
+    fn write_constant_51(&mut self, constant: &Constant) {
+        match constant {
+            Constant::Nil => self.write_u8(chunk::TAG_NIL),
+            Constant::Boolean(b) => {
+                self.write_u8(chunk::TAG_BOOLEAN);
+                self.write_u8(*b as u8);
+            }
+            Constant::Integer(i) => {
+                self.write_u8(chunk::TAG_NUMBER);
+                self.write_lua_num(*i as f64);
+            }
+            Constant::Number(n) => {
+                self.write_u8(chunk::TAG_NUMBER);
+                self.write_lua_num(*n);
+            }
+            Constant::Str(s) => {
+                self.write_u8(chunk::TAG_STRING_51);
+                self.write_string(s);
+            }
+        }
+    }
+
+    fn write_constant_54(&mut self, constant: &Constant) {
+        match constant {
+            Constant::Nil => self.write_u8(chunk::TAG_NIL),
+            Constant::Boolean(false) => self.write_u8(chunk::TAG_FALSE_54),
+            Constant::Boolean(true) => self.write_u8(chunk::TAG_TRUE_54),
+            Constant::Integer(i) => {
+                self.write_u8(chunk::TAG_INTEGER_54);
+                self.write_lua_int(*i);
+            }
+            Constant::Number(n) => {
+                self.write_u8(chunk::TAG_NUMBER_54);
+                self.write_lua_num(*n);
+            }
+            Constant::Str(s) => {
+                if s.len() <= MAX_SHORT_STR_LEN {
+                    self.write_u8(chunk::TAG_SHORT_STR);
+                } else {
+                    self.write_u8(chunk::TAG_LONG_STR);
+                }
+                self.write_string(s);
+            }
+        }
+    }
+
+    fn write_upvalues(&mut self, upvalues: &[UpValue]) {
+        self.write_len(upvalues.len());
+        for u in upvalues {
+            self.write_u8(u.instack);
+            self.write_u8(u.idx);
+            if self.version == LuaVersion::Lua54 {
+                self.write_u8(u.kind);
+            }
+        }
+    }
+
+    fn write_upvalue_names(&mut self, upvalue_names: &[String]) {
+        self.write_len(upvalue_names.len());
+        for name in upvalue_names {
+            self.write_string(name.as_bytes());
+        }
+    }
+
+    fn write_loc_vars(&mut self, loc_vars: &[LocVar]) {
+        self.write_len(loc_vars.len());
+        for l in loc_vars {
+            self.write_string(l.var_name.as_bytes());
+            self.write_int(l.start_pc);
+            self.write_int(l.end_pc);
+        }
+    }
+
+    fn write_line_info(&mut self, line_info: &[u32]) {
+        self.write_len(line_info.len());
+        for line in line_info {
+            self.write_u32(*line);
+        }
+    }
+
+    // 与Reader::read_line_info_54对应，按luaK_code中savelineinfo的规则把绝对行号拆成两张表
+    fn write_line_info_54(&mut self, line_info: &[u32], line_defined: u32) {
+        let mut deltas = Vec::with_capacity(line_info.len());
+        let mut abs_line_info = Vec::new();
+        let mut previous_line = line_defined as i64;
+        let mut iwth_abs = 0;
+        for (pc, line) in line_info.iter().enumerate() {
+            let delta = *line as i64 - previous_line;
+            if delta.abs() >= LIM_LINE_DIFF || iwth_abs >= MAX_IWTH_ABS {
+                abs_line_info.push((pc as u32, *line));
+                deltas.push(chunk::ABS_LINE_INFO);
+                iwth_abs = 1;
+            } else {
+                deltas.push(delta as i8);
+                iwth_abs += 1;
+            }
+            previous_line = *line as i64;
+        }
+
+        self.write_len(deltas.len());
+        for delta in deltas {
+            self.write_u8(delta as u8);
+        }
+        self.write_len(abs_line_info.len());
+        for (pc, line) in abs_line_info {
+            self.write_int(pc);
+            self.write_int(line);
+        }
+    }
+
+    fn write_protos(&mut self, protos: &[Rc<Prototype>], parent_source: &[u8]) {
+        self.write_len(protos.len());
+        for p in protos {
+            self.write_proto(p, parent_source);
+        }
+    }
+
+    // 与luac一致，子函数的源文件名与父函数相同时写入NULL字符串
+    pub fn write_proto(&mut self, proto: &Prototype, parent_source: &[u8]) {
+        match self.version {
+            LuaVersion::Lua51 => return self.write_proto_51(proto, parent_source),
+            LuaVersion::Lua52 => return self.write_proto_52(proto),
+            _ => (),
+        }
+
+        if proto.source == parent_source {
+            self.write_string(b"");
+        } else {
+            self.write_string(&proto.source);
+        }
+
+        self.write_int(proto.line_defined);
+        self.write_int(proto.last_line_defined);
+        self.write_u8(proto.num_params);
+        self.write_u8(proto.is_vararg);
+        self.write_u8(proto.max_stack_size);
+        self.write_code(&proto.code);
+        self.write_constants(&proto.constants);
+        self.write_upvalues(&proto.upvalues);
+        self.write_protos(&proto.protos, &proto.source);
+        match self.version {
+            LuaVersion::Lua54 => self.write_line_info_54(&proto.line_info, proto.line_defined),
+            _ => self.write_line_info(&proto.line_info),
+        }
+        self.write_loc_vars(&proto.loc_vars);
+        self.write_upvalue_names(&proto.upvalue_names);
+    }
+
+    // 5.1只写入upvalue数量，upvalue的来源由父函数中的伪指令描述
+    fn write_proto_51(&mut self, proto: &Prototype, parent_source: &[u8]) {
+        if proto.source == parent_source {
+            self.write_string(b"");
+        } else {
+            self.write_string(&proto.source);
+        }
+
+        self.write_int(proto.line_defined);
+        self.write_int(proto.last_line_defined);
+        self.write_u8(proto.upvalues.len() as u8);
+        self.write_u8(proto.num_params);
+        self.write_u8(proto.is_vararg);
+        self.write_u8(proto.max_stack_size);
+        self.write_code(&proto.code);
+        self.write_constants(&proto.constants);
+        self.write_protos(&proto.protos, &proto.source);
+        self.write_line_info(&proto.line_info);
+        self.write_loc_vars(&proto.loc_vars);
+        self.write_upvalue_names(&proto.upvalue_names);
+    }
+
+    // 与luac5.2一致，每个函数的调试信息中都写入完整的源文件名
+    fn write_proto_52(&mut self, proto: &Prototype) {
+        self.write_int(proto.line_defined);
+        self.write_int(proto.last_line_defined);
+        self.write_u8(proto.num_params);
+        self.write_u8(proto.is_vararg);
+        self.write_u8(proto.max_stack_size);
+        self.write_code(&proto.code);
+        self.write_constants(&proto.constants);
+        self.write_protos(&proto.protos, &proto.source);
+        self.write_upvalues(&proto.upvalues);
+        self.write_string(&proto.source);
+        self.write_line_info(&proto.line_info);
+        self.write_loc_vars(&proto.loc_vars);
+        self.write_upvalue_names(&proto.upvalue_names);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::binary::chunk::{Constant, LocVar, LuaVersion, Prototype, UpValue};
+    use crate::binary::writer::Writer;
+    use crate::binary::{dump, undump};
+
+    // luac 5.3.6 编译 lua/test.lua (print("hello, world!"), 文件名为test.lua) 的输出
+    fn hello_world_chunk() -> Vec<u8> {
+        vec![
+            // header
+            0x1B, 0x4C, 0x75, 0x61, 0x53, 0x00, 0x19, 0x93, 0x0D, 0x0A, 0x1A, 0x0A,
+            0x04, 0x08, 0x04, 0x08, 0x08, 0x78, 0x56, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x28, 0x77, 0x40,
+            // size_upvalues
+            0x01,
+            // source "@test.lua"
+            0x0A, 0x40, 0x74, 0x65, 0x73, 0x74, 0x2E, 0x6C, 0x75, 0x61,
+            // line_defined, last_line_defined
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // num_params, is_vararg, max_stack_size
+            0x00, 0x01, 0x02,
+            // code: GETTABUP 0 0 -1; LOADK 1 -2; CALL 0 2 1; RETURN 0 1
+            0x04, 0x00, 0x00, 0x00, 0x06, 0x00, 0x40, 0x00, 0x41, 0x40, 0x00, 0x00,
+            0x24, 0x40, 0x00, 0x01, 0x26, 0x00, 0x80, 0x00,
+            // constants: "print", "hello, world!"
+            0x02, 0x00, 0x00, 0x00, 0x04, 0x06, 0x70, 0x72, 0x69, 0x6E, 0x74, 0x04,
+            0x0E, 0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x2C, 0x20, 0x77, 0x6F, 0x72, 0x6C,
+            0x64, 0x21,
+            // upvalues: _ENV(instack 1, idx 0)
+            0x01, 0x00, 0x00, 0x00, 0x01, 0x00,
+            // protos
+            0x00, 0x00, 0x00, 0x00,
+            // line_info
+            0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+            // loc_vars
+            0x00, 0x00, 0x00, 0x00,
+            // upvalue_names
+            0x01, 0x00, 0x00, 0x00, 0x05, 0x5F, 0x45, 0x4E, 0x56,
+        ]
+    }
+
+    // luac 5.1 编译 print("hello, world!") 的输出
+    fn hello_world_chunk_51() -> Vec<u8> {
+        let mut data = vec![
+            0x1B, b'L', b'u', b'a', 0x51, 0x00,
+            0x01, 0x04, 0x08, 0x04, 0x08, 0x00,
+        ];
+        // source
+        data.extend(10u64.to_le_bytes());
+        data.extend(b"@test.lua\0");
+        // line_defined, last_line_defined
+        data.extend(0u32.to_le_bytes());
+        data.extend(0u32.to_le_bytes());
+        // num_upvalues, num_params, is_vararg, max_stack_size
+        data.extend([0x00, 0x00, 0x02, 0x02]);
+        // code
+        data.extend(4u32.to_le_bytes());
+        for c in [0x00000005u32, 0x00004041, 0x0100401C, 0x0080001E] {
+            data.extend(c.to_le_bytes());
+        }
+        // constants
+        data.extend(2u32.to_le_bytes());
+        data.push(0x04);
+        data.extend(6u64.to_le_bytes());
+        data.extend(b"print\0");
+        data.push(0x04);
+        data.extend(14u64.to_le_bytes());
+        data.extend(b"hello, world!\0");
+        // protos
+        data.extend(0u32.to_le_bytes());
+        // line_info
+        data.extend(4u32.to_le_bytes());
+        for _ in 0..4 {
+            data.extend(1u32.to_le_bytes());
+        }
+        // loc_vars, upvalue_names
+        data.extend(0u32.to_le_bytes());
+        data.extend(0u32.to_le_bytes());
+        data
+    }
+
+    // luac 5.2 编译 print("hello, world!") 的输出
+    fn hello_world_chunk_52() -> Vec<u8> {
+        let mut data = vec![
+            0x1B, b'L', b'u', b'a', 0x52, 0x00,
+            0x01, 0x04, 0x08, 0x04, 0x08, 0x00,
+            0x19, 0x93, b'\r', b'\n', 0x1A, b'\n',
+        ];
+        // line_defined, last_line_defined
+        data.extend(0u32.to_le_bytes());
+        data.extend(0u32.to_le_bytes());
+        // num_params, is_vararg, max_stack_size
+        data.extend([0x00, 0x01, 0x02]);
+        // code
+        data.extend(4u32.to_le_bytes());
+        for c in [0x00400006u32, 0x00004041, 0x0100401D, 0x0080001F] {
+            data.extend(c.to_le_bytes());
+        }
+        // constants
+        data.extend(2u32.to_le_bytes());
+        data.push(0x04);
+        data.extend(6u64.to_le_bytes());
+        data.extend(b"print\0");
+        data.push(0x04);
+        data.extend(14u64.to_le_bytes());
+        data.extend(b"hello, world!\0");
+        // protos
+        data.extend(0u32.to_le_bytes());
+        // upvalues
+        data.extend(1u32.to_le_bytes());
+        data.extend([0x01, 0x00]);
+        // source
+        data.extend(10u64.to_le_bytes());
+        data.extend(b"@test.lua\0");
+        // line_info
+        data.extend(4u32.to_le_bytes());
+        for _ in 0..4 {
+            data.extend(1u32.to_le_bytes());
+        }
+        // loc_vars
+        data.extend(0u32.to_le_bytes());
+        // upvalue_names
+        data.extend(1u32.to_le_bytes());
+        data.extend(5u64.to_le_bytes());
+        data.extend(b"_ENV\0");
+        data
+    }
+
+    // luac 5.4 编译 print("hello, world!") 的输出
+    fn hello_world_chunk_54() -> Vec<u8> {
+        let mut data = vec![
+            0x1B, b'L', b'u', b'a', 0x54, 0x00,
+            0x19, 0x93, b'\r', b'\n', 0x1A, b'\n',
+            0x04, 0x08, 0x08,
+        ];
+        data.extend(0x5678_i64.to_le_bytes());
+        data.extend(370.5_f64.to_le_bytes());
+        // size_upvalues
+        data.push(0x01);
+        // source
+        data.push(0x8A);
+        data.extend(b"@test.lua");
+        // line_defined, last_line_defined
+        data.extend([0x80, 0x80]);
+        // num_params, is_vararg, max_stack_size
+        data.extend([0x00, 0x01, 0x02]);
+        // code
+        data.push(0x85);
+        for c in [0x00000051u32, 0x0000000B, 0x00008083, 0x01020044, 0x01010046] {
+            data.extend(c.to_le_bytes());
+        }
+        // constants
+        data.push(0x82);
+        data.extend([0x04, 0x86]);
+        data.extend(b"print");
+        data.extend([0x04, 0x8E]);
+        data.extend(b"hello, world!");
+        // upvalues
+        data.extend([0x81, 0x01, 0x00, 0x00]);
+        // protos
+        data.push(0x80);
+        // line_info, abs_line_info
+        data.extend([0x85, 0x01, 0x00, 0x00, 0x00, 0x00]);
+        data.push(0x80);
+        // loc_vars
+        data.push(0x80);
+        // upvalue_names
+        data.extend([0x81, 0x85]);
+        data.extend(b"_ENV");
+        data
+    }
+
+    // 简单的xorshift伪随机数生成器，保证测试可以复现
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        // 字符串常量和源文件名可以包含任意字节
+        fn bytes(&mut self) -> Vec<u8> {
+            let len = self.len();
+            (0..len).map(|_| self.below(256) as u8).collect()
+        }
+
+        fn string(&mut self) -> String {
+            let len = self.len();
+            (0..len)
+                .map(|_| (b' ' + self.below(95) as u8) as char)
+                .collect()
+        }
+
+        fn len(&mut self) -> u64 {
+            match self.below(4) {
+                0 => 0,
+                1 => self.below(40),
+                2 => self.below(300),
+                _ => 250 + self.below(10),
+            }
+        }
+    }
+
+    fn random_proto(rng: &mut Rng, version: LuaVersion, parent_source: &[u8], depth: u32) -> Prototype {
+        let source = if rng.below(3) == 0 {
+            rng.bytes()
+        } else {
+            parent_source.to_vec()
+        };
+        let source = if source.is_empty() {
+            parent_source.to_vec()
+        } else {
+            source
+        };
+
+        let code: Vec<u32> = (0..rng.below(20)).map(|_| rng.next() as u32).collect();
+        let constants = (0..rng.below(10))
+            .map(|_| match rng.below(5) {
+                0 => Constant::Nil,
+                1 => Constant::Boolean(rng.below(2) == 1),
+                // 5.1/5.2中没有整数常量
+                2 if matches!(version, LuaVersion::Lua51 | LuaVersion::Lua52) => Constant::Nil,
+                2 => Constant::Integer(rng.next() as i64),
+                3 => Constant::Number(rng.next() as f64 / 7.0),
+                _ => Constant::Str(rng.bytes()),
+            })
+            .collect();
+        let upvalues = (0..rng.below(5))
+            .map(|_| UpValue {
+                instack: rng.below(2) as u8,
+                idx: rng.below(256) as u8,
+                kind: match version {
+                    LuaVersion::Lua54 => rng.below(4) as u8,
+                    _ => 0,
+                },
+            })
+            .collect();
+        let protos = if depth < 3 {
+            (0..rng.below(3))
+                .map(|_| Rc::new(random_proto(rng, version, &source, depth + 1)))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let line_info = code.iter().map(|_| rng.below(1000) as u32).collect();
+        let loc_vars = (0..rng.below(5))
+            .map(|_| LocVar {
+                var_name: rng.string(),
+                start_pc: rng.below(20) as u32,
+                end_pc: rng.below(20) as u32,
+            })
+            .collect();
+        let upvalue_names = (0..rng.below(5)).map(|_| rng.string()).collect();
+
+        Prototype {
+            version,
+            source,
+            line_defined: rng.below(1000) as u32,
+            last_line_defined: rng.below(1000) as u32,
+            num_params: rng.below(256) as u8,
+            is_vararg: rng.below(2) as u8,
+            max_stack_size: rng.below(256) as u8,
+            code,
+            constants,
+            upvalues,
+            protos,
+            line_info,
+            loc_vars,
+            upvalue_names,
+        }
+    }
+
+    #[test]
+    fn test_write_string() {
+        let mut writer = Writer::new(LuaVersion::Lua53);
+        writer.write_string(b"");
+        writer.write_string(b"hello");
+        assert_eq!(writer.into_bytes(), [0x00, 0x06, b'h', b'e', b'l', b'l', b'o']);
+
+        let long = b"x".repeat(254);
+        let mut writer = Writer::new(LuaVersion::Lua53);
+        writer.write_string(&long);
+        let bytes = writer.into_bytes();
+        assert_eq!(bytes[0], 0xFF);
+        assert_eq!(bytes[1..9], 255u64.to_le_bytes());
+        assert_eq!(bytes.len(), 9 + 254);
+    }
+
+    #[test]
+    fn test_dump_luac_output() {
+        let data = hello_world_chunk();
+        let proto = undump(data.clone()).unwrap();
+        assert_eq!(proto.source, b"@test.lua");
+        assert_eq!(proto.constants[1], Constant::Str(b"hello, world!".to_vec()));
+        assert_eq!(dump(&proto), data);
+    }
+
+    #[test]
+    fn test_dump_luac_51_52_output() {
+        let data = hello_world_chunk_51();
+        let proto = undump(data.clone()).unwrap();
+        assert_eq!(proto.version, LuaVersion::Lua51);
+        assert_eq!(proto.source, b"@test.lua");
+        assert_eq!(proto.constants[1], Constant::Str(b"hello, world!".to_vec()));
+        assert!(proto.upvalues.is_empty());
+        assert_eq!(dump(&proto), data);
+
+        let data = hello_world_chunk_52();
+        let proto = undump(data.clone()).unwrap();
+        assert_eq!(proto.version, LuaVersion::Lua52);
+        assert_eq!(proto.source, b"@test.lua");
+        assert_eq!(proto.upvalues, [UpValue { instack: 1, idx: 0, kind: 0 }]);
+        assert_eq!(proto.upvalue_names, ["_ENV"]);
+        assert_eq!(dump(&proto), data);
+    }
+
+    #[test]
+    fn test_dump_luac_54_output() {
+        let data = hello_world_chunk_54();
+        let proto = undump(data.clone()).unwrap();
+        assert_eq!(proto.version, LuaVersion::Lua54);
+        assert_eq!(proto.source, b"@test.lua");
+        assert_eq!(proto.constants[0], Constant::Str(b"print".to_vec()));
+        assert_eq!(proto.upvalues, [UpValue { instack: 1, idx: 0, kind: 0 }]);
+        assert_eq!(proto.line_info, [1, 1, 1, 1, 1]);
+        assert_eq!(dump(&proto), data);
+    }
+
+    #[test]
+    fn test_dump_round_trip() {
+        let mut rng = Rng(0x2545F4914F6CDD1D);
+        // 长度>=254的字符串使用size_t记录长度，确保两种编码都被覆盖
+        let mut long_strings = 0;
+        for version in [LuaVersion::Lua51, LuaVersion::Lua52, LuaVersion::Lua53, LuaVersion::Lua54] {
+            for _ in 0..200 {
+                let source = rng.bytes();
+                if source.len() >= 254 {
+                    long_strings += 1;
+                }
+                let proto = random_proto(&mut rng, version, &source, 0);
+                let data = dump(&proto);
+                let result = undump(data.clone()).unwrap();
+                // 5.1的upvalue描述由指令还原，随机生成的指令无法还原出原来的描述
+                if version != LuaVersion::Lua51 {
+                    assert_eq!(result, proto);
+                }
+                assert_eq!(dump(&result), data);
+            }
+        }
+        assert!(long_strings > 0);
+    }
+}
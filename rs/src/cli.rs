@@ -0,0 +1,321 @@
+use std::fmt;
+
+pub const USAGE: &str = "\
+usage: rs <command> [options] <file>
+
+commands:
+  list       list the bytecode like luac -l -l
+  run        run a script, the arguments after <file> are passed to it
+  compile    compile to a binary chunk (default output luac.out)
+  dump       write the loaded chunk as a binary chunk (default output stdout)
+  verify     check that the bytecode is well formed
+  stats      print instruction and opcode statistics
+  strip      remove debug information (default output luac.out)
+  decompile  rebuild Lua source from the bytecode
+
+<file> can be Lua source, a binary chunk or a .luasm assembly file.
+'rs <file>' is the same as 'rs list <file>'.
+
+options:
+  -o <file>           write output to <file> ('-' for stdout)
+  -s                  strip debug information (compile, dump)
+  -l                  keep source and line info when stripping (strip)
+  --format <format>   text, json or dot (list); text or json (stats)
+  --no-recurse        leave out nested functions (list, stats)
+  --function <n>      select the n-th function in listing order, 0 is main
+                      (list, verify, stats)
+  -h, --help          show this message
+";
+
+/// 子命令
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    List,
+    Run,
+    Compile,
+    Dump,
+    Verify,
+    Stats,
+    Strip,
+    Decompile,
+}
+
+impl Command {
+    fn from_name(name: &str) -> Option<Command> {
+        let command = match name {
+            "list" => Command::List,
+            "run" => Command::Run,
+            "compile" => Command::Compile,
+            "dump" => Command::Dump,
+            "verify" => Command::Verify,
+            "stats" => Command::Stats,
+            "strip" => Command::Strip,
+            "decompile" => Command::Decompile,
+            _ => return None,
+        };
+        Some(command)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Command::List => "list",
+            Command::Run => "run",
+            Command::Compile => "compile",
+            Command::Dump => "dump",
+            Command::Verify => "verify",
+            Command::Stats => "stats",
+            Command::Strip => "strip",
+            Command::Decompile => "decompile",
+        }
+    }
+
+    // 子命令接受的选项
+    fn accepts(self, option: &str) -> bool {
+        let options: &[&str] = match self {
+            Command::List => &["-o", "--format", "--no-recurse", "--function"],
+            Command::Run => &[],
+            Command::Compile | Command::Dump => &["-o", "-s"],
+            Command::Verify => &["-o", "--function"],
+            Command::Stats => &["-o", "--format", "--no-recurse", "--function"],
+            Command::Strip => &["-o", "-l"],
+            Command::Decompile => &["-o"],
+        };
+        options.contains(&option)
+    }
+
+    // 子命令支持的输出格式
+    fn formats(self) -> &'static [Format] {
+        match self {
+            Command::List => &[Format::Text, Format::Json, Format::Dot],
+            Command::Stats => &[Format::Text, Format::Json],
+            _ => &[Format::Text],
+        }
+    }
+}
+
+/// 输出格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    Dot,
+}
+
+/// 解析后的命令行参数
+#[derive(Debug, Clone, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    // 输入文件
+    pub input: String,
+    // 输出文件，None表示子命令的默认输出，"-"表示标准输出
+    pub output: Option<String>,
+    pub format: Format,
+    // 是否包括嵌套的子函数
+    pub recurse: bool,
+    // 按先序编号选中的函数，0是主函数
+    pub function: Option<usize>,
+    // 去掉调试信息
+    pub strip: bool,
+    // 去掉调试信息时保留源文件名和行号
+    pub keep_lines: bool,
+    // run传给脚本的参数
+    pub script_args: Vec<String>,
+}
+
+/// 命令行用法错误
+#[derive(Debug, Clone, PartialEq)]
+pub enum UsageError {
+    // -h或--help，不是真正的错误
+    Help,
+    MissingCommand,
+    UnknownCommand(String),
+    UnknownOption { command: Command, option: String },
+    MissingValue(String),
+    BadValue { option: String, value: String },
+    MissingInput(Command),
+    ExtraArgument(String),
+}
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsageError::Help => write!(f, "help requested"),
+            UsageError::MissingCommand => write!(f, "no command given"),
+            UsageError::UnknownCommand(name) => write!(f, "unknown command '{name}'"),
+            UsageError::UnknownOption { command, option } => {
+                write!(f, "'{}' does not accept option '{option}'", command.name())
+            }
+            UsageError::MissingValue(option) => write!(f, "'{option}' needs argument"),
+            UsageError::BadValue { option, value } => {
+                write!(f, "bad value '{value}' for '{option}'")
+            }
+            UsageError::MissingInput(command) => write!(f, "'{}' needs a file", command.name()),
+            UsageError::ExtraArgument(arg) => write!(f, "unexpected argument '{arg}'"),
+        }
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+// 解析命令行参数，args不包括程序名
+pub fn parse(args: &[String]) -> Result<Args, UsageError> {
+    let first = args.first().ok_or(UsageError::MissingCommand)?;
+    if first == "-h" || first == "--help" {
+        return Err(UsageError::Help);
+    }
+    let command =
+        Command::from_name(first).ok_or_else(|| UsageError::UnknownCommand(first.clone()))?;
+    let mut parsed = Args {
+        command,
+        input: String::new(),
+        output: None,
+        format: Format::Text,
+        recurse: true,
+        function: None,
+        strip: false,
+        keep_lines: false,
+        script_args: Vec::new(),
+    };
+
+    let mut input = None;
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        // run的脚本之后的参数都属于脚本
+        if input.is_some() && command == Command::Run {
+            parsed.script_args.push(arg.clone());
+            continue;
+        }
+        if arg == "-h" || arg == "--help" {
+            return Err(UsageError::Help);
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            match input {
+                None => input = Some(arg.clone()),
+                Some(_) => return Err(UsageError::ExtraArgument(arg.clone())),
+            }
+            continue;
+        }
+        // --option=value 与 --option value 相同
+        let (option, inline) = match arg.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => (option, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        if !command.accepts(option) {
+            return Err(UsageError::UnknownOption {
+                command,
+                option: option.to_string(),
+            });
+        }
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| rest.next().cloned())
+                .ok_or_else(|| UsageError::MissingValue(option.to_string()))
+        };
+        let bad_value = |value: String| UsageError::BadValue {
+            option: option.to_string(),
+            value,
+        };
+        match option {
+            "-o" => parsed.output = Some(value()?),
+            "-s" => parsed.strip = true,
+            "-l" => parsed.keep_lines = true,
+            "--no-recurse" => parsed.recurse = false,
+            "--format" => {
+                let value = value()?;
+                parsed.format = match value.as_str() {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    "dot" => Format::Dot,
+                    _ => return Err(bad_value(value)),
+                };
+                if !command.formats().contains(&parsed.format) {
+                    return Err(bad_value(value));
+                }
+            }
+            "--function" => {
+                let value = value()?;
+                parsed.function = Some(value.parse().map_err(|_| bad_value(value))?);
+            }
+            _ => unreachable!(),
+        }
+    }
+    parsed.input = input.ok_or(UsageError::MissingInput(command))?;
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cli::{parse, Command, Format, UsageError};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let parsed = parse(&args("list --format dot --no-recurse --function 2 a.lua")).unwrap();
+        assert_eq!(parsed.command, Command::List);
+        assert_eq!(parsed.input, "a.lua");
+        assert_eq!(parsed.format, Format::Dot);
+        assert!(!parsed.recurse);
+        assert_eq!(parsed.function, Some(2));
+        assert_eq!(parsed.output, None);
+
+        let parsed = parse(&args("compile -s a.lua -o out.luac")).unwrap();
+        assert!(parsed.strip);
+        assert_eq!(parsed.output.as_deref(), Some("out.luac"));
+
+        let parsed = parse(&args("stats --format=json a.lua")).unwrap();
+        assert_eq!(parsed.format, Format::Json);
+
+        // verify和stats的报告也可以写入文件
+        for command in ["verify", "stats"] {
+            let parsed = parse(&args(&format!("{command} -o report.txt a.lua"))).unwrap();
+            assert_eq!(parsed.output.as_deref(), Some("report.txt"));
+        }
+
+        // 脚本之后的参数原样传给脚本
+        let parsed = parse(&args("run a.lua -o x --help")).unwrap();
+        assert_eq!(parsed.input, "a.lua");
+        assert_eq!(parsed.script_args, args("-o x --help"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let check = |line: &str, expected: UsageError| {
+            assert_eq!(parse(&args(line)), Err(expected), "{line}");
+        };
+        check("", UsageError::MissingCommand);
+        check("--help", UsageError::Help);
+        check("list -h a.lua", UsageError::Help);
+        check("lsit a.lua", UsageError::UnknownCommand("lsit".to_string()));
+        check("verify", UsageError::MissingInput(Command::Verify));
+        check(
+            "list a.lua b.lua",
+            UsageError::ExtraArgument("b.lua".to_string()),
+        );
+        check("list a.lua -o", UsageError::MissingValue("-o".to_string()));
+        check(
+            "run -s a.lua",
+            UsageError::UnknownOption {
+                command: Command::Run,
+                option: "-s".to_string(),
+            },
+        );
+        check(
+            "stats --format dot a.lua",
+            UsageError::BadValue {
+                option: "--format".to_string(),
+                value: "dot".to_string(),
+            },
+        );
+        check(
+            "list --function x a.lua",
+            UsageError::BadValue {
+                option: "--function".to_string(),
+                value: "x".to_string(),
+            },
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use crate::compiler::token::Token;
+
+/// 代码块
+/// block ::= {stat} [retstat]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    // 结束行号
+    pub last_line: u32,
+    pub stats: Vec<Stat>,
+    // 没有return语句时为None
+    pub ret_exps: Option<Vec<Exp>>,
+}
+
+/// 语句
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stat {
+    // ;
+    Empty,
+    // break
+    Break { line: u32 },
+    // ::Name::
+    Label { line: u32, name: String },
+    // goto Name
+    Goto { line: u32, name: String },
+    // do block end
+    Do(Block),
+    // functioncall
+    FuncCall(Exp),
+    // while exp do block end
+    While { exp: Exp, block: Block },
+    // repeat block until exp
+    Repeat { block: Block, exp: Exp },
+    // if exp then block {elseif exp then block} [else block] end
+    // else 分支被转换为 elseif true
+    If { exps: Vec<Exp>, blocks: Vec<Block> },
+    // for Name = exp, exp [, exp] do block end
+    ForNum {
+        line_of_for: u32,
+        line_of_do: u32,
+        var_name: String,
+        init: Exp,
+        limit: Exp,
+        step: Exp,
+        block: Block,
+    },
+    // for namelist in explist do block end
+    ForIn {
+        line_of_for: u32,
+        line_of_do: u32,
+        name_list: Vec<String>,
+        exp_list: Vec<Exp>,
+        block: Block,
+    },
+    // local namelist [= explist]
+    LocalVar {
+        last_line: u32,
+        name_list: Vec<String>,
+        exp_list: Vec<Exp>,
+    },
+    // varlist = explist
+    // function funcname funcbody 也会被转换为赋值语句
+    Assign {
+        last_line: u32,
+        var_list: Vec<Exp>,
+        exp_list: Vec<Exp>,
+    },
+    // local function Name funcbody
+    LocalFunc { name: String, exp: Exp },
+}
+
+/// 表达式
+#[derive(Debug, Clone, PartialEq)]
+pub enum Exp {
+    Nil { line: u32 },
+    True { line: u32 },
+    False { line: u32 },
+    Vararg { line: u32 },
+    Integer { line: u32, val: i64 },
+    Float { line: u32, val: f64 },
+    Str { line: u32, val: Vec<u8> },
+    Name { line: u32, name: String },
+    // 一元运算
+    Unop { line: u32, op: Token, exp: Box<Exp> },
+    // 二元运算
+    Binop {
+        line: u32,
+        op: Token,
+        exp1: Box<Exp>,
+        exp2: Box<Exp>,
+    },
+    // 连续的拼接运算合并为一个表达式
+    Concat { line: u32, exps: Vec<Exp> },
+    // 表构造器，key为None的是数组部分
+    Table {
+        line: u32,
+        last_line: u32,
+        key_exps: Vec<Option<Exp>>,
+        val_exps: Vec<Exp>,
+    },
+    // 函数定义
+    FuncDef {
+        line: u32,
+        last_line: u32,
+        par_list: Vec<String>,
+        is_vararg: bool,
+        block: Box<Block>,
+    },
+    // 圆括号会把多值表达式截断为一个值
+    Paren(Box<Exp>),
+    // prefixexp[exp]
+    TableAccess {
+        last_line: u32,
+        prefix: Box<Exp>,
+        key: Box<Exp>,
+    },
+    // prefixexp [':' Name] args
+    FuncCall {
+        line: u32,
+        last_line: u32,
+        prefix: Box<Exp>,
+        name: Option<Box<Exp>>,
+        args: Vec<Exp>,
+    },
+}
+
+impl Exp {
+    // 表达式的起始行号
+    pub fn line(&self) -> u32 {
+        match self {
+            Exp::Nil { line }
+            | Exp::True { line }
+            | Exp::False { line }
+            | Exp::Vararg { line }
+            | Exp::Integer { line, .. }
+            | Exp::Float { line, .. }
+            | Exp::Str { line, .. }
+            | Exp::Name { line, .. }
+            | Exp::Unop { line, .. }
+            | Exp::Concat { line, .. }
+            | Exp::Table { line, .. }
+            | Exp::FuncDef { line, .. }
+            | Exp::FuncCall { line, .. } => *line,
+            Exp::Binop { exp1, .. } => exp1.line(),
+            Exp::Paren(exp) => exp.line(),
+            Exp::TableAccess { prefix, .. } => prefix.line(),
+        }
+    }
+
+    // 表达式的结束行号
+    pub fn last_line(&self) -> u32 {
+        match self {
+            Exp::Table { last_line, .. }
+            | Exp::FuncDef { last_line, .. }
+            | Exp::TableAccess { last_line, .. }
+            | Exp::FuncCall { last_line, .. } => *last_line,
+            Exp::Unop { exp, .. } => exp.last_line(),
+            Exp::Binop { exp2, .. } => exp2.last_line(),
+            Exp::Concat { exps, .. } => exps.last().unwrap().last_line(),
+            Exp::Paren(exp) => exp.last_line(),
+            _ => self.line(),
+        }
+    }
+
+    // 函数调用和vararg可以返回多个值
+    pub fn is_multi_value(&self) -> bool {
+        matches!(self, Exp::Vararg { .. } | Exp::FuncCall { .. })
+    }
+}
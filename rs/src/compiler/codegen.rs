@@ -0,0 +1,1016 @@
+use std::rc::Rc;
+
+use crate::binary::chunk::Prototype;
+use crate::compiler::ast::{Block, Exp, Stat};
+use crate::compiler::error::CompileError;
+use crate::compiler::func_info::*;
+use crate::compiler::token::Token;
+use crate::vm::instruction::BIT_RK;
+use crate::vm::opcode::*;
+
+type Result<T> = std::result::Result<T, CompileError>;
+
+// 表构造器每批设置的数组元素数量
+const FIELDS_PER_FLUSH: usize = 50;
+
+// expToOpArg可以接受的操作数类型
+const ARG_CONST: u8 = 1;
+const ARG_REG: u8 = 2;
+const ARG_UPVAL: u8 = 4;
+const ARG_RK: u8 = ARG_REG | ARG_CONST;
+const ARG_RU: u8 = ARG_REG | ARG_UPVAL;
+
+/// 代码生成器，遍历抽象语法树生成函数原型
+/// 正在编译的函数按嵌套顺序保存在栈中，最后一个是当前函数
+pub struct CodeGen {
+    source: String,
+    funcs: Vec<FuncInfo>,
+}
+
+impl CodeGen {
+    pub fn new(source: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            funcs: Vec::new(),
+        }
+    }
+
+    // 主函数是vararg函数，唯一的upvalue是_ENV
+    pub fn gen_main(mut self, block: &Block) -> Result<Prototype> {
+        let mut fi = FuncInfo::new(0, 0);
+        fi.is_vararg = true;
+        fi.upvalues.push(UpvalInfo {
+            name: "_ENV".to_string(),
+            instack: true,
+            idx: 0,
+        });
+        self.gen_func(fi, &[], block, block.last_line)
+    }
+
+    fn fi(&mut self) -> &mut FuncInfo {
+        self.funcs.last_mut().unwrap()
+    }
+
+    fn error(&self, line: u32, msg: impl Into<String>) -> CompileError {
+        CompileError {
+            source: self.source.clone(),
+            line,
+            msg: msg.into(),
+        }
+    }
+
+    fn gen_func(&mut self, fi: FuncInfo, par_list: &[String], block: &Block, last_line: u32) -> Result<Prototype> {
+        self.funcs.push(fi);
+        self.enter_block(false);
+        for param in par_list {
+            self.add_loc_var(param, 0, last_line)?;
+        }
+        self.fi().num_params = par_list.len();
+
+        self.cg_block(block, false)?;
+        self.fi().emit_return(last_line, 0, 0);
+        self.leave_block(last_line)?;
+
+        let fi = self.funcs.pop().unwrap();
+        let line = fi.line;
+        fi.to_proto(&self.source).map_err(|msg| self.error(line, msg))
+    }
+
+    fn add_loc_var(&mut self, name: &str, start_pc: usize, line: u32) -> Result<usize> {
+        if self.fi().active_vars.len() >= MAX_VARS {
+            return Err(self.error(line, format!("too many local variables (limit is {MAX_VARS})")));
+        }
+        Ok(self.fi().add_loc_var(name, start_pc))
+    }
+
+    // 修改跳转指令的偏移量
+    fn patch_jump(&mut self, pc: usize, target: usize, line: u32) -> Result<()> {
+        if self.fi().patch_jump(pc, target) {
+            Ok(())
+        } else {
+            Err(self.error(line, "control structure too long"))
+        }
+    }
+
+    // 在funcs[level]中查找upvalue，必要时从外层函数捕获
+    fn index_of_upval(&mut self, level: usize, name: &str) -> Option<usize> {
+        if let Some(idx) = self.funcs[level].upvalues.iter().position(|uv| uv.name == name) {
+            return Some(idx);
+        }
+        if level == 0 {
+            return None;
+        }
+
+        let parent = &mut self.funcs[level - 1];
+        let upval = if let Some(var) = parent.find_loc_var(name) {
+            let var = &mut parent.loc_vars[var];
+            var.captured = true;
+            UpvalInfo {
+                name: name.to_string(),
+                instack: true,
+                idx: var.slot,
+            }
+        } else {
+            let idx = self.index_of_upval(level - 1, name)?;
+            UpvalInfo {
+                name: name.to_string(),
+                instack: false,
+                idx,
+            }
+        };
+        let upvalues = &mut self.funcs[level].upvalues;
+        upvalues.push(upval);
+        Some(upvalues.len() - 1)
+    }
+
+    fn upval_of_current(&mut self, name: &str) -> Option<usize> {
+        self.index_of_upval(self.funcs.len() - 1, name)
+    }
+
+    /* 代码块和goto */
+
+    fn enter_block(&mut self, is_loop: bool) {
+        let fi = self.fi();
+        let block = BlockInfo {
+            is_loop,
+            n_active: fi.active_vars.len(),
+            first_label: fi.labels.len(),
+            first_goto: fi.gotos.len(),
+        };
+        fi.blocks.push(block);
+    }
+
+    fn leave_block(&mut self, line: u32) -> Result<()> {
+        let fi = self.fi();
+        let block = fi.blocks.pop().unwrap();
+        let has_upval = fi.has_captured(block.n_active);
+        let is_inner = !fi.blocks.is_empty();
+
+        // 离开代码块时关闭被捕获的局部变量
+        if is_inner && has_upval {
+            fi.emit_jmp(line, block.n_active + 1, 0);
+        }
+        // 循环结束的位置是break标签
+        if block.is_loop {
+            let fi = self.fi();
+            let label = LabelInfo {
+                name: "break".to_string(),
+                line,
+                pc: fi.pc(),
+                n_active: fi.active_vars.len(),
+            };
+            self.resolve_gotos(&label, block.first_goto)?;
+        }
+
+        let fi = self.fi();
+        fi.remove_loc_vars(block.n_active);
+        fi.used_regs = block.n_active;
+        fi.labels.truncate(block.first_label);
+
+        if is_inner {
+            // 把未解析的goto移到外层代码块，并尝试与外层的标签匹配
+            let mut i = block.first_goto;
+            while i < self.fi().gotos.len() {
+                let fi = self.fi();
+                if fi.gotos[i].n_active > block.n_active {
+                    if has_upval {
+                        let pc = fi.gotos[i].pc;
+                        fi.patch_close(pc, block.n_active);
+                    }
+                    fi.gotos[i].n_active = block.n_active;
+                }
+                if !self.find_label(i)? {
+                    i += 1;
+                }
+            }
+        } else if let Some(goto) = self.fi().gotos.get(block.first_goto) {
+            let line = goto.line;
+            let msg = if goto.name == "break" {
+                format!("<break> at line {line} not inside a loop")
+            } else {
+                format!("no visible label '{}' for <goto> at line {line}", goto.name)
+            };
+            return Err(self.error(line, msg));
+        }
+        Ok(())
+    }
+
+    // 让gotos[i]跳转到label，并从待处理列表中删除
+    fn close_goto(&mut self, i: usize, label: &LabelInfo) -> Result<()> {
+        let fi = self.fi();
+        let goto = &fi.gotos[i];
+        if goto.n_active < label.n_active {
+            let var = &fi.loc_vars[fi.active_vars[goto.n_active]];
+            let line = goto.line;
+            let msg = format!(
+                "<goto {}> at line {line} jumps into the scope of local '{}'",
+                goto.name, var.name
+            );
+            return Err(self.error(line, msg));
+        }
+        let goto = fi.gotos.remove(i);
+        self.patch_jump(goto.pc, label.pc, goto.line)
+    }
+
+    // 在当前代码块的标签中查找gotos[i]的目标
+    fn find_label(&mut self, i: usize) -> Result<bool> {
+        let fi = self.fi();
+        let block = fi.blocks.last().unwrap();
+        let goto = &fi.gotos[i];
+        let Some(label) = fi.labels[block.first_label..].iter().find(|label| label.name == goto.name) else {
+            return Ok(false);
+        };
+        let label = LabelInfo {
+            name: label.name.clone(),
+            line: label.line,
+            pc: label.pc,
+            n_active: label.n_active,
+        };
+        // 向回跳转时关闭离开作用域的局部变量
+        if goto.n_active > label.n_active {
+            let pc = goto.pc;
+            fi.patch_close(pc, label.n_active);
+        }
+        self.close_goto(i, &label)?;
+        Ok(true)
+    }
+
+    // 解析当前代码块中所有跳转到label的goto
+    fn resolve_gotos(&mut self, label: &LabelInfo, first_goto: usize) -> Result<()> {
+        let mut i = first_goto;
+        while i < self.fi().gotos.len() {
+            if self.fi().gotos[i].name == label.name {
+                self.close_goto(i, label)?;
+            } else {
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn cg_label(&mut self, name: &str, line: u32, at_end: bool) -> Result<()> {
+        let fi = self.fi();
+        let block = fi.blocks.last().unwrap();
+        if let Some(label) = fi.labels[block.first_label..].iter().find(|label| label.name == name) {
+            let msg = format!("label '{}' already defined on line {}", name, label.line);
+            return Err(self.error(line, msg));
+        }
+        // 代码块末尾的标签认为局部变量已经离开作用域
+        let n_active = if at_end { block.n_active } else { fi.active_vars.len() };
+        let first_goto = block.first_goto;
+        let label = LabelInfo {
+            name: name.to_string(),
+            line,
+            pc: fi.pc(),
+            n_active,
+        };
+        self.resolve_gotos(&label, first_goto)?;
+        self.fi().labels.push(label);
+        Ok(())
+    }
+
+    fn cg_goto(&mut self, name: &str, line: u32) -> Result<()> {
+        let fi = self.fi();
+        let pc = fi.emit_jmp(line, 0, 0);
+        let goto = LabelInfo {
+            name: name.to_string(),
+            line,
+            pc,
+            n_active: fi.active_vars.len(),
+        };
+        fi.gotos.push(goto);
+        let i = fi.gotos.len() - 1;
+        self.find_label(i)?;
+        Ok(())
+    }
+
+    /* 语句 */
+
+    // repeat语句的循环体中，until前的标签不算在代码块末尾
+    fn cg_block(&mut self, block: &Block, is_repeat: bool) -> Result<()> {
+        for (i, stat) in block.stats.iter().enumerate() {
+            if let Stat::Label { line, name } = stat {
+                let at_end = !is_repeat
+                    && block.ret_exps.is_none()
+                    && block.stats[i + 1..].iter().all(|s| matches!(s, Stat::Label { .. }));
+                self.cg_label(name, *line, at_end)?;
+            } else {
+                self.cg_stat(stat)?;
+            }
+            let fi = self.fi();
+            fi.used_regs = fi.active_vars.len();
+        }
+        if let Some(exps) = &block.ret_exps {
+            self.cg_ret_stat(exps, block.last_line)?;
+        }
+        Ok(())
+    }
+
+    fn cg_stat(&mut self, stat: &Stat) -> Result<()> {
+        match stat {
+            Stat::Empty | Stat::Label { .. } => Ok(()),
+            Stat::Break { line } => self.cg_goto("break", *line),
+            Stat::Goto { line, name } => self.cg_goto(name, *line),
+            Stat::Do(block) => self.cg_scoped_block(block),
+            Stat::FuncCall(exp) => {
+                let r = self.fi().alloc_reg();
+                self.cg_func_call_exp(exp, r, 0)?;
+                self.fi().free_reg();
+                Ok(())
+            }
+            Stat::While { exp, block } => self.cg_while_stat(exp, block),
+            Stat::Repeat { block, exp } => self.cg_repeat_stat(block, exp),
+            Stat::If { exps, blocks } => self.cg_if_stat(exps, blocks),
+            Stat::ForNum {
+                line_of_for,
+                line_of_do,
+                var_name,
+                init,
+                limit,
+                step,
+                block,
+            } => self.cg_for_num_stat(*line_of_for, *line_of_do, var_name, [init, limit, step], block),
+            Stat::ForIn {
+                line_of_for,
+                line_of_do,
+                name_list,
+                exp_list,
+                block,
+            } => self.cg_for_in_stat(*line_of_for, *line_of_do, name_list, exp_list, block),
+            Stat::LocalVar {
+                last_line,
+                name_list,
+                exp_list,
+            } => {
+                let exps: Vec<&Exp> = exp_list.iter().collect();
+                self.cg_local_var_stat(name_list, &exps, *last_line)
+            }
+            Stat::Assign {
+                last_line,
+                var_list,
+                exp_list,
+            } => self.cg_assign_stat(var_list, exp_list, *last_line),
+            Stat::LocalFunc { name, exp } => {
+                let line = exp.line();
+                let r = self.add_loc_var(name, 0, line)?;
+                self.cg_exp(exp, r, 1)?;
+                // 局部函数在函数体中就可以引用自身，但调试信息中从CLOSURE之后开始
+                let fi = self.fi();
+                let idx = *fi.active_vars.last().unwrap();
+                fi.loc_vars[idx].start_pc = fi.pc();
+                Ok(())
+            }
+        }
+    }
+
+    fn cg_scoped_block(&mut self, block: &Block) -> Result<()> {
+        self.enter_block(false);
+        self.cg_block(block, false)?;
+        self.leave_block(block.last_line)
+    }
+
+    fn cg_ret_stat(&mut self, exps: &[Exp], line: u32) -> Result<()> {
+        let n = exps.len();
+        if n == 0 {
+            self.fi().emit_return(line, 0, 0);
+            return Ok(());
+        }
+
+        if n == 1 {
+            match &exps[0] {
+                Exp::Name { name, .. } => {
+                    if let Some(r) = self.fi().slot_of_loc_var(name) {
+                        self.fi().emit_return(line, r, 1);
+                        return Ok(());
+                    }
+                }
+                // 尾调用
+                Exp::FuncCall { line: call_line, .. } => {
+                    let r = self.fi().alloc_reg();
+                    let n_args = self.prep_func_call(&exps[0], r)?;
+                    self.fi().emit_abc(*call_line, Op::TailCall, r, (n_args + 1) as usize, 0);
+                    self.fi().free_reg();
+                    self.fi().emit_return(line, r, -1);
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+
+        let multi = exps[n - 1].is_multi_value();
+        let first = self.fi().used_regs;
+        for (i, exp) in exps.iter().enumerate() {
+            let r = self.fi().alloc_reg();
+            if i == n - 1 && multi {
+                self.cg_exp(exp, r, -1)?;
+            } else {
+                self.cg_exp(exp, r, 1)?;
+            }
+        }
+        self.fi().free_regs(n);
+        let n = if multi { -1 } else { n as isize };
+        self.fi().emit_return(line, first, n);
+        Ok(())
+    }
+
+    // 条件表达式的值放入寄存器后，为假时跳转，返回跳转指令的位置
+    fn cg_cond_jump(&mut self, exp: &Exp) -> Result<usize> {
+        let old_regs = self.fi().used_regs;
+        let (a, _) = self.exp_to_op_arg(exp, ARG_REG)?;
+        let fi = self.fi();
+        fi.used_regs = old_regs;
+        let line = exp.last_line();
+        fi.emit_test(line, a, 0);
+        Ok(fi.emit_jmp(line, 0, 0))
+    }
+
+    // while exp do block end
+    fn cg_while_stat(&mut self, exp: &Exp, block: &Block) -> Result<()> {
+        let start = self.fi().pc();
+        let jmp_to_end = self.cg_cond_jump(exp)?;
+
+        self.enter_block(true);
+        self.cg_scoped_block(block)?;
+        let pc = self.fi().emit_jmp(block.last_line, 0, 0);
+        self.patch_jump(pc, start, block.last_line)?;
+        self.leave_block(block.last_line)?;
+
+        let end = self.fi().pc();
+        self.patch_jump(jmp_to_end, end, block.last_line)
+    }
+
+    // repeat block until exp
+    // until中的条件表达式可以访问循环体中的局部变量
+    fn cg_repeat_stat(&mut self, block: &Block, exp: &Exp) -> Result<()> {
+        let start = self.fi().pc();
+        self.enter_block(true);
+        self.enter_block(false);
+        self.cg_block(block, true)?;
+
+        let jmp_back = self.cg_cond_jump(exp)?;
+        let line = exp.last_line();
+        self.patch_jump(jmp_back, start, line)?;
+        let fi = self.fi();
+        let n_active = fi.blocks.last().unwrap().n_active;
+        if fi.has_captured(n_active) {
+            fi.patch_close(jmp_back, n_active);
+        }
+        self.leave_block(line)?;
+        self.leave_block(line)
+    }
+
+    // if exp then block {elseif exp then block} [else block] end
+    fn cg_if_stat(&mut self, exps: &[Exp], blocks: &[Block]) -> Result<()> {
+        let mut jmp_to_ends = Vec::new();
+        for (i, (exp, block)) in exps.iter().zip(blocks).enumerate() {
+            // else分支不需要测试条件
+            let jmp_to_next = if let Exp::True { .. } = exp {
+                None
+            } else {
+                Some(self.cg_cond_jump(exp)?)
+            };
+
+            self.cg_scoped_block(block)?;
+            if i < exps.len() - 1 {
+                jmp_to_ends.push((self.fi().emit_jmp(block.last_line, 0, 0), block.last_line));
+            }
+            if let Some(pc) = jmp_to_next {
+                let target = self.fi().pc();
+                self.patch_jump(pc, target, block.last_line)?;
+            }
+        }
+
+        let end = self.fi().pc();
+        for (pc, line) in jmp_to_ends {
+            self.patch_jump(pc, end, line)?;
+        }
+        Ok(())
+    }
+
+    // for Name = exp, exp [, exp] do block end
+    fn cg_for_num_stat(
+        &mut self,
+        line_of_for: u32,
+        line_of_do: u32,
+        var_name: &str,
+        exps: [&Exp; 3],
+        block: &Block,
+    ) -> Result<()> {
+        self.enter_block(true);
+        let names = ["(for index)", "(for limit)", "(for step)"].map(String::from);
+        self.cg_local_var_stat(&names, &exps, line_of_for)?;
+        let base = self.fi().used_regs - 3;
+        let prep = self.fi().emit_asbx(line_of_do, Op::ForPrep, base, 0);
+
+        self.enter_block(false);
+        let start_pc = self.fi().pc();
+        self.add_loc_var(var_name, start_pc, line_of_for)?;
+        self.cg_block(block, false)?;
+        self.leave_block(block.last_line)?;
+
+        let fi = self.fi();
+        let pc = fi.emit_asbx(line_of_for, Op::ForLoop, base, 0);
+        self.patch_jump(prep, pc, line_of_for)?;
+        self.patch_jump(pc, prep + 1, line_of_for)?;
+        self.leave_block(block.last_line)
+    }
+
+    // for namelist in explist do block end
+    fn cg_for_in_stat(
+        &mut self,
+        line_of_for: u32,
+        line_of_do: u32,
+        name_list: &[String],
+        exp_list: &[Exp],
+        block: &Block,
+    ) -> Result<()> {
+        self.enter_block(true);
+        let names = ["(for generator)", "(for state)", "(for control)"].map(String::from);
+        let exps: Vec<&Exp> = exp_list.iter().collect();
+        self.cg_local_var_stat(&names, &exps, line_of_for)?;
+        let base = self.fi().used_regs - 3;
+        let jmp_to_call = self.fi().emit_jmp(line_of_do, 0, 0);
+
+        self.enter_block(false);
+        let start_pc = self.fi().pc();
+        for name in name_list {
+            self.add_loc_var(name, start_pc, line_of_for)?;
+        }
+        self.cg_block(block, false)?;
+        self.leave_block(block.last_line)?;
+
+        let call = self.fi().pc();
+        self.patch_jump(jmp_to_call, call, line_of_for)?;
+        let fi = self.fi();
+        fi.emit_abc(line_of_for, Op::TForCall, base, 0, name_list.len());
+        let pc = fi.emit_asbx(line_of_for, Op::TForLoop, base + 2, 0);
+        self.patch_jump(pc, jmp_to_call + 1, line_of_for)?;
+        self.leave_block(block.last_line)
+    }
+
+    // 把表达式的值依次放入寄存器，返回第一个寄存器
+    // 表达式不足时用nil补齐，最后一个多值表达式会展开
+    fn cg_exps_to_regs(&mut self, exps: &[&Exp], n: usize, line: u32) -> Result<usize> {
+        let first = self.fi().used_regs;
+        let n_exps = exps.len();
+        let mut multi = false;
+        for (i, exp) in exps.iter().enumerate() {
+            let a = self.fi().alloc_reg();
+            if i == n_exps - 1 && exp.is_multi_value() {
+                if n >= n_exps {
+                    multi = true;
+                    let n_results = n - n_exps + 1;
+                    self.cg_exp(exp, a, n_results as isize)?;
+                    self.fi().alloc_regs(n_results - 1);
+                } else {
+                    self.cg_exp(exp, a, 0)?;
+                }
+            } else {
+                self.cg_exp(exp, a, 1)?;
+            }
+        }
+        if !multi && n > n_exps {
+            let fi = self.fi();
+            let a = fi.alloc_regs(n - n_exps);
+            fi.emit_load_nil(line, a, n - n_exps);
+        }
+        Ok(first)
+    }
+
+    // local namelist [= explist]
+    fn cg_local_var_stat(&mut self, name_list: &[String], exps: &[&Exp], line: u32) -> Result<()> {
+        let old_regs = self.fi().used_regs;
+        self.cg_exps_to_regs(exps, name_list.len(), line)?;
+        self.fi().used_regs = old_regs;
+
+        let start_pc = self.fi().pc();
+        for name in name_list {
+            self.add_loc_var(name, start_pc, line)?;
+        }
+        Ok(())
+    }
+
+    // varlist = explist
+    fn cg_assign_stat(&mut self, var_list: &[Exp], exp_list: &[Exp], line: u32) -> Result<()> {
+        let old_regs = self.fi().used_regs;
+
+        // 单个局部变量赋值时直接把结果放入变量的寄存器
+        if let ([Exp::Name { name, .. }], [exp]) = (var_list, exp_list) {
+            if let Some(a) = self.fi().slot_of_loc_var(name) {
+                if writes_target_last(exp) {
+                    self.cg_exp(exp, a, 1)?;
+                    self.fi().used_regs = old_regs;
+                    return Ok(());
+                }
+            }
+        }
+
+        // 被赋值的局部变量如果同时用作表或键，需要先复制一份，保证使用的是赋值前的值
+        let assigned: Vec<&str> = var_list
+            .iter()
+            .filter_map(|var| match var {
+                Exp::Name { name, .. } => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        let conflict = |exp: &Exp| matches!(exp, Exp::Name { name, .. } if assigned.contains(&name.as_str()));
+
+        // 先计算表和键
+        let mut targets = Vec::with_capacity(var_list.len());
+        for var in var_list {
+            let target = match var {
+                Exp::TableAccess { prefix, key, .. } => {
+                    let t = if conflict(prefix) {
+                        let r = self.fi().alloc_reg();
+                        self.cg_exp(prefix, r, 1)?;
+                        r
+                    } else {
+                        self.exp_to_op_arg(prefix, ARG_REG)?.0
+                    };
+                    let k = if conflict(key) {
+                        let r = self.fi().alloc_reg();
+                        self.cg_exp(key, r, 1)?;
+                        r
+                    } else {
+                        self.exp_to_op_arg(key, ARG_RK)?.0
+                    };
+                    Target::Table(t, k)
+                }
+                Exp::Name { name, line } => {
+                    if let Some(slot) = self.fi().slot_of_loc_var(name) {
+                        Target::Local(slot)
+                    } else if let Some(idx) = self.upval_of_current(name) {
+                        Target::Upval(idx)
+                    } else {
+                        // 全局变量 _ENV.name
+                        let key = Exp::Str {
+                            line: *line,
+                            val: name.clone().into_bytes(),
+                        };
+                        let k = self.exp_to_op_arg(&key, ARG_RK)?.0;
+                        match self.fi().slot_of_loc_var("_ENV") {
+                            Some(env) => Target::Table(env, k),
+                            None => Target::TabUp(self.upval_of_current("_ENV").unwrap(), k),
+                        }
+                    }
+                }
+                _ => unreachable!(),
+            };
+            targets.push(target);
+        }
+
+        // 再计算所有的值
+        let exps: Vec<&Exp> = exp_list.iter().collect();
+        let first = self.cg_exps_to_regs(&exps, var_list.len(), line)?;
+
+        // 与luac一样从后往前赋值
+        let fi = self.fi();
+        for (i, target) in targets.iter().enumerate().rev() {
+            let v = first + i;
+            match *target {
+                Target::Local(a) => fi.emit_move(line, a, v),
+                Target::Upval(b) => {
+                    fi.emit_abc(line, Op::SetUpval, v, b, 0);
+                }
+                Target::Table(a, k) => {
+                    fi.emit_abc(line, Op::SetTable, a, k, v);
+                }
+                Target::TabUp(a, k) => {
+                    fi.emit_abc(line, Op::SetTabUp, a, k, v);
+                }
+            }
+        }
+        fi.used_regs = old_regs;
+        Ok(())
+    }
+
+    /* 表达式 */
+
+    // 计算表达式并把结果放入寄存器a，n是需要的结果数量，-1表示全部
+    fn cg_exp(&mut self, exp: &Exp, a: usize, n: isize) -> Result<()> {
+        let fi = self.fi();
+        match exp {
+            Exp::Nil { line } => fi.emit_load_nil(*line, a, n.max(1) as usize),
+            Exp::False { line } => fi.emit_load_bool(*line, a, 0, 0),
+            Exp::True { line } => fi.emit_load_bool(*line, a, 1, 0),
+            Exp::Integer { line, val } => fi.emit_load_k(*line, a, ConstKey::Integer(*val)),
+            Exp::Float { line, val } => fi.emit_load_k(*line, a, ConstKey::Number(val.to_bits())),
+            Exp::Str { line, val } => fi.emit_load_k(*line, a, ConstKey::Str(val.clone())),
+            Exp::Paren(exp) => self.cg_exp(exp, a, 1)?,
+            Exp::Vararg { line } => {
+                if !fi.is_vararg {
+                    return Err(self.error(*line, "cannot use '...' outside a vararg function near '...'"));
+                }
+                fi.emit_vararg(*line, a, n);
+            }
+            Exp::FuncDef {
+                line,
+                last_line,
+                par_list,
+                is_vararg,
+                block,
+            } => {
+                let mut sub = FuncInfo::new(*line, *last_line);
+                sub.is_vararg = *is_vararg;
+                let proto = self.gen_func(sub, par_list, block, *last_line)?;
+                let fi = self.fi();
+                fi.protos.push(Rc::new(proto));
+                let bx = fi.protos.len() - 1;
+                fi.emit_abx(*last_line, Op::Closure, a, bx);
+            }
+            Exp::Table {
+                line,
+                key_exps,
+                val_exps,
+                ..
+            } => self.cg_table_constructor(*line, key_exps, val_exps, a)?,
+            Exp::Unop { line, op, exp } => {
+                let old_regs = fi.used_regs;
+                let (b, _) = self.exp_to_op_arg(exp, ARG_REG)?;
+                let op = match op {
+                    Token::OpMinus => Op::Unm,
+                    Token::OpWave => Op::BNot,
+                    Token::OpLen => Op::Len,
+                    _ => Op::Not,
+                };
+                let fi = self.fi();
+                fi.emit_abc(*line, op, a, b, 0);
+                fi.used_regs = old_regs;
+            }
+            Exp::Binop { line, op, exp1, exp2 } => self.cg_binop_exp(*line, op, exp1, exp2, a)?,
+            Exp::Concat { line, exps } => {
+                let b = fi.used_regs;
+                for exp in exps {
+                    let r = self.fi().alloc_reg();
+                    self.cg_exp(exp, r, 1)?;
+                }
+                let fi = self.fi();
+                let c = b + exps.len() - 1;
+                fi.free_regs(exps.len());
+                fi.emit_abc(*line, Op::Concat, a, b, c);
+            }
+            Exp::Name { line, name } => {
+                if let Some(r) = fi.slot_of_loc_var(name) {
+                    fi.emit_move(*line, a, r);
+                } else if let Some(idx) = self.upval_of_current(name) {
+                    self.fi().emit_abc(*line, Op::GetUpval, a, idx, 0);
+                } else {
+                    // 全局变量 _ENV.name
+                    let prefix = Exp::Name {
+                        line: *line,
+                        name: "_ENV".to_string(),
+                    };
+                    let key = Exp::Str {
+                        line: *line,
+                        val: name.clone().into_bytes(),
+                    };
+                    self.cg_table_access(&prefix, &key, *line, a)?;
+                }
+            }
+            Exp::TableAccess { last_line, prefix, key } => self.cg_table_access(prefix, key, *last_line, a)?,
+            Exp::FuncCall { .. } => self.cg_func_call_exp(exp, a, n)?,
+        }
+        Ok(())
+    }
+
+    fn cg_table_access(&mut self, prefix: &Exp, key: &Exp, line: u32, a: usize) -> Result<()> {
+        let old_regs = self.fi().used_regs;
+        let (b, kind) = self.exp_to_op_arg(prefix, ARG_RU)?;
+        let (c, _) = self.exp_to_op_arg(key, ARG_RK)?;
+        let fi = self.fi();
+        fi.used_regs = old_regs;
+        let op = if kind == ARG_UPVAL { Op::GetTabUp } else { Op::GetTable };
+        fi.emit_abc(line, op, a, b, c);
+        Ok(())
+    }
+
+    fn cg_binop_exp(&mut self, line: u32, op: &Token, exp1: &Exp, exp2: &Exp, a: usize) -> Result<()> {
+        let old_regs = self.fi().used_regs;
+        match op {
+            Token::KwAnd | Token::KwOr => {
+                // a = exp1; if a为假(and)或为真(or) 跳过exp2
+                let (b, _) = self.exp_to_op_arg(exp1, ARG_REG)?;
+                let fi = self.fi();
+                fi.used_regs = old_regs;
+                let c = if *op == Token::KwAnd { 0 } else { 1 };
+                fi.emit_abc(line, Op::TestSet, a, b, c);
+                let jmp = fi.emit_jmp(line, 0, 0);
+
+                let (b, _) = self.exp_to_op_arg(exp2, ARG_REG)?;
+                let fi = self.fi();
+                fi.used_regs = old_regs;
+                fi.emit_move(line, a, b);
+                let end = fi.pc();
+                self.patch_jump(jmp, end, line)?;
+            }
+            _ => {
+                let (b, _) = self.exp_to_op_arg(exp1, ARG_RK)?;
+                let (c, _) = self.exp_to_op_arg(exp2, ARG_RK)?;
+                let fi = self.fi();
+                fi.used_regs = old_regs;
+                let arith = match op {
+                    Token::OpAdd => Some(Op::Add),
+                    Token::OpMinus => Some(Op::Sub),
+                    Token::OpMul => Some(Op::Mul),
+                    Token::OpMod => Some(Op::Mod),
+                    Token::OpPow => Some(Op::Pow),
+                    Token::OpDiv => Some(Op::Div),
+                    Token::OpIDiv => Some(Op::IDiv),
+                    Token::OpBAnd => Some(Op::BAnd),
+                    Token::OpBOr => Some(Op::BOr),
+                    Token::OpWave => Some(Op::BXor),
+                    Token::OpShl => Some(Op::Shl),
+                    Token::OpShr => Some(Op::Shr),
+                    _ => None,
+                };
+                if let Some(arith) = arith {
+                    fi.emit_abc(line, arith, a, b, c);
+                    return Ok(());
+                }
+
+                // 比较运算的结果通过跳转得到布尔值
+                let (cmp, flag, b, c) = match op {
+                    Token::OpEq => (Op::Eq, 1, b, c),
+                    Token::OpNe => (Op::Eq, 0, b, c),
+                    Token::OpLt => (Op::Lt, 1, b, c),
+                    Token::OpGt => (Op::Lt, 1, c, b),
+                    Token::OpLe => (Op::Le, 1, b, c),
+                    _ => (Op::Le, 1, c, b),
+                };
+                fi.emit_abc(line, cmp, flag, b, c);
+                fi.emit_jmp(line, 0, 1);
+                fi.emit_load_bool(line, a, 0, 1);
+                fi.emit_load_bool(line, a, 1, 0);
+            }
+        }
+        Ok(())
+    }
+
+    fn cg_table_constructor(
+        &mut self,
+        line: u32,
+        key_exps: &[Option<Exp>],
+        val_exps: &[Exp],
+        a: usize,
+    ) -> Result<()> {
+        let n_exps = val_exps.len();
+        let n_arr = key_exps.iter().filter(|k| k.is_none()).count();
+        let multi = n_exps > 0 && key_exps[n_exps - 1].is_none() && val_exps[n_exps - 1].is_multi_value();
+        let n_arr_size = if multi { n_arr - 1 } else { n_arr };
+        self.fi().emit_new_table(line, a, n_arr_size, n_exps - n_arr);
+
+        let mut arr_idx = 0;
+        for (i, (key, val)) in key_exps.iter().zip(val_exps).enumerate() {
+            let val_line = val.last_line();
+            match key {
+                None => {
+                    arr_idx += 1;
+                    let tmp = self.fi().alloc_reg();
+                    let is_multi = i == n_exps - 1 && multi;
+                    self.cg_exp(val, tmp, if is_multi { -1 } else { 1 })?;
+
+                    if arr_idx % FIELDS_PER_FLUSH == 0 || arr_idx == n_arr {
+                        let n = match arr_idx % FIELDS_PER_FLUSH {
+                            0 => FIELDS_PER_FLUSH,
+                            n => n,
+                        };
+                        let fi = self.fi();
+                        fi.free_regs(n);
+                        let c = (arr_idx - 1) / FIELDS_PER_FLUSH + 1;
+                        fi.emit_set_list(val_line, a, if is_multi { 0 } else { n }, c);
+                    }
+                }
+                Some(key) => {
+                    let old_regs = self.fi().used_regs;
+                    let (b, _) = self.exp_to_op_arg(key, ARG_RK)?;
+                    let (c, _) = self.exp_to_op_arg(val, ARG_RK)?;
+                    let fi = self.fi();
+                    fi.used_regs = old_regs;
+                    fi.emit_abc(val_line, Op::SetTable, a, b, c);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn cg_func_call_exp(&mut self, exp: &Exp, a: usize, n: isize) -> Result<()> {
+        let n_args = self.prep_func_call(exp, a)?;
+        self.fi().emit_call(exp.line(), a, n_args, n);
+        Ok(())
+    }
+
+    // 把函数和参数依次放入a开始的寄存器，返回参数数量，-1表示参数数量不确定
+    fn prep_func_call(&mut self, exp: &Exp, a: usize) -> Result<isize> {
+        let Exp::FuncCall {
+            line, prefix, name, args, ..
+        } = exp
+        else {
+            unreachable!()
+        };
+
+        self.cg_exp(prefix, a, 1)?;
+        if let Some(name) = name {
+            // obj:name(args) => obj.name(obj, args)
+            self.fi().alloc_reg();
+            let (c, kind) = self.exp_to_op_arg(name, ARG_RK)?;
+            let fi = self.fi();
+            fi.emit_abc(*line, Op::Self_, a, a, c);
+            if kind == ARG_REG {
+                fi.free_reg();
+            }
+        }
+
+        let n_args = args.len();
+        let mut multi = false;
+        for (i, arg) in args.iter().enumerate() {
+            let tmp = self.fi().alloc_reg();
+            if i == n_args - 1 && arg.is_multi_value() {
+                multi = true;
+                self.cg_exp(arg, tmp, -1)?;
+            } else {
+                self.cg_exp(arg, tmp, 1)?;
+            }
+        }
+        self.fi().free_regs(n_args);
+
+        if name.is_some() {
+            self.fi().free_reg();
+        }
+        if multi {
+            return Ok(-1);
+        }
+        Ok(n_args as isize + name.is_some() as isize)
+    }
+
+    // 把表达式转换为指令操作数，返回操作数和类型
+    // 能直接使用常量、局部变量或upvalue时不会生成指令，否则把值放入新分配的寄存器
+    fn exp_to_op_arg(&mut self, exp: &Exp, kinds: u8) -> Result<(usize, u8)> {
+        if kinds & ARG_CONST != 0 {
+            let k = match exp {
+                Exp::Nil { .. } => Some(ConstKey::Nil),
+                Exp::False { .. } => Some(ConstKey::Boolean(false)),
+                Exp::True { .. } => Some(ConstKey::Boolean(true)),
+                Exp::Integer { val, .. } => Some(ConstKey::Integer(*val)),
+                Exp::Float { val, .. } => Some(ConstKey::Number(val.to_bits())),
+                Exp::Str { val, .. } => Some(ConstKey::Str(val.clone())),
+                _ => None,
+            };
+            if let Some(k) = k {
+                let fi = self.fi();
+                if fi.constants.len() <= MAX_INDEX_RK || fi.constants.contains(&k) {
+                    let idx = fi.index_of_constant(k);
+                    if idx <= MAX_INDEX_RK {
+                        return Ok((idx | BIT_RK as usize, ARG_CONST));
+                    }
+                }
+            }
+        }
+
+        if let Exp::Name { name, .. } = exp {
+            if kinds & ARG_REG != 0 {
+                if let Some(r) = self.fi().slot_of_loc_var(name) {
+                    return Ok((r, ARG_REG));
+                }
+            }
+            if kinds & ARG_UPVAL != 0 {
+                if let Some(idx) = self.upval_of_current(name) {
+                    return Ok((idx, ARG_UPVAL));
+                }
+            }
+        }
+
+        let a = self.fi().alloc_reg();
+        self.cg_exp(exp, a, 1)?;
+        Ok((a, ARG_REG))
+    }
+}
+
+// 赋值目标
+enum Target {
+    Local(usize),
+    Upval(usize),
+    // 表和键
+    Table(usize, usize),
+    // upvalue中的表和键
+    TabUp(usize, usize),
+}
+
+// 表达式求值时是否只在最后写入目标寄存器，这样可以直接把局部变量作为目标
+fn writes_target_last(exp: &Exp) -> bool {
+    match exp {
+        Exp::Nil { .. }
+        | Exp::True { .. }
+        | Exp::False { .. }
+        | Exp::Integer { .. }
+        | Exp::Float { .. }
+        | Exp::Str { .. }
+        | Exp::Name { .. }
+        | Exp::Unop { .. }
+        | Exp::Concat { .. }
+        | Exp::FuncDef { .. }
+        | Exp::TableAccess { .. } => true,
+        Exp::Binop { op, .. } => !matches!(op, Token::KwAnd | Token::KwOr),
+        Exp::Paren(exp) => writes_target_last(exp),
+        _ => false,
+    }
+}
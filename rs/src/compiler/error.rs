@@ -0,0 +1,21 @@
+use std::fmt;
+
+use crate::binary::chunk::short_source;
+
+/// 编译错误，包括词法错误和语法错误
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    // 源文件名
+    pub source: String,
+    // 出错的行号
+    pub line: u32,
+    pub msg: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", short_source(&self.source), self.line, self.msg)
+    }
+}
+
+impl std::error::Error for CompileError {}
@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::binary::chunk::{Constant, LocVar, LuaVersion, Prototype, UpValue};
+use crate::vm::instruction::{Instruction, MAX_ARG_BX, MAX_ARG_SBX};
+use crate::vm::opcode::*;
+
+// 一个函数最多使用的寄存器数量
+pub const MAX_REGS: usize = 255;
+// 一个函数最多定义的局部变量数量
+pub const MAX_VARS: usize = 200;
+// 一个函数最多使用的upvalue数量
+pub const MAX_UPVALUES: usize = 255;
+// RK操作数中常量索引的最大值
+pub const MAX_INDEX_RK: usize = 0xFF;
+
+const MAX_ARG_C: usize = (1 << 9) - 1;
+
+/// 常量表的键，浮点数按位比较，整数和浮点数不会合并
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ConstKey {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(u64),
+    Str(Vec<u8>),
+}
+
+/// 局部变量信息
+#[derive(Debug)]
+pub struct LocVarInfo {
+    pub name: String,
+    // 占用的寄存器
+    pub slot: usize,
+    pub start_pc: usize,
+    pub end_pc: usize,
+    // 是否被闭包捕获
+    pub captured: bool,
+}
+
+/// upvalue信息
+#[derive(Debug)]
+pub struct UpvalInfo {
+    pub name: String,
+    // 是否捕获外层函数的局部变量
+    pub instack: bool,
+    // 外层函数的寄存器或upvalue索引
+    pub idx: usize,
+}
+
+/// 代码块信息
+#[derive(Debug)]
+pub struct BlockInfo {
+    // 是否是循环，循环结束的位置有一个隐式的break标签
+    pub is_loop: bool,
+    // 进入代码块时活跃的局部变量数量
+    pub n_active: usize,
+    // 代码块内第一个标签的索引
+    pub first_label: usize,
+    // 代码块内第一个待处理goto的索引
+    pub first_goto: usize,
+}
+
+/// 标签或待处理的goto，break被当作goto break处理
+#[derive(Debug)]
+pub struct LabelInfo {
+    pub name: String,
+    pub line: u32,
+    // 标签的位置或者goto跳转指令的位置
+    pub pc: usize,
+    // 活跃的局部变量数量
+    pub n_active: usize,
+}
+
+/// 编译中的函数状态
+#[derive(Debug, Default)]
+pub struct FuncInfo {
+    pub used_regs: usize,
+    pub max_regs: usize,
+    // 所有局部变量，按定义顺序保存，用于生成调试信息
+    pub loc_vars: Vec<LocVarInfo>,
+    // 当前活跃的局部变量在loc_vars中的索引
+    pub active_vars: Vec<usize>,
+    pub blocks: Vec<BlockInfo>,
+    pub labels: Vec<LabelInfo>,
+    pub gotos: Vec<LabelInfo>,
+    pub upvalues: Vec<UpvalInfo>,
+    pub constants: Vec<ConstKey>,
+    const_index: HashMap<ConstKey, usize>,
+    pub insts: Vec<u32>,
+    pub line_nums: Vec<u32>,
+    pub protos: Vec<Rc<Prototype>>,
+    pub line: u32,
+    pub last_line: u32,
+    pub num_params: usize,
+    pub is_vararg: bool,
+}
+
+impl FuncInfo {
+    pub fn new(line: u32, last_line: u32) -> Self {
+        Self {
+            line,
+            last_line,
+            ..Default::default()
+        }
+    }
+
+    // 下一条指令的位置
+    pub fn pc(&self) -> usize {
+        self.insts.len()
+    }
+
+    /* 常量表 */
+
+    pub fn index_of_constant(&mut self, k: ConstKey) -> usize {
+        if let Some(idx) = self.const_index.get(&k) {
+            return *idx;
+        }
+        let idx = self.constants.len();
+        self.constants.push(k.clone());
+        self.const_index.insert(k, idx);
+        idx
+    }
+
+    /* 寄存器分配 */
+
+    pub fn alloc_reg(&mut self) -> usize {
+        self.used_regs += 1;
+        self.max_regs = self.max_regs.max(self.used_regs);
+        self.used_regs - 1
+    }
+
+    pub fn alloc_regs(&mut self, n: usize) -> usize {
+        for _ in 0..n {
+            self.alloc_reg();
+        }
+        self.used_regs - n
+    }
+
+    pub fn free_reg(&mut self) {
+        self.used_regs -= 1;
+    }
+
+    pub fn free_regs(&mut self, n: usize) {
+        self.used_regs -= n;
+    }
+
+    /* 局部变量 */
+
+    // 定义局部变量，为其分配一个寄存器
+    pub fn add_loc_var(&mut self, name: &str, start_pc: usize) -> usize {
+        let slot = self.alloc_reg();
+        self.active_vars.push(self.loc_vars.len());
+        self.loc_vars.push(LocVarInfo {
+            name: name.to_string(),
+            slot,
+            start_pc,
+            end_pc: 0,
+            captured: false,
+        });
+        slot
+    }
+
+    // 按名字查找活跃的局部变量，返回其在loc_vars中的索引
+    pub fn find_loc_var(&self, name: &str) -> Option<usize> {
+        self.active_vars
+            .iter()
+            .rev()
+            .find(|idx| self.loc_vars[**idx].name == name)
+            .copied()
+    }
+
+    pub fn slot_of_loc_var(&self, name: &str) -> Option<usize> {
+        self.find_loc_var(name).map(|idx| self.loc_vars[idx].slot)
+    }
+
+    // 结束n_active之后的局部变量的作用域
+    pub fn remove_loc_vars(&mut self, n_active: usize) {
+        let pc = self.pc();
+        for idx in self.active_vars.drain(n_active..) {
+            self.loc_vars[idx].end_pc = pc;
+        }
+    }
+
+    // n_active之后是否有局部变量被闭包捕获
+    pub fn has_captured(&self, n_active: usize) -> bool {
+        self.active_vars[n_active..]
+            .iter()
+            .any(|idx| self.loc_vars[*idx].captured)
+    }
+
+    /* 指令 */
+
+    // 编码时的范围检查失败说明代码生成有误，直接panic
+    pub fn emit_abc(&mut self, line: u32, op: Op, a: usize, b: usize, c: usize) -> usize {
+        let i = u32::encode_abc(op, a as isize, b as isize, c as isize);
+        self.emit(line, i.unwrap_or_else(|err| panic!("{err}")))
+    }
+
+    pub fn emit_abx(&mut self, line: u32, op: Op, a: usize, bx: usize) -> usize {
+        let i = u32::encode_abx(op, a as isize, bx as isize);
+        self.emit(line, i.unwrap_or_else(|err| panic!("{err}")))
+    }
+
+    pub fn emit_asbx(&mut self, line: u32, op: Op, a: usize, sbx: isize) -> usize {
+        let i = u32::encode_asbx(op, a as isize, sbx);
+        self.emit(line, i.unwrap_or_else(|err| panic!("{err}")))
+    }
+
+    pub fn emit_ax(&mut self, line: u32, op: Op, ax: usize) -> usize {
+        let i = u32::encode_ax(op, ax as isize);
+        self.emit(line, i.unwrap_or_else(|err| panic!("{err}")))
+    }
+
+    fn emit(&mut self, line: u32, i: u32) -> usize {
+        self.insts.push(i);
+        self.line_nums.push(line);
+        self.insts.len() - 1
+    }
+
+    // 修改跳转指令的偏移量，超出范围时返回false
+    pub fn fix_sbx(&mut self, pc: usize, sbx: isize) -> bool {
+        if sbx.abs() > MAX_ARG_SBX {
+            return false;
+        }
+        let i = self.insts[pc];
+        let op = i.op().expect("not an instruction");
+        match u32::encode_asbx(op, i.asbx().0, sbx) {
+            Ok(i) => {
+                self.insts[pc] = i;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // 让pc处的跳转指令跳转到target
+    pub fn patch_jump(&mut self, pc: usize, target: usize) -> bool {
+        self.fix_sbx(pc, target as isize - pc as isize - 1)
+    }
+
+    // 设置跳转指令的A操作数，跳转时关闭level及以上寄存器的upvalue
+    pub fn patch_close(&mut self, pc: usize, level: usize) {
+        let i = self.insts[pc];
+        let (a, sbx) = i.asbx();
+        if a == 0 || a > level as isize + 1 {
+            let op = i.op().expect("not an instruction");
+            let i = u32::encode_asbx(op, level as isize + 1, sbx);
+            self.insts[pc] = i.unwrap_or_else(|err| panic!("{err}"));
+        }
+    }
+
+    pub fn emit_move(&mut self, line: u32, a: usize, b: usize) {
+        self.emit_abc(line, Op::Move, a, b, 0);
+    }
+
+    pub fn emit_load_nil(&mut self, line: u32, a: usize, n: usize) {
+        self.emit_abc(line, Op::LoadNil, a, n - 1, 0);
+    }
+
+    pub fn emit_load_bool(&mut self, line: u32, a: usize, b: usize, c: usize) {
+        self.emit_abc(line, Op::LoadBool, a, b, c);
+    }
+
+    pub fn emit_load_k(&mut self, line: u32, a: usize, k: ConstKey) {
+        let idx = self.index_of_constant(k);
+        if idx as isize <= MAX_ARG_BX {
+            self.emit_abx(line, Op::LoadK, a, idx);
+        } else {
+            self.emit_abx(line, Op::LoadKX, a, 0);
+            self.emit_ax(line, Op::ExtraArg, idx);
+        }
+    }
+
+    pub fn emit_jmp(&mut self, line: u32, a: usize, sbx: isize) -> usize {
+        self.emit_asbx(line, Op::Jmp, a, sbx)
+    }
+
+    pub fn emit_test(&mut self, line: u32, a: usize, c: usize) {
+        self.emit_abc(line, Op::Test, a, 0, c);
+    }
+
+    pub fn emit_return(&mut self, line: u32, a: usize, n: isize) {
+        self.emit_abc(line, Op::Return, a, (n + 1) as usize, 0);
+    }
+
+    // n为-1时表示数量不确定
+    pub fn emit_call(&mut self, line: u32, a: usize, n_args: isize, n_results: isize) {
+        self.emit_abc(line, Op::Call, a, (n_args + 1) as usize, (n_results + 1) as usize);
+    }
+
+    pub fn emit_vararg(&mut self, line: u32, a: usize, n: isize) {
+        self.emit_abc(line, Op::VarArg, a, (n + 1) as usize, 0);
+    }
+
+    pub fn emit_new_table(&mut self, line: u32, a: usize, n_arr: usize, n_rec: usize) {
+        self.emit_abc(line, Op::NewTable, a, int2fb(n_arr), int2fb(n_rec));
+    }
+
+    // c是批次编号，超出C操作数范围时放在EXTRAARG中
+    pub fn emit_set_list(&mut self, line: u32, a: usize, b: usize, c: usize) {
+        if c <= MAX_ARG_C {
+            self.emit_abc(line, Op::SetList, a, b, c);
+        } else {
+            self.emit_abc(line, Op::SetList, a, b, 0);
+            self.emit_ax(line, Op::ExtraArg, c);
+        }
+    }
+
+    /* 生成函数原型 */
+
+    pub fn to_proto(self, source: &str) -> Result<Prototype, String> {
+        if self.max_regs > MAX_REGS {
+            return Err("function or expression needs too many registers".to_string());
+        }
+        if self.upvalues.len() > MAX_UPVALUES {
+            return Err("too many upvalues".to_string());
+        }
+
+        let mut constants = Vec::with_capacity(self.constants.len());
+        for k in self.constants {
+            constants.push(match k {
+                ConstKey::Nil => Constant::Nil,
+                ConstKey::Boolean(b) => Constant::Boolean(b),
+                ConstKey::Integer(i) => Constant::Integer(i),
+                ConstKey::Number(n) => Constant::Number(f64::from_bits(n)),
+                ConstKey::Str(s) => Constant::Str(s),
+            });
+        }
+
+        Ok(Prototype {
+            version: LuaVersion::Lua53,
+            source: source.as_bytes().to_vec(),
+            line_defined: self.line,
+            last_line_defined: self.last_line,
+            num_params: self.num_params as u8,
+            is_vararg: self.is_vararg as u8,
+            max_stack_size: self.max_regs.max(2) as u8,
+            code: self.insts,
+            constants,
+            upvalues: self
+                .upvalues
+                .iter()
+                .map(|uv| UpValue {
+                    instack: uv.instack as u8,
+                    idx: uv.idx as u8,
+                    kind: 0,
+                })
+                .collect(),
+            protos: self.protos,
+            line_info: self.line_nums,
+            loc_vars: self
+                .loc_vars
+                .into_iter()
+                .map(|var| LocVar {
+                    var_name: var.name,
+                    start_pc: var.start_pc as u32,
+                    end_pc: var.end_pc as u32,
+                })
+                .collect(),
+            upvalue_names: self.upvalues.into_iter().map(|uv| uv.name).collect(),
+        })
+    }
+}
+
+// 把整数编码为浮点字节(eeeeexxx)，NEWTABLE用它表示表的初始大小
+fn int2fb(mut x: usize) -> usize {
+    let mut e = 0;
+    if x < 8 {
+        return x;
+    }
+    while x >= (8 << 4) {
+        x = (x + 0xF) >> 4;
+        e += 4;
+    }
+    while x >= (8 << 1) {
+        x = (x + 1) >> 1;
+        e += 1;
+    }
+    ((e + 1) << 3) | (x - 8)
+}
@@ -0,0 +1,520 @@
+use crate::compiler::error::CompileError;
+use crate::compiler::token::Token;
+use crate::vm::lua_value::{parse_number, LuaValue};
+
+type Result<T> = std::result::Result<T, CompileError>;
+
+/// 词法分析器，按字节处理源码(Lua源码不要求是UTF-8)
+pub struct Lexer<'a> {
+    // 源文件名
+    source: String,
+    chunk: &'a [u8],
+    pos: usize,
+    // 当前行号
+    line: u32,
+    // 预读的词法单元
+    ahead: Option<(u32, Token)>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(chunk: &'a [u8], source: &str) -> Self {
+        let mut lexer = Self {
+            source: source.to_string(),
+            chunk,
+            pos: 0,
+            line: 1,
+            ahead: None,
+        };
+        // 跳过第一行的 #! 注释
+        if chunk.starts_with(b"#") {
+            while lexer.pos < chunk.len() && !is_newline(chunk[lexer.pos]) {
+                lexer.pos += 1;
+            }
+        }
+        lexer
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    pub fn error(&self, msg: impl Into<String>) -> CompileError {
+        CompileError {
+            source: self.source.clone(),
+            line: self.line,
+            msg: msg.into(),
+        }
+    }
+
+    // 预读下一个词法单元
+    pub fn look_ahead(&mut self) -> Result<&Token> {
+        if self.ahead.is_none() {
+            let line = self.line;
+            let token = self.scan()?;
+            let token_line = self.line;
+            self.line = line;
+            self.ahead = Some((token_line, token));
+        }
+        Ok(&self.ahead.as_ref().unwrap().1)
+    }
+
+    // 预读的词法单元所在的行号
+    pub fn look_ahead_line(&self) -> u32 {
+        self.ahead.as_ref().map_or(self.line, |(line, _)| *line)
+    }
+
+    // 读取下一个词法单元，返回(行号, 词法单元)
+    pub fn next_token(&mut self) -> Result<(u32, Token)> {
+        if let Some((line, token)) = self.ahead.take() {
+            self.line = line;
+            return Ok((line, token));
+        }
+        let token = self.scan()?;
+        Ok((self.line, token))
+    }
+
+    fn peek_byte(&self, offset: usize) -> u8 {
+        self.chunk.get(self.pos + offset).copied().unwrap_or(0)
+    }
+
+    fn test(&self, s: &[u8]) -> bool {
+        self.chunk[self.pos..].starts_with(s)
+    }
+
+    // 跳过换行，\n、\r、\r\n、\n\r都算作一个换行
+    fn skip_newline(&mut self) {
+        let c = self.chunk[self.pos];
+        self.pos += 1;
+        let next = self.peek_byte(0);
+        if is_newline(next) && next != c {
+            self.pos += 1;
+        }
+        self.line += 1;
+    }
+
+    fn skip_whitespace_and_comments(&mut self) -> Result<()> {
+        while self.pos < self.chunk.len() {
+            let c = self.chunk[self.pos];
+            if is_newline(c) {
+                self.skip_newline();
+            } else if c.is_ascii_whitespace() || c == 0x0B {
+                self.pos += 1;
+            } else if self.test(b"--") {
+                self.pos += 2;
+                self.skip_comment()?;
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn skip_comment(&mut self) -> Result<()> {
+        if self.peek_byte(0) == b'[' {
+            if let Some(level) = self.long_bracket_level() {
+                self.read_long_string(level, "comment")?;
+                return Ok(());
+            }
+        }
+        while self.pos < self.chunk.len() && !is_newline(self.chunk[self.pos]) {
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn scan(&mut self) -> Result<Token> {
+        self.skip_whitespace_and_comments()?;
+        if self.pos >= self.chunk.len() {
+            return Ok(Token::Eof);
+        }
+
+        let c = self.chunk[self.pos];
+        let (token, len) = match c {
+            b';' => (Token::SepSemi, 1),
+            b',' => (Token::SepComma, 1),
+            b'(' => (Token::SepLParen, 1),
+            b')' => (Token::SepRParen, 1),
+            b']' => (Token::SepRBracket, 1),
+            b'{' => (Token::SepLCurly, 1),
+            b'}' => (Token::SepRCurly, 1),
+            b'+' => (Token::OpAdd, 1),
+            b'-' => (Token::OpMinus, 1),
+            b'*' => (Token::OpMul, 1),
+            b'^' => (Token::OpPow, 1),
+            b'%' => (Token::OpMod, 1),
+            b'&' => (Token::OpBAnd, 1),
+            b'|' => (Token::OpBOr, 1),
+            b'#' => (Token::OpLen, 1),
+            b':' if self.test(b"::") => (Token::SepLabel, 2),
+            b':' => (Token::SepColon, 1),
+            b'/' if self.test(b"//") => (Token::OpIDiv, 2),
+            b'/' => (Token::OpDiv, 1),
+            b'~' if self.test(b"~=") => (Token::OpNe, 2),
+            b'~' => (Token::OpWave, 1),
+            b'=' if self.test(b"==") => (Token::OpEq, 2),
+            b'=' => (Token::OpAssign, 1),
+            b'<' if self.test(b"<<") => (Token::OpShl, 2),
+            b'<' if self.test(b"<=") => (Token::OpLe, 2),
+            b'<' => (Token::OpLt, 1),
+            b'>' if self.test(b">>") => (Token::OpShr, 2),
+            b'>' if self.test(b">=") => (Token::OpGe, 2),
+            b'>' => (Token::OpGt, 1),
+            b'.' if self.test(b"...") => (Token::Vararg, 3),
+            b'.' if self.test(b"..") => (Token::OpConcat, 2),
+            b'.' if self.peek_byte(1).is_ascii_digit() => return self.read_number(),
+            b'.' => (Token::SepDot, 1),
+            b'[' => match self.long_bracket_level() {
+                Some(level) => {
+                    let s = self.read_long_string(level, "string")?;
+                    return Ok(Token::Str(s));
+                }
+                None => {
+                    if self.peek_byte(1) == b'=' {
+                        return Err(self.error("invalid long string delimiter near '['"));
+                    }
+                    (Token::SepLBracket, 1)
+                }
+            },
+            b'\'' | b'"' => return self.read_short_string(c),
+            c if c.is_ascii_digit() => return self.read_number(),
+            c if c == b'_' || c.is_ascii_alphabetic() => {
+                let start = self.pos;
+                while self.peek_byte(0) == b'_' || self.peek_byte(0).is_ascii_alphanumeric() {
+                    self.pos += 1;
+                }
+                let name = std::str::from_utf8(&self.chunk[start..self.pos]).unwrap();
+                return Ok(Token::keyword(name).unwrap_or_else(|| Token::Identifier(name.to_string())));
+            }
+            c => {
+                return Err(self.error(format!(
+                    "unexpected symbol near '{}'",
+                    escape_char(c)
+                )))
+            }
+        };
+        self.pos += len;
+        Ok(token)
+    }
+
+    // 当前位置是 [=*[ 时返回等号的数量
+    fn long_bracket_level(&self) -> Option<usize> {
+        let mut level = 0;
+        while self.peek_byte(1 + level) == b'=' {
+            level += 1;
+        }
+        (self.peek_byte(1 + level) == b'[').then_some(level)
+    }
+
+    fn read_long_string(&mut self, level: usize, what: &str) -> Result<Vec<u8>> {
+        self.pos += level + 2;
+        // 紧跟在开始括号后的换行会被忽略
+        if self.pos < self.chunk.len() && is_newline(self.chunk[self.pos]) {
+            self.skip_newline();
+        }
+
+        let mut buf = Vec::new();
+        loop {
+            if self.pos >= self.chunk.len() {
+                return Err(self.error(format!("unfinished long {what} near '<eof>'")));
+            }
+            let c = self.chunk[self.pos];
+            if c == b']' {
+                let mut n = 0;
+                while self.peek_byte(1 + n) == b'=' {
+                    n += 1;
+                }
+                if n == level && self.peek_byte(1 + n) == b']' {
+                    self.pos += level + 2;
+                    return Ok(buf);
+                }
+                buf.push(c);
+                self.pos += 1;
+            } else if is_newline(c) {
+                self.skip_newline();
+                buf.push(b'\n');
+            } else {
+                buf.push(c);
+                self.pos += 1;
+            }
+        }
+    }
+
+    fn read_short_string(&mut self, delimiter: u8) -> Result<Token> {
+        self.pos += 1;
+        let mut buf = Vec::new();
+        loop {
+            if self.pos >= self.chunk.len() {
+                return Err(self.error("unfinished string near '<eof>'"));
+            }
+            let c = self.chunk[self.pos];
+            if c == delimiter {
+                self.pos += 1;
+                return Ok(Token::Str(buf));
+            }
+            if is_newline(c) {
+                return Err(self.error(format!(
+                    "unfinished string near '{}{}'",
+                    delimiter as char,
+                    String::from_utf8_lossy(&buf)
+                )));
+            }
+            if c != b'\\' {
+                buf.push(c);
+                self.pos += 1;
+                continue;
+            }
+
+            // 转义序列
+            self.pos += 1;
+            let c = self.peek_byte(0);
+            match c {
+                b'a' => buf.push(0x07),
+                b'b' => buf.push(0x08),
+                b'f' => buf.push(0x0C),
+                b'n' => buf.push(b'\n'),
+                b'r' => buf.push(b'\r'),
+                b't' => buf.push(b'\t'),
+                b'v' => buf.push(0x0B),
+                b'\\' | b'"' | b'\'' => buf.push(c),
+                b'\n' | b'\r' => {
+                    self.skip_newline();
+                    buf.push(b'\n');
+                    continue;
+                }
+                b'x' => {
+                    let hex = |b: u8| (b as char).to_digit(16);
+                    match (hex(self.peek_byte(1)), hex(self.peek_byte(2))) {
+                        (Some(h), Some(l)) => {
+                            buf.push((h * 16 + l) as u8);
+                            self.pos += 3;
+                            continue;
+                        }
+                        _ => return Err(self.error("hexadecimal digit expected")),
+                    }
+                }
+                b'z' => {
+                    self.pos += 1;
+                    while self.pos < self.chunk.len() && self.chunk[self.pos].is_ascii_whitespace() {
+                        if is_newline(self.chunk[self.pos]) {
+                            self.skip_newline();
+                        } else {
+                            self.pos += 1;
+                        }
+                    }
+                    continue;
+                }
+                b'u' => {
+                    let code = self.read_utf8_escape()?;
+                    utf8_encode(code, &mut buf);
+                    continue;
+                }
+                c if c.is_ascii_digit() => {
+                    let mut n: u32 = 0;
+                    let mut len = 0;
+                    while len < 3 && self.peek_byte(0).is_ascii_digit() {
+                        n = n * 10 + (self.peek_byte(0) - b'0') as u32;
+                        self.pos += 1;
+                        len += 1;
+                    }
+                    if n > 0xFF {
+                        return Err(self.error("decimal escape too large"));
+                    }
+                    buf.push(n as u8);
+                    continue;
+                }
+                _ => return Err(self.error("invalid escape sequence")),
+            }
+            self.pos += 1;
+        }
+    }
+
+    // \u{XXX}
+    fn read_utf8_escape(&mut self) -> Result<u32> {
+        self.pos += 1;
+        if self.peek_byte(0) != b'{' {
+            return Err(self.error("missing '{' in \\u{xxxx}"));
+        }
+        self.pos += 1;
+        let mut code: u32 = 0;
+        let mut digits = 0;
+        while let Some(d) = (self.peek_byte(0) as char).to_digit(16) {
+            code = code
+                .checked_mul(16)
+                .and_then(|c| c.checked_add(d))
+                .filter(|c| *c <= 0x7FFFFFFF)
+                .ok_or_else(|| self.error("UTF-8 value too large"))?;
+            self.pos += 1;
+            digits += 1;
+        }
+        if digits == 0 {
+            return Err(self.error("hexadecimal digit expected"));
+        }
+        if self.peek_byte(0) != b'}' {
+            return Err(self.error("missing '}' in \\u{xxxx}"));
+        }
+        self.pos += 1;
+        Ok(code)
+    }
+
+    fn read_number(&mut self) -> Result<Token> {
+        let start = self.pos;
+        let hex = self.test(b"0x") || self.test(b"0X");
+        if hex {
+            self.pos += 2;
+        }
+        let exp_chars: &[u8] = if hex { b"Pp" } else { b"Ee" };
+        loop {
+            let c = self.peek_byte(0);
+            if exp_chars.contains(&c) {
+                self.pos += 1;
+                if matches!(self.peek_byte(0), b'+' | b'-') {
+                    self.pos += 1;
+                }
+            } else if c.is_ascii_hexdigit() || c == b'.' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+
+        let text = std::str::from_utf8(&self.chunk[start..self.pos]).unwrap();
+        match parse_number(text) {
+            Some(LuaValue::Integer(i)) => Ok(Token::Integer(i)),
+            Some(LuaValue::Number(n)) => Ok(Token::Float(n)),
+            _ => Err(self.error(format!("malformed number near '{text}'"))),
+        }
+    }
+}
+
+fn is_newline(c: u8) -> bool {
+    c == b'\n' || c == b'\r'
+}
+
+fn escape_char(c: u8) -> String {
+    if c.is_ascii_graphic() {
+        (c as char).to_string()
+    } else {
+        format!("<\\{c}>")
+    }
+}
+
+// 按照Lua的规则编码UTF-8，最多支持6字节(2^31)
+fn utf8_encode(code: u32, buf: &mut Vec<u8>) {
+    if code < 0x80 {
+        buf.push(code as u8);
+        return;
+    }
+    let mut bytes = Vec::new();
+    let mut code = code;
+    // 首字节能容纳的最大值
+    let mut max_first: u32 = 0x3F;
+    loop {
+        bytes.push(0x80 | (code & 0x3F) as u8);
+        code >>= 6;
+        max_first >>= 1;
+        if code <= max_first {
+            break;
+        }
+    }
+    let first = ((!max_first << 1) as u8) | code as u8;
+    buf.push(first);
+    buf.extend(bytes.iter().rev());
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compiler::lexer::Lexer;
+    use crate::compiler::token::Token;
+
+    fn tokens(src: &str) -> Vec<Token> {
+        let mut lexer = Lexer::new(src.as_bytes(), "=test");
+        let mut tokens = Vec::new();
+        loop {
+            let (_, token) = lexer.next_token().unwrap();
+            if token == Token::Eof {
+                return tokens;
+            }
+            tokens.push(token);
+        }
+    }
+
+    #[test]
+    fn test_tokens() {
+        assert_eq!(
+            tokens("local x = a.b:c(...) // 2 ~= 3 --comment\n::l:: [[long]]"),
+            vec![
+                Token::KwLocal,
+                Token::Identifier("x".to_string()),
+                Token::OpAssign,
+                Token::Identifier("a".to_string()),
+                Token::SepDot,
+                Token::Identifier("b".to_string()),
+                Token::SepColon,
+                Token::Identifier("c".to_string()),
+                Token::SepLParen,
+                Token::Vararg,
+                Token::SepRParen,
+                Token::OpIDiv,
+                Token::Integer(2),
+                Token::OpNe,
+                Token::Integer(3),
+                Token::SepLabel,
+                Token::Identifier("l".to_string()),
+                Token::SepLabel,
+                Token::Str(b"long".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(
+            tokens("3 3.0 0xff 1e2 .5 0x1p4 9223372036854775808"),
+            vec![
+                Token::Integer(3),
+                Token::Float(3.0),
+                Token::Integer(255),
+                Token::Float(100.0),
+                Token::Float(0.5),
+                Token::Float(16.0),
+                Token::Float(9223372036854775808.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(
+            tokens(r#"'a\tb' "\x41\65\u{4E2D}" "a\z
+                  b" [==[
+x]]==]"#),
+            vec![
+                Token::Str(b"a\tb".to_vec()),
+                Token::Str("AA中".as_bytes().to_vec()),
+                Token::Str(b"ab".to_vec()),
+                Token::Str(b"x]".to_vec()),
+            ]
+        );
+        assert_eq!(tokens(r#""\xff\0""#), vec![Token::Str(vec![0xFF, 0x00])]);
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut lexer = Lexer::new(b"a\n--[[\n]]\r\nb", "=test");
+        assert_eq!(lexer.next_token().unwrap().0, 1);
+        assert_eq!(lexer.look_ahead().unwrap(), &Token::Identifier("b".to_string()));
+        assert_eq!(lexer.line(), 1);
+        assert_eq!(lexer.next_token().unwrap().0, 4);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut lexer = Lexer::new(b"\n'abc", "@test.lua");
+        let err = lexer.next_token().unwrap_err();
+        assert_eq!(err.to_string(), "test.lua:2: unfinished string near '<eof>'");
+    }
+}
@@ -0,0 +1,155 @@
+pub mod ast;
+pub mod codegen;
+pub mod error;
+pub mod func_info;
+pub mod lexer;
+pub mod parser;
+pub mod token;
+
+use crate::binary::chunk::Prototype;
+use crate::compiler::codegen::CodeGen;
+use crate::compiler::error::CompileError;
+use crate::compiler::lexer::Lexer;
+use crate::compiler::parser::Parser;
+
+/// 把Lua源码编译为主函数原型
+/// chunk_name 与luac一样，"@file" 表示文件名，"=name" 表示原样显示
+pub fn compile(source: &[u8], chunk_name: &str) -> Result<Prototype, CompileError> {
+    let block = Parser::new(Lexer::new(source, chunk_name)).parse_chunk()?;
+    CodeGen::new(chunk_name).gen_main(&block)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::binary;
+    use crate::compiler::compile;
+    use crate::stdlib;
+    use crate::vm::lua_state::LuaState;
+    use crate::vm::lua_value::LuaValue;
+
+    fn run(src: &str) -> Vec<LuaValue> {
+        let proto = compile(src.as_bytes(), "=test").unwrap();
+        let mut state = LuaState::new();
+        stdlib::open_libs(&mut state);
+        let main = state.load(proto).unwrap();
+        state.call(main, Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_run() {
+        use LuaValue::{Boolean, Integer, Number};
+        let src = r##"
+            local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end
+            local t = {n = 0}
+            function t:add(x) self.n = self.n + x return self end
+            for i = 1, 10 do t:add(i) end
+            for _, v in ipairs({1, 2, 3}) do t.n = t.n + v end
+            local a, b = ...
+            return fib(15), t.n, 7 // 2, 2 ^ 2, 1 < 2 and "yes" or "no", a, select("#", ...)
+        "##;
+        let proto = compile(src.as_bytes(), "=test").unwrap();
+        let mut state = LuaState::new();
+        stdlib::open_libs(&mut state);
+        let main = state.load(proto).unwrap();
+        let results = state.call(main, vec![Boolean(true)]).unwrap();
+        assert_eq!(
+            results,
+            vec![
+                Integer(610),
+                Integer(61),
+                Integer(3),
+                Number(4.0),
+                LuaValue::from("yes"),
+                Boolean(true),
+                Integer(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_upvalues() {
+        // 每次循环创建新的局部变量，break和goto跳出时关闭upvalue
+        let src = r#"
+            local fs = {}
+            local i = 1
+            while true do
+                local j = i
+                fs[i] = function() j = j + 10 return j end
+                if i == 3 then break end
+                i = i + 1
+            end
+            do
+                local k = 0
+                ::again::
+                local c = k
+                fs[#fs + 1] = function() return c end
+                k = k + 1
+                if k < 2 then goto again end
+            end
+            return fs[1](), fs[1](), fs[2](), fs[3](), fs[4](), fs[5]()
+        "#;
+        let results: Vec<i64> = run(src).iter().map(|v| v.to_integer().unwrap()).collect();
+        assert_eq!(results, vec![11, 21, 12, 13, 0, 1]);
+    }
+
+    #[test]
+    fn test_multiple_assignment() {
+        // 赋值前先计算所有表达式，t[i]使用的是旧的i
+        let src = r#"
+            local t = {}
+            local i = 1
+            i, t[i] = i + 1, 20
+            local a, b, c = (function() return 1, 2, 3 end)()
+            a, b = b, a
+            local big = {}
+            for n = 1, 120 do big[n] = n end
+            local list = {0, 1, 2, (function() return 3, 4 end)()}
+            return i, t[1], a, b, c, #big, #list
+        "#;
+        let results: Vec<i64> = run(src).iter().map(|v| v.to_integer().unwrap()).collect();
+        assert_eq!(results, vec![2, 20, 2, 1, 3, 120, 5]);
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let src = "local x = 1\nlocal function f(a)\n  return a + x\nend\nreturn f(2)\n";
+        let proto = compile(src.as_bytes(), "@test.lua").unwrap();
+        assert_eq!(proto.source, b"@test.lua");
+        assert_eq!(proto.is_vararg, 1);
+        assert_eq!(proto.upvalue_names, vec!["_ENV".to_string()]);
+        let names: Vec<&str> = proto.loc_vars.iter().map(|v| v.var_name.as_str()).collect();
+        assert_eq!(names, vec!["x", "f"]);
+        assert_eq!(proto.loc_vars[0].start_pc, 1);
+        assert_eq!(proto.loc_vars[1].start_pc, 2);
+        assert_eq!(proto.loc_vars[0].end_pc as usize, proto.code.len());
+        assert_eq!(proto.line_info.len(), proto.code.len());
+        assert_eq!(proto.line_info[0], 1);
+        assert_eq!(*proto.line_info.last().unwrap(), 5);
+
+        let f = &proto.protos[0];
+        assert_eq!((f.line_defined, f.last_line_defined), (2, 4));
+        assert_eq!(f.num_params, 1);
+        assert_eq!(f.upvalue_names, vec!["x".to_string()]);
+        assert_eq!((f.upvalues[0].instack, f.upvalues[0].idx), (1, 0));
+        assert_eq!(f.line_info[0], 3);
+
+        // 编译结果可以序列化为二进制chunk
+        let data = binary::dump(&proto);
+        assert_eq!(binary::undump(data).unwrap(), proto);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let err = |src: &str| compile(src.as_bytes(), "@test.lua").unwrap_err().to_string();
+        assert_eq!(err("x = = 1"), "test.lua:1: unexpected symbol near '='");
+        assert_eq!(err("\nbreak"), "test.lua:2: <break> at line 2 not inside a loop");
+        assert_eq!(
+            err("goto l; local a; ::l:: print(a)"),
+            "test.lua:1: <goto l> at line 1 jumps into the scope of local 'a'"
+        );
+        assert_eq!(
+            err("function f() return ... end"),
+            "test.lua:1: cannot use '...' outside a vararg function near '...'"
+        );
+    }
+}
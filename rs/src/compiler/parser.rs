@@ -0,0 +1,770 @@
+use crate::compiler::ast::{Block, Exp, Stat};
+use crate::compiler::error::CompileError;
+use crate::compiler::lexer::Lexer;
+use crate::compiler::token::Token;
+use crate::vm::arith::{arith, ArithOp};
+use crate::vm::lua_value::LuaValue;
+
+type Result<T> = std::result::Result<T, CompileError>;
+
+// 一元运算符的优先级
+const UNARY_PRIORITY: u8 = 12;
+
+/// 语法分析器，递归下降地把词法单元序列解析为抽象语法树
+pub struct Parser<'a> {
+    lexer: Lexer<'a>,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(lexer: Lexer<'a>) -> Self {
+        Self { lexer }
+    }
+
+    // chunk ::= block
+    pub fn parse_chunk(mut self) -> Result<Block> {
+        let block = self.parse_block()?;
+        self.expect(Token::Eof)?;
+        Ok(block)
+    }
+
+    fn error_near(&mut self, msg: &str) -> CompileError {
+        let near = match self.lexer.look_ahead() {
+            Ok(Token::Eof) => "<eof>".to_string(),
+            Ok(token) => format!("'{token}'"),
+            Err(err) => return err,
+        };
+        // 错误报告在预读的词法单元所在的行
+        let mut err = self.lexer.error(format!("{msg} near {near}"));
+        err.line = self.lexer.look_ahead_line();
+        err
+    }
+
+    fn look_ahead(&mut self) -> Result<&Token> {
+        self.lexer.look_ahead()
+    }
+
+    fn check(&mut self, token: &Token) -> Result<bool> {
+        Ok(self.look_ahead()? == token)
+    }
+
+    // 当前词法单元是token时跳过并返回true
+    fn test_next(&mut self, token: &Token) -> Result<bool> {
+        if self.check(token)? {
+            self.lexer.next_token()?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    // 读取指定的词法单元，返回所在行号
+    fn expect(&mut self, token: Token) -> Result<u32> {
+        if !self.check(&token)? {
+            return Err(self.error_near(&format!("'{token}' expected")));
+        }
+        Ok(self.lexer.next_token()?.0)
+    }
+
+    // 读取与line行的what配对的结束符
+    fn expect_match(&mut self, token: Token, what: Token, line: u32) -> Result<u32> {
+        if self.check(&token)? {
+            return Ok(self.lexer.next_token()?.0);
+        }
+        if line == self.lexer.look_ahead_line() {
+            Err(self.error_near(&format!("'{token}' expected")))
+        } else {
+            Err(self.error_near(&format!(
+                "'{token}' expected (to close '{what}' at line {line})"
+            )))
+        }
+    }
+
+    fn expect_identifier(&mut self) -> Result<(u32, String)> {
+        if let Token::Identifier(_) = self.look_ahead()? {
+            let (line, token) = self.lexer.next_token()?;
+            let Token::Identifier(name) = token else {
+                unreachable!()
+            };
+            return Ok((line, name));
+        }
+        Err(self.error_near("<name> expected"))
+    }
+
+    // block ::= {stat} [retstat]
+    fn parse_block(&mut self) -> Result<Block> {
+        let mut stats = Vec::new();
+        while !self.block_follow()? && !self.check(&Token::KwReturn)? {
+            let stat = self.parse_stat()?;
+            if stat != Stat::Empty {
+                stats.push(stat);
+            }
+        }
+        let ret_exps = self.parse_ret_exps()?;
+        Ok(Block {
+            last_line: self.lexer.line(),
+            stats,
+            ret_exps,
+        })
+    }
+
+    fn block_follow(&mut self) -> Result<bool> {
+        Ok(matches!(
+            self.look_ahead()?,
+            Token::Eof | Token::KwEnd | Token::KwElse | Token::KwElseIf | Token::KwUntil
+        ))
+    }
+
+    // retstat ::= return [explist] [';']
+    fn parse_ret_exps(&mut self) -> Result<Option<Vec<Exp>>> {
+        if !self.test_next(&Token::KwReturn)? {
+            return Ok(None);
+        }
+        let exps = if self.block_follow()? || self.check(&Token::SepSemi)? {
+            Vec::new()
+        } else {
+            self.parse_exp_list()?
+        };
+        self.test_next(&Token::SepSemi)?;
+        if !self.block_follow()? {
+            return Err(self.error_near("'<eof>' expected"));
+        }
+        Ok(Some(exps))
+    }
+
+    fn parse_stat(&mut self) -> Result<Stat> {
+        match self.look_ahead()? {
+            Token::SepSemi => {
+                self.lexer.next_token()?;
+                Ok(Stat::Empty)
+            }
+            Token::KwBreak => {
+                let (line, _) = self.lexer.next_token()?;
+                Ok(Stat::Break { line })
+            }
+            Token::SepLabel => {
+                let (line, _) = self.lexer.next_token()?;
+                let (_, name) = self.expect_identifier()?;
+                self.expect(Token::SepLabel)?;
+                Ok(Stat::Label { line, name })
+            }
+            Token::KwGoto => {
+                let (line, _) = self.lexer.next_token()?;
+                let (_, name) = self.expect_identifier()?;
+                Ok(Stat::Goto { line, name })
+            }
+            Token::KwDo => {
+                let (line, _) = self.lexer.next_token()?;
+                let block = self.parse_block()?;
+                self.expect_match(Token::KwEnd, Token::KwDo, line)?;
+                Ok(Stat::Do(block))
+            }
+            Token::KwWhile => {
+                let (line, _) = self.lexer.next_token()?;
+                let exp = self.parse_exp()?;
+                self.expect(Token::KwDo)?;
+                let block = self.parse_block()?;
+                self.expect_match(Token::KwEnd, Token::KwWhile, line)?;
+                Ok(Stat::While { exp, block })
+            }
+            Token::KwRepeat => {
+                let (line, _) = self.lexer.next_token()?;
+                let block = self.parse_block()?;
+                self.expect_match(Token::KwUntil, Token::KwRepeat, line)?;
+                let exp = self.parse_exp()?;
+                Ok(Stat::Repeat { block, exp })
+            }
+            Token::KwIf => self.parse_if_stat(),
+            Token::KwFor => self.parse_for_stat(),
+            Token::KwFunction => self.parse_func_def_stat(),
+            Token::KwLocal => {
+                self.lexer.next_token()?;
+                if self.check(&Token::KwFunction)? {
+                    let (line, _) = self.lexer.next_token()?;
+                    let (_, name) = self.expect_identifier()?;
+                    let exp = self.parse_func_body(line)?;
+                    Ok(Stat::LocalFunc { name, exp })
+                } else {
+                    self.parse_local_var_stat()
+                }
+            }
+            _ => self.parse_assign_or_func_call_stat(),
+        }
+    }
+
+    // if exp then block {elseif exp then block} [else block] end
+    fn parse_if_stat(&mut self) -> Result<Stat> {
+        let (line, _) = self.lexer.next_token()?;
+        let mut exps = Vec::new();
+        let mut blocks = Vec::new();
+
+        exps.push(self.parse_exp()?);
+        self.expect(Token::KwThen)?;
+        blocks.push(self.parse_block()?);
+
+        while self.test_next(&Token::KwElseIf)? {
+            exps.push(self.parse_exp()?);
+            self.expect(Token::KwThen)?;
+            blocks.push(self.parse_block()?);
+        }
+
+        if self.check(&Token::KwElse)? {
+            let (else_line, _) = self.lexer.next_token()?;
+            exps.push(Exp::True { line: else_line });
+            blocks.push(self.parse_block()?);
+        }
+
+        self.expect_match(Token::KwEnd, Token::KwIf, line)?;
+        Ok(Stat::If { exps, blocks })
+    }
+
+    fn parse_for_stat(&mut self) -> Result<Stat> {
+        let (line_of_for, _) = self.lexer.next_token()?;
+        let (_, name) = self.expect_identifier()?;
+        if self.test_next(&Token::OpAssign)? {
+            // for Name = exp, exp [, exp] do block end
+            let init = self.parse_exp()?;
+            self.expect(Token::SepComma)?;
+            let limit = self.parse_exp()?;
+            let step = if self.test_next(&Token::SepComma)? {
+                self.parse_exp()?
+            } else {
+                Exp::Integer {
+                    line: self.lexer.line(),
+                    val: 1,
+                }
+            };
+            let line_of_do = self.expect(Token::KwDo)?;
+            let block = self.parse_block()?;
+            self.expect_match(Token::KwEnd, Token::KwFor, line_of_for)?;
+            Ok(Stat::ForNum {
+                line_of_for,
+                line_of_do,
+                var_name: name,
+                init,
+                limit,
+                step,
+                block,
+            })
+        } else if matches!(self.look_ahead()?, Token::SepComma | Token::KwIn) {
+            // for namelist in explist do block end
+            let mut name_list = vec![name];
+            while self.test_next(&Token::SepComma)? {
+                name_list.push(self.expect_identifier()?.1);
+            }
+            self.expect(Token::KwIn)?;
+            let exp_list = self.parse_exp_list()?;
+            let line_of_do = self.expect(Token::KwDo)?;
+            let block = self.parse_block()?;
+            self.expect_match(Token::KwEnd, Token::KwFor, line_of_for)?;
+            Ok(Stat::ForIn {
+                line_of_for,
+                line_of_do,
+                name_list,
+                exp_list,
+                block,
+            })
+        } else {
+            Err(self.error_near("'=' or 'in' expected"))
+        }
+    }
+
+    // function funcname funcbody
+    // funcname ::= Name {'.' Name} [':' Name]
+    // function a.b:c() end 会被转换为 a.b.c = function(self) end
+    fn parse_func_def_stat(&mut self) -> Result<Stat> {
+        let (line, _) = self.lexer.next_token()?;
+        let (name_line, name) = self.expect_identifier()?;
+        let mut var = Exp::Name {
+            line: name_line,
+            name,
+        };
+        let mut has_colon = false;
+        loop {
+            let is_colon = self.check(&Token::SepColon)?;
+            if !is_colon && !self.check(&Token::SepDot)? {
+                break;
+            }
+            self.lexer.next_token()?;
+            let (line, name) = self.expect_identifier()?;
+            var = Exp::TableAccess {
+                last_line: line,
+                prefix: Box::new(var),
+                key: Box::new(Exp::Str {
+                    line,
+                    val: name.into_bytes(),
+                }),
+            };
+            if is_colon {
+                has_colon = true;
+                break;
+            }
+        }
+
+        let mut exp = self.parse_func_body(line)?;
+        if has_colon {
+            if let Exp::FuncDef { par_list, .. } = &mut exp {
+                par_list.insert(0, "self".to_string());
+            }
+        }
+        Ok(Stat::Assign {
+            last_line: line,
+            var_list: vec![var],
+            exp_list: vec![exp],
+        })
+    }
+
+    // local namelist [= explist]
+    fn parse_local_var_stat(&mut self) -> Result<Stat> {
+        let mut name_list = vec![self.expect_identifier()?.1];
+        while self.test_next(&Token::SepComma)? {
+            name_list.push(self.expect_identifier()?.1);
+        }
+        let exp_list = if self.test_next(&Token::OpAssign)? {
+            self.parse_exp_list()?
+        } else {
+            Vec::new()
+        };
+        Ok(Stat::LocalVar {
+            last_line: self.lexer.line(),
+            name_list,
+            exp_list,
+        })
+    }
+
+    // varlist '=' explist | functioncall
+    fn parse_assign_or_func_call_stat(&mut self) -> Result<Stat> {
+        let exp = self.parse_prefix_exp()?;
+        if !matches!(self.look_ahead()?, Token::OpAssign | Token::SepComma) {
+            if let Exp::FuncCall { .. } = exp {
+                return Ok(Stat::FuncCall(exp));
+            }
+            return Err(self.error_near("syntax error"));
+        }
+
+        let mut var_list = vec![self.check_var(exp)?];
+        while self.test_next(&Token::SepComma)? {
+            let exp = self.parse_prefix_exp()?;
+            var_list.push(self.check_var(exp)?);
+        }
+        self.expect(Token::OpAssign)?;
+        let exp_list = self.parse_exp_list()?;
+        Ok(Stat::Assign {
+            last_line: self.lexer.line(),
+            var_list,
+            exp_list,
+        })
+    }
+
+    fn check_var(&mut self, exp: Exp) -> Result<Exp> {
+        match exp {
+            Exp::Name { .. } | Exp::TableAccess { .. } => Ok(exp),
+            _ => Err(self.error_near("syntax error")),
+        }
+    }
+
+    // explist ::= exp {',' exp}
+    fn parse_exp_list(&mut self) -> Result<Vec<Exp>> {
+        let mut exps = vec![self.parse_exp()?];
+        while self.test_next(&Token::SepComma)? {
+            exps.push(self.parse_exp()?);
+        }
+        Ok(exps)
+    }
+
+    fn parse_exp(&mut self) -> Result<Exp> {
+        self.parse_sub_exp(0)
+    }
+
+    // 按照运算符优先级解析表达式，只处理优先级高于limit的二元运算符
+    fn parse_sub_exp(&mut self, limit: u8) -> Result<Exp> {
+        let token = self.look_ahead()?.clone();
+        let mut exp = if matches!(token, Token::KwNot | Token::OpMinus | Token::OpWave | Token::OpLen) {
+            let (line, op) = self.lexer.next_token()?;
+            let exp = self.parse_sub_exp(UNARY_PRIORITY)?;
+            fold_unop(line, op, exp)
+        } else {
+            self.parse_simple_exp()?
+        };
+
+        loop {
+            let op = self.look_ahead()?.clone();
+            let Some((left, right)) = binary_priority(&op) else {
+                break;
+            };
+            if left <= limit {
+                break;
+            }
+            let (line, _) = self.lexer.next_token()?;
+            let exp2 = self.parse_sub_exp(right)?;
+            exp = fold_binop(line, op, exp, exp2);
+        }
+        Ok(exp)
+    }
+
+    // simpleexp ::= nil | false | true | Numeral | LiteralString | '...' |
+    //               functiondef | tableconstructor | prefixexp
+    fn parse_simple_exp(&mut self) -> Result<Exp> {
+        let exp = match self.look_ahead()? {
+            Token::KwNil => Exp::Nil {
+                line: self.lexer.next_token()?.0,
+            },
+            Token::KwTrue => Exp::True {
+                line: self.lexer.next_token()?.0,
+            },
+            Token::KwFalse => Exp::False {
+                line: self.lexer.next_token()?.0,
+            },
+            Token::Vararg => Exp::Vararg {
+                line: self.lexer.next_token()?.0,
+            },
+            Token::Integer(_) | Token::Float(_) | Token::Str(_) => match self.lexer.next_token()? {
+                (line, Token::Integer(val)) => Exp::Integer { line, val },
+                (line, Token::Float(val)) => Exp::Float { line, val },
+                (line, Token::Str(val)) => Exp::Str { line, val },
+                _ => unreachable!(),
+            },
+            Token::SepLCurly => self.parse_table_constructor()?,
+            Token::KwFunction => {
+                let (line, _) = self.lexer.next_token()?;
+                self.parse_func_body(line)?
+            }
+            _ => self.parse_prefix_exp()?,
+        };
+        Ok(exp)
+    }
+
+    // funcbody ::= '(' [parlist] ')' block end
+    // parlist ::= namelist [',' '...'] | '...'
+    fn parse_func_body(&mut self, line: u32) -> Result<Exp> {
+        self.expect(Token::SepLParen)?;
+        let mut par_list = Vec::new();
+        let mut is_vararg = false;
+        if !self.check(&Token::SepRParen)? {
+            loop {
+                if self.test_next(&Token::Vararg)? {
+                    is_vararg = true;
+                    break;
+                }
+                par_list.push(self.expect_identifier()?.1);
+                if !self.test_next(&Token::SepComma)? {
+                    break;
+                }
+            }
+        }
+        self.expect(Token::SepRParen)?;
+        let block = self.parse_block()?;
+        let last_line = self.expect_match(Token::KwEnd, Token::KwFunction, line)?;
+        Ok(Exp::FuncDef {
+            line,
+            last_line,
+            par_list,
+            is_vararg,
+            block: Box::new(block),
+        })
+    }
+
+    // tableconstructor ::= '{' [fieldlist] '}'
+    // fieldlist ::= field {fieldsep field} [fieldsep]
+    // field ::= '[' exp ']' '=' exp | Name '=' exp | exp
+    fn parse_table_constructor(&mut self) -> Result<Exp> {
+        let line = self.expect(Token::SepLCurly)?;
+        let mut key_exps = Vec::new();
+        let mut val_exps = Vec::new();
+        while !self.check(&Token::SepRCurly)? {
+            if self.test_next(&Token::SepLBracket)? {
+                key_exps.push(Some(self.parse_exp()?));
+                self.expect(Token::SepRBracket)?;
+                self.expect(Token::OpAssign)?;
+                val_exps.push(self.parse_exp()?);
+            } else {
+                let exp = self.parse_exp()?;
+                match exp {
+                    Exp::Name { line, name } if self.check(&Token::OpAssign)? => {
+                        self.lexer.next_token()?;
+                        key_exps.push(Some(Exp::Str {
+                            line,
+                            val: name.into_bytes(),
+                        }));
+                        val_exps.push(self.parse_exp()?);
+                    }
+                    exp => {
+                        key_exps.push(None);
+                        val_exps.push(exp);
+                    }
+                }
+            }
+            if !self.test_next(&Token::SepComma)? && !self.test_next(&Token::SepSemi)? {
+                break;
+            }
+        }
+        let last_line = self.expect_match(Token::SepRCurly, Token::SepLCurly, line)?;
+        Ok(Exp::Table {
+            line,
+            last_line,
+            key_exps,
+            val_exps,
+        })
+    }
+
+    // prefixexp ::= Name | '(' exp ')' | prefixexp '[' exp ']' | prefixexp '.' Name |
+    //               prefixexp [':' Name] args
+    fn parse_prefix_exp(&mut self) -> Result<Exp> {
+        let mut exp = match self.look_ahead()? {
+            Token::Identifier(_) => {
+                let (line, name) = self.expect_identifier()?;
+                Exp::Name { line, name }
+            }
+            Token::SepLParen => {
+                let (line, _) = self.lexer.next_token()?;
+                let exp = self.parse_exp()?;
+                self.expect_match(Token::SepRParen, Token::SepLParen, line)?;
+                match exp {
+                    Exp::Vararg { .. } | Exp::FuncCall { .. } | Exp::Name { .. } | Exp::TableAccess { .. } => {
+                        Exp::Paren(Box::new(exp))
+                    }
+                    exp => exp,
+                }
+            }
+            _ => return Err(self.error_near("unexpected symbol")),
+        };
+
+        loop {
+            match self.look_ahead()? {
+                Token::SepLBracket => {
+                    self.lexer.next_token()?;
+                    let key = self.parse_exp()?;
+                    let last_line = self.expect(Token::SepRBracket)?;
+                    exp = Exp::TableAccess {
+                        last_line,
+                        prefix: Box::new(exp),
+                        key: Box::new(key),
+                    };
+                }
+                Token::SepDot => {
+                    self.lexer.next_token()?;
+                    let (line, name) = self.expect_identifier()?;
+                    exp = Exp::TableAccess {
+                        last_line: line,
+                        prefix: Box::new(exp),
+                        key: Box::new(Exp::Str {
+                            line,
+                            val: name.into_bytes(),
+                        }),
+                    };
+                }
+                Token::SepColon | Token::SepLParen | Token::SepLCurly | Token::Str(_) => {
+                    exp = self.parse_func_call(exp)?;
+                }
+                _ => return Ok(exp),
+            }
+        }
+    }
+
+    // functioncall ::= prefixexp [':' Name] args
+    fn parse_func_call(&mut self, prefix: Exp) -> Result<Exp> {
+        let name = if self.test_next(&Token::SepColon)? {
+            let (line, name) = self.expect_identifier()?;
+            Some(Box::new(Exp::Str {
+                line,
+                val: name.into_bytes(),
+            }))
+        } else {
+            None
+        };
+
+        // args ::= '(' [explist] ')' | tableconstructor | LiteralString
+        let line = self.lexer.line();
+        let args = match self.look_ahead()? {
+            Token::SepLParen => {
+                let (line, _) = self.lexer.next_token()?;
+                let args = if self.check(&Token::SepRParen)? {
+                    Vec::new()
+                } else {
+                    self.parse_exp_list()?
+                };
+                self.expect_match(Token::SepRParen, Token::SepLParen, line)?;
+                args
+            }
+            Token::SepLCurly => vec![self.parse_table_constructor()?],
+            Token::Str(_) => vec![self.parse_simple_exp()?],
+            _ => return Err(self.error_near("function arguments expected")),
+        };
+        Ok(Exp::FuncCall {
+            line,
+            last_line: self.lexer.line(),
+            prefix: Box::new(prefix),
+            name,
+            args,
+        })
+    }
+}
+
+// 二元运算符的左右优先级，右结合的运算符右优先级较低
+fn binary_priority(op: &Token) -> Option<(u8, u8)> {
+    let priority = match op {
+        Token::KwOr => (1, 1),
+        Token::KwAnd => (2, 2),
+        Token::OpLt | Token::OpGt | Token::OpLe | Token::OpGe | Token::OpNe | Token::OpEq => (3, 3),
+        Token::OpBOr => (4, 4),
+        Token::OpWave => (5, 5),
+        Token::OpBAnd => (6, 6),
+        Token::OpShl | Token::OpShr => (7, 7),
+        Token::OpConcat => (9, 8),
+        Token::OpAdd | Token::OpMinus => (10, 10),
+        Token::OpMul | Token::OpDiv | Token::OpIDiv | Token::OpMod => (11, 11),
+        Token::OpPow => (14, 13),
+        _ => return None,
+    };
+    Some(priority)
+}
+
+fn to_value(exp: &Exp) -> Option<LuaValue> {
+    match exp {
+        Exp::Integer { val, .. } => Some(LuaValue::Integer(*val)),
+        Exp::Float { val, .. } => Some(LuaValue::Number(*val)),
+        _ => None,
+    }
+}
+
+// 常量折叠，与luac一样不折叠会出错以及结果为NaN或0.0的运算
+fn fold_arith(line: u32, op: ArithOp, a: &Exp, b: &Exp) -> Option<Exp> {
+    let (x, y) = (to_value(a)?, to_value(b)?);
+    match arith(op, &x, &y) {
+        Ok(Some(LuaValue::Integer(val))) => Some(Exp::Integer { line, val }),
+        Ok(Some(LuaValue::Number(val))) if !val.is_nan() && val != 0.0 => Some(Exp::Float { line, val }),
+        _ => None,
+    }
+}
+
+fn fold_unop(line: u32, op: Token, exp: Exp) -> Exp {
+    let folded = match (&op, &exp) {
+        (Token::KwNot, Exp::Nil { .. } | Exp::False { .. }) => Some(Exp::True { line }),
+        (
+            Token::KwNot,
+            Exp::True { .. } | Exp::Integer { .. } | Exp::Float { .. } | Exp::Str { .. },
+        ) => Some(Exp::False { line }),
+        (Token::OpMinus, _) => fold_arith(line, ArithOp::Unm, &exp, &exp),
+        (Token::OpWave, _) => fold_arith(line, ArithOp::BNot, &exp, &exp),
+        _ => None,
+    };
+    folded.unwrap_or(Exp::Unop {
+        line,
+        op,
+        exp: Box::new(exp),
+    })
+}
+
+fn fold_binop(line: u32, op: Token, exp1: Exp, exp2: Exp) -> Exp {
+    let arith_op = match op {
+        Token::OpAdd => Some(ArithOp::Add),
+        Token::OpMinus => Some(ArithOp::Sub),
+        Token::OpMul => Some(ArithOp::Mul),
+        Token::OpMod => Some(ArithOp::Mod),
+        Token::OpPow => Some(ArithOp::Pow),
+        Token::OpDiv => Some(ArithOp::Div),
+        Token::OpIDiv => Some(ArithOp::IDiv),
+        Token::OpBAnd => Some(ArithOp::BAnd),
+        Token::OpBOr => Some(ArithOp::BOr),
+        Token::OpWave => Some(ArithOp::BXor),
+        Token::OpShl => Some(ArithOp::Shl),
+        Token::OpShr => Some(ArithOp::Shr),
+        _ => None,
+    };
+    if let Some(folded) = arith_op.and_then(|op| fold_arith(line, op, &exp1, &exp2)) {
+        return folded;
+    }
+
+    if op == Token::OpConcat {
+        // a .. b .. c 是右结合的，合并为一个拼接表达式
+        let mut exps = vec![exp1];
+        match exp2 {
+            Exp::Concat { exps: rest, .. } => exps.extend(rest),
+            exp2 => exps.push(exp2),
+        }
+        return Exp::Concat { line, exps };
+    }
+
+    Exp::Binop {
+        line,
+        op,
+        exp1: Box::new(exp1),
+        exp2: Box::new(exp2),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compiler::ast::{Exp, Stat};
+    use crate::compiler::lexer::Lexer;
+    use crate::compiler::parser::Parser;
+    use crate::compiler::token::Token;
+
+    fn parse_exp(src: &str) -> Exp {
+        let chunk = format!("return {src}");
+        let block = Parser::new(Lexer::new(chunk.as_bytes(), "=test"))
+            .parse_chunk()
+            .unwrap();
+        block.ret_exps.unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_precedence() {
+        // 1 + (x * (y ^ (-(z ^ 3))))
+        let Exp::Binop { op, exp2, .. } = parse_exp("1 + x * y ^ -z ^ 3") else {
+            panic!()
+        };
+        assert_eq!(op, Token::OpAdd);
+        let Exp::Binop { op, exp2, .. } = *exp2 else { panic!() };
+        assert_eq!(op, Token::OpMul);
+        let Exp::Binop { op, exp2, .. } = *exp2 else { panic!() };
+        assert_eq!(op, Token::OpPow);
+        assert!(matches!(*exp2, Exp::Unop { op: Token::OpMinus, .. }));
+
+        let Exp::Concat { exps, .. } = parse_exp("a .. b .. c") else {
+            panic!()
+        };
+        assert_eq!(exps.len(), 3);
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        assert_eq!(parse_exp("1 + 2 * 3"), Exp::Integer { line: 1, val: 7 });
+        assert_eq!(parse_exp("2 ^ 10"), Exp::Float { line: 1, val: 1024.0 });
+        assert_eq!(parse_exp("-1 // 2"), Exp::Integer { line: 1, val: -1 });
+        assert_eq!(parse_exp("not nil"), Exp::True { line: 1 });
+        assert!(matches!(parse_exp("1 // 0"), Exp::Binop { .. }));
+        assert!(matches!(parse_exp("1 - 1.0"), Exp::Binop { .. }));
+    }
+
+    #[test]
+    fn test_statements() {
+        let src = "local function f(a, ...) end\nfunction t.a.b:c() end\nfor i = 1, 2 do end";
+        let block = Parser::new(Lexer::new(src.as_bytes(), "=test"))
+            .parse_chunk()
+            .unwrap();
+        assert_eq!(block.stats.len(), 3);
+        let Stat::Assign { exp_list, .. } = &block.stats[1] else {
+            panic!()
+        };
+        let Exp::FuncDef { par_list, .. } = &exp_list[0] else {
+            panic!()
+        };
+        assert_eq!(par_list, &vec!["self".to_string()]);
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let parse = |src: &str| {
+            Parser::new(Lexer::new(src.as_bytes(), "=test"))
+                .parse_chunk()
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(parse("x ="), "test:1: unexpected symbol near <eof>");
+        assert_eq!(
+            parse("function f()\n  return 1\n"),
+            "test:3: 'end' expected (to close 'function' at line 1) near <eof>"
+        );
+        assert_eq!(parse("f() = 1"), "test:1: syntax error near '='");
+        assert_eq!(parse("return 1 x"), "test:1: '<eof>' expected near 'x'");
+    }
+}
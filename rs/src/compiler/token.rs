@@ -0,0 +1,200 @@
+use std::fmt;
+
+/// 词法单元
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Eof,
+    // ...
+    Vararg,
+    // ;
+    SepSemi,
+    // ,
+    SepComma,
+    // .
+    SepDot,
+    // :
+    SepColon,
+    // ::
+    SepLabel,
+    // (
+    SepLParen,
+    // )
+    SepRParen,
+    // [
+    SepLBracket,
+    // ]
+    SepRBracket,
+    // {
+    SepLCurly,
+    // }
+    SepRCurly,
+    // =
+    OpAssign,
+    // -
+    OpMinus,
+    // ~
+    OpWave,
+    // +
+    OpAdd,
+    // *
+    OpMul,
+    // /
+    OpDiv,
+    // //
+    OpIDiv,
+    // ^
+    OpPow,
+    // %
+    OpMod,
+    // &
+    OpBAnd,
+    // |
+    OpBOr,
+    // >>
+    OpShr,
+    // <<
+    OpShl,
+    // ..
+    OpConcat,
+    // <
+    OpLt,
+    // <=
+    OpLe,
+    // >
+    OpGt,
+    // >=
+    OpGe,
+    // ==
+    OpEq,
+    // ~=
+    OpNe,
+    // #
+    OpLen,
+    KwAnd,
+    KwBreak,
+    KwDo,
+    KwElse,
+    KwElseIf,
+    KwEnd,
+    KwFalse,
+    KwFor,
+    KwFunction,
+    KwGoto,
+    KwIf,
+    KwIn,
+    KwLocal,
+    KwNil,
+    KwNot,
+    KwOr,
+    KwRepeat,
+    KwReturn,
+    KwThen,
+    KwTrue,
+    KwUntil,
+    KwWhile,
+    Identifier(String),
+    Integer(i64),
+    Float(f64),
+    // Lua字符串可以包含任意字节
+    Str(Vec<u8>),
+}
+
+impl Token {
+    // 关键字
+    pub fn keyword(name: &str) -> Option<Token> {
+        let token = match name {
+            "and" => Token::KwAnd,
+            "break" => Token::KwBreak,
+            "do" => Token::KwDo,
+            "else" => Token::KwElse,
+            "elseif" => Token::KwElseIf,
+            "end" => Token::KwEnd,
+            "false" => Token::KwFalse,
+            "for" => Token::KwFor,
+            "function" => Token::KwFunction,
+            "goto" => Token::KwGoto,
+            "if" => Token::KwIf,
+            "in" => Token::KwIn,
+            "local" => Token::KwLocal,
+            "nil" => Token::KwNil,
+            "not" => Token::KwNot,
+            "or" => Token::KwOr,
+            "repeat" => Token::KwRepeat,
+            "return" => Token::KwReturn,
+            "then" => Token::KwThen,
+            "true" => Token::KwTrue,
+            "until" => Token::KwUntil,
+            "while" => Token::KwWhile,
+            _ => return None,
+        };
+        Some(token)
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Token::Eof => "<eof>",
+            Token::Vararg => "...",
+            Token::SepSemi => ";",
+            Token::SepComma => ",",
+            Token::SepDot => ".",
+            Token::SepColon => ":",
+            Token::SepLabel => "::",
+            Token::SepLParen => "(",
+            Token::SepRParen => ")",
+            Token::SepLBracket => "[",
+            Token::SepRBracket => "]",
+            Token::SepLCurly => "{",
+            Token::SepRCurly => "}",
+            Token::OpAssign => "=",
+            Token::OpMinus => "-",
+            Token::OpWave => "~",
+            Token::OpAdd => "+",
+            Token::OpMul => "*",
+            Token::OpDiv => "/",
+            Token::OpIDiv => "//",
+            Token::OpPow => "^",
+            Token::OpMod => "%",
+            Token::OpBAnd => "&",
+            Token::OpBOr => "|",
+            Token::OpShr => ">>",
+            Token::OpShl => "<<",
+            Token::OpConcat => "..",
+            Token::OpLt => "<",
+            Token::OpLe => "<=",
+            Token::OpGt => ">",
+            Token::OpGe => ">=",
+            Token::OpEq => "==",
+            Token::OpNe => "~=",
+            Token::OpLen => "#",
+            Token::KwAnd => "and",
+            Token::KwBreak => "break",
+            Token::KwDo => "do",
+            Token::KwElse => "else",
+            Token::KwElseIf => "elseif",
+            Token::KwEnd => "end",
+            Token::KwFalse => "false",
+            Token::KwFor => "for",
+            Token::KwFunction => "function",
+            Token::KwGoto => "goto",
+            Token::KwIf => "if",
+            Token::KwIn => "in",
+            Token::KwLocal => "local",
+            Token::KwNil => "nil",
+            Token::KwNot => "not",
+            Token::KwOr => "or",
+            Token::KwRepeat => "repeat",
+            Token::KwReturn => "return",
+            Token::KwThen => "then",
+            Token::KwTrue => "true",
+            Token::KwUntil => "until",
+            Token::KwWhile => "while",
+            Token::Identifier(name) => return write!(f, "{name}"),
+            Token::Integer(i) => return write!(f, "{i}"),
+            Token::Float(n) => return write!(f, "{n}"),
+            Token::Str(s) => return write!(f, "{}", String::from_utf8_lossy(s)),
+        };
+        write!(f, "{s}")
+    }
+}
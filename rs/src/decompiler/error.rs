@@ -0,0 +1,39 @@
+use std::fmt;
+
+use crate::analysis::VerifyError;
+
+/// 反编译时可能出现的错误
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecompileError {
+    // 函数原型没有通过校验
+    Verify(VerifyError),
+    // 无法还原为源码的指令序列，line是函数的起始行号，pc从0开始
+    Unsupported {
+        line: u32,
+        pc: usize,
+        msg: &'static str,
+    },
+}
+
+impl fmt::Display for DecompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecompileError::Verify(err) => write!(f, "{err}"),
+            DecompileError::Unsupported { line, pc, msg } => write!(
+                f,
+                "cannot decompile function at line {}: {} at pc {}",
+                line,
+                msg,
+                pc + 1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecompileError {}
+
+impl From<VerifyError> for DecompileError {
+    fn from(err: VerifyError) -> Self {
+        DecompileError::Verify(err)
+    }
+}
@@ -0,0 +1,1478 @@
+use std::collections::BTreeSet;
+
+use super::error::DecompileError;
+use crate::binary::chunk::{Constant, LocVar, Prototype};
+use crate::compiler::ast::{Block, Exp, Stat};
+use crate::compiler::token::Token;
+use crate::decompiler::printer::is_identifier;
+use crate::decompiler::usage;
+use crate::vm::arith::ArithOp;
+use crate::vm::decoded::{Decoded, Rk};
+use crate::vm::instruction::Instruction;
+use crate::vm::opcode::Op;
+
+/// 寄存器中还没有被使用的值
+#[derive(Debug, Clone)]
+enum Value {
+    Exp(Exp),
+    // 多返回值中第一个之后的值，和第一个值一起出现在表达式列表中
+    Rest,
+    // SELF指令取出的方法，调用时使用obj:name(...)的形式
+    Method { obj: Exp, name: Exp },
+    // SELF指令复制的对象
+    SelfArg,
+    // 正在构造的表
+    Table(TableBuilder),
+}
+
+/// 表构造器，数组部分的值要等到SETLIST才写入表中
+/// 在此之前出现的字段先占住位置，保持各项的求值顺序
+#[derive(Debug, Clone, Default)]
+struct TableBuilder {
+    line: u32,
+    // 键为None的是数组部分，值为None的是还没有写入的位置
+    items: Vec<(Option<Exp>, Option<Exp>)>,
+}
+
+impl TableBuilder {
+    fn into_exp(self) -> Exp {
+        let (key_exps, val_exps) = self
+            .items
+            .into_iter()
+            .filter_map(|(key, val)| Some((key, val?)))
+            .unzip();
+        Exp::Table {
+            line: self.line,
+            last_line: self.line,
+            key_exps,
+            val_exps,
+        }
+    }
+}
+
+/// 反编译过程中会改变的状态，尝试按某种模式还原失败时整体回退
+#[derive(Debug, Clone)]
+struct State {
+    regs: Vec<Option<Value>>,
+    // 不定数量的值之后的第一个寄存器
+    top: Option<usize>,
+    // 局部变量是否已经声明
+    declared: Vec<bool>,
+    // 用到的临时变量(无法确定用途的寄存器)
+    temps: BTreeSet<usize>,
+    // 无法还原为结构化语句的跳转目标
+    gotos: BTreeSet<usize>,
+    // 上一条赋值语句的pc和值所在的寄存器，用于合并多重赋值
+    last_store: Option<(usize, usize)>,
+    // 需要在某个pc保存到临时变量的寄存器，用于会被多次读取的表
+    spills: Vec<(usize, usize)>,
+}
+
+/// 单个函数原型的反编译
+struct FuncDecompiler<'a> {
+    proto: &'a Prototype,
+    // 局部变量，去掉调试信息的函数只有按参数数量生成的参数名
+    locals: Vec<LocVar>,
+    // upvalue的名字
+    upvals: Vec<String>,
+    // 外层函数中可见的局部变量名
+    outer: Vec<String>,
+    // 需要输出标签的pc
+    labels: BTreeSet<usize>,
+    // 外层循环的出口
+    loops: Vec<usize>,
+    // 局部变量开始生效的pc和所在的寄存器，写入的值在这里成为局部变量
+    decls: BTreeSet<(usize, usize)>,
+    // 正在还原的and/or表达式的结果所在的寄存器，其中的值还要参与运算
+    exempt: Vec<usize>,
+    s: State,
+}
+
+// 反编译函数原型，返回参数列表和函数体
+// outer是外层可见的局部变量名，upvals是各个upvalue的名字
+pub fn function(
+    proto: &Prototype,
+    outer: &[String],
+    upvals: &[String],
+) -> Result<(Vec<String>, Block), DecompileError> {
+    let mut labels = BTreeSet::new();
+    loop {
+        let mut d = FuncDecompiler::new(proto, outer, upvals, labels.clone());
+        let mut block = d.block(0, proto.code.len())?;
+        d.flush(0, &mut block.stats);
+        if !d.s.gotos.is_subset(&labels) {
+            // 第一次遍历才能知道哪些位置需要标签
+            labels.extend(d.s.gotos);
+            continue;
+        }
+        if !d.s.temps.is_empty() {
+            let names = d.s.temps.iter().map(|r| d.temp(*r)).collect();
+            block.stats.insert(
+                0,
+                Stat::LocalVar {
+                    last_line: proto.line_defined,
+                    name_list: names,
+                    exp_list: Vec::new(),
+                },
+            );
+        }
+        let params = d.locals[..proto.num_params as usize]
+            .iter()
+            .map(|l| l.var_name.clone())
+            .collect();
+        return Ok((params, block));
+    }
+}
+
+// 调试信息中没有标签名，用标签所在指令的位置(从1开始)命名
+fn label_name(pc: usize) -> String {
+    format!("label_{}", pc + 1)
+}
+
+fn constant(k: &Constant, line: u32) -> Exp {
+    match k {
+        Constant::Nil => Exp::Nil { line },
+        Constant::Boolean(true) => Exp::True { line },
+        Constant::Boolean(false) => Exp::False { line },
+        Constant::Integer(val) => Exp::Integer { line, val: *val },
+        Constant::Number(val) => Exp::Float { line, val: *val },
+        Constant::Str(s) => Exp::Str {
+            line,
+            val: s.clone(),
+        },
+    }
+}
+
+fn arith_token(op: ArithOp) -> Token {
+    match op {
+        ArithOp::Add => Token::OpAdd,
+        ArithOp::Sub | ArithOp::Unm => Token::OpMinus,
+        ArithOp::Mul => Token::OpMul,
+        ArithOp::Mod => Token::OpMod,
+        ArithOp::Pow => Token::OpPow,
+        ArithOp::Div => Token::OpDiv,
+        ArithOp::IDiv => Token::OpIDiv,
+        ArithOp::BAnd => Token::OpBAnd,
+        ArithOp::BOr => Token::OpBOr,
+        ArithOp::BXor | ArithOp::BNot => Token::OpWave,
+        ArithOp::Shl => Token::OpShl,
+        ArithOp::Shr => Token::OpShr,
+    }
+}
+
+fn binop(line: u32, op: Token, exp1: Exp, exp2: Exp) -> Exp {
+    Exp::Binop {
+        line,
+        op,
+        exp1: Box::new(exp1),
+        exp2: Box::new(exp2),
+    }
+}
+
+// 条件取反，比较运算直接换成相反的运算
+fn negate(exp: Exp) -> Exp {
+    match exp {
+        Exp::Unop {
+            op: Token::KwNot,
+            exp,
+            ..
+        } => *exp,
+        Exp::Binop {
+            line,
+            op: Token::OpEq,
+            exp1,
+            exp2,
+        } => binop(line, Token::OpNe, *exp1, *exp2),
+        Exp::Binop {
+            line,
+            op: Token::OpNe,
+            exp1,
+            exp2,
+        } => binop(line, Token::OpEq, *exp1, *exp2),
+        exp => Exp::Unop {
+            line: exp.line(),
+            op: Token::KwNot,
+            exp: Box::new(exp),
+        },
+    }
+}
+
+// 列表中最后一个表达式的值的数量固定时，多值表达式需要用圆括号截断
+fn truncate_last(exps: &mut [Exp]) {
+    if let Some(last) = exps.last_mut() {
+        if last.is_multi_value() {
+            let exp = std::mem::replace(last, Exp::Nil { line: 0 });
+            *last = Exp::Paren(Box::new(exp));
+        }
+    }
+}
+
+// 去掉末尾用于补齐的nil，前面是多值表达式时不能去掉
+fn trim_nils(exps: &mut Vec<Exp>) {
+    while let [.., prev, Exp::Nil { .. }] = &exps[..] {
+        if prev.is_multi_value() {
+            break;
+        }
+        exps.pop();
+    }
+    if let [Exp::Nil { .. }] = &exps[..] {
+        exps.pop();
+    }
+}
+
+// 表达式中是否有满足条件的子表达式，不进入函数定义
+fn any_exp(exp: &Exp, f: &dyn Fn(&Exp) -> bool) -> bool {
+    if f(exp) {
+        return true;
+    }
+    match exp {
+        Exp::Unop { exp, .. } | Exp::Paren(exp) => any_exp(exp, f),
+        Exp::Binop { exp1, exp2, .. } => any_exp(exp1, f) || any_exp(exp2, f),
+        Exp::Concat { exps, .. } => exps.iter().any(|e| any_exp(e, f)),
+        Exp::Table {
+            key_exps, val_exps, ..
+        } => {
+            key_exps.iter().flatten().any(|e| any_exp(e, f))
+                || val_exps.iter().any(|e| any_exp(e, f))
+        }
+        Exp::TableAccess { prefix, key, .. } => any_exp(prefix, f) || any_exp(key, f),
+        Exp::FuncCall { prefix, args, .. } => {
+            any_exp(prefix, f) || args.iter().any(|e| any_exp(e, f))
+        }
+        _ => false,
+    }
+}
+
+// 对var赋值之后，exp的值是否可能改变
+fn depends_on(exp: &Exp, var: &Exp) -> bool {
+    any_exp(exp, &|e| match (e, var) {
+        (Exp::FuncCall { .. }, _) => true,
+        (Exp::Name { name, .. }, Exp::Name { name: var, .. }) => name == var,
+        (Exp::TableAccess { .. }, Exp::TableAccess { .. }) => true,
+        _ => false,
+    })
+}
+
+impl<'a> FuncDecompiler<'a> {
+    fn new(
+        proto: &'a Prototype,
+        outer: &[String],
+        upvals: &[String],
+        labels: BTreeSet<usize>,
+    ) -> Self {
+        let mut locals = proto.loc_vars.clone();
+        // 没有调试信息时按位置给参数命名
+        let n_params = proto.num_params as usize;
+        if locals.len() < n_params {
+            locals = (0..n_params)
+                .map(|i| LocVar {
+                    var_name: format!("a{}", i + 1),
+                    start_pc: 0,
+                    end_pc: proto.code.len() as u32,
+                })
+                .collect();
+        }
+        let mut declared = vec![false; locals.len()];
+        declared[..n_params].fill(true);
+        let mut decls = BTreeSet::new();
+        for (l, v) in locals.iter().enumerate() {
+            let start = v.start_pc as usize;
+            let active = locals[..l]
+                .iter()
+                .filter(|u| u.start_pc as usize <= start && start < u.end_pc as usize)
+                .count();
+            decls.insert((start, active));
+        }
+        FuncDecompiler {
+            proto,
+            locals,
+            upvals: upvals.to_vec(),
+            outer: outer.to_vec(),
+            labels,
+            loops: Vec::new(),
+            decls,
+            exempt: Vec::new(),
+            s: State {
+                regs: vec![None; proto.max_stack_size as usize + 1],
+                top: None,
+                declared,
+                temps: BTreeSet::new(),
+                gotos: BTreeSet::new(),
+                last_store: None,
+                spills: Vec::new(),
+            },
+        }
+    }
+
+    // 无法确定用途的寄存器作为临时变量，不能与外层的名字重复
+    fn temp(&self, r: usize) -> String {
+        let mut name = format!("r{r}");
+        while self.outer.contains(&name) || self.upvals.contains(&name) {
+            name.push('_');
+        }
+        name
+    }
+
+    fn unsupported(&self, pc: usize, msg: &'static str) -> DecompileError {
+        DecompileError::Unsupported {
+            line: self.proto.line_defined,
+            pc,
+            msg,
+        }
+    }
+
+    fn line(&self, pc: usize) -> u32 {
+        self.proto.line_info.get(pc).copied().unwrap_or(0)
+    }
+
+    fn decode(&self, pc: usize) -> Option<Decoded> {
+        self.proto.code.get(pc).and_then(|i| Decoded::decode(*i))
+    }
+
+    fn is_test(&self, pc: usize) -> bool {
+        self.proto
+            .code
+            .get(pc)
+            .and_then(|i| i.op())
+            .is_some_and(|op| op.info().test_flag == 1)
+    }
+
+    /* 局部变量 */
+
+    // pc处活跃的局部变量，下标即寄存器
+    fn active(&self, pc: usize) -> Vec<usize> {
+        (0..self.locals.len())
+            .filter(|&i| {
+                let l = &self.locals[i];
+                l.start_pc as usize <= pc && pc < l.end_pc as usize
+            })
+            .collect()
+    }
+
+    // for循环的内部变量没有名字可以引用
+    fn is_hidden(&self, l: usize) -> bool {
+        self.locals[l].var_name.starts_with('(')
+    }
+
+    // pc处寄存器r对应的有名字的局部变量
+    fn local_at(&self, pc: usize, r: usize) -> Option<usize> {
+        self.active(pc)
+            .get(r)
+            .copied()
+            .filter(|&l| !self.is_hidden(l))
+    }
+
+    // pc处可见的局部变量名
+    fn visible(&self, pc: usize) -> Vec<String> {
+        let mut names = self.outer.clone();
+        for l in self.active(pc) {
+            if !self.is_hidden(l) {
+                names.push(self.locals[l].var_name.clone());
+            }
+        }
+        names
+    }
+
+    // 从pc开始生效的寄存器r对应的局部变量名，由循环语句声明
+    fn claim(&mut self, pc: usize, r: usize) -> String {
+        match self.active(pc).get(r) {
+            Some(&l) if self.locals[l].start_pc as usize == pc => {
+                self.s.declared[l] = true;
+                self.locals[l].var_name.clone()
+            }
+            _ => self.temp(r),
+        }
+    }
+
+    // 声明从pc开始生效的局部变量，at_end为true时只处理在pc结束的局部变量
+    fn declare(&mut self, pc: usize, at_end: bool, stats: &mut Vec<Stat>) {
+        let mut names = Vec::new();
+        let mut exps = Vec::new();
+        let mut active = self.active(pc);
+        if at_end {
+            // 在代码块末尾声明的局部变量作用域为空
+            active = (0..self.locals.len())
+                .filter(|&l| {
+                    let v = &self.locals[l];
+                    v.start_pc as usize <= pc && v.end_pc as usize >= pc
+                })
+                .collect();
+        }
+        for (r, &l) in active.iter().enumerate() {
+            let v = &self.locals[l];
+            if self.s.declared[l] || v.start_pc as usize != pc || self.is_hidden(l) {
+                continue;
+            }
+            if at_end && v.end_pc as usize != pc {
+                continue;
+            }
+            self.s.declared[l] = true;
+            names.push(v.var_name.clone());
+            match self.s.regs[r].take() {
+                Some(Value::Rest) => (),
+                Some(Value::Exp(exp)) => exps.push(exp),
+                Some(Value::Table(t)) => exps.push(t.into_exp()),
+                _ => exps.push(Exp::Nil {
+                    line: self.line(pc),
+                }),
+            }
+        }
+        if names.is_empty() {
+            return;
+        }
+        trim_nils(&mut exps);
+        stats.push(Stat::LocalVar {
+            last_line: self.line(pc.saturating_sub(1)),
+            name_list: names,
+            exp_list: exps,
+        });
+        self.s.last_store = None;
+    }
+
+    /* 寄存器 */
+
+    // 读取寄存器的值，局部变量直接引用名字
+    fn read(&mut self, pc: usize, r: usize) -> Exp {
+        let line = self.line(pc);
+        if let Some(l) = self.local_at(pc, r) {
+            return Exp::Name {
+                line,
+                name: self.locals[l].var_name.clone(),
+            };
+        }
+        match self.s.regs.get_mut(r).and_then(Option::take) {
+            Some(Value::Exp(exp)) => exp,
+            Some(Value::Table(t)) => t.into_exp(),
+            _ => {
+                self.s.temps.insert(r);
+                Exp::Name {
+                    line,
+                    name: self.temp(r),
+                }
+            }
+        }
+    }
+
+    fn rk(&mut self, pc: usize, x: Rk) -> Exp {
+        match x {
+            Rk::Const(k) => constant(&self.proto.constants[k], self.line(pc)),
+            Rk::Reg(r) => self.read(pc, r),
+        }
+    }
+
+    // 把寄存器from开始的n个值(None表示直到top)作为表达式列表
+    fn collect(
+        &mut self,
+        pc: usize,
+        from: usize,
+        n: Option<usize>,
+    ) -> Result<Vec<Exp>, DecompileError> {
+        let end = match n {
+            Some(n) => from + n,
+            None => self
+                .s
+                .top
+                .take()
+                .ok_or(self.unsupported(pc, "unknown number of values"))?,
+        };
+        let mut exps = Vec::new();
+        for r in from..end.max(from) {
+            match self.s.regs[r] {
+                Some(Value::Rest) | Some(Value::SelfArg) if self.local_at(pc, r).is_none() => {
+                    self.s.regs[r] = None;
+                }
+                _ => exps.push(self.read(pc, r)),
+            }
+        }
+        if n.is_some() {
+            truncate_last(&mut exps);
+        }
+        Ok(exps)
+    }
+
+    // 写入寄存器，已经声明的局部变量生成赋值语句，否则留待使用
+    fn write(&mut self, pc: usize, r: usize, value: Exp, stats: &mut Vec<Stat>) {
+        match self.local_at(pc, r) {
+            Some(l) => {
+                let var = Exp::Name {
+                    line: self.line(pc),
+                    name: self.locals[l].var_name.clone(),
+                };
+                self.assign(pc, var, value, stats);
+            }
+            None => {
+                self.s.regs[r] = Some(Value::Exp(value));
+                if !self.single_use(pc, r, false) {
+                    self.spill(pc, r, stats);
+                }
+            }
+        }
+    }
+
+    // pc处写入寄存器r的值是否只会被读取一次，成为局部变量的值由局部变量名引用
+    fn single_use(&self, pc: usize, r: usize, ctor: bool) -> bool {
+        if self.exempt.contains(&r) {
+            return true;
+        }
+        usage::single_use(self.proto, &self.decls, pc, r, ctor)
+    }
+
+    // 写入从a开始的n个寄存器，第一个是多值表达式，其余是它的后续值
+    fn write_multi(&mut self, pc: usize, a: usize, n: usize, value: Exp, stats: &mut Vec<Stat>) {
+        let locals: Vec<usize> = (a..a + n).filter_map(|r| self.local_at(pc, r)).collect();
+        if n > 1 && locals.len() == n {
+            let line = self.line(pc);
+            let var_list = locals
+                .iter()
+                .map(|&l| Exp::Name {
+                    line,
+                    name: self.locals[l].var_name.clone(),
+                })
+                .collect();
+            stats.push(Stat::Assign {
+                last_line: line,
+                var_list,
+                exp_list: vec![value],
+            });
+            self.s.last_store = None;
+            return;
+        }
+        // 会被多次读取的值全部保存到临时变量
+        if (a..a + n).any(|r| self.local_at(pc, r).is_none() && !self.single_use(pc, r, false)) {
+            let line = self.line(pc);
+            let var_list = (a..a + n)
+                .map(|r| match self.local_at(pc, r) {
+                    Some(l) => self.locals[l].var_name.clone(),
+                    None => {
+                        self.s.temps.insert(r);
+                        self.temp(r)
+                    }
+                })
+                .map(|name| Exp::Name { line, name })
+                .collect();
+            stats.push(Stat::Assign {
+                last_line: line,
+                var_list,
+                exp_list: vec![value],
+            });
+            self.s.last_store = None;
+            return;
+        }
+        self.write(pc, a, value, stats);
+        for r in a + 1..a + n {
+            self.s.regs[r] = Some(Value::Rest);
+        }
+    }
+
+    fn assign(&mut self, pc: usize, var: Exp, value: Exp, stats: &mut Vec<Stat>) {
+        self.protect(pc, &var, &[], stats);
+        stats.push(Stat::Assign {
+            last_line: self.line(pc),
+            var_list: vec![var],
+            exp_list: vec![value],
+        });
+        self.s.last_store = None;
+    }
+
+    // 把src的值赋给var
+    // 多重赋值的值依次放在临时寄存器中，从后往前赋值，相邻的赋值合并为一条语句
+    fn store(&mut self, pc: usize, var: Exp, src: Rk, stats: &mut Vec<Stat>) {
+        let temp = match src {
+            Rk::Reg(r) if self.local_at(pc, r).is_none() && self.s.regs[r].is_some() => Some(r),
+            _ => None,
+        };
+        let merge = match (temp, self.s.last_store) {
+            (Some(r), Some((last_pc, last_r))) => last_pc + 1 == pc && last_r == r + 1,
+            _ => false,
+        };
+        let value = match temp {
+            Some(r) if merge && matches!(self.s.regs[r], Some(Value::Rest)) => {
+                self.s.regs[r] = None;
+                None
+            }
+            _ => Some(self.rk(pc, src)),
+        };
+        match stats.last_mut() {
+            Some(Stat::Assign {
+                var_list, exp_list, ..
+            }) if merge => {
+                var_list.insert(0, var);
+                if let Some(value) = value {
+                    exp_list.insert(0, value);
+                }
+            }
+            _ => {
+                // 多重赋值中之后赋给其他变量的值会在同一条语句中使用
+                let keep = match temp {
+                    Some(r) => self.store_chain(pc + 1, r),
+                    None => Vec::new(),
+                };
+                self.protect(pc, &var, &keep, stats);
+                stats.push(Stat::Assign {
+                    last_line: self.line(pc),
+                    var_list: vec![var],
+                    exp_list: value.into_iter().collect(),
+                })
+            }
+        }
+        self.s.last_store = temp.map(|r| (pc, r));
+    }
+
+    // 从pc开始连续赋值给其他变量的寄存器r-1、r-2...
+    fn store_chain(&self, pc: usize, r: usize) -> Vec<usize> {
+        let mut keep = Vec::new();
+        for (p, expected) in (pc..self.proto.code.len()).zip((0..r).rev()) {
+            let src = match self.decode(p) {
+                Some(Decoded::Move { a, b }) if self.local_at(p, a).is_some() => Rk::Reg(b),
+                Some(Decoded::SetTable { value, .. } | Decoded::SetTabUp { value, .. }) => value,
+                Some(Decoded::SetUpval { a, .. }) => Rk::Reg(a),
+                _ => break,
+            };
+            if src != Rk::Reg(expected) {
+                break;
+            }
+            keep.push(expected);
+        }
+        keep
+    }
+
+    // 对var赋值之前，把值会因此改变的待用值保存到临时变量，keep中的寄存器除外
+    fn protect(&mut self, pc: usize, var: &Exp, keep: &[usize], stats: &mut Vec<Stat>) {
+        for r in (0..self.s.regs.len()).filter(|r| !keep.contains(r)) {
+            let affected = match &self.s.regs[r] {
+                Some(Value::Exp(exp)) => depends_on(exp, var),
+                Some(Value::Table(t)) => t
+                    .items
+                    .iter()
+                    .flat_map(|(k, v)| k.iter().chain(v))
+                    .any(|e| depends_on(e, var)),
+                _ => false,
+            };
+            if affected {
+                self.spill(pc, r, stats);
+            }
+        }
+    }
+
+    // 控制结构之前，把还没有使用的值保存到临时变量中
+    fn flush(&mut self, pc: usize, stats: &mut Vec<Stat>) {
+        for r in 0..self.s.regs.len() {
+            self.spill(pc, r, stats);
+        }
+        self.s.top = None;
+    }
+
+    // 把寄存器r中还没有使用的值保存到临时变量
+    fn spill(&mut self, pc: usize, r: usize, stats: &mut Vec<Stat>) {
+        let exp = match self.s.regs[r].take() {
+            Some(Value::Exp(exp)) => exp,
+            Some(Value::Table(t)) => t.into_exp(),
+            value => {
+                self.s.regs[r] = value;
+                return;
+            }
+        };
+        self.s.temps.insert(r);
+        let var = Exp::Name {
+            line: self.line(pc),
+            name: self.temp(r),
+        };
+        self.assign(pc, var, exp, stats);
+    }
+
+    // upvalue中的表以字符串为键的字段，_ENV中的字段是全局变量
+    fn upval_index(&self, pc: usize, upval: usize, key: Exp) -> Exp {
+        let line = self.line(pc);
+        let name = self.upvals[upval].clone();
+        if name == "_ENV" {
+            if let Exp::Str { val, .. } = &key {
+                let global = String::from_utf8_lossy(val).to_string();
+                // 同名的局部变量会遮蔽全局变量
+                let shadowed = self.visible(pc).contains(&global) || self.upvals.contains(&global);
+                if is_identifier(val) && !shadowed {
+                    return Exp::Name { line, name: global };
+                }
+            }
+        }
+        Exp::TableAccess {
+            last_line: line,
+            prefix: Box::new(Exp::Name { line, name }),
+            key: Box::new(key),
+        }
+    }
+
+    /* 语句 */
+
+    fn block(&mut self, start: usize, end: usize) -> Result<Block, DecompileError> {
+        let mut stats = Vec::new();
+        let mut ret_exps = None;
+        let mut pc = start;
+        while pc < end {
+            // 表构造器结束后保存会被多次读取的表
+            while let Some(i) = self.s.spills.iter().position(|&(p, _)| p == pc) {
+                let (_, r) = self.s.spills.remove(i);
+                self.spill(pc, r, &mut stats);
+            }
+            // 在代码块结束之前失效的局部变量属于do语句
+            if let Some(e) = self.scope_end(pc, end) {
+                let block = self.body(pc, e)?;
+                stats.push(Stat::Do(block));
+                self.s.last_store = None;
+                pc = e;
+                continue;
+            }
+            self.declare(pc, false, &mut stats);
+            if self.labels.contains(&pc) {
+                self.flush(pc, &mut stats);
+                stats.push(Stat::Label {
+                    line: self.line(pc),
+                    name: label_name(pc),
+                });
+                self.s.last_store = None;
+            }
+            pc = self.stat(pc, end, &mut stats, &mut ret_exps)?;
+        }
+        self.declare(end, true, &mut stats);
+        Ok(Block {
+            last_line: self.line(end.saturating_sub(1)),
+            stats,
+            ret_exps,
+        })
+    }
+
+    // 从pc开始生效、在end之前失效的局部变量中最晚的失效位置
+    fn scope_end(&self, pc: usize, end: usize) -> Option<usize> {
+        self.active(pc)
+            .into_iter()
+            .filter(|&l| !self.s.declared[l] && !self.is_hidden(l))
+            .map(|l| &self.locals[l])
+            .filter(|v| v.start_pc as usize == pc && (v.end_pc as usize) < end)
+            .map(|v| v.end_pc as usize)
+            .max()
+    }
+
+    // 作为结构化语句的代码块，结束时保存还没有使用的值
+    fn body(&mut self, start: usize, end: usize) -> Result<Block, DecompileError> {
+        let mut block = self.block(start, end)?;
+        self.flush(end.saturating_sub(1), &mut block.stats);
+        Ok(block)
+    }
+
+    // 尝试把[start, end)还原为只计算值不产生语句的代码，失败时回退状态
+    fn pure(&mut self, start: usize, end: usize) -> bool {
+        let saved = self.s.clone();
+        match self.block(start, end) {
+            Ok(block) if block.stats.is_empty() && block.ret_exps.is_none() => true,
+            _ => {
+                self.s = saved;
+                false
+            }
+        }
+    }
+
+    // 还原从pc开始的一条语句，返回下一条语句的pc
+    fn stat(
+        &mut self,
+        pc: usize,
+        end: usize,
+        stats: &mut Vec<Stat>,
+        ret_exps: &mut Option<Vec<Exp>>,
+    ) -> Result<usize, DecompileError> {
+        let line = self.line(pc);
+        let decoded = self
+            .decode(pc)
+            .ok_or(self.unsupported(pc, "unknown opcode"))?;
+        if let Decoded::ForPrep { a, offset } = decoded {
+            return self.for_num(pc, a, offset, stats);
+        }
+        if let Some(next) = self.for_in(pc, end, stats)? {
+            return Ok(next);
+        }
+        if let Some(j) = (pc..end).rev().find(|&j| self.is_back_jump(j, pc)) {
+            return self.loop_stat(pc, j, stats);
+        }
+        if self.is_test(pc) {
+            return self.branch(pc, end, stats);
+        }
+
+        match decoded {
+            Decoded::Move { a, b } => match self.local_at(pc, a) {
+                Some(l) => {
+                    let var = Exp::Name {
+                        line,
+                        name: self.locals[l].var_name.clone(),
+                    };
+                    self.store(pc, var, Rk::Reg(b), stats);
+                }
+                None => {
+                    let exp = self.read(pc, b);
+                    self.write(pc, a, exp, stats);
+                }
+            },
+            Decoded::LoadK { a, k } => {
+                let exp = constant(&self.proto.constants[k], line);
+                self.write(pc, a, exp, stats);
+            }
+            Decoded::LoadKX { a } => {
+                let k = self.proto.code[pc + 1].ax() as usize;
+                let exp = constant(&self.proto.constants[k], line);
+                self.write(pc, a, exp, stats);
+                return Ok(pc + 2);
+            }
+            Decoded::LoadBool { a, value, skip } => {
+                if skip {
+                    return Err(self.unsupported(pc, "unexpected LOADBOOL"));
+                }
+                let exp = match value {
+                    true => Exp::True { line },
+                    false => Exp::False { line },
+                };
+                self.write(pc, a, exp, stats);
+            }
+            Decoded::LoadNil { a, n } => {
+                for r in a..a + n {
+                    self.write(pc, r, Exp::Nil { line }, stats);
+                }
+            }
+            Decoded::GetUpval { a, upval } => {
+                let name = self.upvals[upval].clone();
+                self.write(pc, a, Exp::Name { line, name }, stats);
+            }
+            Decoded::GetTabUp { a, upval, key } => {
+                let key = self.rk(pc, key);
+                let exp = self.upval_index(pc, upval, key);
+                self.write(pc, a, exp, stats);
+            }
+            Decoded::GetTable { a, table, key } => {
+                let key = self.rk(pc, key);
+                let prefix = self.read(pc, table);
+                let exp = Exp::TableAccess {
+                    last_line: line,
+                    prefix: Box::new(prefix),
+                    key: Box::new(key),
+                };
+                self.write(pc, a, exp, stats);
+            }
+            Decoded::SetTabUp { upval, key, value } => {
+                let key = self.rk(pc, key);
+                let var = self.upval_index(pc, upval, key);
+                self.store(pc, var, value, stats);
+            }
+            Decoded::SetUpval { a, upval } => {
+                let name = self.upvals[upval].clone();
+                self.store(pc, Exp::Name { line, name }, Rk::Reg(a), stats);
+            }
+            Decoded::SetTable { table, key, value } => {
+                if let (None, Some(Value::Table(_))) =
+                    (self.local_at(pc, table), &self.s.regs[table])
+                {
+                    self.table_field(pc, table, key, value);
+                } else {
+                    let key = self.rk(pc, key);
+                    let prefix = self.read(pc, table);
+                    let var = Exp::TableAccess {
+                        last_line: line,
+                        prefix: Box::new(prefix),
+                        key: Box::new(key),
+                    };
+                    self.store(pc, var, value, stats);
+                }
+            }
+            Decoded::NewTable { a, .. } => match self.local_at(pc, a) {
+                Some(_) => {
+                    let exp = TableBuilder {
+                        line,
+                        items: Vec::new(),
+                    }
+                    .into_exp();
+                    self.write(pc, a, exp, stats);
+                }
+                None => {
+                    self.s.regs[a] = Some(Value::Table(TableBuilder {
+                        line,
+                        items: Vec::new(),
+                    }));
+                    if !self.single_use(pc, a, true) {
+                        let end = usage::ctor_end(self.proto, pc, a);
+                        self.s.spills.push((end, a));
+                    }
+                }
+            },
+            Decoded::Self_ { a, table, key } => {
+                let name = self.rk(pc, key);
+                let obj = self.read(pc, table);
+                self.s.regs[a] = Some(Value::Method { obj, name });
+                self.s.regs[a + 1] = Some(Value::SelfArg);
+            }
+            Decoded::Arith { op, a, b, c } => {
+                let exp1 = self.rk(pc, b);
+                let exp2 = self.rk(pc, c);
+                let exp = binop(line, arith_token(op), exp1, exp2);
+                self.write(pc, a, exp, stats);
+            }
+            Decoded::Unary { op, a, b } => {
+                let exp = self.read(pc, b);
+                self.unop(pc, a, arith_token(op), exp, stats);
+            }
+            Decoded::Not { a, b } => {
+                let exp = self.read(pc, b);
+                self.unop(pc, a, Token::KwNot, exp, stats);
+            }
+            Decoded::Len { a, b } => {
+                let exp = self.read(pc, b);
+                self.unop(pc, a, Token::OpLen, exp, stats);
+            }
+            Decoded::Concat { a, first, last } => {
+                let exps = (first..=last).map(|r| self.read(pc, r)).collect();
+                self.write(pc, a, Exp::Concat { line, exps }, stats);
+            }
+            Decoded::Jmp { offset, .. } => {
+                let target = (pc as isize + 1 + offset) as usize;
+                if target == pc + 1 {
+                    // 只用于关闭upvalue的跳转
+                } else if self.loops.last() == Some(&target) {
+                    self.flush(pc, stats);
+                    stats.push(Stat::Break { line });
+                } else {
+                    self.flush(pc, stats);
+                    self.s.gotos.insert(target);
+                    stats.push(Stat::Goto {
+                        line,
+                        name: label_name(target),
+                    });
+                }
+            }
+            Decoded::Call { a, args, results } => {
+                let call = self.call(pc, a, args)?;
+                match results {
+                    Some(0) => {
+                        // 调用之前计算的值不能移到调用之后
+                        self.flush(pc, stats);
+                        stats.push(Stat::FuncCall(call));
+                        self.s.last_store = None;
+                    }
+                    Some(n) => self.write_multi(pc, a, n, call, stats),
+                    None => {
+                        self.s.regs[a] = Some(Value::Exp(call));
+                        self.s.top = Some(a + 1);
+                    }
+                }
+            }
+            Decoded::TailCall { a, args } => {
+                let call = self.call(pc, a, args)?;
+                self.s.regs[a] = Some(Value::Exp(call));
+                self.s.top = Some(a + 1);
+            }
+            Decoded::Return { a, n } => {
+                let exps = self.collect(pc, a, n)?;
+                self.flush(pc, stats);
+                if self.is_final_return(pc) {
+                    return Ok(pc + 1);
+                }
+                // 之后只剩末尾自动生成的return时也是代码块的最后一条语句
+                let last = pc + 1 == end
+                    || (end == self.proto.code.len()
+                        && pc + 2 == end
+                        && self.is_final_return(pc + 1));
+                if last {
+                    *ret_exps = Some(exps);
+                } else {
+                    stats.push(Stat::Do(Block {
+                        last_line: line,
+                        stats: Vec::new(),
+                        ret_exps: Some(exps),
+                    }));
+                }
+            }
+            Decoded::SetList { a, n, block } => {
+                let exps = self.collect(pc, a + 1, n)?;
+                let block = match block {
+                    Some(block) => block,
+                    None => self.proto.code[pc + 1].ax() as usize,
+                };
+                self.set_list(pc, a, block, exps, stats);
+                if block == 0
+                    || self
+                        .decode(pc)
+                        .is_some_and(|d| matches!(d, Decoded::SetList { block: None, .. }))
+                {
+                    return Ok(pc + 2);
+                }
+            }
+            Decoded::Closure { a, proto } => self.closure(pc, a, proto, stats)?,
+            Decoded::VarArg { a, n } => {
+                let exp = Exp::Vararg { line };
+                match n {
+                    Some(n) => self.write_multi(pc, a, n, exp, stats),
+                    None => {
+                        self.s.regs[a] = Some(Value::Exp(exp));
+                        self.s.top = Some(a + 1);
+                    }
+                }
+            }
+            Decoded::ExtraArg { .. } => (),
+            _ => return Err(self.unsupported(pc, "unexpected jump")),
+        }
+        Ok(pc + 1)
+    }
+
+    fn unop(&mut self, pc: usize, a: usize, op: Token, exp: Exp, stats: &mut Vec<Stat>) {
+        let line = self.line(pc);
+        let exp = Exp::Unop {
+            line,
+            op,
+            exp: Box::new(exp),
+        };
+        self.write(pc, a, exp, stats);
+    }
+
+    // 函数调用，SELF取出的方法使用冒号语法
+    fn call(&mut self, pc: usize, a: usize, args: Option<usize>) -> Result<Exp, DecompileError> {
+        let line = self.line(pc);
+        let func = self.s.regs[a].take();
+        let args = self.collect(pc, a + 1, args)?;
+        let (prefix, name) = match func {
+            Some(Value::Method { obj, name }) => (obj, Some(Box::new(name))),
+            func => {
+                self.s.regs[a] = func;
+                (self.read(pc, a), None)
+            }
+        };
+        Ok(Exp::FuncCall {
+            line,
+            last_line: line,
+            prefix: Box::new(prefix),
+            name,
+            args,
+        })
+    }
+
+    // 表构造器中的字段
+    fn table_field(&mut self, pc: usize, table: usize, key: Rk, value: Rk) {
+        let key = self.rk(pc, key);
+        let value = self.rk(pc, value);
+        // 此前计算好但还没有写入的数组元素
+        let n_array = (table + 1..self.s.regs.len())
+            .take_while(|&r| self.s.regs[r].is_some())
+            .count();
+        if let Some(Value::Table(t)) = &mut self.s.regs[table] {
+            let reserved = t
+                .items
+                .iter()
+                .filter(|(k, v)| k.is_none() && v.is_none())
+                .count();
+            for _ in reserved..n_array {
+                t.items.push((None, None));
+            }
+            t.items.push((Some(key), Some(value)));
+        }
+    }
+
+    // SETLIST把数组元素写入表中，不是表构造器时还原为赋值语句
+    fn set_list(
+        &mut self,
+        pc: usize,
+        a: usize,
+        block: usize,
+        exps: Vec<Exp>,
+        stats: &mut Vec<Stat>,
+    ) {
+        if let (None, Some(Value::Table(t))) = (self.local_at(pc, a), &mut self.s.regs[a]) {
+            let mut exps = exps.into_iter();
+            for item in t.items.iter_mut() {
+                if item.0.is_none() && item.1.is_none() {
+                    item.1 = exps.next();
+                }
+            }
+            t.items.extend(exps.map(|exp| (None, Some(exp))));
+            return;
+        }
+        let line = self.line(pc);
+        let table = self.read(pc, a);
+        let first = (block as i64 - 1) * 50;
+        for (i, exp) in exps.into_iter().enumerate() {
+            let var = Exp::TableAccess {
+                last_line: line,
+                prefix: Box::new(table.clone()),
+                key: Box::new(Exp::Integer {
+                    line,
+                    val: first + i as i64 + 1,
+                }),
+            };
+            self.assign(pc, var, exp, stats);
+        }
+    }
+
+    fn closure(
+        &mut self,
+        pc: usize,
+        a: usize,
+        index: usize,
+        stats: &mut Vec<Stat>,
+    ) -> Result<(), DecompileError> {
+        let line = self.line(pc);
+        let child = &self.proto.protos[index];
+        // 子函数的upvalue来自当前函数的局部变量或upvalue
+        let upvals: Vec<String> = child
+            .upvalues
+            .iter()
+            .enumerate()
+            .map(|(i, upval)| match child.upvalue_names.get(i) {
+                Some(name) if !name.is_empty() => name.clone(),
+                _ if upval.instack == 1 => {
+                    let r = upval.idx as usize;
+                    match self.active(pc + 1).get(r) {
+                        Some(&l) => self.locals[l].var_name.clone(),
+                        None => self.temp(r),
+                    }
+                }
+                _ => self.upvals[upval.idx as usize].clone(),
+            })
+            .collect();
+        let mut outer = self.visible(pc);
+        // 局部函数可以引用自身
+        let local = self
+            .active(pc + 1)
+            .get(a)
+            .copied()
+            .filter(|&l| self.locals[l].start_pc as usize == pc + 1);
+        let recursive = child
+            .upvalues
+            .iter()
+            .any(|u| u.instack == 1 && u.idx as usize == a);
+        if let (Some(l), true) = (local, recursive) {
+            outer.push(self.locals[l].var_name.clone());
+        }
+        let (par_list, block) = function(child, &outer, &upvals)?;
+        let exp = Exp::FuncDef {
+            line: child.line_defined,
+            last_line: child.last_line_defined,
+            par_list,
+            is_vararg: child.is_vararg != 0,
+            block: Box::new(block),
+        };
+        match (local, recursive) {
+            (Some(l), true) => {
+                self.s.declared[l] = true;
+                stats.push(Stat::LocalFunc {
+                    name: self.locals[l].var_name.clone(),
+                    exp,
+                });
+                self.s.last_store = None;
+            }
+            _ => self.write(pc, a, exp, stats),
+        }
+        let _ = line;
+        Ok(())
+    }
+
+    // 函数末尾自动生成的return
+    fn is_final_return(&self, pc: usize) -> bool {
+        pc + 1 == self.proto.code.len()
+            && matches!(self.decode(pc), Some(Decoded::Return { n: Some(0), .. }))
+    }
+
+    /* 控制结构 */
+
+    // j处是否是跳转到target的JMP
+    fn is_back_jump(&self, j: usize, target: usize) -> bool {
+        match self.decode(j) {
+            Some(d @ Decoded::Jmp { .. }) => d.jump_target(j) == Some(target),
+            _ => false,
+        }
+    }
+
+    // 条件成立时顺序执行，不成立时执行pc+1处的跳转
+    fn cond(&mut self, pc: usize) -> Exp {
+        let line = self.line(pc);
+        let compare = |op, expect: bool, exp1, exp2| {
+            let exp = binop(line, op, exp1, exp2);
+            match expect {
+                true => negate(exp),
+                false => exp,
+            }
+        };
+        match self.decode(pc).unwrap() {
+            Decoded::Eq { expect, b, c } => {
+                let (b, c) = (self.rk(pc, b), self.rk(pc, c));
+                compare(Token::OpEq, expect, b, c)
+            }
+            Decoded::Lt { expect, b, c } => {
+                let (b, c) = (self.rk(pc, b), self.rk(pc, c));
+                compare(Token::OpLt, expect, b, c)
+            }
+            Decoded::Le { expect, b, c } => {
+                let (b, c) = (self.rk(pc, b), self.rk(pc, c));
+                compare(Token::OpLe, expect, b, c)
+            }
+            Decoded::Test { a, expect } | Decoded::TestSet { b: a, expect, .. } => {
+                let exp = self.read(pc, a);
+                match expect {
+                    true => negate(exp),
+                    false => exp,
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // test指令和之后的JMP：比较的结果、and/or表达式、if语句或者条件跳转
+    fn branch(
+        &mut self,
+        pc: usize,
+        end: usize,
+        stats: &mut Vec<Stat>,
+    ) -> Result<usize, DecompileError> {
+        let line = self.line(pc);
+        let target = self
+            .decode(pc + 1)
+            .and_then(|d| d.jump_target(pc + 1))
+            .unwrap();
+        let decoded = self.decode(pc).unwrap();
+
+        // CMP; JMP 1; LOADBOOL a 0 1; LOADBOOL a 1 0 是比较运算的值
+        if let (
+            Some(Decoded::LoadBool {
+                a,
+                value: false,
+                skip: true,
+            }),
+            Some(Decoded::LoadBool {
+                a: a2,
+                value: true,
+                skip: false,
+            }),
+        ) = (self.decode(pc + 2), self.decode(pc + 3))
+        {
+            if a == a2 && target == pc + 3 && pc + 3 < end && !self.is_test(pc + 1) {
+                let exp = negate(self.cond(pc));
+                self.write(pc + 3, a, exp, stats);
+                return Ok(pc + 4);
+            }
+        }
+
+        // TEST(SET); JMP; 计算第二个操作数 是and/or表达式
+        if let Decoded::Test { a, expect } | Decoded::TestSet { a, expect, .. } = decoded {
+            if target > pc + 2 && target <= end {
+                let saved = self.s.clone();
+                let src = match decoded {
+                    Decoded::TestSet { b, .. } => b,
+                    _ => a,
+                };
+                let exp1 = self.read(pc, src);
+                self.exempt.push(a);
+                let pure = self.local_at(pc, a).is_none() && self.pure(pc + 2, target);
+                self.exempt.pop();
+                if pure {
+                    if let Some(Value::Exp(exp2)) = self.s.regs[a].take() {
+                        let op = match expect {
+                            true => Token::KwOr,
+                            false => Token::KwAnd,
+                        };
+                        self.write(target - 1, a, binop(line, op, exp1, exp2), stats);
+                        return Ok(target);
+                    }
+                }
+                self.s = saved;
+            }
+            if let Decoded::TestSet { .. } = decoded {
+                return Err(self.unsupported(pc, "unexpected TESTSET"));
+            }
+        }
+
+        let cond = self.cond(pc);
+        self.flush(pc, stats);
+        if target > pc + 1 && target <= end {
+            // then分支最后的JMP跳过else分支
+            let mut then_end = target;
+            let mut else_end = None;
+            if target >= pc + 3 && !self.is_test(target - 2) {
+                if let Some(e) = self
+                    .decode(target - 1)
+                    .and_then(|d| d.jump_target(target - 1))
+                {
+                    let is_jmp = self.proto.code[target - 1].op() == Some(Op::Jmp);
+                    if is_jmp && e > target && e <= end && self.loops.last() != Some(&e) {
+                        then_end = target - 1;
+                        else_end = Some(e);
+                    }
+                }
+            }
+            let then_block = self.body(pc + 2, then_end)?;
+            let mut exps = vec![cond];
+            let mut blocks = vec![then_block];
+            let next = match else_end {
+                Some(e) => {
+                    let else_block = self.body(target, e)?;
+                    // then分支为空时只保留else分支
+                    if blocks[0].stats.is_empty() && blocks[0].ret_exps.is_none() {
+                        exps = vec![negate(exps.remove(0))];
+                        blocks = vec![else_block];
+                    } else {
+                        exps.push(Exp::True { line });
+                        blocks.push(else_block);
+                    }
+                    e
+                }
+                None => target,
+            };
+            stats.push(Stat::If { exps, blocks });
+            self.s.last_store = None;
+            return Ok(next);
+        }
+
+        // 跳出当前代码块的条件跳转
+        let stat = match self.loops.last() == Some(&target) {
+            true => Stat::Break { line },
+            false => {
+                self.s.gotos.insert(target);
+                Stat::Goto {
+                    line,
+                    name: label_name(target),
+                }
+            }
+        };
+        stats.push(Stat::If {
+            exps: vec![negate(cond)],
+            blocks: vec![Block {
+                last_line: line,
+                stats: vec![stat],
+                ret_exps: None,
+            }],
+        });
+        self.s.last_store = None;
+        Ok(pc + 2)
+    }
+
+    // 以pc开始、在j处跳回pc的循环：while或repeat
+    fn loop_stat(
+        &mut self,
+        pc: usize,
+        j: usize,
+        stats: &mut Vec<Stat>,
+    ) -> Result<usize, DecompileError> {
+        let line = self.line(pc);
+        self.flush(pc, stats);
+        if j > pc && self.is_test(j - 1) {
+            // repeat block until exp
+            self.loops.push(j + 1);
+            let mut block = self.block(pc, j - 1)?;
+            // until中的条件可以引用循环体中最后声明的局部变量
+            self.declare(j - 1, false, &mut block.stats);
+            let exp = self.cond(j - 1);
+            self.flush(j - 1, &mut block.stats);
+            self.loops.pop();
+            stats.push(Stat::Repeat { block, exp });
+            self.s.last_store = None;
+            return Ok(j + 1);
+        }
+
+        // while exp do block end，条件为假时跳出循环
+        let mut exp = Exp::True { line };
+        let mut start = pc;
+        let exit = (pc..j).find(|&c| {
+            self.is_test(c) && self.decode(c + 1).and_then(|d| d.jump_target(c + 1)) == Some(j + 1)
+        });
+        if let Some(c) = exit {
+            let saved = self.s.clone();
+            if self.pure(pc, c) {
+                exp = self.cond(c);
+                start = c + 2;
+            } else {
+                self.s = saved;
+            }
+        }
+        self.loops.push(j + 1);
+        let block = self.body(start, j)?;
+        self.loops.pop();
+        stats.push(Stat::While { exp, block });
+        self.s.last_store = None;
+        Ok(j + 1)
+    }
+
+    // for Name = exp, exp, exp do block end
+    fn for_num(
+        &mut self,
+        pc: usize,
+        a: usize,
+        offset: isize,
+        stats: &mut Vec<Stat>,
+    ) -> Result<usize, DecompileError> {
+        let line = self.line(pc);
+        let init = self.read(pc, a);
+        let limit = self.read(pc, a + 1);
+        let step = self.read(pc, a + 2);
+        self.flush(pc, stats);
+        let end = (pc as isize + 1 + offset) as usize;
+        let var_name = self.claim(pc + 1, a + 3);
+        self.loops.push(end + 1);
+        let block = self.body(pc + 1, end)?;
+        self.loops.pop();
+        stats.push(Stat::ForNum {
+            line_of_for: line,
+            line_of_do: line,
+            var_name,
+            init,
+            limit,
+            step,
+            block,
+        });
+        self.s.last_store = None;
+        Ok(end + 1)
+    }
+
+    // for namelist in explist do block end
+    // JMP到TFORCALL，循环体之后是TFORCALL和跳回循环体开头的TFORLOOP
+    fn for_in(
+        &mut self,
+        pc: usize,
+        end: usize,
+        stats: &mut Vec<Stat>,
+    ) -> Result<Option<usize>, DecompileError> {
+        let Some(d @ Decoded::Jmp { .. }) = self.decode(pc) else {
+            return Ok(None);
+        };
+        let call = d.jump_target(pc).unwrap();
+        let (Some(Decoded::TForCall { a, results }), Some(Decoded::TForLoop { a: a2, offset })) =
+            (self.decode(call), self.decode(call + 1))
+        else {
+            return Ok(None);
+        };
+        if call <= pc
+            || call + 1 >= end
+            || a2 != a + 2
+            || (call as isize + 2 + offset) as usize != pc + 1
+        {
+            return Ok(None);
+        }
+        let line = self.line(pc);
+        let mut exp_list = self.collect(pc, a, Some(3))?;
+        if let Some(Exp::Paren(exp)) = exp_list.last() {
+            // 三个值的位置是固定的，最后的多值表达式不需要截断
+            if exp_list.len() < 3 {
+                let exp = (**exp).clone();
+                *exp_list.last_mut().unwrap() = exp;
+            }
+        }
+        trim_nils(&mut exp_list);
+        if exp_list.is_empty() {
+            exp_list.push(Exp::Nil { line });
+        }
+        self.flush(pc, stats);
+        let name_list = (a + 3..a + 3 + results)
+            .map(|r| self.claim(pc + 1, r))
+            .collect();
+        self.loops.push(call + 2);
+        let block = self.body(pc + 1, call)?;
+        self.loops.pop();
+        stats.push(Stat::ForIn {
+            line_of_for: line,
+            line_of_do: line,
+            name_list,
+            exp_list,
+            block,
+        });
+        self.s.last_store = None;
+        Ok(Some(call + 2))
+    }
+}
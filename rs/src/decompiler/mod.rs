@@ -0,0 +1,146 @@
+pub mod error;
+mod function;
+mod printer;
+mod usage;
+
+pub use error::DecompileError;
+
+use crate::analysis;
+use crate::binary::chunk::Prototype;
+
+/// 把主函数原型还原为Lua源码
+/// 局部变量和upvalue使用调试信息中的名字，没有调试信息时生成r0、a1这样的名字
+/// 字节码的调试信息不包含标签名，goto的标签按目标指令的位置命名为label_<pc>，
+/// 所以源码中的::done::这样的标签在往返之后会被改名
+pub fn decompile(proto: &Prototype) -> Result<String, DecompileError> {
+    analysis::verify(proto)?;
+    // 主函数唯一的upvalue是_ENV
+    let upvals: Vec<String> = (0..proto.upvalues.len())
+        .map(|i| match proto.upvalue_names.get(i) {
+            Some(name) if !name.is_empty() => name.clone(),
+            _ if i == 0 => "_ENV".to_string(),
+            _ => format!("u{i}"),
+        })
+        .collect();
+    let (_, block) = function::function(proto, &[], &upvals)?;
+    Ok(printer::block_to_string(&block))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::binary;
+    use crate::binary::strip::strip;
+    use crate::compiler::compile;
+    use crate::decompiler::decompile;
+    use crate::stdlib;
+    use crate::vm::lua_state::LuaState;
+    use crate::vm::lua_value::LuaValue;
+
+    fn run(src: &[u8]) -> Vec<LuaValue> {
+        let proto = compile(src, "=test").unwrap();
+        let mut state = LuaState::new();
+        stdlib::open_libs(&mut state);
+        let main = state.load(proto).unwrap();
+        state.call(main, Vec::new()).unwrap()
+    }
+
+    // 反编译的结果重新编译后运行结果不变，并且再次反编译得到相同的源码
+    fn round_trip(src: &str) -> String {
+        let proto = compile(src.as_bytes(), "=test").unwrap();
+        let text = decompile(&proto).unwrap();
+        let proto2 =
+            compile(text.as_bytes(), "=test").unwrap_or_else(|err| panic!("{err}\n{text}"));
+        assert_eq!(decompile(&proto2).unwrap(), text);
+        assert_eq!(run(text.as_bytes()), run(src.as_bytes()), "\n{text}");
+        text
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let text = round_trip(
+            "local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end\n\
+             local t = {10, 20, n = 0, [3] = 'x', 30}\n\
+             function t:add(x) self.n = self.n + x return self end\n\
+             for i = 1, 10 do t:add(i) end\n\
+             for i = 10, 1, -2 do t.n = t.n - i end\n\
+             for k, v in ipairs(t) do t.n = t.n + v end\n\
+             return fib(15), t.n, #t, t[3]",
+        );
+        assert!(text.contains("local function fib(n)"));
+        assert!(text.contains("function t:add(x)"));
+        assert!(text.contains("for i = 1, 10 do"));
+        assert!(text.contains("for i = 10, 1, -2 do"));
+        assert!(text.contains("for k, v in ipairs(t) do"));
+
+        let text = round_trip(
+            "local a, b, c = 1, 2\n\
+             local s = 0\n\
+             while a < 100 do\n  if a % 3 == 0 then s = s + a elseif a % 5 == 0 then s = s - 1 else s = s + 1 end\n  a = a * 2\nend\n\
+             repeat local d = a; a = a - 7 until d < 50\n\
+             a, b = b, a\n\
+             local x = a > b and 'gt' or 'le'\n\
+             local y = not (a == b)\n\
+             return a, b, c, s, x, y, a ~= b, -a .. 'z'",
+        );
+        assert!(text.contains("while a < 100 do"));
+        assert!(text.contains("elseif"));
+        assert!(text.contains("repeat"));
+        assert!(text.contains("a, b = b, a"));
+
+        round_trip(
+            "local t = {}\n\
+             for i = 1, 20 do\n  if i > 15 then break end\n  t[#t + 1] = i\nend\n\
+             local function counter()\n  local n = 0\n  return function() n = n + 1; return n end\nend\n\
+             local c = counter(); c(); c()\n\
+             g = {c(), select('#', 1, 2)}\n\
+             return #t, g[1], g[2], tostring(3) .. 'x', 1 // 0.0 < 0",
+        );
+
+        // do语句限制局部变量的作用域，无法还原的跳转变成循环或者goto
+        let text = round_trip(
+            "local x, n = 1, 0
+             do local x = 2; n = n + x end
+             ::top::
+             n = n + 1
+             if n < 5 then goto top end
+             for i = 1, 3 do
+  for j = 1, 3 do
+    if j == 2 then goto continue end
+    n = n + i * j
+    ::continue::
+  end
+end
+             return x, n",
+        );
+        assert!(text.contains("do\n  local x = 2\n"));
+        assert!(text.contains("if j ~= 2 then"));
+
+        // 标签名没有保存在字节码中，按目标指令的位置重新命名
+        let text = round_trip(
+            "local n = 0
+             for i = 1, 3 do
+  for j = 1, 3 do
+    if i * j == 4 then goto done end
+    n = n + 1
+  end
+end
+             ::done::
+             return n",
+        );
+        assert!(text.contains("goto label_21\n"), "{text}");
+        assert!(text.contains("::label_21::\nreturn n"), "{text}");
+    }
+
+    #[test]
+    fn test_stripped() {
+        // 没有调试信息时使用生成的名字，多次使用的值保存在临时变量中
+        let src = b"local a, b = 1, 2\nlocal f = function(x, y) return x + y end\n\
+            local t = {}\nfor i = 1, 3 do t[i] = f(i, b) end\n\
+            a, b = b, a\nreturn f(a, b), #t, t[3]";
+        let proto = compile(src, "=test").unwrap();
+        let proto = binary::undump(binary::dump(&strip(&proto, false))).unwrap();
+        let text = decompile(&proto).unwrap();
+        assert!(text.contains("(a1, a2)\n  return a1 + a2\n"), "{text}");
+        assert_eq!(run(text.as_bytes()), run(src), "\n{text}");
+    }
+}
@@ -0,0 +1,534 @@
+use crate::compiler::ast::{Block, Exp, Stat};
+use crate::compiler::token::Token;
+
+// 缩进宽度
+const INDENT: &str = "  ";
+
+// 一元运算的优先级
+const UNARY_PRIORITY: u8 = 12;
+// 不需要加括号的表达式的优先级
+const ATOM_PRIORITY: u8 = 100;
+
+// 把代码块输出为Lua源码
+pub fn block_to_string(block: &Block) -> String {
+    let mut printer = Printer {
+        out: String::new(),
+        indent: 0,
+    };
+    printer.block(block);
+    printer.out
+}
+
+// 能否作为标识符，关键字不能作为标识符
+pub fn is_identifier(s: &[u8]) -> bool {
+    match s.first() {
+        Some(c) if c.is_ascii_alphabetic() || *c == b'_' => {
+            s.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+                && Token::keyword(std::str::from_utf8(s).unwrap()).is_none()
+        }
+        _ => false,
+    }
+}
+
+struct Printer {
+    out: String,
+    indent: usize,
+}
+
+impl Printer {
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn block(&mut self, block: &Block) {
+        for stat in &block.stats {
+            self.stat(stat);
+        }
+        if let Some(exps) = &block.ret_exps {
+            match exps.is_empty() {
+                true => self.line("return"),
+                false => {
+                    let exps = self.exp_list(exps);
+                    self.line(&format!("return {exps}"));
+                }
+            }
+        }
+    }
+
+    fn nested(&mut self, block: &Block) {
+        self.indent += 1;
+        self.block(block);
+        self.indent -= 1;
+    }
+
+    fn stat(&mut self, stat: &Stat) {
+        match stat {
+            Stat::Empty => (),
+            Stat::Break { .. } => self.line("break"),
+            Stat::Label { name, .. } => self.line(&format!("::{name}::")),
+            Stat::Goto { name, .. } => self.line(&format!("goto {name}")),
+            Stat::Do(block) => {
+                self.line("do");
+                self.nested(block);
+                self.line("end");
+            }
+            Stat::FuncCall(exp) => {
+                // 以圆括号开头的语句会被当作上一条语句的调用参数
+                let text = self.exp(exp, 0);
+                match text.starts_with('(') {
+                    true => self.line(&format!(";{text}")),
+                    false => self.line(&text),
+                }
+            }
+            Stat::While { exp, block } => {
+                let exp = self.exp(exp, 0);
+                self.line(&format!("while {exp} do"));
+                self.nested(block);
+                self.line("end");
+            }
+            Stat::Repeat { block, exp } => {
+                self.line("repeat");
+                self.nested(block);
+                let exp = self.exp(exp, 0);
+                self.line(&format!("until {exp}"));
+            }
+            Stat::If { exps, blocks } => self.if_stat(exps, blocks),
+            Stat::ForNum {
+                var_name,
+                init,
+                limit,
+                step,
+                block,
+                ..
+            } => {
+                let mut head = format!(
+                    "for {} = {}, {}",
+                    var_name,
+                    self.exp(init, 0),
+                    self.exp(limit, 0)
+                );
+                if !matches!(step, Exp::Integer { val: 1, .. }) {
+                    head.push_str(&format!(", {}", self.exp(step, 0)));
+                }
+                self.line(&format!("{head} do"));
+                self.nested(block);
+                self.line("end");
+            }
+            Stat::ForIn {
+                name_list,
+                exp_list,
+                block,
+                ..
+            } => {
+                let exps = self.exp_list(exp_list);
+                self.line(&format!("for {} in {} do", name_list.join(", "), exps));
+                self.nested(block);
+                self.line("end");
+            }
+            Stat::LocalVar {
+                name_list,
+                exp_list,
+                ..
+            } => match exp_list.is_empty() {
+                true => self.line(&format!("local {}", name_list.join(", "))),
+                false => {
+                    let exps = self.exp_list(exp_list);
+                    self.line(&format!("local {} = {}", name_list.join(", "), exps));
+                }
+            },
+            Stat::Assign {
+                var_list, exp_list, ..
+            } => {
+                // name = function ... end 输出为 function name ... end
+                if let (
+                    [var],
+                    [Exp::FuncDef {
+                        par_list,
+                        is_vararg,
+                        block,
+                        ..
+                    }],
+                ) = (&var_list[..], &exp_list[..])
+                {
+                    if let Some(name) = func_name(var) {
+                        let method = !name.contains(':')
+                            && name.contains('.')
+                            && par_list.first().is_some_and(|p| p == "self");
+                        let (name, params) = match method {
+                            true => {
+                                let dot = name.rfind('.').unwrap();
+                                (
+                                    format!("{}:{}", &name[..dot], &name[dot + 1..]),
+                                    &par_list[1..],
+                                )
+                            }
+                            false => (name, &par_list[..]),
+                        };
+                        self.function(&format!("function {name}"), params, *is_vararg, block, "");
+                        return;
+                    }
+                }
+                let vars: Vec<String> = var_list.iter().map(|var| self.exp(var, 0)).collect();
+                let exps = self.exp_list(exp_list);
+                self.line(&format!("{} = {}", vars.join(", "), exps));
+            }
+            Stat::LocalFunc { name, exp } => match exp {
+                Exp::FuncDef {
+                    par_list,
+                    is_vararg,
+                    block,
+                    ..
+                } => self.function(
+                    &format!("local function {name}"),
+                    par_list,
+                    *is_vararg,
+                    block,
+                    "",
+                ),
+                _ => {
+                    let exp = self.exp(exp, 0);
+                    self.line(&format!("local {name} = {exp}"));
+                }
+            },
+        }
+    }
+
+    // elseif会被转换为else分支中唯一的if语句，这里再把它们合并
+    fn if_stat(&mut self, exps: &[Exp], blocks: &[Block]) {
+        let mut keyword = "if";
+        let mut exps = exps.to_vec();
+        let mut blocks = blocks.to_vec();
+        let mut i = 0;
+        while i < exps.len() {
+            let is_else = i > 0 && i == exps.len() - 1 && matches!(exps[i], Exp::True { .. });
+            if is_else {
+                if let [Stat::If {
+                    exps: inner,
+                    blocks: inner_blocks,
+                }] = &blocks[i].stats[..]
+                {
+                    if blocks[i].ret_exps.is_none() {
+                        let (inner, inner_blocks) = (inner.clone(), inner_blocks.clone());
+                        exps.splice(i.., inner);
+                        blocks.splice(i.., inner_blocks);
+                        keyword = "elseif";
+                        continue;
+                    }
+                }
+                self.line("else");
+            } else {
+                let exp = self.exp(&exps[i], 0);
+                self.line(&format!("{keyword} {exp} then"));
+            }
+            self.nested(&blocks[i]);
+            keyword = "elseif";
+            i += 1;
+        }
+        self.line("end");
+    }
+
+    // 输出函数定义，prefix是function之前的部分，suffix是end之后的部分
+    fn function(
+        &mut self,
+        prefix: &str,
+        par_list: &[String],
+        is_vararg: bool,
+        block: &Block,
+        suffix: &str,
+    ) {
+        let mut params = par_list.to_vec();
+        if is_vararg {
+            params.push("...".to_string());
+        }
+        self.line(&format!("{}({})", prefix, params.join(", ")));
+        self.nested(block);
+        self.line(&format!("end{suffix}"));
+    }
+
+    fn exp_list(&mut self, exps: &[Exp]) -> String {
+        let exps: Vec<String> = exps.iter().map(|exp| self.exp(exp, 0)).collect();
+        exps.join(", ")
+    }
+
+    // 输出表达式，外层运算的优先级高于表达式自身时加上圆括号
+    fn exp(&mut self, exp: &Exp, limit: u8) -> String {
+        let (text, priority) = self.exp_priority(exp);
+        match priority < limit {
+            true => format!("({text})"),
+            false => text,
+        }
+    }
+
+    fn exp_priority(&mut self, exp: &Exp) -> (String, u8) {
+        let text = match exp {
+            Exp::Nil { .. } => "nil".to_string(),
+            Exp::True { .. } => "true".to_string(),
+            Exp::False { .. } => "false".to_string(),
+            Exp::Vararg { .. } => "...".to_string(),
+            Exp::Integer { val, .. } => {
+                return match *val {
+                    // 十进制无法表示最小的整数，十六进制整数会回绕
+                    i64::MIN => ("0x8000000000000000".to_string(), ATOM_PRIORITY),
+                    val if val < 0 => (val.to_string(), UNARY_PRIORITY),
+                    val => (val.to_string(), ATOM_PRIORITY),
+                };
+            }
+            Exp::Float { val, .. } => return float_to_string(*val),
+            Exp::Str { val, .. } => quote(val),
+            Exp::Name { name, .. } => name.clone(),
+            Exp::Unop { op, exp, .. } => {
+                let operand = self.exp(exp, UNARY_PRIORITY);
+                let text = match op {
+                    Token::KwNot => format!("not {operand}"),
+                    Token::OpLen => format!("#{operand}"),
+                    Token::OpWave => format!("~{operand}"),
+                    // --会被当作注释
+                    _ if operand.starts_with('-') => format!("- {operand}"),
+                    _ => format!("-{operand}"),
+                };
+                return (text, UNARY_PRIORITY);
+            }
+            Exp::Binop { op, exp1, exp2, .. } => {
+                let (symbol, priority, right_assoc) = binop(op);
+                let (left, right) = match right_assoc {
+                    true => (priority + 1, priority),
+                    false => (priority, priority + 1),
+                };
+                let exp1 = self.exp(exp1, left);
+                let exp2 = self.exp(exp2, right);
+                return (format!("{exp1} {symbol} {exp2}"), priority);
+            }
+            Exp::Concat { exps, .. } => {
+                let (_, priority, _) = binop(&Token::OpConcat);
+                let n = exps.len();
+                let parts: Vec<String> = exps
+                    .iter()
+                    .enumerate()
+                    .map(|(i, exp)| match exp {
+                        // 拼接的元合并成一条指令，嵌套的拼接需要保留圆括号
+                        Exp::Concat { .. } if i < n - 1 => format!("({})", self.exp(exp, 0)),
+                        _ => self.exp(exp, priority + 1),
+                    })
+                    .collect();
+                return (parts.join(" .. "), priority);
+            }
+            Exp::Table {
+                key_exps, val_exps, ..
+            } => {
+                let items: Vec<String> = key_exps
+                    .iter()
+                    .zip(val_exps)
+                    .map(|(key, val)| {
+                        let val = self.exp(val, 0);
+                        match key {
+                            None => val,
+                            Some(Exp::Str { val: name, .. }) if is_identifier(name) => {
+                                format!("{} = {}", String::from_utf8_lossy(name), val)
+                            }
+                            Some(key) => format!("[{}] = {}", self.exp(key, 0), val),
+                        }
+                    })
+                    .collect();
+                format!("{{{}}}", items.join(", "))
+            }
+            Exp::FuncDef {
+                par_list,
+                is_vararg,
+                block,
+                ..
+            } => {
+                // 函数体单独输出后再缩进到当前位置
+                let mut inner = Printer {
+                    out: String::new(),
+                    indent: self.indent,
+                };
+                inner.function("function", par_list, *is_vararg, block, "");
+                inner.out.trim().to_string()
+            }
+            Exp::Paren(exp) => format!("({})", self.exp(exp, 0)),
+            Exp::TableAccess { prefix, key, .. } => {
+                let prefix = self.prefix_exp(prefix);
+                match &**key {
+                    Exp::Str { val, .. } if is_identifier(val) => {
+                        format!("{}.{}", prefix, String::from_utf8_lossy(val))
+                    }
+                    key => format!("{}[{}]", prefix, self.exp(key, 0)),
+                }
+            }
+            Exp::FuncCall {
+                prefix, name, args, ..
+            } => {
+                let mut text = self.prefix_exp(prefix);
+                if let Some(name) = name {
+                    if let Exp::Str { val, .. } = &**name {
+                        text.push(':');
+                        text.push_str(&String::from_utf8_lossy(val));
+                    }
+                }
+                let args = self.exp_list(args);
+                format!("{text}({args})")
+            }
+        };
+        (text, ATOM_PRIORITY)
+    }
+
+    // 只有变量、函数调用和圆括号表达式可以直接索引或调用
+    fn prefix_exp(&mut self, exp: &Exp) -> String {
+        let text = self.exp(exp, 0);
+        match exp {
+            Exp::Name { .. } | Exp::TableAccess { .. } | Exp::FuncCall { .. } | Exp::Paren(_) => {
+                text
+            }
+            _ => format!("({text})"),
+        }
+    }
+}
+
+// 可以用在function语句中的函数名，如 a.b.c
+fn func_name(exp: &Exp) -> Option<String> {
+    match exp {
+        Exp::Name { name, .. } => Some(name.clone()),
+        Exp::TableAccess { prefix, key, .. } => match &**key {
+            Exp::Str { val, .. } if is_identifier(val) => {
+                let prefix = func_name(prefix)?;
+                Some(format!("{}.{}", prefix, String::from_utf8_lossy(val)))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// 二元运算符的符号、优先级和是否右结合
+fn binop(op: &Token) -> (&'static str, u8, bool) {
+    match op {
+        Token::KwOr => ("or", 1, false),
+        Token::KwAnd => ("and", 2, false),
+        Token::OpLt => ("<", 3, false),
+        Token::OpGt => (">", 3, false),
+        Token::OpLe => ("<=", 3, false),
+        Token::OpGe => (">=", 3, false),
+        Token::OpNe => ("~=", 3, false),
+        Token::OpEq => ("==", 3, false),
+        Token::OpBOr => ("|", 4, false),
+        Token::OpWave => ("~", 5, false),
+        Token::OpBAnd => ("&", 6, false),
+        Token::OpShl => ("<<", 7, false),
+        Token::OpShr => (">>", 7, false),
+        Token::OpConcat => ("..", 9, true),
+        Token::OpAdd => ("+", 10, false),
+        Token::OpMinus => ("-", 10, false),
+        Token::OpMul => ("*", 11, false),
+        Token::OpDiv => ("/", 11, false),
+        Token::OpIDiv => ("//", 11, false),
+        Token::OpMod => ("%", 11, false),
+        _ => ("^", 14, true),
+    }
+}
+
+// 浮点数输出为能精确还原的形式，并且总是带有小数点或指数
+fn float_to_string(val: f64) -> (String, u8) {
+    if val.is_nan() {
+        return ("(0/0)".to_string(), ATOM_PRIORITY);
+    }
+    let text = if val.is_infinite() {
+        // 超出范围的浮点数是无穷大
+        match val > 0.0 {
+            true => "1e9999".to_string(),
+            false => "-1e9999".to_string(),
+        }
+    } else {
+        format!("{val:?}")
+    };
+    match text.starts_with('-') {
+        true => (text, UNARY_PRIORITY),
+        false => (text, ATOM_PRIORITY),
+    }
+}
+
+// 输出带引号的字符串，不可打印的字节使用转义序列
+pub fn quote(s: &[u8]) -> String {
+    let utf8 = std::str::from_utf8(s).is_ok();
+    let mut out = String::from("\"");
+    let mut i = 0;
+    while i < s.len() {
+        let b = s[i];
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7E => out.push(b as char),
+            0x80.. if utf8 => {
+                // 合法的UTF-8字符原样输出
+                let len = s[i..]
+                    .iter()
+                    .skip(1)
+                    .take_while(|c| (**c & 0xC0) == 0x80)
+                    .count()
+                    + 1;
+                out.push_str(std::str::from_utf8(&s[i..i + len]).unwrap());
+                i += len;
+                continue;
+            }
+            _ => out.push_str(&format!("\\{b:03}")),
+        }
+        i += 1;
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compiler::ast::Block;
+    use crate::compiler::lexer::Lexer;
+    use crate::compiler::parser::Parser;
+    use crate::decompiler::printer::{block_to_string, quote};
+
+    fn parse(source: &str) -> Block {
+        Parser::new(Lexer::new(source.as_bytes(), "=test"))
+            .parse_chunk()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_print() {
+        let source = "local a, b = 1, -2.5\n\
+            local function f(x, ...)\n  return x ^ -1, -x ^ 2, (a + b) * 2, a - (b - 1), a ^ b ^ x, (a ^ b) ^ x\nend\n\
+            function a.b.c:m(y)\n  self.y = y\nend\n\
+            t = {1, \"two\", x = {}, [1.5] = f, [\"a b\"] = ...}\n\
+            if a then\n  print(a)\nelseif b then\n  print(b)\nelse\n  print(not a == b)\nend\n\
+            while a < b and not (a > 3 or b <= 1) do\n  a = a .. (b .. \"x\") .. - -a\nend\n\
+            repeat\n  goto l\n  ::l::\nuntil #t >= 1\n\
+            for i = 1, 2, 3 do\n  break\nend\n\
+            for k, v in pairs(t) do\n  (\"x\"):rep(2)\nend\n";
+        let block = parse(source);
+        let text = block_to_string(&block);
+        // 输出的源码再次解析后得到相同的语法树
+        assert_eq!(block_to_string(&parse(&text)), text);
+        assert!(text.contains(
+            "return x ^ (-1), -x ^ 2, (a + b) * 2, a - (b - 1), a ^ b ^ x, (a ^ b) ^ x\n"
+        ));
+        assert!(text.contains("function a.b.c:m(y)\n"));
+        assert!(text.contains("local function f(x, ...)\n"));
+        assert!(text.contains("t = {1, \"two\", x = {}, [1.5] = f, [\"a b\"] = ...}\n"));
+        assert!(text.contains("elseif b then\n"));
+        assert!(text.contains("while a < b and not (a > 3 or b <= 1) do\n"));
+        assert!(text.contains("a = a .. (b .. \"x\") .. - -a\n"));
+        assert!(text.contains(";(\"x\"):rep(2)\n"));
+        assert!(text.contains("for i = 1, 2, 3 do\n"));
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote(b"a\"b\\c\n\0\x7f"), "\"a\\\"b\\\\c\\n\\000\\127\"");
+        assert_eq!(quote("中文".as_bytes()), "\"中文\"");
+        assert_eq!(quote(b"\xff1"), "\"\\2551\"");
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::BTreeSet;
+
+use crate::analysis::cfg::successors;
+use crate::binary::chunk::Prototype;
+use crate::vm::decoded::{Decoded, Rk};
+
+/// 指令对某个寄存器的读取次数，Many表示被子函数捕获等无法确定次数的情况
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reads {
+    None,
+    Once,
+    Many,
+}
+
+// 寄存器r是否在[from, to)范围内，to为None表示直到栈顶
+fn in_range(r: usize, from: usize, to: Option<usize>) -> bool {
+    r >= from && to.is_none_or(|to| r < to)
+}
+
+// pc处的指令对寄存器r的读写，返回读取次数和是否写入
+fn touches(proto: &Prototype, pc: usize, r: usize) -> (Reads, bool) {
+    let Some(decoded) = Decoded::decode(proto.code[pc]) else {
+        return (Reads::Many, true);
+    };
+    let rk = |x: Rk| x == Rk::Reg(r);
+    let (read, write) = match decoded {
+        Decoded::Move { a, b } => (b == r, a == r),
+        Decoded::LoadK { a, .. }
+        | Decoded::LoadKX { a }
+        | Decoded::LoadBool { a, .. }
+        | Decoded::GetUpval { a, .. }
+        | Decoded::NewTable { a, .. } => (false, a == r),
+        Decoded::LoadNil { a, n } => (false, in_range(r, a, Some(a + n))),
+        Decoded::GetTabUp { a, key, .. } => (rk(key), a == r),
+        Decoded::GetTable { a, table, key } => (table == r || rk(key), a == r),
+        Decoded::SetTabUp { key, value, .. } => (rk(key) || rk(value), false),
+        Decoded::SetUpval { a, .. } | Decoded::Test { a, .. } => (a == r, false),
+        Decoded::SetTable { table, key, value } => (table == r || rk(key) || rk(value), false),
+        Decoded::Self_ { a, table, key } => (table == r || rk(key), a == r || a + 1 == r),
+        Decoded::Arith { a, b, c, .. } => (rk(b) || rk(c), a == r),
+        Decoded::Unary { a, b, .. } | Decoded::Not { a, b } | Decoded::Len { a, b } => {
+            (b == r, a == r)
+        }
+        Decoded::Concat { a, first, last } => (in_range(r, first, Some(last + 1)), a == r),
+        Decoded::Eq { b, c, .. } | Decoded::Lt { b, c, .. } | Decoded::Le { b, c, .. } => {
+            (rk(b) || rk(c), false)
+        }
+        Decoded::TestSet { a, b, .. } => (b == r, a == r),
+        Decoded::Call { a, args, results } => (
+            in_range(r, a, args.map(|n| a + 1 + n)),
+            in_range(r, a, results.map(|n| a + n)),
+        ),
+        // 之后的RETURN返回的是尾调用的所有返回值
+        Decoded::TailCall { a, args } => (in_range(r, a, args.map(|n| a + 1 + n)), r >= a),
+        Decoded::Return { a, n } => (in_range(r, a, n.map(|n| a + n)), false),
+        Decoded::ForLoop { a, .. } => (in_range(r, a, Some(a + 3)), a == r || a + 3 == r),
+        Decoded::ForPrep { a, .. } => (in_range(r, a, Some(a + 3)), a == r),
+        Decoded::TForCall { a, results } => (
+            in_range(r, a, Some(a + 3)),
+            in_range(r, a + 3, Some(a + 3 + results)),
+        ),
+        Decoded::TForLoop { a, .. } => (a + 1 == r, a == r),
+        Decoded::SetList { a, n, .. } => (in_range(r, a, n.map(|n| a + 1 + n)), false),
+        Decoded::Closure { a, proto: index } => {
+            // 被子函数捕获的寄存器可能在任何时候被读取
+            let captured = proto.protos[index]
+                .upvalues
+                .iter()
+                .any(|u| u.instack == 1 && u.idx as usize == r);
+            if captured {
+                return (Reads::Many, a == r);
+            }
+            (false, a == r)
+        }
+        Decoded::VarArg { a, n } => (false, in_range(r, a, n.map(|n| a + n))),
+        Decoded::Jmp { .. } | Decoded::ExtraArg { .. } => (false, false),
+    };
+    let reads = match read {
+        true => Reads::Once,
+        false => Reads::None,
+    };
+    (reads, write)
+}
+
+// 表构造器中写入表r的指令不算作对r的读取
+fn is_ctor(proto: &Prototype, pc: usize, r: usize) -> bool {
+    matches!(
+        Decoded::decode(proto.code[pc]),
+        Some(Decoded::SetTable { table, .. }) if table == r
+    ) || matches!(
+        Decoded::decode(proto.code[pc]),
+        Some(Decoded::SetList { a, .. }) if a == r
+    )
+}
+
+// pc处写入寄存器r的值是否在之后的所有路径上总共只被读取一次，并且不会在循环中被反复读取
+// 只读取一次的值可以直接放到读取它的表达式中，否则要先保存到临时变量
+// ctor为true时忽略表构造器向表中写入字段的指令
+// decls是局部变量开始生效的pc和寄存器，值成为局部变量后由名字引用，不再计算读取次数
+pub fn single_use(
+    proto: &Prototype,
+    decls: &BTreeSet<(usize, usize)>,
+    pc: usize,
+    r: usize,
+    ctor: bool,
+) -> bool {
+    let mut reads = 0;
+    let mut visited = BTreeSet::new();
+    // 第二个值表示是否经过了回跳，此后的读取会随着循环重复执行
+    let mut stack: Vec<(usize, bool)> = next(proto, pc, false);
+    while let Some((p, looped)) = stack.pop() {
+        if !visited.insert((p, looped)) || decls.contains(&(p, r)) {
+            continue;
+        }
+        let (read, write) = match ctor && is_ctor(proto, p, r) {
+            true => (Reads::None, false),
+            false => touches(proto, p, r),
+        };
+        match read {
+            Reads::Many => return false,
+            Reads::Once if looped => return false,
+            Reads::Once => reads += 1,
+            Reads::None => (),
+        }
+        if reads > 1 {
+            return false;
+        }
+        // for循环把初始值保存在循环内部的寄存器中，之后不再读取
+        let consumed = read == Reads::Once
+            && matches!(
+                Decoded::decode(proto.code[p]),
+                Some(Decoded::ForPrep { .. } | Decoded::TForCall { .. })
+            );
+        if write || consumed {
+            continue;
+        }
+        stack.extend(next(proto, p, looped));
+    }
+    true
+}
+
+fn next(proto: &Prototype, pc: usize, looped: bool) -> Vec<(usize, bool)> {
+    successors(proto, pc)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|s| (s, looped || s <= pc))
+        .collect()
+}
+
+// pc处NEWTABLE创建的表r的构造器结束的位置，即最后一条向表中写入字段的指令之后
+pub fn ctor_end(proto: &Prototype, pc: usize, r: usize) -> usize {
+    let mut end = pc + 1;
+    for p in pc + 1..proto.code.len() {
+        if is_ctor(proto, p, r) {
+            end = p + 1;
+            if let Some(Decoded::SetList { block: None, .. }) = Decoded::decode(proto.code[p]) {
+                end += 1;
+            }
+            continue;
+        }
+        let (read, write) = touches(proto, p, r);
+        let back = successors(proto, p)
+            .unwrap_or_default()
+            .iter()
+            .any(|&s| s <= p);
+        if read != Reads::None || write || back {
+            break;
+        }
+    }
+    end
+}
@@ -0,0 +1,6 @@
+pub mod analysis;
+pub mod binary;
+pub mod compiler;
+pub mod decompiler;
+pub mod stdlib;
+pub mod vm;
@@ -1,161 +1,227 @@
-mod binary;
-mod vm;
+mod cli;
+
 use std::env;
 use std::fs;
-
-use binary::chunk::Constant;
-use vm::instruction::Instruction;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+
+use rs::analysis;
+use rs::analysis::cfg;
+use rs::analysis::stats::Stats;
+use rs::binary;
+use rs::binary::chunk::{Prototype, LUA_SIGNATURE};
+use rs::binary::listing::{FunctionListing, Listing};
+use rs::binary::strip;
+use rs::compiler;
+use rs::decompiler;
+use rs::stdlib;
+use rs::vm::closure::Closure;
+use rs::vm::error::LuaResult;
+use rs::vm::lua_state::LuaState;
+use rs::vm::lua_value::LuaValue;
+
+use cli::{Args, Command, Format, UsageError};
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() > 1 {
-        let data = fs::read(args[1].clone()).expect("cannot read file");
-        let proto = binary::undump(data);
-        list(&proto);
-    }
-}
+    let argv: Vec<String> = env::args().skip(1).collect();
+    let args = match cli::parse(&argv) {
+        Ok(args) => args,
+        Err(UsageError::Help) => {
+            print!("{}", cli::USAGE);
+            return;
+        }
+        // 兼容旧的用法，rs <file>列出字节码
+        Err(UsageError::UnknownCommand(name)) if Path::new(&name).is_file() => {
+            let argv: Vec<String> = ["list".to_string()].into_iter().chain(argv).collect();
+            cli::parse(&argv).unwrap_or_else(|err| usage_error(err))
+        }
+        Err(err) => usage_error(err),
+    };
 
-fn list(proto: &binary::chunk::Prototype) {
-    print_header(&proto);
-    print_code(&proto);
-    print_detail(&proto);
-    for p in proto.protos.iter() {
-        list(p);
+    let proto = load(&args.input);
+    match args.command {
+        Command::List => list(&args, &proto),
+        Command::Run => run(&args, proto),
+        Command::Compile => dump(&args, &proto, "luac.out"),
+        Command::Dump => dump(&args, &proto, "-"),
+        Command::Verify => {
+            let proto = select(&args, &proto);
+            check(&args.input, analysis::verify(proto));
+            let report = format!("{}: ok\n", args.input);
+            write_output(args.output.as_deref(), report.as_bytes());
+        }
+        Command::Stats => {
+            let stats = Stats::collect(select(&args, &proto), args.recurse);
+            let text = match args.format {
+                Format::Json => format!("{}\n", stats.to_json()),
+                _ => stats.to_string(),
+            };
+            write_output(args.output.as_deref(), text.as_bytes());
+        }
+        Command::Strip => {
+            let before = binary::dump(&proto).len();
+            let data = binary::dump(&strip::strip(&proto, args.keep_lines));
+            let output = args.output.as_deref().unwrap_or("luac.out");
+            write_output(Some(output), &data);
+            if output != "-" {
+                println!(
+                    "{}: {} -> {} bytes, {} bytes saved",
+                    output,
+                    before,
+                    data.len(),
+                    before - data.len()
+                );
+            }
+        }
+        Command::Decompile => {
+            let source = check(&args.input, decompiler::decompile(&proto));
+            write_output(args.output.as_deref(), source.as_bytes());
+        }
     }
 }
 
-fn print_header(proto: &binary::chunk::Prototype) {
-    let func_type = if proto.line_defined <= 0 {
-        "main"
-    } else {
-        "function"
-    };
-
-    let vararg_flag = if proto.is_vararg > 0 { "+" } else { "" };
-
-    println!(
-        "{func_type} <{}:{}, {}> ({} instructions)",
-        proto.source,
-        proto.line_defined,
-        proto.last_line_defined,
-        proto.code.len()
-    );
-
-    print!(
-        "{}{} params, {} slots, {} upvalues, ",
-        proto.num_params,
-        vararg_flag,
-        proto.max_stack_size,
-        proto.upvalues.len()
-    );
-    println!(
-        "{} locals, {} constants, {} functions",
-        proto.loc_vars.len(),
-        proto.constants.len(),
-        proto.protos.len()
-    );
+// 打印用法错误和用法说明，以状态码2退出
+fn usage_error(err: UsageError) -> ! {
+    eprintln!("rs: {}", err);
+    eprint!("{}", cli::USAGE);
+    process::exit(2);
 }
 
-fn print_code(proto: &binary::chunk::Prototype) {
-    for (i, c) in proto.code.iter().enumerate() {
-        let line = if proto.line_info.len() > 0 {
-            format!("{}", proto.line_info[i])
-        } else {
-            "-".to_string()
-        };
+// 出错时打印错误并以状态码1退出
+fn check<T, E: std::fmt::Display>(path: &str, result: Result<T, E>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{}: {}", path, err);
+        process::exit(1);
+    })
+}
 
-        let instruction = c.clone();
-        print!("\t{}\t[{}]\t{} \t", i + 1, line, instruction.op_name());
-        print_operands(instruction);
-        println!();
+// 写入输出文件，None或"-"表示标准输出
+fn write_output(output: Option<&str>, data: &[u8]) {
+    match output {
+        None | Some("-") => {
+            let mut stdout = io::stdout().lock();
+            check(
+                "stdout",
+                stdout.write_all(data).and_then(|_| stdout.flush()),
+            );
+        }
+        Some(path) => check(path, fs::write(path, data)),
     }
 }
 
-fn print_operands(instruction: u32) {
-    match instruction.op_mode() {
-        vm::opcode::OP_MODE_ABC => {
-            let (a, b, c) = instruction.abc();
-            print!("{}", a);
-            if instruction.b_arg_mode() != vm::opcode::OP_ARG_N {
-                if b > 0xFF {
-                    print!(" {}", -1 - (b & 0xFF));
-                } else {
-                    print!(" {}", b);
-                }
-            }
-
-            if instruction.c_arg_mode() != vm::opcode::OP_ARG_N {
-                if c > 0xFF {
-                    print!(" {}", -1 - (c & 0xFF));
-                } else {
-                    print!(" {}", c);
-                }
-            }
-        }
-        vm::opcode::OP_MODE_ABX => {
-            let (a, bx) = instruction.abx();
-            print!("{}", a);
-            if instruction.b_arg_mode() == vm::opcode::OP_ARG_K {
-                print!(" {}", -1 - bx);
-            } else if instruction.b_arg_mode() == vm::opcode::OP_ARG_U {
-                print!(" {}", bx)
-            }
-        }
-        vm::opcode::OP_MODE_ASBX => {
-            let (a, sbx) = instruction.asbx();
-            print!("{} {}", a, sbx);
-        }
-        vm::opcode::OP_MODE_AX => {
-            let ax = instruction.ax();
-            print!("{}", -1 - ax)
+// 按--function选中函数，函数按先序编号，0是主函数
+fn select<'a>(args: &Args, proto: &'a Prototype) -> &'a Prototype {
+    let Some(n) = args.function else {
+        return proto;
+    };
+    let mut functions = Vec::new();
+    let mut stack = vec![proto];
+    while let Some(p) = stack.pop() {
+        functions.push(p);
+        stack.extend(p.protos.iter().rev().map(Rc::as_ref));
+    }
+    match functions.get(n) {
+        Some(p) => p,
+        None => {
+            eprintln!(
+                "{}: no function {} (the chunk has {} functions)",
+                args.input,
+                n,
+                functions.len()
+            );
+            process::exit(1);
         }
-        _ => ()
     }
 }
 
-fn print_detail(proto: &binary::chunk::Prototype) {
-    println!("constants ({}):", proto.constants.len());
-    for (i, c) in proto.constants.iter().enumerate() {
-        println!("\t{}\t{}", i + 1, constant_to_string(c));
-    }
+fn list(args: &Args, proto: &Prototype) {
+    let proto = select(args, proto);
+    // 不包括子函数时，json和dot输出去掉子函数的副本
+    let mut single = proto.clone();
+    single.protos.clear();
+    let text = match (args.format, args.recurse) {
+        (Format::Text, true) => Listing(proto).to_string(),
+        (Format::Text, false) => FunctionListing(proto).to_string(),
+        (Format::Json, true) => format!("{}\n", binary::json::to_json(proto)),
+        (Format::Json, false) => format!("{}\n", binary::json::to_json(&single)),
+        (Format::Dot, true) => check(&args.input, cfg::to_dot(proto)),
+        (Format::Dot, false) => check(&args.input, cfg::to_dot(&single)),
+    };
+    write_output(args.output.as_deref(), text.as_bytes());
+}
 
-    println!("locals ({}):", proto.loc_vars.len());
-    for (i, l) in proto.loc_vars.iter().enumerate() {
-        println!(
-            "\t{}\t{}\t{}\t{}",
-            i,
-            l.var_name,
-            l.start_pc + 1,
-            l.end_pc + 1
-        );
-    }
+// 写出二进制chunk，default是没有-o时的输出文件
+fn dump(args: &Args, proto: &Prototype, default: &str) {
+    let data = match args.strip {
+        true => binary::dump(&strip::strip(proto, false)),
+        false => binary::dump(proto),
+    };
+    write_output(Some(args.output.as_deref().unwrap_or(default)), &data);
+}
 
-    println!("upvalues ({}):", proto.upvalues.len());
-    for (i, u) in proto.upvalues.iter().enumerate() {
-        println!(
-            "\t{}\t{}\t{}\t{}",
-            i,
-            upvalue_name(proto, i),
-            u.instack,
-            u.idx
-        );
+// 加载二进制chunk，.luasm文件作为字节码汇编，其他不是以chunk签名开头的文件作为Lua源码编译
+fn load(path: &str) -> Prototype {
+    let data = fs::read(path).unwrap_or_else(|err| {
+        eprintln!("cannot open {}: {}", path, err);
+        process::exit(1);
+    });
+    if path.ends_with(".luasm") {
+        let text = String::from_utf8_lossy(&data);
+        return check(path, binary::asm::assemble(&text));
+    }
+    if !data.starts_with(&LUA_SIGNATURE) {
+        return compiler::compile(&data, &format!("@{path}")).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
     }
+    check(path, binary::undump(data))
 }
 
-fn constant_to_string(constant: &Constant) -> String {
-    match constant {
-        Constant::Nil => "nil".to_string(),
-        Constant::Boolean(b) => b.to_string(),
-        Constant::Number(n) => n.to_string(),
-        Constant::Integer(i) => i.to_string(),
-        Constant::Str(s) => format!("\"{}\"", s),
+// 执行脚本，脚本参数既作为可变参数传入，也放在全局表arg中，arg[0]是脚本名
+fn run(args: &Args, proto: Prototype) {
+    let mut state = LuaState::new();
+    stdlib::open_libs(&mut state);
+    let script_args: Vec<LuaValue> = args
+        .script_args
+        .iter()
+        .map(|arg| LuaValue::from(arg.as_str()))
+        .collect();
+    let arg = LuaValue::new_table(script_args.len(), 1);
+    let names = std::iter::once(&args.input).chain(&args.script_args);
+    for (i, name) in names.enumerate() {
+        let key = LuaValue::Integer(i as i64);
+        check(
+            "lua",
+            state.set_index(&arg, key, LuaValue::from(name.as_str())),
+        );
+    }
+    state.set_global("arg", arg);
+    // load先校验，拒绝格式错误的字节码
+    let main = check(&args.input, state.load(proto));
+    let handler = LuaValue::Function(Rc::new(Closure::new_native("msghandler", msghandler)));
+    let result = state.pcall(main, script_args, Some(handler));
+    // 与lua相同，出错时也要关闭状态，调用__gc元方法
+    state.close();
+    if let Err(err) = result {
+        eprintln!("lua: {}", err);
+        process::exit(1);
     }
 }
 
-fn upvalue_name(proto: &binary::chunk::Prototype, index: usize) -> String {
-    if proto.upvalue_names.len() >= index {
-        proto.upvalue_names[index].clone()
-    } else {
-        "-".to_string()
-    }
+// 与lua.c的msghandler相同，在错误信息后附加调用栈回溯
+fn msghandler(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let msg = args.into_iter().next().unwrap_or_default();
+    let msg = match msg {
+        LuaValue::Str(s) => s.to_vec(),
+        LuaValue::Integer(_) | LuaValue::Number(_) => msg.to_string().into_bytes(),
+        _ if !state.metafield(&msg, "__tostring").is_nil() => {
+            return Ok(vec![state.tostring(&msg)?]);
+        }
+        _ => format!("(error object is a {} value)", msg.type_name()).into_bytes(),
+    };
+    let trace = state.traceback(1);
+    Ok(vec![LuaValue::from([msg, b"\n".to_vec(), trace.into_bytes()].concat())])
 }
@@ -0,0 +1,458 @@
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::stdlib::{arg, arg_error, check_any, check_integer, check_table, type_error};
+use crate::vm::closure::Closure;
+use crate::vm::error::{LuaError, LuaResult};
+use crate::vm::lua_state::LuaState;
+use crate::vm::lua_value::{parse_number, LuaValue};
+
+/// 基础库
+pub fn open(state: &mut LuaState) {
+    state.register("print", print);
+    state.register("type", lua_type);
+    state.register("tostring", tostring);
+    state.register("tonumber", tonumber);
+    state.register("ipairs", ipairs);
+    state.register("pairs", pairs);
+    state.register("next", next);
+    state.register("select", select);
+    state.register("assert", assert);
+    state.register("error", error);
+    state.register("pcall", pcall);
+    state.register("xpcall", xpcall);
+    state.register("rawequal", rawequal);
+    state.register("rawlen", rawlen);
+    state.register("rawget", rawget);
+    state.register("rawset", rawset);
+    state.register("setmetatable", setmetatable);
+    state.register("getmetatable", getmetatable);
+    state.register("collectgarbage", collectgarbage);
+    let globals = state.globals();
+    state.set_global("_G", globals);
+    state.set_global("_VERSION", LuaValue::from("Lua 5.3"));
+}
+
+// print (···)，字符串按原始字节输出
+fn print(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let mut line = Vec::new();
+    for (i, v) in args.iter().enumerate() {
+        if i > 0 {
+            line.push(b'\t');
+        }
+        match state.tostring(v)? {
+            LuaValue::Str(s) => line.extend_from_slice(&s),
+            v => line.extend_from_slice(v.to_string().as_bytes()),
+        }
+    }
+    line.push(b'\n');
+    let mut stdout = io::stdout().lock();
+    stdout
+        .write_all(&line)
+        .map_err(|err| LuaError::runtime(err.to_string()))?;
+    Ok(vec![])
+}
+
+// type (v)
+fn lua_type(_state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let v = check_any(&args, 0, "type")?;
+    Ok(vec![LuaValue::from(v.type_name())])
+}
+
+// tostring (v)
+fn tostring(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let v = check_any(&args, 0, "tostring")?;
+    Ok(vec![state.tostring(&v)?])
+}
+
+// tonumber (e [, base])
+fn tonumber(_state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    if matches!(args.get(1), None | Some(LuaValue::Nil)) {
+        let v = check_any(&args, 0, "tonumber")?;
+        let n = match &v {
+            LuaValue::Integer(_) | LuaValue::Number(_) => v.clone(),
+            LuaValue::Str(s) => s.to_str().and_then(parse_number).unwrap_or_default(),
+            _ => LuaValue::Nil,
+        };
+        return Ok(vec![n]);
+    }
+
+    let base = check_integer(&args, 1, "tonumber")?;
+    let LuaValue::Str(s) = arg(&args, 0) else {
+        return Err(arg_error(0, "tonumber", "string expected"));
+    };
+    if !(2..=36).contains(&base) {
+        return Err(arg_error(1, "tonumber", "base out of range"));
+    }
+    let Some(s) = s.to_str() else {
+        return Ok(vec![LuaValue::Nil]);
+    };
+
+    let s = s.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    if digits.is_empty() {
+        return Ok(vec![LuaValue::Nil]);
+    }
+    let mut n: i64 = 0;
+    for c in digits.chars() {
+        match c.to_digit(base as u32) {
+            Some(d) => n = n.wrapping_mul(base).wrapping_add(d as i64),
+            None => return Ok(vec![LuaValue::Nil]),
+        }
+    }
+    Ok(vec![LuaValue::Integer(if neg { n.wrapping_neg() } else { n })])
+}
+
+// ipairs (t)
+fn ipairs(_state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let t = check_any(&args, 0, "ipairs")?;
+    let aux = LuaValue::Function(Rc::new(Closure::new_native("ipairs_aux", ipairs_aux)));
+    Ok(vec![aux, t, LuaValue::Integer(0)])
+}
+
+fn ipairs_aux(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let i = check_integer(&args, 1, "ipairs_aux")?.wrapping_add(1);
+    let v = state.index(&arg(&args, 0), &LuaValue::Integer(i))?;
+    if v.is_nil() {
+        Ok(vec![LuaValue::Nil])
+    } else {
+        Ok(vec![LuaValue::Integer(i), v])
+    }
+}
+
+// pairs (t)，有__pairs元方法时返回它的前三个返回值
+fn pairs(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let t = check_any(&args, 0, "pairs")?;
+    let tm = state.metafield(&t, "__pairs");
+    if !tm.is_nil() {
+        let mut results = state.call(tm, vec![t])?;
+        results.resize(3, LuaValue::Nil);
+        return Ok(results);
+    }
+    let t = check_table(&args, 0, "pairs")?;
+    let next = state.get_global("next");
+    let next = if next.is_nil() {
+        LuaValue::Function(Rc::new(Closure::new_native("next", self::next)))
+    } else {
+        next
+    };
+    Ok(vec![next, t, LuaValue::Nil])
+}
+
+// next (table [, index])
+fn next(_state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let LuaValue::Table(t) = check_table(&args, 0, "next")? else {
+        unreachable!()
+    };
+    let result = t.borrow().next(&arg(&args, 1));
+    match result.map_err(LuaError::runtime)? {
+        Some((k, v)) => Ok(vec![k, v]),
+        None => Ok(vec![LuaValue::Nil]),
+    }
+}
+
+// select (index, ···)
+fn select(_state: &mut LuaState, mut args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let n = args.len() as i64 - 1;
+    if let Some(LuaValue::Str(s)) = args.first() {
+        if s.as_bytes() == b"#" {
+            return Ok(vec![LuaValue::Integer(n)]);
+        }
+    }
+    let i = check_integer(&args, 0, "select")?;
+    let start = if i < 0 {
+        if -i > n {
+            return Err(arg_error(0, "select", "index out of range"));
+        }
+        n + i
+    } else if i == 0 {
+        return Err(arg_error(0, "select", "index out of range"));
+    } else {
+        (i - 1).min(n)
+    };
+    Ok(args.split_off(start as usize + 1))
+}
+
+// assert (v [, message])，message原样作为错误对象
+fn assert(_state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let v = check_any(&args, 0, "assert")?;
+    if v.to_boolean() {
+        return Ok(args);
+    }
+    match args.get(1) {
+        Some(msg) => Err(LuaError::Object(msg.clone())),
+        None => Err(LuaError::runtime("assertion failed!")),
+    }
+}
+
+// error (message [, level])，字符串信息加上第level层函数的位置，level为0时不加
+fn error(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let level = match arg(&args, 1) {
+        LuaValue::Nil => 1,
+        _ => check_integer(&args, 1, "error")?,
+    };
+    let msg = match arg(&args, 0) {
+        LuaValue::Str(s) if level > 0 => {
+            let location = state.location(level as usize);
+            LuaValue::from([location.as_bytes(), &s].concat())
+        }
+        msg => msg,
+    };
+    Err(LuaError::Object(msg))
+}
+
+// pcall (f [, arg1, ···])
+fn pcall(state: &mut LuaState, mut args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let f = check_any(&args, 0, "pcall")?;
+    args.remove(0);
+    protected_results(state.pcall(f, args, None))
+}
+
+// xpcall (f, msgh [, arg1, ···])
+fn xpcall(state: &mut LuaState, mut args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let handler = match args.get(1) {
+        Some(h @ LuaValue::Function(_)) => h.clone(),
+        v => return Err(type_error(v, 1, "xpcall", "function")),
+    };
+    let f = args.remove(0);
+    args.remove(0);
+    protected_results(state.pcall(f, args, Some(handler)))
+}
+
+// 保护调用成功时返回true和所有返回值，失败时返回false和错误对象
+fn protected_results(result: LuaResult<Vec<LuaValue>>) -> LuaResult<Vec<LuaValue>> {
+    match result {
+        Ok(mut results) => {
+            results.insert(0, LuaValue::Boolean(true));
+            Ok(results)
+        }
+        Err(err) => Ok(vec![LuaValue::Boolean(false), err.value()]),
+    }
+}
+
+// rawequal (v1, v2)
+fn rawequal(_state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let a = check_any(&args, 0, "rawequal")?;
+    let b = check_any(&args, 1, "rawequal")?;
+    Ok(vec![LuaValue::Boolean(a.raw_equals(&b))])
+}
+
+// rawlen (v)
+fn rawlen(_state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    match arg(&args, 0) {
+        LuaValue::Table(t) => Ok(vec![LuaValue::Integer(t.borrow().len() as i64)]),
+        LuaValue::Str(s) => Ok(vec![LuaValue::Integer(s.len() as i64)]),
+        _ => Err(arg_error(0, "rawlen", "table or string expected")),
+    }
+}
+
+// rawget (table, index)
+fn rawget(_state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let LuaValue::Table(t) = check_table(&args, 0, "rawget")? else {
+        unreachable!()
+    };
+    let k = check_any(&args, 1, "rawget")?;
+    let v = t.borrow().get(&k);
+    Ok(vec![v])
+}
+
+// rawset (table, index, value)
+fn rawset(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let t = check_table(&args, 0, "rawset")?;
+    let k = check_any(&args, 1, "rawset")?;
+    let v = check_any(&args, 2, "rawset")?;
+    if let LuaValue::Table(tbl) = &t {
+        tbl.borrow_mut().put(k, v).map_err(LuaError::runtime)?;
+        state.table_barrier(tbl);
+    }
+    Ok(vec![t])
+}
+
+// setmetatable (table, metatable)
+fn setmetatable(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let t = check_table(&args, 0, "setmetatable")?;
+    // 与Lua相同，第二个参数不能省略
+    let mt = match args.get(1) {
+        Some(LuaValue::Nil) => None,
+        Some(LuaValue::Table(mt)) => Some(mt.clone()),
+        _ => return Err(arg_error(1, "setmetatable", "nil or table expected")),
+    };
+    if !state.metafield(&t, "__metatable").is_nil() {
+        return Err(LuaError::runtime("cannot change a protected metatable"));
+    }
+    state.set_metatable(&t, mt);
+    Ok(vec![t])
+}
+
+// getmetatable (object)，元表有__metatable字段时返回该字段的值
+fn getmetatable(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let v = check_any(&args, 0, "getmetatable")?;
+    let Some(mt) = state.get_metatable(&v) else {
+        return Ok(vec![LuaValue::Nil]);
+    };
+    let protected = mt.borrow().get(&LuaValue::from("__metatable"));
+    if !protected.is_nil() {
+        return Ok(vec![protected]);
+    }
+    Ok(vec![LuaValue::Table(mt)])
+}
+
+// collectgarbage ([opt [, arg]])
+fn collectgarbage(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let opt = match arg(&args, 0) {
+        LuaValue::Nil => "collect".to_string(),
+        LuaValue::Str(s) => s.to_str_lossy().into_owned(),
+        // 与luaL_checkoption相同，数字转换为字符串
+        v @ (LuaValue::Integer(_) | LuaValue::Number(_)) => v.to_string(),
+        v => return Err(type_error(Some(&v), 0, "collectgarbage", "string")),
+    };
+    let n = match arg(&args, 1) {
+        LuaValue::Nil => 0,
+        _ => check_integer(&args, 1, "collectgarbage")?.max(0) as usize,
+    };
+    let result = match opt.as_str() {
+        "collect" => {
+            state.collect_garbage()?;
+            LuaValue::Integer(0)
+        }
+        "stop" => {
+            state.set_gc_running(false);
+            LuaValue::Integer(0)
+        }
+        "restart" => {
+            state.set_gc_running(true);
+            LuaValue::Integer(0)
+        }
+        "count" => LuaValue::Number(state.memory_used() as f64 / 1024.0),
+        "step" => LuaValue::Boolean(state.gc_step(n)?),
+        "setpause" => LuaValue::Integer(state.set_gc_pause(n) as i64),
+        "setstepmul" => LuaValue::Integer(state.set_gc_step_mul(n) as i64),
+        "isrunning" => LuaValue::Boolean(state.is_gc_running()),
+        _ => return Err(arg_error(0, "collectgarbage", &format!("invalid option '{opt}'"))),
+    };
+    Ok(vec![result])
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compiler::compile;
+    use crate::stdlib;
+    use crate::vm::lua_state::LuaState;
+    use crate::vm::lua_value::LuaValue;
+
+    fn run(src: &str) -> Vec<LuaValue> {
+        let mut state = LuaState::new();
+        stdlib::open_libs(&mut state);
+        let main = state.load(compile(src.as_bytes(), "=test").unwrap()).unwrap();
+        state.call(main, Vec::new()).unwrap()
+    }
+
+    fn strings(values: Vec<LuaValue>) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_metatable_errors() {
+        let src = r#"
+            local p = setmetatable({}, {__metatable = "locked"})
+            local _, a = pcall(setmetatable, 1, {})
+            local _, b = pcall(setmetatable, {}, 1)
+            local _, c = pcall(setmetatable, {})
+            local _, d = pcall(setmetatable, p, {})
+            local _, e = pcall(rawset, 1, 1, 1)
+            local _, f = pcall(rawset, {}, nil, 1)
+            local _, g = pcall(rawget, "x", 1)
+            local _, h = pcall(rawlen, 1)
+            local _, i = pcall(rawequal, 1)
+            return getmetatable(p), a, b, c, d, e, f, g, h, i
+        "#;
+        let expected = vec![
+            "locked",
+            "bad argument #1 to 'setmetatable' (table expected, got number)",
+            "bad argument #2 to 'setmetatable' (nil or table expected)",
+            "bad argument #2 to 'setmetatable' (nil or table expected)",
+            "cannot change a protected metatable",
+            "bad argument #1 to 'rawset' (table expected, got number)",
+            "table index is nil",
+            "bad argument #1 to 'rawget' (table expected, got string)",
+            "bad argument #1 to 'rawlen' (table or string expected)",
+            "bad argument #2 to 'rawequal' (value expected)",
+        ];
+        assert_eq!(strings(run(src)), expected);
+    }
+
+    #[test]
+    fn test_error_levels() {
+        // level 1是调用error的函数，level 2是它的调用者
+        // 该层是Rust函数(这里是pcall)、level 0或消息不是字符串时不加位置
+        let src = r#"
+            local function lv3() error("deep", 3) end
+            local function mid() lv3() end
+            local function top() mid() end
+            function mid2() error("lvl2", 2) end
+            local _, a = pcall(error, "lvl1", 1)
+            local _, b = pcall(function() mid2() end)
+            local _, c = pcall(top)
+            local _, d = pcall(function() error("none", 0) end)
+            local _, e = pcall(function() error(42) end)
+            return a, b, c, d, e
+        "#;
+        assert_eq!(strings(run(src)), vec!["lvl1", "test:7: lvl2", "test:4: deep", "none", "42"]);
+    }
+
+    #[test]
+    fn test_error_objects() {
+        // 任意类型的错误对象都原样传给pcall的调用者
+        let src = r##"
+            local t = {code = 7}
+            local ok1, e1 = pcall(error, t)
+            local ok2, e2 = pcall(error)
+            local n = select("#", pcall(error))
+            return ok1, e1 == t, ok2, e2, n
+        "##;
+        use LuaValue::{Boolean, Integer, Nil};
+        assert_eq!(run(src), vec![Boolean(false), Boolean(true), Boolean(false), Nil, Integer(2)]);
+    }
+
+    #[test]
+    fn test_pcall_errors() {
+        let src = r#"
+            local _, a = pcall(pcall)
+            local _, b = pcall(xpcall, print)
+            local _, c = pcall(1)
+            local _, d = pcall(function() local t = nil; return t.x end)
+            local ok, e = pcall(setmetatable({}, {__call = function(_, x) return x * 2 end}), 21)
+            return a, b, c, d, ok, e
+        "#;
+        let expected = vec![
+            "bad argument #1 to 'pcall' (value expected)",
+            "bad argument #2 to 'xpcall' (function expected, got no value)",
+            "attempt to call a number value",
+            "test:5: attempt to index a nil value",
+            "true",
+            "42",
+        ];
+        assert_eq!(strings(run(src)), expected);
+    }
+
+    #[test]
+    fn test_xpcall_handler() {
+        // 消息处理函数只有第一个返回值作为错误对象
+        let src = r#"
+            local r1 = {xpcall(error, function(m) return m .. "!", 2 end, "x")}
+            local r2 = {xpcall(function() return 1, 2 end, print)}
+            local ok, tb = xpcall(function() error("boom") end, debug.traceback)
+            return #r1, r1[2], #r2, r2[3], ok, tb
+        "#;
+        let expected = "test:4: boom\nstack traceback:\n\
+            \t[C]: in function 'error'\n\
+            \ttest:4: in function <test:4>\n\
+            \t[C]: in function 'xpcall'\n\
+            \ttest:4: in main chunk\n\
+            \t[C]: in ?";
+        assert_eq!(strings(run(src)), vec!["2", "x!", "3", "2", "false", expected]);
+    }
+}
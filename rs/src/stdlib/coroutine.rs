@@ -0,0 +1,221 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::stdlib::{arg_error, type_error};
+use crate::vm::closure::{Closure, NativeFn};
+use crate::vm::error::{LuaError, LuaResult};
+use crate::vm::lua_state::LuaState;
+use crate::vm::lua_value::LuaValue;
+
+/// 协程库
+pub fn open(state: &mut LuaState) {
+    let funcs: [(&'static str, NativeFn); 7] = [
+        ("create", create),
+        ("resume", resume),
+        ("yield", lua_yield),
+        ("status", status),
+        ("wrap", wrap),
+        ("isyieldable", isyieldable),
+        ("running", running),
+    ];
+    let lib = LuaValue::new_table(0, funcs.len());
+    for (name, func) in funcs {
+        let f = LuaValue::Function(Rc::new(Closure::new_native(name, func)));
+        state.set_index(&lib, LuaValue::from(name), f).unwrap();
+    }
+    state.set_global("coroutine", lib);
+}
+
+// 与Lua相同，参数错误中的函数名带上库名，如'coroutine.create'
+fn check_function(args: &[LuaValue], n: usize, fname: &str) -> LuaResult<LuaValue> {
+    match args.get(n) {
+        Some(f @ LuaValue::Function(_)) => Ok(f.clone()),
+        v => Err(type_error(v, n, fname, "function")),
+    }
+}
+
+fn check_thread(args: &[LuaValue], n: usize, fname: &str) -> LuaResult<Rc<RefCell<LuaState>>> {
+    match args.get(n) {
+        Some(LuaValue::Thread(co)) => Ok(co.clone()),
+        _ => Err(arg_error(n, fname, "thread expected")),
+    }
+}
+
+// coroutine.create (f)
+fn create(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let f = check_function(&args, 0, "coroutine.create")?;
+    Ok(vec![state.new_thread(f)])
+}
+
+// coroutine.resume (co [, val1, ···])，出错时返回false和错误信息
+fn resume(state: &mut LuaState, mut args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let co = check_thread(&args, 0, "coroutine.resume")?;
+    args.remove(0);
+    match state.resume(&co, args) {
+        Ok(mut results) => {
+            results.insert(0, LuaValue::Boolean(true));
+            Ok(results)
+        }
+        Err(err) => Ok(vec![LuaValue::Boolean(false), err.value()]),
+    }
+}
+
+// coroutine.yield (···)，让出的值沿调用链返回到resume
+fn lua_yield(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    if !state.is_yieldable() {
+        // 和Lua一样，这个错误在yield内部抛出，不带出错位置
+        let (_, is_main) = state.running();
+        return Err(LuaError::Object(LuaValue::from(if is_main {
+            "attempt to yield from outside a coroutine"
+        } else {
+            "attempt to yield across a C-call boundary"
+        })));
+    }
+    Err(LuaError::Yield(args))
+}
+
+// coroutine.status (co)
+fn status(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let co = check_thread(&args, 0, "coroutine.status")?;
+    Ok(vec![LuaValue::from(state.thread_status(&co))])
+}
+
+// coroutine.wrap (f)，返回的函数每次调用都恢复协程，协程中的错误传播给调用者
+// 与Lua相同，字符串错误信息再加上调用者的位置
+fn wrap(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let f = check_function(&args, 0, "coroutine.wrap")?;
+    let co = state.new_thread(f);
+    Ok(vec![state.new_native_closure("wrap", wrap_aux, vec![co])])
+}
+
+fn wrap_aux(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let LuaValue::Thread(co) = state.native_upvalue(0) else {
+        unreachable!()
+    };
+    state.resume(&co, args).map_err(|err| match err.value() {
+        LuaValue::Str(msg) => {
+            let location = state.location(1);
+            LuaError::Object(LuaValue::from([location.as_bytes(), &msg].concat()))
+        }
+        v => LuaError::Object(v),
+    })
+}
+
+// coroutine.isyieldable ()
+fn isyieldable(state: &mut LuaState, _args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    Ok(vec![LuaValue::Boolean(state.is_yieldable())])
+}
+
+// coroutine.running ()，返回当前线程和它是否为主线程
+fn running(state: &mut LuaState, _args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let (thread, is_main) = state.running();
+    Ok(vec![thread, LuaValue::Boolean(is_main)])
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::compiler::compile;
+    use crate::stdlib;
+    use crate::vm::lua_state::LuaState;
+    use crate::vm::lua_value::LuaValue;
+
+    fn run(src: &str) -> Vec<String> {
+        let mut state = LuaState::new();
+        stdlib::open_libs(&mut state);
+        let main = state.load(compile(src.as_bytes(), "=test").unwrap()).unwrap();
+        let results = state.call(main, Vec::new()).unwrap();
+        results.iter().map(LuaValue::to_string).collect()
+    }
+
+    #[test]
+    fn test_resume_errors() {
+        let src = r#"
+            local co = coroutine.create(function() return 1 end)
+            coroutine.resume(co)
+            local _, dead = coroutine.resume(co)
+            local this
+            this = coroutine.create(function() return coroutine.resume(this) end)
+            local _, _, running = coroutine.resume(this)
+            local outer
+            outer = coroutine.create(function()
+                local inner = coroutine.create(function() return coroutine.resume(outer) end)
+                return coroutine.resume(inner)
+            end)
+            local _, _, _, normal = coroutine.resume(outer)
+            local w = coroutine.wrap(function() return 1 end)
+            w()
+            local _, wrapped = pcall(w)
+            return dead, running, normal, wrapped, coroutine.status(co)
+        "#;
+        let expected = vec![
+            "cannot resume dead coroutine",
+            "cannot resume non-suspended coroutine",
+            "cannot resume non-suspended coroutine",
+            "cannot resume dead coroutine",
+            "dead",
+        ];
+        assert_eq!(run(src), expected);
+    }
+
+    #[test]
+    fn test_error_propagation() {
+        // 协程中的错误由resume返回，wrap的函数把错误传播给调用者
+        let src = r#"
+            local co = coroutine.create(function() local x = coroutine.yield() error("oops") end)
+            coroutine.resume(co)
+            local ok, err = coroutine.resume(co)
+            local t = {}
+            local _, obj = coroutine.resume(coroutine.create(function() error(t) end))
+            local it = coroutine.wrap(function()
+                for k in function() error("in iterator") end do coroutine.yield(k) end
+            end)
+            local _, wrapped = pcall(it)
+            return ok, err, coroutine.status(co), obj == t, wrapped
+        "#;
+        let expected = vec!["false", "test:2: oops", "dead", "true", "test:8: in iterator"];
+        assert_eq!(run(src), expected);
+    }
+
+    #[test]
+    fn test_yield_errors() {
+        // pcall是不能让出的Rust调用，让出失败时协程仍然可以继续执行
+        let src = r#"
+            local _, main = pcall(coroutine.yield, 1)
+            local co = coroutine.create(function()
+                local ok, err = pcall(coroutine.yield, 1)
+                coroutine.yield(ok, err)
+                return "end"
+            end)
+            local _, ok, err = coroutine.resume(co)
+            local _, last = coroutine.resume(co)
+            return main, ok, err, last, coroutine.isyieldable()
+        "#;
+        let expected = vec![
+            "attempt to yield from outside a coroutine",
+            "false",
+            "attempt to yield across a C-call boundary",
+            "end",
+            "false",
+        ];
+        assert_eq!(run(src), expected);
+    }
+
+    #[test]
+    fn test_bad_arguments() {
+        let src = r#"
+            local _, a = pcall(coroutine.resume, 1)
+            local _, b = pcall(coroutine.create, 1)
+            local _, c = pcall(coroutine.status, {})
+            local _, d = pcall(coroutine.wrap)
+            return a, b, c, d
+        "#;
+        let expected = vec![
+            "bad argument #1 to 'coroutine.resume' (thread expected)",
+            "bad argument #1 to 'coroutine.create' (function expected, got number)",
+            "bad argument #1 to 'coroutine.status' (thread expected)",
+            "bad argument #1 to 'coroutine.wrap' (function expected, got no value)",
+        ];
+        assert_eq!(run(src), expected);
+    }
+}
@@ -0,0 +1,130 @@
+use std::rc::Rc;
+
+use crate::stdlib::check_integer;
+use crate::vm::closure::{Closure, NativeFn};
+use crate::vm::error::LuaResult;
+use crate::vm::lua_state::LuaState;
+use crate::vm::lua_value::LuaValue;
+
+/// 调试库，目前只有traceback
+pub fn open(state: &mut LuaState) {
+    let funcs: [(&'static str, NativeFn); 1] = [("traceback", traceback)];
+    let lib = LuaValue::new_table(0, funcs.len());
+    for (name, func) in funcs {
+        let f = LuaValue::Function(Rc::new(Closure::new_native(name, func)));
+        state.set_index(&lib, LuaValue::from(name), f).unwrap();
+    }
+    state.set_global("debug", lib);
+}
+
+// debug.traceback ([thread,] [message [, level]])
+// message不是字符串、数字或nil时原样返回；当前线程的level默认为1，即调用traceback的函数
+fn traceback(state: &mut LuaState, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let thread = match args.first() {
+        Some(LuaValue::Thread(co)) => Some(co.clone()),
+        _ => None,
+    };
+    // 与Lua相同，参数的序号包括线程参数
+    let arg = usize::from(thread.is_some());
+    // 数字和字符串一样转换为消息
+    let msg = match args.get(arg).cloned().unwrap_or_default() {
+        v @ (LuaValue::Integer(_) | LuaValue::Number(_)) => LuaValue::from(v.to_string()),
+        v @ (LuaValue::Nil | LuaValue::Str(_)) => v,
+        v => return Ok(vec![v]),
+    };
+    let level = match args.get(arg + 1) {
+        None | Some(LuaValue::Nil) => None,
+        Some(_) => Some(check_integer(&args, arg + 1, "debug.traceback")?.max(0) as usize),
+    };
+    let trace = match &thread {
+        // 正在运行的协程已被可变借用，它在当前线程的调用链上，没有可显示的栈
+        Some(co) if !std::ptr::eq(co.as_ptr(), state) => match co.try_borrow() {
+            Ok(co) => co.traceback(level.unwrap_or(0)),
+            Err(_) => "stack traceback:".to_string(),
+        },
+        _ => state.traceback(level.unwrap_or(1)),
+    };
+    let mut result = Vec::new();
+    if let LuaValue::Str(msg) = &msg {
+        result.extend_from_slice(msg);
+        result.push(b'\n');
+    }
+    result.extend_from_slice(trace.as_bytes());
+    Ok(vec![LuaValue::from(result)])
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compiler::compile;
+    use crate::stdlib;
+    use crate::vm::lua_state::LuaState;
+    use crate::vm::lua_value::LuaValue;
+
+    fn run(src: &str) -> Vec<LuaValue> {
+        let mut state = LuaState::new();
+        stdlib::open_libs(&mut state);
+        let main = state.load(compile(src.as_bytes(), "=test").unwrap()).unwrap();
+        state.call(main, Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_traceback() {
+        // 主线程最底层是调用主函数的宿主程序
+        let results = run("local t = {}\nreturn debug.traceback(12), debug.traceback(t) == t");
+        let expected = "12\nstack traceback:\n\ttest:2: in main chunk\n\t[C]: in ?";
+        assert_eq!(results, vec![LuaValue::from(expected), LuaValue::Boolean(true)]);
+
+        let results = run("return debug.traceback(nil, 0), debug.traceback('m', 5)");
+        let expected = "stack traceback:\n\
+            \t[C]: in function 'debug.traceback'\n\
+            \ttest:1: in main chunk\n\
+            \t[C]: in ?";
+        assert_eq!(results, vec![LuaValue::from(expected), LuaValue::from("m\nstack traceback:")]);
+
+        let src = r#"
+            local _, a = pcall(debug.traceback, "m", "x")
+            local _, b = pcall(debug.traceback, coroutine.create(print), "m", {})
+            return a, b
+        "#;
+        let expected = vec![
+            LuaValue::from("bad argument #2 to 'debug.traceback' (number expected, got string)"),
+            LuaValue::from("bad argument #3 to 'debug.traceback' (number expected, got table)"),
+        ];
+        assert_eq!(run(src), expected);
+
+        // 层数很多时省略中间的部分
+        let results = run(
+            "local function deep(n) if n == 0 then return debug.traceback() end \
+             return (deep(n - 1)) end\nlocal s = deep(25)\nreturn s",
+        );
+        let trace = results[0].to_string();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 1 + 10 + 1 + 11);
+        assert_eq!(lines[1], "\ttest:1: in upvalue 'deep'");
+        assert_eq!(lines[11], "\t...");
+        let bottom = ["\ttest:1: in local 'deep'", "\ttest:2: in main chunk", "\t[C]: in ?"];
+        assert_eq!(lines[20..], bottom);
+    }
+
+    #[test]
+    fn test_coroutine_traceback() {
+        // 让出的协程停在coroutine.yield中，出错结束的协程保留出错时的调用栈
+        let results = run(
+            "local co = coroutine.create(function() coroutine.yield() end)\n\
+             coroutine.resume(co)\n\
+             local dead = coroutine.create(function() error('oops') end)\n\
+             coroutine.resume(dead)\n\
+             return debug.traceback(co), debug.traceback(co, 'x', 1), debug.traceback(dead)",
+        );
+        let expected = [
+            "stack traceback:\n\
+             \t[C]: in function 'coroutine.yield'\n\
+             \ttest:1: in function <test:1>",
+            "x\nstack traceback:\n\ttest:1: in function <test:1>",
+            "stack traceback:\n\
+             \t[C]: in function 'error'\n\
+             \ttest:3: in function <test:3>",
+        ];
+        assert_eq!(results, expected.map(LuaValue::from).to_vec());
+    }
+}
@@ -0,0 +1,58 @@
+use crate::vm::error::{LuaError, LuaResult};
+use crate::vm::lua_state::LuaState;
+use crate::vm::lua_value::LuaValue;
+
+pub mod base;
+pub mod coroutine;
+pub mod debug;
+
+/// 打开所有标准库
+pub fn open_libs(state: &mut LuaState) {
+    base::open(state);
+    coroutine::open(state);
+    debug::open(state);
+}
+
+// 获取第n个参数(从0开始)，不存在时返回nil
+pub(crate) fn arg(args: &[LuaValue], n: usize) -> LuaValue {
+    args.get(n).cloned().unwrap_or_default()
+}
+
+// 第n个参数必须存在
+pub(crate) fn check_any(args: &[LuaValue], n: usize, fname: &str) -> LuaResult<LuaValue> {
+    args.get(n)
+        .cloned()
+        .ok_or_else(|| arg_error(n, fname, "value expected"))
+}
+
+pub(crate) fn check_table(args: &[LuaValue], n: usize, fname: &str) -> LuaResult<LuaValue> {
+    match args.get(n) {
+        Some(v @ LuaValue::Table(_)) => Ok(v.clone()),
+        v => Err(type_error(v, n, fname, "table")),
+    }
+}
+
+pub(crate) fn check_integer(args: &[LuaValue], n: usize, fname: &str) -> LuaResult<i64> {
+    match args.get(n) {
+        Some(v) => v.to_integer().ok_or_else(|| {
+            if v.to_number().is_some() {
+                arg_error(n, fname, "number has no integer representation")
+            } else {
+                type_error(Some(v), n, fname, "number")
+            }
+        }),
+        None => Err(type_error(None, n, fname, "number")),
+    }
+}
+
+pub(crate) fn arg_error(n: usize, fname: &str, msg: &str) -> LuaError {
+    LuaError::runtime(format!("bad argument #{} to '{}' ({})", n + 1, fname, msg))
+}
+
+pub(crate) fn type_error(v: Option<&LuaValue>, n: usize, fname: &str, expected: &str) -> LuaError {
+    let got = match v {
+        Some(v) => v.type_name(),
+        None => "no value",
+    };
+    arg_error(n, fname, &format!("{expected} expected, got {got}"))
+}
@@ -0,0 +1,301 @@
+use crate::vm::error::{LuaError, LuaResult};
+use crate::vm::lua_value::LuaValue;
+
+/// 算术和按位运算符，顺序与OP_ADD ~ OP_BNOT操作码一致
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Unm,
+    BNot,
+}
+
+const ARITH_OPS: [ArithOp; 14] = [
+    ArithOp::Add,
+    ArithOp::Sub,
+    ArithOp::Mul,
+    ArithOp::Mod,
+    ArithOp::Pow,
+    ArithOp::Div,
+    ArithOp::IDiv,
+    ArithOp::BAnd,
+    ArithOp::BOr,
+    ArithOp::BXor,
+    ArithOp::Shl,
+    ArithOp::Shr,
+    ArithOp::Unm,
+    ArithOp::BNot,
+];
+
+impl ArithOp {
+    // 根据与OP_ADD的偏移量获取运算符
+    pub fn from_offset(offset: u8) -> ArithOp {
+        ARITH_OPS[offset as usize]
+    }
+
+    pub fn is_bitwise(self) -> bool {
+        matches!(
+            self,
+            ArithOp::BAnd | ArithOp::BOr | ArithOp::BXor | ArithOp::Shl | ArithOp::Shr | ArithOp::BNot
+        )
+    }
+
+    // 操作数不是数字时查找的元方法
+    pub fn event(self) -> &'static str {
+        match self {
+            ArithOp::Add => "__add",
+            ArithOp::Sub => "__sub",
+            ArithOp::Mul => "__mul",
+            ArithOp::Mod => "__mod",
+            ArithOp::Pow => "__pow",
+            ArithOp::Div => "__div",
+            ArithOp::IDiv => "__idiv",
+            ArithOp::BAnd => "__band",
+            ArithOp::BOr => "__bor",
+            ArithOp::BXor => "__bxor",
+            ArithOp::Shl => "__shl",
+            ArithOp::Shr => "__shr",
+            ArithOp::Unm => "__unm",
+            ArithOp::BNot => "__bnot",
+        }
+    }
+}
+
+/// 执行算术运算，操作数无法转换为数字(按位运算要求整数)时返回None
+/// 整数除零和整数取模零会返回错误
+pub fn arith(op: ArithOp, a: &LuaValue, b: &LuaValue) -> LuaResult<Option<LuaValue>> {
+    if op.is_bitwise() {
+        let (Some(x), Some(y)) = (a.to_integer(), b.to_integer()) else {
+            return Ok(None);
+        };
+        let result = match op {
+            ArithOp::BAnd => x & y,
+            ArithOp::BOr => x | y,
+            ArithOp::BXor => x ^ y,
+            ArithOp::Shl => shift_left(x, y),
+            ArithOp::Shr => shift_left(x, y.wrapping_neg()),
+            ArithOp::BNot => !x,
+            _ => unreachable!(),
+        };
+        return Ok(Some(LuaValue::Integer(result)));
+    }
+
+    let (Some(x), Some(y)) = (a.to_arith(), b.to_arith()) else {
+        return Ok(None);
+    };
+
+    // 除法和乘方总是使用浮点数运算
+    if let (LuaValue::Integer(x), LuaValue::Integer(y)) = (&x, &y) {
+        let (x, y) = (*x, *y);
+        let result = match op {
+            ArithOp::Add => Some(x.wrapping_add(y)),
+            ArithOp::Sub => Some(x.wrapping_sub(y)),
+            ArithOp::Mul => Some(x.wrapping_mul(y)),
+            ArithOp::Mod => {
+                if y == 0 {
+                    return Err(LuaError::runtime("attempt to perform 'n%0'"));
+                }
+                Some(int_mod(x, y))
+            }
+            ArithOp::IDiv => {
+                // 与Lua 5.3相同，整除和取模的错误信息不同
+                if y == 0 {
+                    return Err(LuaError::runtime("attempt to divide by zero"));
+                }
+                Some(int_floor_div(x, y))
+            }
+            ArithOp::Unm => Some(x.wrapping_neg()),
+            _ => None,
+        };
+        if let Some(result) = result {
+            return Ok(Some(LuaValue::Integer(result)));
+        }
+    }
+
+    let (x, y) = (x.to_number().unwrap(), y.to_number().unwrap());
+    let result = match op {
+        ArithOp::Add => x + y,
+        ArithOp::Sub => x - y,
+        ArithOp::Mul => x * y,
+        ArithOp::Mod => float_mod(x, y),
+        ArithOp::Pow => x.powf(y),
+        ArithOp::Div => x / y,
+        ArithOp::IDiv => (x / y).floor(),
+        ArithOp::Unm => -x,
+        _ => unreachable!(),
+    };
+    Ok(Some(LuaValue::Number(result)))
+}
+
+// 向下取整的整数除法
+fn int_floor_div(a: i64, b: i64) -> i64 {
+    if b == -1 {
+        return a.wrapping_neg();
+    }
+    let q = a / b;
+    if (a % b != 0) && ((a < 0) != (b < 0)) {
+        q - 1
+    } else {
+        q
+    }
+}
+
+// 结果符号与除数相同的取模
+fn int_mod(a: i64, b: i64) -> i64 {
+    if b == -1 {
+        return 0;
+    }
+    let r = a % b;
+    if r != 0 && (r ^ b) < 0 {
+        r + b
+    } else {
+        r
+    }
+}
+
+fn float_mod(a: f64, b: f64) -> f64 {
+    let r = a % b;
+    if r != 0.0 && (r < 0.0) != (b < 0.0) {
+        r + b
+    } else {
+        r
+    }
+}
+
+// 左移，n为负数时右移，都是逻辑移位
+fn shift_left(a: i64, n: i64) -> i64 {
+    if n <= -64 || n >= 64 {
+        0
+    } else if n >= 0 {
+        ((a as u64) << n) as i64
+    } else {
+        ((a as u64) >> -n) as i64
+    }
+}
+
+/// 比较两个值的大小，只支持数字和字符串，其他类型返回None
+pub fn less_than(a: &LuaValue, b: &LuaValue) -> Option<bool> {
+    match (a, b) {
+        (LuaValue::Integer(x), LuaValue::Integer(y)) => Some(x < y),
+        (LuaValue::Str(x), LuaValue::Str(y)) => Some(x < y),
+        (LuaValue::Integer(_) | LuaValue::Number(_), LuaValue::Integer(_) | LuaValue::Number(_)) => {
+            Some(num_less_than(a, b))
+        }
+        _ => None,
+    }
+}
+
+pub fn less_equal(a: &LuaValue, b: &LuaValue) -> Option<bool> {
+    match (a, b) {
+        (LuaValue::Integer(x), LuaValue::Integer(y)) => Some(x <= y),
+        (LuaValue::Str(x), LuaValue::Str(y)) => Some(x <= y),
+        (LuaValue::Integer(_) | LuaValue::Number(_), LuaValue::Integer(_) | LuaValue::Number(_)) => {
+            // NaN参与比较时结果总是false
+            if is_nan(a) || is_nan(b) {
+                Some(false)
+            } else {
+                Some(!num_less_than(b, a))
+            }
+        }
+        _ => None,
+    }
+}
+
+fn is_nan(v: &LuaValue) -> bool {
+    matches!(v, LuaValue::Number(n) if n.is_nan())
+}
+
+// 整数和浮点数混合比较时需要精确比较，不能简单转换为浮点数
+fn num_less_than(a: &LuaValue, b: &LuaValue) -> bool {
+    match (a, b) {
+        (LuaValue::Integer(x), LuaValue::Integer(y)) => x < y,
+        (LuaValue::Number(x), LuaValue::Number(y)) => x < y,
+        (LuaValue::Integer(i), LuaValue::Number(f)) => int_less_than_float(*i, *f),
+        (LuaValue::Number(f), LuaValue::Integer(i)) => float_less_than_int(*f, *i),
+        _ => unreachable!(),
+    }
+}
+
+fn int_less_than_float(i: i64, f: f64) -> bool {
+    if f.is_nan() {
+        false
+    } else if f >= 9223372036854775808.0 {
+        true
+    } else if f > -9223372036854775808.0 {
+        // i < f <=> i < ceil(f)
+        i < f.ceil() as i64
+    } else {
+        false
+    }
+}
+
+fn float_less_than_int(f: f64, i: i64) -> bool {
+    if f.is_nan() || f >= 9223372036854775808.0 {
+        false
+    } else if f >= -9223372036854775808.0 {
+        // f < i <=> floor(f) < i
+        (f.floor() as i64) < i
+    } else {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::vm::arith::{arith, less_equal, less_than, ArithOp};
+    use crate::vm::lua_value::LuaValue;
+
+    // 同时比较类型，避免整数和浮点数被判定为相等
+    fn check(op: ArithOp, a: LuaValue, b: LuaValue, expected: LuaValue) {
+        let result = arith(op, &a, &b).unwrap().unwrap();
+        assert_eq!(std::mem::discriminant(&result), std::mem::discriminant(&expected));
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_arith() {
+        use LuaValue::{Integer, Number};
+        check(ArithOp::Add, Integer(1), Integer(2), Integer(3));
+        check(ArithOp::Add, Integer(1), Number(2.0), Number(3.0));
+        check(ArithOp::Add, Integer(i64::MAX), Integer(1), Integer(i64::MIN));
+        check(ArithOp::Div, Integer(7), Integer(2), Number(3.5));
+        check(ArithOp::IDiv, Integer(-7), Integer(2), Integer(-4));
+        check(ArithOp::IDiv, Number(7.0), Integer(2), Number(3.0));
+        check(ArithOp::Mod, Integer(-7), Integer(3), Integer(2));
+        check(ArithOp::Mod, Integer(7), Integer(-3), Integer(-2));
+        check(ArithOp::Mod, Number(-7.5), Integer(2), Number(0.5));
+        check(ArithOp::Pow, Integer(2), Integer(10), Number(1024.0));
+        check(ArithOp::Shl, Integer(1), Integer(63), Integer(i64::MIN));
+        check(ArithOp::Shr, Integer(-1), Integer(63), Integer(1));
+        check(ArithOp::Shl, Integer(1), Integer(64), Integer(0));
+        check(ArithOp::BAnd, Number(3.0), LuaValue::from("6"), Integer(2));
+        check(ArithOp::Add, LuaValue::from("0x10"), Integer(1), Integer(17));
+        assert_eq!(arith(ArithOp::BOr, &Number(1.5), &Integer(1)).unwrap(), None);
+        assert_eq!(arith(ArithOp::Add, &LuaValue::Nil, &Integer(1)).unwrap(), None);
+        let err = |op| arith(op, &Integer(1), &Integer(0)).unwrap_err().to_string();
+        assert_eq!(err(ArithOp::Mod), "attempt to perform 'n%0'");
+        assert_eq!(err(ArithOp::IDiv), "attempt to divide by zero");
+    }
+
+    #[test]
+    fn test_compare() {
+        use LuaValue::{Integer, Number};
+        assert_eq!(less_than(&Integer(1), &Number(1.5)), Some(true));
+        assert_eq!(less_than(&Number(f64::NAN), &Integer(1)), Some(false));
+        assert_eq!(less_equal(&Number(f64::NAN), &Number(f64::NAN)), Some(false));
+        assert_eq!(less_than(&Integer(i64::MAX), &Number(9223372036854775808.0)), Some(true));
+        assert_eq!(less_equal(&Integer(2), &Number(2.0)), Some(true));
+        assert_eq!(less_than(&LuaValue::from("a"), &LuaValue::from("b")), Some(true));
+        assert_eq!(less_than(&LuaValue::from("a"), &Integer(1)), None);
+    }
+}
@@ -0,0 +1,78 @@
+use std::cell::RefCell;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::binary::chunk::Prototype;
+use crate::vm::error::LuaResult;
+use crate::vm::lua_state::LuaState;
+use crate::vm::lua_value::LuaValue;
+
+/// Rust实现的函数，参数和返回值都通过Vec传递
+pub type NativeFn = fn(&mut LuaState, Vec<LuaValue>) -> LuaResult<Vec<LuaValue>>;
+
+/// 闭包，分为Lua闭包和Rust函数
+pub enum Closure {
+    Lua(LuaClosure),
+    Native(NativeFunction),
+}
+
+/// 加载到虚拟机中的函数原型，常量在加载时一次性转换为运行时的值，字符串常量已驻留
+/// 同一个原型实例化的所有闭包共享这些常量
+pub struct FuncProto {
+    pub proto: Rc<Prototype>,
+    pub constants: Vec<LuaValue>,
+    pub protos: Vec<Rc<FuncProto>>,
+}
+
+// 除constants和protos外的字段直接访问chunk中的原型
+impl Deref for FuncProto {
+    type Target = Prototype;
+
+    fn deref(&self) -> &Prototype {
+        &self.proto
+    }
+}
+
+pub struct LuaClosure {
+    pub proto: Rc<FuncProto>,
+    pub upvalues: Vec<UpvalueRef>,
+}
+
+pub struct NativeFunction {
+    pub name: &'static str,
+    pub func: NativeFn,
+    // 函数执行时通过LuaState::native_upvalue访问
+    pub upvalues: Vec<LuaValue>,
+}
+
+/// upvalue在外层函数返回之前处于open状态，直接引用外层函数的寄存器
+/// 外层函数返回(或者离开局部变量作用域)时，upvalue被关闭，寄存器的值被复制到upvalue中
+#[derive(Debug)]
+pub enum Upvalue {
+    // 寄存器所在线程的栈和寄存器在栈中的绝对位置，在其他线程中通过栈访问寄存器
+    Open(StackRef, usize),
+    Closed(LuaValue),
+}
+
+pub type UpvalueRef = Rc<RefCell<Upvalue>>;
+
+/// 线程不在运行时，它的栈保存在这里，其他线程中的闭包可以访问它的open upvalue
+pub type StackRef = Rc<RefCell<Vec<LuaValue>>>;
+
+impl Closure {
+    pub fn new_lua(proto: Rc<FuncProto>, upvalues: Vec<UpvalueRef>) -> Self {
+        Closure::Lua(LuaClosure { proto, upvalues })
+    }
+
+    pub fn new_native(name: &'static str, func: NativeFn) -> Self {
+        Self::new_native_closure(name, func, Vec::new())
+    }
+
+    pub fn new_native_closure(name: &'static str, func: NativeFn, upvalues: Vec<LuaValue>) -> Self {
+        Closure::Native(NativeFunction {
+            name,
+            func,
+            upvalues,
+        })
+    }
+}
@@ -0,0 +1,135 @@
+use crate::binary::chunk::short_source;
+use crate::vm::closure::FuncProto;
+use crate::vm::decoded::{Decoded, Rk};
+use crate::vm::instruction::Instruction;
+use crate::vm::lua_value::LuaValue;
+
+// 源文件名的显示形式，没有调试信息时为"?"
+pub fn chunk_id(proto: &FuncProto) -> String {
+    if proto.source.is_empty() {
+        return "?".to_string();
+    }
+    short_source(&String::from_utf8_lossy(&proto.source))
+}
+
+// pc处指令的行号，没有行号信息时返回None
+pub fn line_at(proto: &FuncProto, pc: usize) -> Option<u32> {
+    proto.line_info.get(pc).copied()
+}
+
+// 第n个(从1开始)在pc处有效的局部变量的名字，对应Lua的luaF_getlocalname
+pub fn local_name(proto: &FuncProto, mut n: usize, pc: usize) -> Option<&str> {
+    for var in &proto.loc_vars {
+        if var.start_pc as usize > pc {
+            break;
+        }
+        if pc < var.end_pc as usize {
+            n -= 1;
+            if n == 0 {
+                return Some(&var.var_name);
+            }
+        }
+    }
+    None
+}
+
+fn upvalue_name(proto: &FuncProto, idx: usize) -> &str {
+    proto.upvalue_names.get(idx).map_or("?", String::as_str)
+}
+
+// 常量key的名字，不是字符串常量时为"?"
+fn key_name(proto: &FuncProto, key: Rk) -> String {
+    match key {
+        Rk::Const(k) => match &proto.constants[k] {
+            LuaValue::Str(s) => s.to_str_lossy().into_owned(),
+            _ => "?".to_string(),
+        },
+        Rk::Reg(_) => "?".to_string(),
+    }
+}
+
+// 找到last_pc之前最后一条给寄存器reg赋值的指令，对应Lua的findsetreg
+// 跳转目标之前的赋值是有条件的，不能确定寄存器的来源
+fn find_set_reg(proto: &FuncProto, last_pc: usize, reg: usize) -> Option<usize> {
+    let mut set_reg = None;
+    let mut jmp_target = 0;
+    for pc in 0..last_pc {
+        let i = proto.code[pc];
+        let Some(decoded) = Decoded::decode(i) else {
+            continue;
+        };
+        let change = match decoded {
+            Decoded::LoadNil { a, n } => a <= reg && reg < a + n,
+            Decoded::TForCall { a, .. } => reg >= a + 2,
+            Decoded::Call { a, .. } | Decoded::TailCall { a, .. } => reg >= a,
+            Decoded::Jmp { .. } => {
+                let dest = decoded.jump_target(pc).unwrap();
+                if pc < dest && dest <= last_pc && dest > jmp_target {
+                    jmp_target = dest;
+                }
+                false
+            }
+            _ => decoded.op().info().set_a_flag != 0 && i.abc().0 as usize == reg,
+        };
+        if change {
+            set_reg = if pc < jmp_target { None } else { Some(pc) };
+        }
+    }
+    set_reg
+}
+
+/// 根据last_pc之前的指令推断寄存器reg中的值的名字，返回名字的种类和名字
+/// 对应Lua的getobjname，种类为local、global、field、upvalue、constant或method
+pub fn obj_name(proto: &FuncProto, last_pc: usize, reg: usize) -> Option<(&'static str, String)> {
+    if let Some(name) = local_name(proto, reg + 1, last_pc) {
+        return Some(("local", name.to_string()));
+    }
+    let pc = find_set_reg(proto, last_pc, reg)?;
+    match Decoded::decode(proto.code[pc])? {
+        Decoded::Move { a, b } if b < a => obj_name(proto, pc, b),
+        Decoded::GetTabUp { upval, key, .. } => {
+            let what = if upvalue_name(proto, upval) == "_ENV" {
+                "global"
+            } else {
+                "field"
+            };
+            Some((what, key_name(proto, key)))
+        }
+        Decoded::GetTable { table, key, .. } => {
+            let what = if local_name(proto, table + 1, pc) == Some("_ENV") {
+                "global"
+            } else {
+                "field"
+            };
+            Some((what, key_name(proto, key)))
+        }
+        Decoded::GetUpval { upval, .. } => {
+            Some(("upvalue", upvalue_name(proto, upval).to_string()))
+        }
+        Decoded::LoadK { k, .. } => match &proto.constants[k] {
+            LuaValue::Str(s) => Some(("constant", s.to_str_lossy().into_owned())),
+            _ => None,
+        },
+        Decoded::Self_ { key, .. } => Some(("method", key_name(proto, key))),
+        _ => None,
+    }
+}
+
+/// 根据调用者在pc处的指令推断被调用函数的名字，对应Lua的getfuncname
+/// 与Lua 5.3相同，元方法的名字带"__"前缀
+pub fn call_name(proto: &FuncProto, pc: usize) -> Option<(&'static str, String)> {
+    let event = match Decoded::decode(*proto.code.get(pc)?)? {
+        Decoded::Call { a, .. } | Decoded::TailCall { a, .. } => return obj_name(proto, pc, a),
+        Decoded::TForCall { .. } => return Some(("for iterator", "for iterator".to_string())),
+        Decoded::Self_ { .. } | Decoded::GetTabUp { .. } | Decoded::GetTable { .. } => "__index",
+        Decoded::SetTabUp { .. } | Decoded::SetTable { .. } => "__newindex",
+        Decoded::Arith { op, .. } | Decoded::Unary { op, .. } => op.event(),
+        Decoded::Len { .. } => "__len",
+        Decoded::Concat { .. } => "__concat",
+        Decoded::Eq { .. } => "__eq",
+        Decoded::Lt { .. } => "__lt",
+        Decoded::Le { .. } => "__le",
+        _ => return None,
+    };
+    Some(("metamethod", event.to_string()))
+}
@@ -0,0 +1,566 @@
+use std::fmt;
+
+use crate::vm::arith::ArithOp;
+use crate::vm::instruction::{is_constant, Instruction, MAX_INDEX_RK};
+use crate::vm::opcode::{self, Op, OP_ADD};
+
+/// RK操作数，表示寄存器或常量表中的常量
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rk {
+    Reg(usize),
+    Const(usize),
+}
+
+impl Rk {
+    // 按参数模式解释B、C操作数，只有OpArgK模式的参数才可能是常量
+    pub fn from_arg(x: isize, arg_mode: u8) -> Rk {
+        if arg_mode == opcode::OP_ARG_K && is_constant(x) {
+            Rk::Const((x & MAX_INDEX_RK) as usize)
+        } else {
+            Rk::Reg(x as usize)
+        }
+    }
+}
+
+// 与luac一致，常量k显示为-1-k
+impl fmt::Display for Rk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rk::Reg(r) => write!(f, "{r}"),
+            Rk::Const(k) => write!(f, "{}", -1 - *k as isize),
+        }
+    }
+}
+
+/// 解码后的5.3指令
+/// 寄存器是相对于当前函数栈底的索引，常量、upvalue、子函数都是各自表中的索引，
+/// 跳转偏移量相对于下一条指令；数量为None时表示直到栈顶(或多返回值)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decoded {
+    // R(A) := R(B)
+    Move {
+        a: usize,
+        b: usize,
+    },
+    // R(A) := Kst(Bx)
+    LoadK {
+        a: usize,
+        k: usize,
+    },
+    // R(A) := Kst(extra arg)
+    LoadKX {
+        a: usize,
+    },
+    // R(A) := value; if skip then pc++
+    LoadBool {
+        a: usize,
+        value: bool,
+        skip: bool,
+    },
+    // R(A), R(A+1), ..., R(A+n-1) := nil
+    LoadNil {
+        a: usize,
+        n: usize,
+    },
+    // R(A) := UpValue[upval]
+    GetUpval {
+        a: usize,
+        upval: usize,
+    },
+    // R(A) := UpValue[upval][key]
+    GetTabUp {
+        a: usize,
+        upval: usize,
+        key: Rk,
+    },
+    // R(A) := R(table)[key]
+    GetTable {
+        a: usize,
+        table: usize,
+        key: Rk,
+    },
+    // UpValue[upval][key] := value
+    SetTabUp {
+        upval: usize,
+        key: Rk,
+        value: Rk,
+    },
+    // UpValue[upval] := R(A)
+    SetUpval {
+        a: usize,
+        upval: usize,
+    },
+    // R(table)[key] := value
+    SetTable {
+        table: usize,
+        key: Rk,
+        value: Rk,
+    },
+    // R(A) := {}，数组和哈希部分的大小是浮点字节编码
+    NewTable {
+        a: usize,
+        array: usize,
+        hash: usize,
+    },
+    // R(A+1) := R(table); R(A) := R(table)[key]
+    Self_ {
+        a: usize,
+        table: usize,
+        key: Rk,
+    },
+    // R(A) := b op c，op是二元算术或按位运算
+    Arith {
+        op: ArithOp,
+        a: usize,
+        b: Rk,
+        c: Rk,
+    },
+    // R(A) := op R(B)，op是Unm或BNot
+    Unary {
+        op: ArithOp,
+        a: usize,
+        b: usize,
+    },
+    // R(A) := not R(B)
+    Not {
+        a: usize,
+        b: usize,
+    },
+    // R(A) := length of R(B)
+    Len {
+        a: usize,
+        b: usize,
+    },
+    // R(A) := R(first).. ... ..R(last)
+    Concat {
+        a: usize,
+        first: usize,
+        last: usize,
+    },
+    // pc += offset; 关闭close及以上寄存器的upvalue
+    Jmp {
+        close: Option<usize>,
+        offset: isize,
+    },
+    // if (b == c) ~= expect then pc++
+    Eq {
+        expect: bool,
+        b: Rk,
+        c: Rk,
+    },
+    // if (b < c) ~= expect then pc++
+    Lt {
+        expect: bool,
+        b: Rk,
+        c: Rk,
+    },
+    // if (b <= c) ~= expect then pc++
+    Le {
+        expect: bool,
+        b: Rk,
+        c: Rk,
+    },
+    // if R(A) is not expect then pc++
+    Test {
+        a: usize,
+        expect: bool,
+    },
+    // if R(B) is expect then R(A) := R(B) else pc++
+    TestSet {
+        a: usize,
+        b: usize,
+        expect: bool,
+    },
+    // R(A), ... := R(A)(R(A+1), ..., R(A+args))
+    Call {
+        a: usize,
+        args: Option<usize>,
+        results: Option<usize>,
+    },
+    // return R(A)(R(A+1), ..., R(A+args))
+    TailCall {
+        a: usize,
+        args: Option<usize>,
+    },
+    // return R(A), ..., R(A+n-1)
+    Return {
+        a: usize,
+        n: Option<usize>,
+    },
+    // R(A) += R(A+2); if R(A) <?= R(A+1) then { pc += offset; R(A+3) := R(A) }
+    ForLoop {
+        a: usize,
+        offset: isize,
+    },
+    // R(A) -= R(A+2); pc += offset
+    ForPrep {
+        a: usize,
+        offset: isize,
+    },
+    // R(A+3), ..., R(A+2+results) := R(A)(R(A+1), R(A+2))
+    TForCall {
+        a: usize,
+        results: usize,
+    },
+    // if R(A+1) ~= nil then { R(A) := R(A+1); pc += offset }
+    TForLoop {
+        a: usize,
+        offset: isize,
+    },
+    // R(A)[(block-1)*FPF+i] := R(A+i), 1 <= i <= n；block为None时在下一条EXTRAARG中
+    SetList {
+        a: usize,
+        n: Option<usize>,
+        block: Option<usize>,
+    },
+    // R(A) := closure(KPROTO[proto])
+    Closure {
+        a: usize,
+        proto: usize,
+    },
+    // R(A), R(A+1), ..., R(A+n-1) := vararg
+    VarArg {
+        a: usize,
+        n: Option<usize>,
+    },
+    // 上一条指令的扩展参数
+    ExtraArg {
+        ax: usize,
+    },
+}
+
+// 0表示数量不确定，否则是数量加1
+fn count(x: isize) -> Option<usize> {
+    (x != 0).then(|| x as usize - 1)
+}
+
+fn encode_count(n: Option<usize>) -> usize {
+    n.map_or(0, |n| n + 1)
+}
+
+impl Decoded {
+    // 解码指令，未知的操作码返回None
+    pub fn decode(i: u32) -> Option<Decoded> {
+        let op = i.op()?;
+        let info = op.info();
+        let (a, b, c) = i.abc();
+        let (_, bx) = i.abx();
+        let (_, sbx) = i.asbx();
+        let rb = Rk::from_arg(b, info.b_arg_mode);
+        let rc = Rk::from_arg(c, info.c_arg_mode);
+        let (a, b, c, bx) = (a as usize, b as usize, c as usize, bx as usize);
+        let decoded = match op {
+            Op::Move => Decoded::Move { a, b },
+            Op::LoadK => Decoded::LoadK { a, k: bx },
+            Op::LoadKX => Decoded::LoadKX { a },
+            Op::LoadBool => Decoded::LoadBool {
+                a,
+                value: b != 0,
+                skip: c != 0,
+            },
+            Op::LoadNil => Decoded::LoadNil { a, n: b + 1 },
+            Op::GetUpval => Decoded::GetUpval { a, upval: b },
+            Op::GetTabUp => Decoded::GetTabUp {
+                a,
+                upval: b,
+                key: rc,
+            },
+            Op::GetTable => Decoded::GetTable {
+                a,
+                table: b,
+                key: rc,
+            },
+            Op::SetTabUp => Decoded::SetTabUp {
+                upval: a,
+                key: rb,
+                value: rc,
+            },
+            Op::SetUpval => Decoded::SetUpval { a, upval: b },
+            Op::SetTable => Decoded::SetTable {
+                table: a,
+                key: rb,
+                value: rc,
+            },
+            Op::NewTable => Decoded::NewTable {
+                a,
+                array: b,
+                hash: c,
+            },
+            Op::Self_ => Decoded::Self_ {
+                a,
+                table: b,
+                key: rc,
+            },
+            Op::Add
+            | Op::Sub
+            | Op::Mul
+            | Op::Mod
+            | Op::Pow
+            | Op::Div
+            | Op::IDiv
+            | Op::BAnd
+            | Op::BOr
+            | Op::BXor
+            | Op::Shl
+            | Op::Shr => {
+                let op = ArithOp::from_offset(op as u8 - OP_ADD);
+                Decoded::Arith {
+                    op,
+                    a,
+                    b: rb,
+                    c: rc,
+                }
+            }
+            Op::Unm | Op::BNot => {
+                let op = ArithOp::from_offset(op as u8 - OP_ADD);
+                Decoded::Unary { op, a, b }
+            }
+            Op::Not => Decoded::Not { a, b },
+            Op::Len => Decoded::Len { a, b },
+            Op::Concat => Decoded::Concat {
+                a,
+                first: b,
+                last: c,
+            },
+            Op::Jmp => Decoded::Jmp {
+                close: a.checked_sub(1),
+                offset: sbx,
+            },
+            Op::Eq => Decoded::Eq {
+                expect: a != 0,
+                b: rb,
+                c: rc,
+            },
+            Op::Lt => Decoded::Lt {
+                expect: a != 0,
+                b: rb,
+                c: rc,
+            },
+            Op::Le => Decoded::Le {
+                expect: a != 0,
+                b: rb,
+                c: rc,
+            },
+            Op::Test => Decoded::Test { a, expect: c != 0 },
+            Op::TestSet => Decoded::TestSet {
+                a,
+                b,
+                expect: c != 0,
+            },
+            Op::Call => Decoded::Call {
+                a,
+                args: count(b as isize),
+                results: count(c as isize),
+            },
+            Op::TailCall => Decoded::TailCall {
+                a,
+                args: count(b as isize),
+            },
+            Op::Return => Decoded::Return {
+                a,
+                n: count(b as isize),
+            },
+            Op::ForLoop => Decoded::ForLoop { a, offset: sbx },
+            Op::ForPrep => Decoded::ForPrep { a, offset: sbx },
+            Op::TForCall => Decoded::TForCall { a, results: c },
+            Op::TForLoop => Decoded::TForLoop { a, offset: sbx },
+            Op::SetList => Decoded::SetList {
+                a,
+                n: (b != 0).then_some(b),
+                block: (c != 0).then_some(c),
+            },
+            Op::Closure => Decoded::Closure { a, proto: bx },
+            Op::VarArg => Decoded::VarArg {
+                a,
+                n: count(b as isize),
+            },
+            Op::ExtraArg => Decoded::ExtraArg {
+                ax: i.ax() as usize,
+            },
+        };
+        Some(decoded)
+    }
+
+    // 指令的操作码
+    pub fn op(&self) -> Op {
+        match self {
+            Decoded::Move { .. } => Op::Move,
+            Decoded::LoadK { .. } => Op::LoadK,
+            Decoded::LoadKX { .. } => Op::LoadKX,
+            Decoded::LoadBool { .. } => Op::LoadBool,
+            Decoded::LoadNil { .. } => Op::LoadNil,
+            Decoded::GetUpval { .. } => Op::GetUpval,
+            Decoded::GetTabUp { .. } => Op::GetTabUp,
+            Decoded::GetTable { .. } => Op::GetTable,
+            Decoded::SetTabUp { .. } => Op::SetTabUp,
+            Decoded::SetUpval { .. } => Op::SetUpval,
+            Decoded::SetTable { .. } => Op::SetTable,
+            Decoded::NewTable { .. } => Op::NewTable,
+            Decoded::Self_ { .. } => Op::Self_,
+            Decoded::Arith { op, .. } | Decoded::Unary { op, .. } => {
+                Op::ALL[(OP_ADD + *op as u8) as usize]
+            }
+            Decoded::Not { .. } => Op::Not,
+            Decoded::Len { .. } => Op::Len,
+            Decoded::Concat { .. } => Op::Concat,
+            Decoded::Jmp { .. } => Op::Jmp,
+            Decoded::Eq { .. } => Op::Eq,
+            Decoded::Lt { .. } => Op::Lt,
+            Decoded::Le { .. } => Op::Le,
+            Decoded::Test { .. } => Op::Test,
+            Decoded::TestSet { .. } => Op::TestSet,
+            Decoded::Call { .. } => Op::Call,
+            Decoded::TailCall { .. } => Op::TailCall,
+            Decoded::Return { .. } => Op::Return,
+            Decoded::ForLoop { .. } => Op::ForLoop,
+            Decoded::ForPrep { .. } => Op::ForPrep,
+            Decoded::TForCall { .. } => Op::TForCall,
+            Decoded::TForLoop { .. } => Op::TForLoop,
+            Decoded::SetList { .. } => Op::SetList,
+            Decoded::Closure { .. } => Op::Closure,
+            Decoded::VarArg { .. } => Op::VarArg,
+            Decoded::ExtraArg { .. } => Op::ExtraArg,
+        }
+    }
+
+    // 跳转指令的目标pc，pc是当前指令的位置；不是跳转指令时返回None
+    pub fn jump_target(&self, pc: usize) -> Option<usize> {
+        match *self {
+            Decoded::Jmp { offset, .. }
+            | Decoded::ForLoop { offset, .. }
+            | Decoded::ForPrep { offset, .. }
+            | Decoded::TForLoop { offset, .. } => Some((pc as isize + 1 + offset) as usize),
+            _ => None,
+        }
+    }
+}
+
+// 与luac -l一致的操作数格式，依次为A、B、C(省略OpArgN模式的参数)
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Decoded::Move { a, b }
+            | Decoded::GetUpval { a, upval: b }
+            | Decoded::SetUpval { a, upval: b }
+            | Decoded::Unary { a, b, .. }
+            | Decoded::Not { a, b }
+            | Decoded::Len { a, b } => write!(f, "{a} {b}"),
+            Decoded::LoadK { a, k } => write!(f, "{a} {}", -1 - k as isize),
+            Decoded::LoadKX { a } => write!(f, "{a}"),
+            Decoded::LoadBool { a, value, skip } => write!(f, "{a} {} {}", value as u8, skip as u8),
+            Decoded::LoadNil { a, n } => write!(f, "{a} {}", n - 1),
+            Decoded::GetTabUp { a, upval: b, key }
+            | Decoded::GetTable { a, table: b, key }
+            | Decoded::Self_ { a, table: b, key } => write!(f, "{a} {b} {key}"),
+            Decoded::SetTabUp {
+                upval: a,
+                key,
+                value,
+            }
+            | Decoded::SetTable {
+                table: a,
+                key,
+                value,
+            } => write!(f, "{a} {key} {value}"),
+            Decoded::NewTable { a, array, hash } => write!(f, "{a} {array} {hash}"),
+            Decoded::Arith { a, b, c, .. } => write!(f, "{a} {b} {c}"),
+            Decoded::Concat { a, first, last } => write!(f, "{a} {first} {last}"),
+            Decoded::Jmp { close, offset } => write!(f, "{} {offset}", encode_count(close)),
+            Decoded::Eq { expect, b, c }
+            | Decoded::Lt { expect, b, c }
+            | Decoded::Le { expect, b, c } => {
+                write!(f, "{} {b} {c}", expect as u8)
+            }
+            Decoded::Test { a, expect } => write!(f, "{a} {}", expect as u8),
+            Decoded::TestSet { a, b, expect } => write!(f, "{a} {b} {}", expect as u8),
+            Decoded::Call { a, args, results } => {
+                write!(f, "{a} {} {}", encode_count(args), encode_count(results))
+            }
+            Decoded::TailCall { a, args } => write!(f, "{a} {} 0", encode_count(args)),
+            Decoded::Return { a, n } | Decoded::VarArg { a, n } => {
+                write!(f, "{a} {}", encode_count(n))
+            }
+            Decoded::ForLoop { a, offset }
+            | Decoded::ForPrep { a, offset }
+            | Decoded::TForLoop { a, offset } => write!(f, "{a} {offset}"),
+            Decoded::TForCall { a, results } => write!(f, "{a} {results}"),
+            Decoded::SetList { a, n, block } => {
+                write!(f, "{a} {} {}", n.unwrap_or(0), block.unwrap_or(0))
+            }
+            Decoded::Closure { a, proto } => write!(f, "{a} {proto}"),
+            Decoded::ExtraArg { ax } => write!(f, "{}", -1 - ax as isize),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::vm::arith::ArithOp;
+    use crate::vm::decoded::{Decoded, Rk};
+    use crate::vm::instruction::{rk_constant, Instruction};
+    use crate::vm::opcode::Op;
+
+    #[test]
+    fn test_decode() {
+        let k1 = rk_constant(1).unwrap();
+        let i = u32::encode_abc(Op::GetTabUp, 0, 0, k1).unwrap();
+        let d = Decoded::decode(i).unwrap();
+        assert_eq!(
+            d,
+            Decoded::GetTabUp {
+                a: 0,
+                upval: 0,
+                key: Rk::Const(1)
+            }
+        );
+        assert_eq!(d.op(), Op::GetTabUp);
+        assert_eq!(d.to_string(), "0 0 -2");
+
+        // 只有OpArgK模式的参数才是RK，NEWTABLE的B是浮点字节编码的大小
+        let i = u32::encode_abc(Op::NewTable, 0, 0x100, 0).unwrap();
+        assert_eq!(
+            Decoded::decode(i),
+            Some(Decoded::NewTable {
+                a: 0,
+                array: 0x100,
+                hash: 0
+            })
+        );
+
+        let i = u32::encode_abc(Op::Sub, 2, 3, k1).unwrap();
+        let d = Decoded::decode(i).unwrap();
+        assert_eq!(
+            d,
+            Decoded::Arith {
+                op: ArithOp::Sub,
+                a: 2,
+                b: Rk::Reg(3),
+                c: Rk::Const(1)
+            }
+        );
+        assert_eq!(d.op(), Op::Sub);
+
+        let i = u32::encode_asbx(Op::Jmp, 3, -2).unwrap();
+        let d = Decoded::decode(i).unwrap();
+        assert_eq!(
+            d,
+            Decoded::Jmp {
+                close: Some(2),
+                offset: -2
+            }
+        );
+        assert_eq!(d.jump_target(5), Some(4));
+        assert_eq!(d.to_string(), "3 -2");
+
+        let i = u32::encode_abc(Op::Call, 0, 0, 2).unwrap();
+        assert_eq!(
+            Decoded::decode(i),
+            Some(Decoded::Call {
+                a: 0,
+                args: None,
+                results: Some(1)
+            })
+        );
+        assert_eq!(Decoded::decode(0x3F), None);
+    }
+}
@@ -0,0 +1,50 @@
+use std::fmt;
+
+use crate::vm::lua_value::LuaValue;
+
+/// Lua运行时错误
+/// 错误在抛出的位置被处理一次：字符串信息加上出错位置，再交给当前的消息处理函数，
+/// 之后作为Handled沿调用链原样传播，直到被pcall捕获或者返回给宿主程序
+#[derive(Debug, Clone, PartialEq)]
+pub enum LuaError {
+    // 虚拟机或Rust函数产生的错误信息，还没有加上出错位置
+    Runtime(String),
+    // error函数抛出的错误对象，可以是任意Lua值
+    Object(LuaValue),
+    // 已经在抛出的位置处理过的错误对象
+    Handled(LuaValue),
+    // 协程让出，不是真正的错误，携带让出的值沿调用链返回到resume
+    Yield(Vec<LuaValue>),
+}
+
+pub type LuaResult<T> = Result<T, LuaError>;
+
+impl LuaError {
+    pub fn runtime(msg: impl Into<String>) -> Self {
+        LuaError::Runtime(msg.into())
+    }
+
+    // 错误对象，pcall把它作为第二个返回值
+    pub fn value(self) -> LuaValue {
+        match self {
+            LuaError::Runtime(msg) => LuaValue::from(msg),
+            LuaError::Object(v) | LuaError::Handled(v) => v,
+            err @ LuaError::Yield(_) => LuaValue::from(err.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LuaError::Runtime(msg) => write!(f, "{msg}"),
+            LuaError::Object(v) | LuaError::Handled(v) => match v {
+                LuaValue::Str(_) | LuaValue::Integer(_) | LuaValue::Number(_) => write!(f, "{v}"),
+                _ => write!(f, "(error object is a {} value)", v.type_name()),
+            },
+            LuaError::Yield(_) => write!(f, "attempt to yield from outside a coroutine"),
+        }
+    }
+}
+
+impl std::error::Error for LuaError {}
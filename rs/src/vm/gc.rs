@@ -0,0 +1,1020 @@
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::{Rc, Weak};
+
+use crate::vm::closure::{Closure, Upvalue, UpvalueRef};
+use crate::vm::lua_state::LuaState;
+use crate::vm::lua_table::LuaTable;
+use crate::vm::lua_value::LuaValue;
+use crate::vm::userdata::UserData;
+
+// 回收间歇的默认值，与Lua的LUAI_GCPAUSE保持一致，内存增长到上次回收后的2倍时开始新一轮回收
+pub const DEFAULT_PAUSE: usize = 200;
+// 步进倍率的默认值，与Lua的LUAI_GCMUL保持一致
+pub const DEFAULT_STEP_MUL: usize = 200;
+// 内存很少时不必频繁回收，计算回收阈值时内存至少按这个值计算
+const MIN_ESTIMATE: usize = 64 * 1024;
+// 回收进行中每分配这么多字节执行一步，也是step(0)偿还的债务
+const STEP_SIZE: usize = 4 * 1024;
+// 扫描或清空一个对象的工作量，与Lua的GCSWEEPCOST类似，遍历对象的工作量是它的估计字节数
+const SCAN_COST: isize = 16;
+
+/// 被回收器跟踪的对象，只保存弱引用，对象的内存仍然在引用计数归零时释放
+#[derive(Clone)]
+pub enum GcObject {
+    Table(Weak<RefCell<LuaTable>>),
+    Closure(Weak<Closure>),
+    Upvalue(Weak<RefCell<Upvalue>>),
+    UserData(Weak<UserData>),
+    Thread(Weak<RefCell<LuaState>>),
+}
+
+/// 回收期间持有的对象强引用
+#[derive(Clone)]
+enum GcRef {
+    Table(Rc<RefCell<LuaTable>>),
+    Closure(Rc<Closure>),
+    Upvalue(UpvalueRef),
+    UserData(Rc<UserData>),
+    Thread(Rc<RefCell<LuaState>>),
+}
+
+/// 对象持有的一个引用
+pub enum Child<'a> {
+    Value(&'a LuaValue),
+    Table(&'a Rc<RefCell<LuaTable>>),
+    Closure(&'a Rc<Closure>),
+    Upvalue(&'a UpvalueRef),
+}
+
+/// 一轮回收所处的阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    // 两轮回收之间，分配债务达到阈值时开始新一轮回收
+    Pause,
+    // 增量标记，灰色对象用完之后扫描对象列表，记录仍为白色的对象
+    Propagate,
+    // 原子阶段已经确定了不可达对象，增量地清空它们
+    Sweep,
+}
+
+// 已标记对象的颜色，不在colors中的对象是白色的
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Color {
+    Gray,
+    Black,
+}
+
+/// 记录所有可能形成环的对象(表、闭包、upvalue、用户数据和线程)，对它们做增量的标记清除
+///
+/// 引用计数会在对象不再被引用时立即释放它，回收器只负责引用计数无法释放的环。
+/// 一轮回收分步进行，每一步只做step_mul决定的有限工作：
+/// 1. 标记：从全局环境、类型元表和当前线程出发做三色标记。向已遍历的表写入时，
+///    写屏障把表退回灰色；向已遍历的upvalue或用户数据写入时，写屏障标记写入的值。
+///    灰色对象用完之后扫描对象列表，记录仍为白色的对象。
+/// 2. 原子阶段：重新遍历线程、弱表和被写屏障退回的表，然后只在白色对象之间用引用
+///    抵消引用计数，剩下的引用来自Rust代码或宿主程序持有的值，这些对象也是根。
+///    之后清理弱表，复活需要终结的对象，仍为白色的对象就是不可达的。
+/// 3. 清除：逐步清空不可达对象的内容，环被打断后由引用计数释放。
+///
+/// 白色对象被任何非白色对象引用时都会在原子阶段成为根，所以遗漏写屏障只会让对象
+/// 晚一些被标记，不会错误地回收对象。本轮开始之后创建的对象不参与本轮回收。
+pub struct Heap {
+    objects: Vec<GcObject>,
+    // 弱引用仍然占用已释放对象的内存，objects增长到这个长度时清除已释放的对象
+    prune_at: usize,
+    // 上次回收后存活对象的估计字节数
+    estimate: usize,
+    // 两轮回收之间新分配的字节数，回收进行中是还未偿还的分配债务
+    debt: usize,
+    pub pause: usize,
+    pub step_mul: usize,
+    // collectgarbage("stop")之后为false，不再自动回收
+    pub running: bool,
+    phase: Phase,
+    // 本轮开始时objects的长度，之后创建的对象在它后面
+    snapshot: usize,
+    colors: HashMap<usize, Color>,
+    // 已标记但还未遍历的对象
+    gray: Vec<GcRef>,
+    // 原子阶段重新遍历的对象：被写屏障退回的表、线程、open upvalue和遍历时正在被借用的对象
+    gray_again: Vec<GcRef>,
+    // 遍历过的弱表及其弱键、弱值标志，key为表的地址
+    weak: HashMap<usize, (Rc<RefCell<LuaTable>>, bool, bool)>,
+    // 遍历时含有被删除的key的表
+    dead_keys: HashMap<usize, Rc<RefCell<LuaTable>>>,
+    // 扫描对象列表的读写位置，扫描时顺便移除已释放的对象
+    scan_read: usize,
+    scan_write: usize,
+    // 扫描时仍为白色的对象
+    candidates: Vec<GcObject>,
+    // 原子阶段的白色对象，key为地址，之后被标记的对象不再是白色的
+    whites: HashMap<usize, usize>,
+    atomic: bool,
+    // 原子阶段确定的不可达对象，清除阶段逐个清空
+    garbage: Vec<GcRef>,
+    // 本轮被复活的需要调用__gc的对象，按标记的顺序排列
+    to_finalize: Vec<LuaValue>,
+    // 扫描到的存活对象的估计字节数
+    survived: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            prune_at: 1024,
+            estimate: 0,
+            debt: 0,
+            pause: DEFAULT_PAUSE,
+            step_mul: DEFAULT_STEP_MUL,
+            running: true,
+            phase: Phase::Pause,
+            snapshot: 0,
+            colors: HashMap::new(),
+            gray: Vec::new(),
+            gray_again: Vec::new(),
+            weak: HashMap::new(),
+            dead_keys: HashMap::new(),
+            scan_read: 0,
+            scan_write: 0,
+            candidates: Vec::new(),
+            whites: HashMap::new(),
+            atomic: false,
+            garbage: Vec::new(),
+            to_finalize: Vec::new(),
+            survived: 0,
+        }
+    }
+
+    // 跟踪新创建的对象，它的估计字节数计入分配债务
+    pub fn track(&mut self, obj: GcObject) {
+        if let Some(r) = obj.upgrade() {
+            let size = r.memory();
+            self.debt += size;
+        }
+        // 回收进行中对象列表的位置不能改变
+        if self.phase == Phase::Pause && self.objects.len() >= self.prune_at {
+            self.prune();
+        }
+        self.objects.push(obj);
+    }
+
+    fn prune(&mut self) {
+        self.objects.retain(|obj| obj.upgrade().is_some());
+        self.prune_at = (self.objects.len() * 2).max(1024);
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    // 自动回收时这一步的工作量，分配债务还不足以执行一步时返回None
+    pub fn step_budget(&mut self) -> Option<isize> {
+        if !self.running {
+            return None;
+        }
+        let bytes = match self.phase {
+            Phase::Pause if self.debt >= self.threshold() => STEP_SIZE,
+            Phase::Propagate | Phase::Sweep if self.debt >= STEP_SIZE => self.debt,
+            _ => return None,
+        };
+        self.debt = 0;
+        Some(self.work(bytes))
+    }
+
+    // collectgarbage("step")的工作量，kb为0时执行一个基本步
+    pub fn manual_budget(&mut self, kb: usize) -> isize {
+        let bytes = match kb {
+            0 => STEP_SIZE,
+            kb => kb.saturating_mul(1024),
+        };
+        self.debt = 0;
+        self.work(bytes)
+    }
+
+    // 偿还bytes字节的分配债务需要做的工作量
+    fn work(&self, bytes: usize) -> isize {
+        (bytes / 100).saturating_mul(self.step_mul).clamp(1, isize::MAX as usize) as isize
+    }
+
+    fn threshold(&self) -> usize {
+        self.estimate.max(MIN_ESTIMATE) / 100 * self.pause.saturating_sub(100).max(1)
+    }
+
+    // 当前被跟踪的存活对象的估计字节数
+    pub fn memory(&self) -> usize {
+        self.objects
+            .iter()
+            .filter_map(GcObject::upgrade)
+            .map(|obj| obj.memory())
+            .sum()
+    }
+
+    // 开始新一轮回收，根由调用者随后标记
+    pub fn start(&mut self) {
+        self.phase = Phase::Propagate;
+        self.snapshot = self.objects.len();
+        self.scan_read = 0;
+        self.scan_write = 0;
+        self.survived = 0;
+        self.debt = 0;
+    }
+
+    // 放弃标记到一半的一轮回收，此时还没有清空任何对象
+    pub fn abandon(&mut self) {
+        if self.phase == Phase::Propagate {
+            self.end_scan();
+            self.reset_marks();
+            self.phase = Phase::Pause;
+        }
+    }
+
+    fn reset_marks(&mut self) {
+        self.colors.clear();
+        self.gray.clear();
+        self.gray_again.clear();
+        self.weak.clear();
+        self.dead_keys.clear();
+        self.candidates.clear();
+        self.whites.clear();
+        self.atomic = false;
+    }
+
+    pub fn mark(&mut self, child: Child) {
+        let Some(a) = child.addr() else {
+            return;
+        };
+        if let Entry::Vacant(e) = self.colors.entry(a) {
+            e.insert(Color::Gray);
+            self.gray.extend(child.to_ref());
+        }
+    }
+
+    /// 表的写屏障，向已遍历的表写入之后调用，表退回灰色并在原子阶段重新遍历
+    pub fn barrier_back(&mut self, t: &Rc<RefCell<LuaTable>>) {
+        if self.phase != Phase::Propagate {
+            return;
+        }
+        if let Some(color @ Color::Black) = self.colors.get_mut(&addr(t)) {
+            *color = Color::Gray;
+            self.gray_again.push(GcRef::Table(t.clone()));
+        }
+    }
+
+    /// upvalue和用户数据的写屏障，已遍历的owner引用了新的值v时标记v
+    pub fn barrier(&mut self, owner: Child, v: Child) {
+        if self.phase != Phase::Propagate {
+            return;
+        }
+        let black = owner.addr().and_then(|a| self.colors.get(&a)) == Some(&Color::Black);
+        if black {
+            self.mark(v);
+        }
+    }
+
+    /// 增量标记，用完budget或者可以进入原子阶段时返回，返回是否可以进入原子阶段
+    pub fn propagate(&mut self, budget: &mut isize) -> bool {
+        while *budget > 0 {
+            if let Some(obj) = self.gray.pop() {
+                *budget -= self.traverse(obj);
+            } else if self.scan_read < self.snapshot {
+                self.scan();
+                *budget -= SCAN_COST;
+            } else {
+                return true;
+            }
+        }
+        false
+    }
+
+    // 扫描对象列表中的下一个对象
+    fn scan(&mut self) {
+        let obj = self.objects[self.scan_read].clone();
+        self.scan_read += 1;
+        let Some(r) = obj.upgrade() else {
+            return;
+        };
+        self.survived += r.memory();
+        if !self.colors.contains_key(&r.addr()) {
+            self.candidates.push(obj.clone());
+        }
+        self.objects[self.scan_write] = obj;
+        self.scan_write += 1;
+    }
+
+    // 移除扫描时跳过的已释放对象，新创建的对象移到存活对象之后
+    fn end_scan(&mut self) {
+        self.objects.drain(self.scan_write..self.scan_read);
+        self.snapshot -= self.scan_read - self.scan_write;
+        self.scan_read = self.scan_write;
+    }
+
+    // 不是被跟踪对象的值(数字、字符串等)总是存活的
+    // 原子阶段之前只有已标记的对象是存活的，之后只有白色对象是不可达的
+    fn is_alive(&self, v: &LuaValue) -> bool {
+        let Some(a) = Child::Value(v).addr() else {
+            return true;
+        };
+        if self.atomic && !self.whites.contains_key(&a) {
+            return true;
+        }
+        self.colors.contains_key(&a)
+    }
+
+    fn is_white(&self, child: &Child) -> Option<usize> {
+        let a = child.addr()?;
+        let &i = self.whites.get(&a)?;
+        (!self.colors.contains_key(&a)).then_some(i)
+    }
+
+    // 遍历对象，标记它引用的对象，返回工作量
+    fn traverse(&mut self, obj: GcRef) -> isize {
+        let cost = obj.memory() as isize;
+        self.colors.insert(obj.addr(), Color::Black);
+        match &obj {
+            GcRef::Table(t) => {
+                let Ok(t) = t.try_borrow() else {
+                    self.retry(obj.clone());
+                    return cost;
+                };
+                let (weak_keys, weak_values) = weak_mode(&t);
+                let a = obj.addr();
+                let GcRef::Table(rc) = &obj else {
+                    unreachable!()
+                };
+                if weak_keys || weak_values {
+                    self.weak.insert(a, (rc.clone(), weak_keys, weak_values));
+                }
+                if t.dead_keys().any(|k| Child::Value(k).addr().is_some()) {
+                    self.dead_keys.insert(a, rc.clone());
+                }
+                if let Some(mt) = t.metatable() {
+                    self.mark(Child::Table(&mt));
+                }
+                t.for_each_entry(|k, v| {
+                    if !weak_keys {
+                        self.mark(Child::Value(k));
+                    }
+                    if !weak_values && (!weak_keys || self.is_alive(k)) {
+                        self.mark(Child::Value(v));
+                    }
+                });
+            }
+            // open upvalue引用其他线程栈中的寄存器，寄存器的值通过它保持存活
+            GcRef::Upvalue(uv) => {
+                let Ok(uv) = uv.try_borrow() else {
+                    self.retry(obj.clone());
+                    return cost;
+                };
+                match &*uv {
+                    Upvalue::Open(stack, idx) => {
+                        if let Ok(stack) = stack.try_borrow() {
+                            if let Some(v) = stack.get(*idx) {
+                                self.mark(Child::Value(v));
+                            }
+                        }
+                        self.retry(obj.clone());
+                    }
+                    Upvalue::Closed(v) => self.mark(Child::Value(v)),
+                }
+            }
+            // 线程的栈随时在变化，原子阶段总是重新遍历
+            GcRef::Thread(t) => {
+                if let Ok(t) = t.try_borrow() {
+                    t.for_each_ref(&mut |child| self.mark(child));
+                }
+                self.retry(obj.clone());
+            }
+            obj => obj.for_each_ref(&mut |child| self.mark(child)),
+        }
+        cost
+    }
+
+    // 在原子阶段重新遍历，原子阶段中无法遍历的对象引用的白色对象都会成为根
+    fn retry(&mut self, obj: GcRef) {
+        if !self.atomic {
+            self.gray_again.push(obj);
+        }
+    }
+
+    // 标记所有可达对象，然后反复遍历弱键表(ephemeron)，直到没有新的值因为key可达而被标记
+    fn propagate_all(&mut self) {
+        loop {
+            while let Some(obj) = self.gray.pop() {
+                self.traverse(obj);
+            }
+            let ephemerons: Vec<_> = self
+                .weak
+                .values()
+                .filter(|(_, wk, wv)| *wk && !*wv)
+                .map(|(t, _, _)| t.clone())
+                .collect();
+            for t in ephemerons {
+                let Ok(t) = t.try_borrow() else {
+                    continue;
+                };
+                t.for_each_entry(|k, v| {
+                    if self.is_alive(k) {
+                        self.mark(Child::Value(v));
+                    }
+                });
+            }
+            if self.gray.is_empty() {
+                break;
+            }
+        }
+    }
+
+    /// 原子阶段，调用者已经重新标记了根
+    /// finalizers中不可达的对象被移出并复活，它们引用的对象在这一轮中都不会被清空
+    pub fn atomic(&mut self, finalizers: &mut Vec<LuaValue>) {
+        self.end_scan();
+        for obj in mem::take(&mut self.gray_again) {
+            self.colors.insert(obj.addr(), Color::Gray);
+            self.gray.push(obj);
+        }
+        for (t, _, _) in mem::take(&mut self.weak).into_values() {
+            self.colors.insert(addr(&t), Color::Gray);
+            self.gray.push(GcRef::Table(t));
+        }
+        self.propagate_all();
+
+        // 扫描之后可能已经被标记或者释放的候选对象不再是白色的
+        let whites: Vec<GcRef> = mem::take(&mut self.candidates)
+            .iter()
+            .filter_map(GcObject::upgrade)
+            .filter(|obj| !self.colors.contains_key(&obj.addr()))
+            .collect();
+        self.whites = whites.iter().enumerate().map(|(i, obj)| (obj.addr(), i)).collect();
+        self.atomic = true;
+        // 白色的key现在才能确定，弱键表中key不是白色的值都要标记
+        self.propagate_all();
+
+        // 外部引用数 = 引用计数 - 这里持有的引用 - 白色对象、弱引用和终结列表持有的引用
+        // 已标记的弱表中引用白色对象的项都是弱引用，被删除的key也不算引用
+        let mut refs: Vec<isize> = whites
+            .iter()
+            .map(|obj| obj.strong_count() as isize - 1)
+            .collect();
+        let mut unref = |child: Child| {
+            if let Some(i) = self.is_white(&child) {
+                refs[i] -= 1;
+            }
+        };
+        for obj in &whites {
+            obj.for_each_ref(&mut unref);
+        }
+        for (t, _, _) in self.weak.values() {
+            if let Ok(t) = t.try_borrow() {
+                t.for_each_ref(&mut unref);
+            }
+        }
+        for (a, t) in &self.dead_keys {
+            if self.weak.contains_key(a) {
+                continue;
+            }
+            if let Ok(t) = t.try_borrow() {
+                t.dead_keys().for_each(|k| unref(Child::Value(k)));
+            }
+        }
+        for obj in finalizers.iter() {
+            unref(Child::Value(obj));
+        }
+        for (obj, r) in whites.iter().zip(refs) {
+            if r > 0 {
+                self.colors.insert(obj.addr(), Color::Gray);
+                self.gray.push(obj.clone());
+            }
+        }
+        self.propagate_all();
+
+        // 与Lua相同，弱值在复活需要终结的对象之前清除，弱键在之后清除
+        self.clear_weak(false);
+        let mut to_finalize = Vec::new();
+        finalizers.retain(|obj| {
+            if self.is_alive(obj) {
+                true
+            } else {
+                to_finalize.push(obj.clone());
+                false
+            }
+        });
+        for obj in &to_finalize {
+            self.mark(Child::Value(obj));
+        }
+        self.propagate_all();
+        self.clear_weak(true);
+
+        self.garbage = whites
+            .into_iter()
+            .filter(|obj| !self.colors.contains_key(&obj.addr()))
+            .collect();
+        self.to_finalize = to_finalize;
+        self.reset_marks();
+        self.phase = Phase::Sweep;
+    }
+
+    // 清除弱表中引用了不可达对象的项，keys为false时只清除弱值
+    // keys为true时同时把表中不可达的被删除的key替换为NaN
+    fn clear_weak(&self, keys: bool) {
+        for (t, weak_keys, weak_values) in self.weak.values() {
+            if !keys && !weak_values {
+                continue;
+            }
+            if let Ok(mut t) = t.try_borrow_mut() {
+                t.clear_dead(*weak_keys && keys, *weak_values, |v| self.is_alive(v));
+            }
+        }
+        if !keys {
+            return;
+        }
+        for (a, t) in &self.dead_keys {
+            if self.weak.contains_key(a) {
+                continue;
+            }
+            if let Ok(mut t) = t.try_borrow_mut() {
+                t.clear_dead(false, false, |v| self.is_alive(v));
+            }
+        }
+    }
+
+    /// 增量清除，返回这一步要清空的对象，全部清空后回到Pause阶段并带上需要终结的对象
+    pub fn sweep(&mut self, budget: &mut isize) -> Garbage {
+        let mut objects = Vec::new();
+        while *budget > 0 {
+            let Some(obj) = self.garbage.pop() else {
+                break;
+            };
+            let size = obj.memory();
+            self.survived = self.survived.saturating_sub(size);
+            *budget -= SCAN_COST;
+            objects.push(obj);
+        }
+        let to_finalize = if self.garbage.is_empty() {
+            self.phase = Phase::Pause;
+            mem::take(&mut self.to_finalize)
+        } else {
+            Vec::new()
+        };
+        Garbage {
+            objects,
+            to_finalize,
+        }
+    }
+
+    // 一轮回收结束，重新估计存活内存，other是被跟踪对象以外的内存(如字符串)
+    pub fn finish(&mut self, other: usize) {
+        self.estimate = self.survived + other;
+        self.prune_at = (self.objects.len() * 2).max(1024);
+        self.debt = 0;
+    }
+}
+
+impl GcObject {
+    fn upgrade(&self) -> Option<GcRef> {
+        match self {
+            GcObject::Table(t) => t.upgrade().map(GcRef::Table),
+            GcObject::Closure(c) => c.upgrade().map(GcRef::Closure),
+            GcObject::Upvalue(uv) => uv.upgrade().map(GcRef::Upvalue),
+            GcObject::UserData(u) => u.upgrade().map(GcRef::UserData),
+            GcObject::Thread(t) => t.upgrade().map(GcRef::Thread),
+        }
+    }
+}
+
+// 用对象的地址作为标识
+fn addr<T: ?Sized>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+impl Child<'_> {
+    fn addr(&self) -> Option<usize> {
+        match self {
+            Child::Value(LuaValue::Table(t)) => Some(addr(t)),
+            Child::Value(LuaValue::Function(f)) => Some(addr(f)),
+            Child::Value(LuaValue::UserData(u)) => Some(addr(u)),
+            Child::Value(LuaValue::Thread(t)) => Some(addr(t)),
+            Child::Value(_) => None,
+            Child::Table(t) => Some(addr(t)),
+            Child::Closure(f) => Some(addr(f)),
+            Child::Upvalue(uv) => Some(addr(uv)),
+        }
+    }
+
+    fn to_ref(&self) -> Option<GcRef> {
+        match self {
+            Child::Value(LuaValue::Table(t)) => Some(GcRef::Table(t.clone())),
+            Child::Value(LuaValue::Function(f)) => Some(GcRef::Closure(f.clone())),
+            Child::Value(LuaValue::UserData(u)) => Some(GcRef::UserData(u.clone())),
+            Child::Value(LuaValue::Thread(t)) => Some(GcRef::Thread(t.clone())),
+            Child::Value(_) => None,
+            Child::Table(t) => Some(GcRef::Table(Rc::clone(t))),
+            Child::Closure(f) => Some(GcRef::Closure(Rc::clone(f))),
+            Child::Upvalue(uv) => Some(GcRef::Upvalue(Rc::clone(uv))),
+        }
+    }
+}
+
+impl GcRef {
+    fn addr(&self) -> usize {
+        match self {
+            GcRef::Table(t) => addr(t),
+            GcRef::Closure(f) => addr(f),
+            GcRef::Upvalue(uv) => addr(uv),
+            GcRef::UserData(u) => addr(u),
+            GcRef::Thread(t) => addr(t),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            GcRef::Table(t) => Rc::strong_count(t),
+            GcRef::Closure(f) => Rc::strong_count(f),
+            GcRef::Upvalue(uv) => Rc::strong_count(uv),
+            GcRef::UserData(u) => Rc::strong_count(u),
+            GcRef::Thread(t) => Rc::strong_count(t),
+        }
+    }
+
+    // 遍历对象持有的所有强引用，正在被借用的对象无法遍历，它引用的对象都会被当作根
+    fn for_each_ref(&self, f: &mut dyn FnMut(Child)) {
+        match self {
+            GcRef::Table(t) => {
+                if let Ok(t) = t.try_borrow() {
+                    t.for_each_ref(f);
+                }
+            }
+            GcRef::Closure(c) => match &**c {
+                Closure::Lua(c) => c.upvalues.iter().for_each(|uv| f(Child::Upvalue(uv))),
+                Closure::Native(c) => c.upvalues.iter().for_each(|v| f(Child::Value(v))),
+            },
+            GcRef::Upvalue(uv) => {
+                if let Ok(uv) = uv.try_borrow() {
+                    if let Upvalue::Closed(v) = &*uv {
+                        f(Child::Value(v));
+                    }
+                }
+            }
+            GcRef::UserData(u) => u.for_each_ref(f),
+            GcRef::Thread(t) => {
+                if let Ok(t) = t.try_borrow() {
+                    t.for_each_ref(f);
+                }
+            }
+        }
+    }
+
+    fn memory(&self) -> usize {
+        match self {
+            GcRef::Table(t) => t.try_borrow().map_or(0, |t| t.memory()),
+            GcRef::Closure(c) => {
+                let n = match &**c {
+                    Closure::Lua(c) => c.upvalues.len() * mem::size_of::<UpvalueRef>(),
+                    Closure::Native(c) => c.upvalues.len() * mem::size_of::<LuaValue>(),
+                };
+                mem::size_of::<Closure>() + n
+            }
+            GcRef::Upvalue(_) => mem::size_of::<RefCell<Upvalue>>(),
+            GcRef::UserData(u) => u.memory(),
+            GcRef::Thread(t) => t.try_borrow().map_or(0, |t| t.memory()),
+        }
+    }
+}
+
+/// 清除阶段一步的结果
+pub struct Garbage {
+    // 不可达的对象，释放全局状态的借用之后再清空
+    objects: Vec<GcRef>,
+    // 一轮回收结束时被复活的需要调用__gc的对象，按标记的顺序排列
+    pub to_finalize: Vec<LuaValue>,
+}
+
+// 根据元表的__mode字段判断表的key和value是否为弱引用
+fn weak_mode(t: &LuaTable) -> (bool, bool) {
+    let Some(mt) = t.metatable() else {
+        return (false, false);
+    };
+    let Ok(mt) = mt.try_borrow() else {
+        return (false, false);
+    };
+    match mt.get(&LuaValue::from("__mode")) {
+        LuaValue::Str(mode) => (mode.contains(&b'k'), mode.contains(&b'v')),
+        _ => (false, false),
+    }
+}
+
+impl Garbage {
+    /// 清空不可达对象的内容，打断它们之间的环，对象随后由引用计数释放
+    /// 必须在释放全局状态的借用之后调用，对象被释放时可能访问全局状态
+    pub fn release(self) {
+        let (threads, others): (Vec<_>, Vec<_>) = self
+            .objects
+            .into_iter()
+            .partition(|obj| matches!(obj, GcRef::Thread(_)));
+        // 先关闭线程的upvalue，之后再清空upvalue，避免关闭时重新引用不可达的值
+        for obj in threads.iter().chain(&others) {
+            match obj {
+                GcRef::Table(t) => {
+                    let old = t.try_borrow_mut().map(|mut t| mem::take(&mut *t));
+                    drop(old);
+                }
+                GcRef::Closure(_) => {}
+                GcRef::Upvalue(uv) => {
+                    let old = uv
+                        .try_borrow_mut()
+                        .map(|mut uv| mem::replace(&mut *uv, Upvalue::Closed(LuaValue::Nil)));
+                    drop(old);
+                }
+                GcRef::UserData(u) => u.set_metatable(None),
+                GcRef::Thread(t) => {
+                    if let Ok(mut t) = t.try_borrow_mut() {
+                        t.release();
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::compiler::compile;
+    use crate::stdlib::open_libs;
+    use crate::vm::error::LuaResult;
+    use crate::vm::lua_state::LuaState;
+    use crate::vm::lua_value::LuaValue;
+
+    fn run_source(state: &mut LuaState, src: &str) -> LuaResult<Vec<LuaValue>> {
+        let proto = compile(src.as_bytes(), "=test").unwrap();
+        let f = state.load(proto).unwrap();
+        state.call(f, vec![])
+    }
+
+    fn new_state() -> LuaState {
+        let mut state = LuaState::new();
+        open_libs(&mut state);
+        state
+    }
+
+    #[test]
+    fn test_cycles() {
+        let mut state = new_state();
+        let results = run_source(
+            &mut state,
+            "
+            local weak = setmetatable({}, {__mode = 'v'})
+            local function make()
+                local t = {}
+                t.self = t
+                local holder = {}
+                holder.f = function() return holder end
+                weak[1], weak[2] = t, holder.f
+            end
+            make()
+            local before = weak[1] ~= nil and weak[2] ~= nil
+            collectgarbage()
+            return before, weak[1], weak[2]
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            results,
+            vec![LuaValue::Boolean(true), LuaValue::Nil, LuaValue::Nil]
+        );
+
+        // 宿主程序持有的对象是根，即使它只被环引用
+        let t = run_source(&mut state, "local t = {x = 1}; t.self = t; return t").unwrap();
+        let before = state.memory_used();
+        state.collect_garbage().unwrap();
+        let LuaValue::Table(t) = &t[0] else { panic!() };
+        assert_eq!(t.borrow().get(&LuaValue::from("x")), LuaValue::Integer(1));
+        assert!(Rc::strong_count(t) > 1);
+        assert!(state.memory_used() <= before);
+    }
+
+    #[test]
+    fn test_weak_tables() {
+        let mut state = new_state();
+        let results = run_source(
+            &mut state,
+            "
+            local wk = setmetatable({}, {__mode = 'k'})
+            local wv = setmetatable({}, {__mode = 'v'})
+            local kept = {}
+            wk[kept], wk[{}] = 1, 2
+            wv.a, wv.b, wv.s = kept, {}, 'string'
+            -- 值只被自己的key引用的ephemeron项可以被回收，key可达时值也可达
+            local key = {}
+            wk[key] = {key}
+            wk[{}] = {'cycle'}
+            collectgarbage()
+            local n = 0
+            for k, v in pairs(wk) do n = n + 1 end
+            return n, wk[kept], wk[key][1] == key, wv.a == kept, wv.b, wv.s
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                LuaValue::Integer(2),
+                LuaValue::Integer(1),
+                LuaValue::Boolean(true),
+                LuaValue::Boolean(true),
+                LuaValue::Nil,
+                LuaValue::from("string"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_gc_metamethod() {
+        let mut state = new_state();
+        let results = run_source(
+            &mut state,
+            "
+            local log = {}
+            local mt = {__gc = function(o) log[#log + 1] = o.name; o.self = nil end}
+            local function make()
+                local a = setmetatable({name = 'a'}, mt)
+                local b = setmetatable({name = 'b', other = a}, mt)
+                a.self, b.self = a, b
+            end
+            make()
+            collectgarbage()
+            local first = #log
+            collectgarbage()
+            return first, log[1], log[2], #log
+            ",
+        )
+        .unwrap();
+        // 按标记的相反顺序调用，被复活的a在__gc中仍然保留内容
+        assert_eq!(
+            results,
+            vec![
+                LuaValue::Integer(2),
+                LuaValue::from("b"),
+                LuaValue::from("a"),
+                LuaValue::Integer(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_incremental() {
+        let mut state = new_state();
+        let results = run_source(
+            &mut state,
+            "
+            collectgarbage('stop')
+            local weak = setmetatable({}, {__mode = 'v'})
+            local live = {}
+            for i = 1, 2000 do live[i] = {i} end
+            local cycle = {}
+            cycle.self = cycle
+            weak[1], cycle = cycle, nil
+            -- 标记期间继续修改已遍历的表和upvalue，写屏障保证新写入的值不被回收
+            local kept
+            local function keep(v) kept = v end
+            local steps, done = 0, false
+            while not done do
+                steps = steps + 1
+                live[#live + 1] = {#live + 1}
+                keep({'kept'})
+                done = collectgarbage('step', 1)
+            end
+            local ok = true
+            for i = 1, #live do ok = ok and live[i][1] == i end
+            return steps > 1, weak[1], ok, kept[1]
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                LuaValue::Boolean(true),
+                LuaValue::Nil,
+                LuaValue::Boolean(true),
+                LuaValue::from("kept"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_weak_values_mid_cycle() {
+        let mut state = new_state();
+        let results = run_source(
+            &mut state,
+            "
+            collectgarbage('stop')
+            local root = {}
+            for i = 1, 3000 do root[i] = {i} end
+            local weak = setmetatable({}, {__mode = 'v'})
+            weak.old = {'old'}
+            local finished = collectgarbage('step', 1)
+            -- 标记到一半时写入弱表，强引用的值存活，只被弱引用的值被清除
+            local kept = {'kept'}
+            weak.kept, weak.new = kept, {'new'}
+            local done = false
+            while not done do done = collectgarbage('step', 1) end
+            -- 本轮开始之后创建的对象在本轮中存活，下一轮才被回收
+            local stepped = {weak.kept == kept, weak.old, weak.new ~= nil, finished}
+            collectgarbage()
+            return stepped[1], stepped[2], stepped[3], stepped[4], weak.kept == kept, weak.new
+            ",
+        )
+        .unwrap();
+        use LuaValue::{Boolean, Nil};
+        assert_eq!(
+            results,
+            vec![Boolean(true), Nil, Boolean(true), Boolean(false), Boolean(true), Nil]
+        );
+    }
+
+    #[test]
+    fn test_gc_errors() {
+        let mut state = new_state();
+        let results = run_source(
+            &mut state,
+            "
+            local log = {}
+            setmetatable({}, {__gc = function() log[#log + 1] = 'second' end})
+            setmetatable({}, {__gc = function() error('first') end})
+            local ok, err = pcall(collectgarbage)
+            setmetatable({}, {__gc = function() error({}) end})
+            local _, obj = pcall(collectgarbage)
+            -- 出错的__gc不影响其他对象的__gc，对象也不会再次被终结
+            local again = pcall(collectgarbage)
+            local _, opt = pcall(collectgarbage, 1)
+            local _, arg = pcall(collectgarbage, 'step', 'x')
+            return ok, err, #log, obj, again, opt, arg
+            ",
+        )
+        .unwrap();
+        let results: Vec<String> = results.iter().map(LuaValue::to_string).collect();
+        assert_eq!(
+            results,
+            vec![
+                "false",
+                "error in __gc metamethod (test:4: first)",
+                "1",
+                "error in __gc metamethod (no message)",
+                "true",
+                "bad argument #1 to 'collectgarbage' (invalid option '1')",
+                "bad argument #2 to 'collectgarbage' (number expected, got string)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collectgarbage() {
+        let mut state = new_state();
+        let results = run_source(
+            &mut state,
+            "
+            local count = collectgarbage('count')
+            collectgarbage('stop')
+            local stopped = collectgarbage('isrunning')
+            local keep = {}
+            for i = 1, 10000 do keep[i] = {i} end
+            local grown = collectgarbage('count') > count
+            collectgarbage('restart')
+            local pause = collectgarbage('setpause', 100)
+            -- 每一步只做有限的工作，一轮回收结束时step才返回true
+            local steps = 1
+            while not collectgarbage('step', 0) do steps = steps + 1 end
+            return type(count), stopped, grown, collectgarbage('isrunning'), pause,
+                collectgarbage('setpause', pause), steps > 1
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                LuaValue::from("number"),
+                LuaValue::Boolean(false),
+                LuaValue::Boolean(true),
+                LuaValue::Boolean(true),
+                LuaValue::Integer(200),
+                LuaValue::Integer(100),
+                LuaValue::Boolean(true),
+            ]
+        );
+        let err = run_source(&mut state, "collectgarbage('bogus')").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test:1: bad argument #1 to 'collectgarbage' (invalid option 'bogus')"
+        );
+    }
+}
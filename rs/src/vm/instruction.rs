@@ -1,10 +1,14 @@
-use crate::vm::opcode;
+use std::fmt;
+
+use crate::vm::opcode::{self, Op};
 
 /// Lua虚拟机指令
 /// 通过本地trait的方式，能够给u32实现虚拟机指令需要的方法
-pub trait Instruction {
+pub trait Instruction: Sized {
     // 从指令中解码op_code
     fn op_code(self) -> u8;
+    // 从指令中解码操作码，未知的操作码返回None
+    fn op(self) -> Option<Op>;
     // I_ABC模式，提取参数
     fn abc(self) -> (isize, isize, isize);
     // I_ABx模式，提取参数
@@ -13,18 +17,114 @@ pub trait Instruction {
     fn asbx(self) -> (isize, isize);
     // I_Ax模式，提取参数
     fn ax(self) -> isize;
-    // 获取op name
-    fn op_name(self) -> &'static str;
+    // 获取op name，未知的操作码返回None
+    fn op_name(self) -> Option<&'static str>;
     // 获取op mode
-    fn op_mode(self) -> u8;
+    fn op_mode(self) -> Option<u8>;
     // 获取b arg mode
-    fn b_arg_mode(self) -> u8;
+    fn b_arg_mode(self) -> Option<u8>;
     // 获取c arg mode
-    fn c_arg_mode(self) -> u8;
+    fn c_arg_mode(self) -> Option<u8>;
+    // I_ABC模式，编码指令，B、C是常量索引时需要用rk_constant加上常量标志
+    fn encode_abc(op: Op, a: isize, b: isize, c: isize) -> Result<Self, EncodeError>;
+    // I_ABx模式，编码指令
+    fn encode_abx(op: Op, a: isize, bx: isize) -> Result<Self, EncodeError>;
+    // I_AsBx模式，编码指令
+    fn encode_asbx(op: Op, a: isize, sbx: isize) -> Result<Self, EncodeError>;
+    // I_Ax模式，编码指令
+    fn encode_ax(op: Op, ax: isize) -> Result<Self, EncodeError>;
+}
+
+pub const MAX_ARG_A: isize = (1 << 8) - 1;
+pub const MAX_ARG_B: isize = (1 << 9) - 1;
+pub const MAX_ARG_C: isize = (1 << 9) - 1;
+pub const MAX_ARG_BX: isize = (1 << 18) - 1;
+pub const MAX_ARG_SBX: isize = MAX_ARG_BX >> 1;
+pub const MAX_ARG_AX: isize = (1 << 26) - 1;
+
+// RK操作数中表示常量的标志位，低8位是常量索引
+pub const BIT_RK: isize = 1 << 8;
+pub const MAX_INDEX_RK: isize = BIT_RK - 1;
+
+// 把常量索引转换为RK操作数，索引超出MAX_INDEX_RK时返回None
+pub fn rk_constant(index: isize) -> Option<isize> {
+    (0..=MAX_INDEX_RK).contains(&index).then_some(index | BIT_RK)
+}
+
+// RK操作数是否表示常量
+pub fn is_constant(rk: isize) -> bool {
+    rk & BIT_RK != 0
+}
+
+/// 编码指令时的错误
+#[derive(Debug, Clone, PartialEq)]
+pub enum EncodeError {
+    // 操作数超出了字段或参数模式允许的范围
+    OutOfRange {
+        op: Op,
+        operand: &'static str,
+        value: isize,
+    },
+    // 操作码的编码模式与使用的编码方法不一致
+    WrongMode { op: Op, mode: u8 },
 }
 
-const MAX_ARG_BX: isize = (1 << 18) - 1;
-const MAX_ARG_SBX: isize = MAX_ARG_BX >> 1;
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::OutOfRange { op, operand, value } => {
+                write!(f, "{} operand {operand} out of range: {value}", op.name())
+            }
+            EncodeError::WrongMode { op, mode } => {
+                let mode = match *mode {
+                    opcode::OP_MODE_ABC => "iABC",
+                    opcode::OP_MODE_ABX => "iABx",
+                    opcode::OP_MODE_ASBX => "iAsBx",
+                    _ => "iAx",
+                };
+                write!(f, "{} is encoded in {mode} mode", op.name())
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+// 检查操作数是否在min..=max之内
+fn check(
+    op: Op,
+    operand: &'static str,
+    value: isize,
+    min: isize,
+    max: isize,
+) -> Result<isize, EncodeError> {
+    if value < min || value > max {
+        return Err(EncodeError::OutOfRange { op, operand, value });
+    }
+    Ok(value)
+}
+
+// 按参数模式检查B、C操作数：N必须为0，R只能是寄存器，U和K可以使用整个字段
+fn check_arg(
+    op: Op,
+    operand: &'static str,
+    value: isize,
+    arg_mode: u8,
+    max: isize,
+) -> Result<isize, EncodeError> {
+    match arg_mode {
+        opcode::OP_ARG_N => check(op, operand, value, 0, 0),
+        opcode::OP_ARG_R => check(op, operand, value, 0, MAX_ARG_A),
+        _ => check(op, operand, value, 0, max),
+    }
+}
+
+fn check_mode(op: Op, mode: u8) -> Result<(), EncodeError> {
+    if op.info().op_mode != mode {
+        return Err(EncodeError::WrongMode { op, mode: op.info().op_mode });
+    }
+    Ok(())
+}
 
 impl Instruction for u32 {
     // 从指令中解码op_code
@@ -32,12 +132,16 @@ impl Instruction for u32 {
         self as u8 & 0x3F
     }
 
+    // 从指令中解码操作码，未知的操作码返回None
+    fn op(self) -> Option<Op> {
+        Op::from_u8(self.op_code())
+    }
+
     // I_ABC模式，提取参数
     fn abc(self) -> (isize, isize, isize) {
         let a = (self >> 6 & 0xFF) as isize;
-        let b = (self >> 14 & 0x1FF) as isize;
-        let c = (self >> 23 & 0x1FF) as isize;
-        println!("{a} {b} {c}");
+        let c = (self >> 14 & 0x1FF) as isize;
+        let b = (self >> 23 & 0x1FF) as isize;
         (a, b, c)
     }
 
@@ -59,23 +163,194 @@ impl Instruction for u32 {
         (self >> 6) as isize
     }
 
-    // 获取op name
-    fn op_name(self) -> &'static str {
-        opcode::OP_CODES[self.op_code() as usize].name
+    // 获取op name，未知的操作码返回None
+    fn op_name(self) -> Option<&'static str> {
+        self.op().map(|op| op.info().name)
     }
 
     // 获取op mode
-    fn op_mode(self) -> u8 {
-        opcode::OP_CODES[self.op_code() as usize].op_mode
+    fn op_mode(self) -> Option<u8> {
+        self.op().map(|op| op.info().op_mode)
     }
 
     // 获取b arg mode
-    fn b_arg_mode(self) -> u8 {
-        opcode::OP_CODES[self.op_code() as usize].b_arg_mode
+    fn b_arg_mode(self) -> Option<u8> {
+        self.op().map(|op| op.info().b_arg_mode)
     }
 
     // 获取c arg mode
-    fn c_arg_mode(self) -> u8 {
-        opcode::OP_CODES[self.op_code() as usize].c_arg_mode
+    fn c_arg_mode(self) -> Option<u8> {
+        self.op().map(|op| op.info().c_arg_mode)
+    }
+
+    // I_ABC模式，编码指令，是abc的逆过程
+    fn encode_abc(op: Op, a: isize, b: isize, c: isize) -> Result<u32, EncodeError> {
+        check_mode(op, opcode::OP_MODE_ABC)?;
+        let a = check(op, "A", a, 0, MAX_ARG_A)?;
+        let b = check_arg(op, "B", b, op.info().b_arg_mode, MAX_ARG_B)?;
+        let c = check_arg(op, "C", c, op.info().c_arg_mode, MAX_ARG_C)?;
+        Ok((b as u32) << 23 | (c as u32) << 14 | (a as u32) << 6 | op as u32)
+    }
+
+    // I_ABx模式，编码指令，是abx的逆过程
+    fn encode_abx(op: Op, a: isize, bx: isize) -> Result<u32, EncodeError> {
+        check_mode(op, opcode::OP_MODE_ABX)?;
+        let a = check(op, "A", a, 0, MAX_ARG_A)?;
+        let bx = check_arg(op, "Bx", bx, op.info().b_arg_mode, MAX_ARG_BX)?;
+        Ok((bx as u32) << 14 | (a as u32) << 6 | op as u32)
+    }
+
+    // I_AsBx模式，编码指令，是asbx的逆过程
+    fn encode_asbx(op: Op, a: isize, sbx: isize) -> Result<u32, EncodeError> {
+        check_mode(op, opcode::OP_MODE_ASBX)?;
+        let a = check(op, "A", a, 0, MAX_ARG_A)?;
+        let sbx = check(op, "sBx", sbx, -MAX_ARG_SBX, MAX_ARG_BX - MAX_ARG_SBX)?;
+        Ok(((sbx + MAX_ARG_SBX) as u32) << 14 | (a as u32) << 6 | op as u32)
+    }
+
+    // I_Ax模式，编码指令，是ax的逆过程
+    fn encode_ax(op: Op, ax: isize) -> Result<u32, EncodeError> {
+        check_mode(op, opcode::OP_MODE_AX)?;
+        let ax = check(op, "Ax", ax, 0, MAX_ARG_AX)?;
+        Ok((ax as u32) << 6 | op as u32)
+    }
+}
+
+const MAX_ARG_BX_54: isize = (1 << 17) - 1;
+const OFFSET_SBX_54: isize = MAX_ARG_BX_54 >> 1;
+const OFFSET_SJ_54: isize = ((1 << 25) - 1) >> 1;
+const OFFSET_SC_54: isize = 0xFF >> 1;
+
+/// Lua5.4虚拟机指令
+/// 5.4的字段布局与5.3不同，用新类型包装u32，避免与Instruction的方法混用
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction54(pub u32);
+
+impl Instruction54 {
+    // 从指令中解码op_code
+    pub fn op_code(self) -> u8 {
+        (self.0 & 0x7F) as u8
+    }
+
+    // I_ABC模式，提取参数A、B、C和k标志
+    pub fn abck(self) -> (isize, isize, isize, bool) {
+        let a = (self.0 >> 7 & 0xFF) as isize;
+        let k = self.0 >> 15 & 0x1 != 0;
+        let b = (self.0 >> 16 & 0xFF) as isize;
+        let c = (self.0 >> 24 & 0xFF) as isize;
+        (a, b, c, k)
+    }
+
+    // B、C作为有符号立即数(sB、sC)时的值
+    pub fn signed(arg: isize) -> isize {
+        arg - OFFSET_SC_54
+    }
+
+    // I_ABx模式，提取参数
+    pub fn abx(self) -> (isize, isize) {
+        let a = (self.0 >> 7 & 0xFF) as isize;
+        let bx = (self.0 >> 15) as isize;
+        (a, bx)
+    }
+
+    // I_AsBx模式，提取参数
+    pub fn asbx(self) -> (isize, isize) {
+        let (a, bx) = self.abx();
+        (a, bx - OFFSET_SBX_54)
+    }
+
+    // I_Ax模式，提取参数
+    pub fn ax(self) -> isize {
+        (self.0 >> 7) as isize
+    }
+
+    // I_sJ模式，提取参数
+    pub fn sj(self) -> isize {
+        (self.0 >> 7) as isize - OFFSET_SJ_54
+    }
+
+    // 获取op name，未知的操作码返回None
+    pub fn op_name(self) -> Option<&'static str> {
+        opcode::lua54::OP_CODES.get(self.op_code() as usize).map(|op| op.name)
+    }
+
+    // 获取op mode
+    pub fn op_mode(self) -> Option<u8> {
+        opcode::lua54::OP_CODES.get(self.op_code() as usize).map(|op| op.op_mode)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::vm::instruction::{rk_constant, EncodeError, Instruction, Instruction54};
+    use crate::vm::opcode::lua54::*;
+    use crate::vm::opcode::Op;
+
+    #[test]
+    fn test_encode() {
+        let i = u32::encode_abc(Op::GetTabUp, 0, 0, rk_constant(1).unwrap()).unwrap();
+        assert_eq!(i.op(), Some(Op::GetTabUp));
+        assert_eq!(i.abc(), (0, 0, 0x101));
+        assert_eq!(u32::encode_abx(Op::LoadK, 1, 300).unwrap().abx(), (1, 300));
+        assert_eq!(u32::encode_asbx(Op::Jmp, 0, -131071).unwrap().asbx(), (0, -131071));
+        assert_eq!(u32::encode_ax(Op::ExtraArg, 1 << 20).unwrap().ax(), 1 << 20);
+        assert_eq!(rk_constant(256), None);
+        assert_eq!(Op::from_u8(47), None);
+        assert_eq!(0x3Fu32.op(), None);
+        // 47到63不是5.3的操作码
+        assert_eq!(47u32.op_name(), None);
+        assert_eq!(0x3Fu32.op_mode(), None);
+        assert_eq!(0x3Fu32.b_arg_mode(), None);
+        assert_eq!(0x3Fu32.c_arg_mode(), None);
+
+        let out_of_range = |op, operand, value| EncodeError::OutOfRange { op, operand, value };
+        assert_eq!(u32::encode_abc(Op::Move, 256, 0, 0), Err(out_of_range(Op::Move, "A", 256)));
+        // R模式的操作数不能是常量，N模式的操作数必须为0
+        assert_eq!(
+            u32::encode_abc(Op::Move, 0, 0x100, 0),
+            Err(out_of_range(Op::Move, "B", 0x100))
+        );
+        assert_eq!(u32::encode_abc(Op::Move, 0, 1, 1), Err(out_of_range(Op::Move, "C", 1)));
+        assert_eq!(
+            u32::encode_asbx(Op::ForLoop, 0, 131073),
+            Err(out_of_range(Op::ForLoop, "sBx", 131073))
+        );
+        assert_eq!(
+            u32::encode_abc(Op::LoadK, 0, 0, 0),
+            Err(EncodeError::WrongMode { op: Op::LoadK, mode: 1 })
+        );
+        assert_eq!(
+            u32::encode_abc(Op::LoadK, 0, 0, 0).unwrap_err().to_string(),
+            "LOADK is encoded in iABx mode"
+        );
+    }
+
+    #[test]
+    fn test_decode_54() {
+        // GETTABUP 0 0 0
+        let i = Instruction54(0x0000_000B);
+        assert_eq!(i.op_code(), OP_GETTABUP);
+        assert_eq!(i.op_name(), Some("GETTABUP"));
+        assert_eq!(i.abck(), (0, 0, 0, false));
+
+        // SETFIELD 1 2 3k
+        let i = Instruction54(OP_SETFIELD as u32 | 1 << 7 | 1 << 15 | 2 << 16 | 3 << 24);
+        assert_eq!(i.abck(), (1, 2, 3, true));
+
+        // ADDI 0 1 -1
+        let i = Instruction54(OP_ADDI as u32 | 1 << 16 | 126 << 24);
+        let (_, _, c, _) = i.abck();
+        assert_eq!(Instruction54::signed(c), -1);
+
+        // LOADI 0 -5
+        let i = Instruction54(OP_LOADI as u32 | ((65535 - 5) << 15));
+        assert_eq!(i.asbx(), (0, -5));
+
+        // JMP 3
+        let i = Instruction54(OP_JMP as u32 | ((16777215 + 3) << 7));
+        assert_eq!(i.op_mode(), Some(OP_MODE_SJ));
+        assert_eq!(i.sj(), 3);
+
+        assert_eq!(Instruction54(0x7F).op_name(), None);
     }
 }
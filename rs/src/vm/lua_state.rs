@@ -0,0 +1,2199 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::rc::{Rc, Weak};
+
+use crate::analysis::{self, VerifyError};
+use crate::binary::chunk::{Constant, LuaVersion, Prototype};
+use crate::vm::arith::{self, ArithOp};
+use crate::vm::closure::{Closure, FuncProto, NativeFn, StackRef, Upvalue, UpvalueRef};
+use crate::vm::debug;
+use crate::vm::decoded::{Decoded, Rk};
+use crate::vm::error::{LuaError, LuaResult};
+use crate::vm::gc::{Child, GcObject, Heap, Phase};
+use crate::vm::instruction::Instruction;
+use crate::vm::lua_string::StringPool;
+use crate::vm::lua_table::LuaTable;
+use crate::vm::lua_value::LuaValue;
+use crate::vm::userdata::UserData;
+
+// Rust层嵌套调用的最大深度，与Lua的LUAI_MAXCCALLS保持一致
+const MAX_CALLS: usize = 200;
+// 栈的最大长度，与Lua的LUAI_MAXSTACK保持一致
+const MAX_STACK_SIZE: usize = 1_000_000;
+// SETLIST每次最多设置的元素数量，与Lua的LFIELDS_PER_FLUSH保持一致
+const FIELDS_PER_FLUSH: usize = 50;
+// __index和__newindex链的最大长度，与Lua的MAXTAGLOOP保持一致
+const MAX_TAG_LOOP: usize = 2000;
+
+// 调用栈回溯显示的开始和最后的层数，与Lua的LEVELS1、LEVELS2保持一致
+const TRACEBACK_FIRST: usize = 10;
+const TRACEBACK_LAST: usize = 11;
+
+/// 函数调用帧
+struct CallInfo {
+    closure: Rc<Closure>,
+    proto: Rc<FuncProto>,
+    // 被调用函数在栈中的位置，返回值从这里开始存放
+    func: usize,
+    // 寄存器R(0)在栈中的位置
+    base: usize,
+    // 下一条要执行的指令
+    pc: usize,
+    // 调用者期望的返回值数量，-1表示全部返回值
+    nresults: isize,
+    // 传入的变长参数
+    varargs: Vec<LuaValue>,
+    // 调用时正在执行的Rust函数的数量，用于在调用栈中确定Rust函数的位置
+    n_native: usize,
+    // 是否由尾调用产生，尾调用的调用者已经不在调用栈中
+    tail_call: bool,
+}
+
+/// 调用栈中的一层，Lua函数是frames中的下标，Rust函数是native_calls中的下标
+/// Host是调用主线程的宿主程序，相当于lua.c中调用主函数的C函数
+#[derive(Debug, Clone, Copy)]
+enum Level {
+    Lua(usize),
+    Native(usize),
+    Host,
+}
+
+/// 所有线程共享的状态
+struct GlobalState {
+    // 全局环境，即主函数的_ENV
+    globals: LuaValue,
+    // 短字符串驻留池
+    strings: StringPool,
+    // 表和用户数据以外的类型共享的元表，key为类型名
+    type_metatables: HashMap<&'static str, Rc<RefCell<LuaTable>>>,
+    // 设置元表时元表中有__gc字段的对象，按标记的顺序排列
+    finalizers: Vec<LuaValue>,
+    heap: Heap,
+}
+
+/// 线程的状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadStatus {
+    // 主线程，不能被恢复
+    Main,
+    // 尚未开始或者已经让出
+    Suspended,
+    // 正在运行，或者恢复了其他协程正在等待它让出
+    Running,
+    // 主函数已经返回或者出错
+    Dead,
+}
+
+/// Lua解释器状态，即一个线程，包括值栈和调用帧，全局环境由所有线程共享
+/// 协程是另一个共享全局状态的LuaState，有自己的栈和调用帧
+pub struct LuaState {
+    stack: Vec<LuaValue>,
+    // 线程不在运行(恢复了其他协程、让出或结束)时，栈被移到这里
+    parked_stack: StackRef,
+    // CALL、VARARG产生多个值(B或C为0)时，记录最后一个值之后的位置
+    top: usize,
+    frames: Vec<CallInfo>,
+    // 仍处于open状态的upvalue，key为寄存器在栈中的绝对位置
+    open_upvalues: BTreeMap<usize, UpvalueRef>,
+    // 当前Rust层嵌套调用的深度
+    n_calls: usize,
+    // 正在执行的Rust函数，用于访问它们的upvalue
+    native_calls: Vec<Rc<Closure>>,
+    // 不能让出的Rust层调用的数量，为0时才能让出，主线程总是为1
+    nny: usize,
+    // 每层保护调用的消息处理函数，pcall为None
+    handlers: Vec<Option<LuaValue>>,
+    status: ThreadStatus,
+    // 协程的主函数，第一次恢复时被取出
+    body: Option<LuaValue>,
+    // 协程在Lua函数调用的Rust函数中让出，恢复时把参数作为该调用的返回值存放到这里
+    resume_point: Option<(usize, isize)>,
+    // 协程自身，用于coroutine.running
+    this: Weak<RefCell<LuaState>>,
+    // 代表主线程的值，第一次调用coroutine.running时创建
+    main_thread: Option<Rc<RefCell<LuaState>>>,
+    g: Rc<RefCell<GlobalState>>,
+}
+
+impl Default for LuaState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LuaState {
+    pub fn new() -> Self {
+        let g = GlobalState {
+            globals: LuaValue::new_table(0, 0),
+            strings: StringPool::new(),
+            type_metatables: HashMap::new(),
+            finalizers: Vec::new(),
+            heap: Heap::new(),
+        };
+        Self::with_global(Rc::new(RefCell::new(g)), ThreadStatus::Main)
+    }
+
+    fn with_global(g: Rc<RefCell<GlobalState>>, status: ThreadStatus) -> Self {
+        Self {
+            stack: Vec::new(),
+            parked_stack: Rc::new(RefCell::new(Vec::new())),
+            top: 0,
+            frames: Vec::new(),
+            open_upvalues: BTreeMap::new(),
+            n_calls: 0,
+            native_calls: Vec::new(),
+            nny: if status == ThreadStatus::Main { 1 } else { 0 },
+            handlers: Vec::new(),
+            status,
+            body: None,
+            resume_point: None,
+            this: Weak::new(),
+            main_thread: None,
+            g,
+        }
+    }
+
+    // 创建字符串，短字符串会被驻留
+    pub fn new_string(&mut self, bytes: &[u8]) -> LuaValue {
+        LuaValue::Str(self.g.borrow_mut().strings.intern(bytes))
+    }
+
+    /// 创建表，表由垃圾回收器跟踪
+    pub fn new_table(&mut self, n_arr: usize, n_rec: usize) -> LuaValue {
+        let t = Rc::new(RefCell::new(LuaTable::new(n_arr, n_rec)));
+        self.track(GcObject::Table(Rc::downgrade(&t)));
+        LuaValue::Table(t)
+    }
+
+    /// 创建用户数据，用户数据由垃圾回收器跟踪
+    pub fn new_userdata<T: Any>(&mut self, value: T) -> LuaValue {
+        let u = Rc::new(UserData::new(value));
+        self.track(GcObject::UserData(Rc::downgrade(&u)));
+        LuaValue::UserData(u)
+    }
+
+    /// 创建带upvalue的Rust函数，函数由垃圾回收器跟踪
+    pub fn new_native_closure(
+        &mut self,
+        name: &'static str,
+        func: NativeFn,
+        upvalues: Vec<LuaValue>,
+    ) -> LuaValue {
+        let f = Rc::new(Closure::new_native_closure(name, func, upvalues));
+        self.track(GcObject::Closure(Rc::downgrade(&f)));
+        LuaValue::Function(f)
+    }
+
+    fn new_lua_closure(&mut self, proto: Rc<FuncProto>, upvalues: Vec<UpvalueRef>) -> LuaValue {
+        let f = Rc::new(Closure::new_lua(proto, upvalues));
+        self.track(GcObject::Closure(Rc::downgrade(&f)));
+        LuaValue::Function(f)
+    }
+
+    fn new_upvalue(&mut self, uv: Upvalue) -> UpvalueRef {
+        let uv = Rc::new(RefCell::new(uv));
+        self.track(GcObject::Upvalue(Rc::downgrade(&uv)));
+        uv
+    }
+
+    fn track(&self, obj: GcObject) {
+        self.g.borrow_mut().heap.track(obj);
+    }
+
+    pub fn globals(&self) -> LuaValue {
+        self.g.borrow().globals.clone()
+    }
+
+    pub fn get_global(&self, name: &str) -> LuaValue {
+        match self.globals() {
+            LuaValue::Table(t) => t.borrow().get(&LuaValue::from(name)),
+            _ => LuaValue::Nil,
+        }
+    }
+
+    pub fn set_global(&mut self, name: &str, value: LuaValue) {
+        if let LuaValue::Table(t) = self.globals() {
+            t.borrow_mut().put(LuaValue::from(name), value).unwrap();
+            self.table_barrier(&t);
+        }
+    }
+
+    // 注册Rust函数为全局函数
+    pub fn register(&mut self, name: &'static str, func: NativeFn) {
+        let f = LuaValue::Function(Rc::new(Closure::new_native(name, func)));
+        self.set_global(name, f);
+    }
+
+    /// 把主函数原型实例化为闭包，第一个upvalue(_ENV)设置为全局环境
+    /// 加载前先用analysis::verify校验，虚拟机只执行通过校验的字节码
+    pub fn load(&mut self, proto: Prototype) -> Result<LuaValue, VerifyError> {
+        analysis::verify(&proto)?;
+        let upvalues = (0..proto.upvalues.len())
+            .map(|i| {
+                let value = if i == 0 { self.globals() } else { LuaValue::Nil };
+                self.new_upvalue(Upvalue::Closed(value))
+            })
+            .collect();
+        let proto = self.load_proto(Rc::new(proto));
+        Ok(self.new_lua_closure(proto, upvalues))
+    }
+
+    // 转换函数原型及其所有子函数的常量
+    fn load_proto(&mut self, proto: Rc<Prototype>) -> Rc<FuncProto> {
+        let constants = proto
+            .constants
+            .iter()
+            .map(|k| match k {
+                Constant::Str(s) => self.new_string(s),
+                k => LuaValue::from(k),
+            })
+            .collect();
+        let protos = proto
+            .protos
+            .iter()
+            .map(|p| self.load_proto(p.clone()))
+            .collect();
+        Rc::new(FuncProto {
+            proto,
+            constants,
+            protos,
+        })
+    }
+
+    /// 获取值的元表，表和用户数据有各自的元表，其他类型的值共享同一类型的元表
+    pub fn get_metatable(&self, v: &LuaValue) -> Option<Rc<RefCell<LuaTable>>> {
+        match v {
+            LuaValue::Table(t) => t.borrow().metatable(),
+            LuaValue::UserData(u) => u.metatable(),
+            _ => self.g.borrow().type_metatables.get(v.type_name()).cloned(),
+        }
+    }
+
+    /// 设置值的元表，mt为None时移除元表
+    /// 与Lua相同，只有设置元表时元表中已有__gc字段的对象才会在关闭时被终结
+    pub fn set_metatable(&mut self, v: &LuaValue, mt: Option<Rc<RefCell<LuaTable>>>) {
+        let has_gc = mt
+            .as_ref()
+            .is_some_and(|mt| !mt.borrow().get(&LuaValue::from("__gc")).is_nil());
+        match v {
+            LuaValue::Table(t) => t.borrow_mut().set_metatable(mt.clone()),
+            LuaValue::UserData(u) => u.set_metatable(mt.clone()),
+            _ => {
+                let old = match mt {
+                    Some(mt) => self.g.borrow_mut().type_metatables.insert(v.type_name(), mt),
+                    None => self.g.borrow_mut().type_metatables.remove(v.type_name()),
+                };
+                // 旧的元表在释放借用之后再丢弃
+                drop(old);
+                return;
+            }
+        }
+        let mut g = self.g.borrow_mut();
+        match (v, &mt) {
+            (LuaValue::Table(t), _) => g.heap.barrier_back(t),
+            (_, Some(mt)) => g.heap.barrier(Child::Value(v), Child::Table(mt)),
+            _ => {}
+        }
+        if has_gc && !g.finalizers.iter().any(|obj| obj.raw_equals(v)) {
+            g.finalizers.push(v.clone());
+        }
+    }
+
+    /// 获取元表中的字段，没有元表时返回nil
+    pub fn metafield(&self, v: &LuaValue, event: &str) -> LuaValue {
+        match self.get_metatable(v) {
+            Some(mt) => mt.borrow().get(&LuaValue::from(event)),
+            None => LuaValue::Nil,
+        }
+    }
+
+    /// 关闭状态，按标记的相反顺序调用所有对象的__gc元方法，忽略其中的错误
+    pub fn close(&mut self) {
+        loop {
+            let Some(obj) = self.g.borrow_mut().finalizers.pop() else {
+                break;
+            };
+            let gc = self.metafield(&obj, "__gc");
+            if let LuaValue::Function(_) = gc {
+                let _ = self.call(gc, vec![obj]);
+            }
+        }
+    }
+
+    /// 执行一轮完整的垃圾回收，然后按标记的相反顺序调用不可达对象的__gc元方法
+    /// 所有__gc都会被调用，返回其中的第一个错误
+    pub fn collect_garbage(&mut self) -> LuaResult<()> {
+        // 先完成正在清除的一轮，标记到一半的一轮直接放弃，然后从头执行完整的一轮
+        let mut result = Ok(false);
+        if self.g.borrow().heap.phase() == Phase::Sweep {
+            result = self.gc_run(isize::MAX);
+        }
+        self.g.borrow_mut().heap.abandon();
+        let full = self.gc_run(isize::MAX);
+        result.and(full).map(|_| ())
+    }
+
+    // 标记根：全局环境、类型元表和当前线程
+    fn mark_roots(&mut self) {
+        let mut g = self.g.borrow_mut();
+        let g = &mut *g;
+        g.heap.mark(Child::Value(&g.globals));
+        for mt in g.type_metatables.values() {
+            g.heap.mark(Child::Table(mt));
+        }
+        self.for_each_ref(&mut |child| g.heap.mark(child));
+    }
+
+    // 推进回收直到用完budget或者一轮回收结束，返回是否结束了一轮回收
+    fn gc_run(&mut self, mut budget: isize) -> LuaResult<bool> {
+        while budget > 0 {
+            let phase = self.g.borrow().heap.phase();
+            match phase {
+                Phase::Pause => {
+                    self.g.borrow_mut().heap.start();
+                    self.mark_roots();
+                }
+                Phase::Propagate => {
+                    if self.g.borrow_mut().heap.propagate(&mut budget) {
+                        // 线程的栈在标记期间一直在变化，原子阶段重新标记根
+                        self.mark_roots();
+                        let mut g = self.g.borrow_mut();
+                        let g = &mut *g;
+                        g.heap.atomic(&mut g.finalizers);
+                    }
+                }
+                Phase::Sweep => {
+                    let mut garbage = self.g.borrow_mut().heap.sweep(&mut budget);
+                    let to_finalize = mem::take(&mut garbage.to_finalize);
+                    garbage.release();
+                    if self.g.borrow().heap.phase() == Phase::Pause {
+                        {
+                            let mut g = self.g.borrow_mut();
+                            g.strings.sweep();
+                            let strings = g.strings.memory();
+                            g.heap.finish(strings);
+                        }
+                        self.finalize(to_finalize)?;
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    // 按标记的相反顺序调用__gc元方法，返回其中的第一个错误
+    fn finalize(&mut self, objects: Vec<LuaValue>) -> LuaResult<()> {
+        // 与Lua相同，调用__gc期间不自动回收
+        let running = mem::replace(&mut self.g.borrow_mut().heap.running, false);
+        let mut result = Ok(());
+        for obj in objects.into_iter().rev() {
+            let gc = self.metafield(&obj, "__gc");
+            if let LuaValue::Function(_) = gc {
+                if let Err(err) = self.call(gc, vec![obj]) {
+                    if result.is_ok() {
+                        // 与Lua相同，错误对象不是字符串时没有信息
+                        let msg = match err.value() {
+                            LuaValue::Str(s) => s.to_str_lossy().into_owned(),
+                            _ => "no message".to_string(),
+                        };
+                        let msg = format!("error in __gc metamethod ({msg})");
+                        result = Err(LuaError::runtime(msg));
+                    }
+                }
+            }
+        }
+        self.g.borrow_mut().heap.running = running;
+        result
+    }
+
+    // 分配债务足够时执行一步增量回收
+    fn check_gc(&mut self) -> LuaResult<()> {
+        let budget = self.g.borrow_mut().heap.step_budget();
+        if let Some(budget) = budget {
+            self.gc_run(budget)?;
+        }
+        Ok(())
+    }
+
+    /// 执行一步增量回收，工作量相当于分配kb千字节(乘以步进倍率)，kb为0时执行一个基本步
+    /// 返回这一步是否完成了一轮回收
+    pub fn gc_step(&mut self, kb: usize) -> LuaResult<bool> {
+        let budget = self.g.borrow_mut().heap.manual_budget(kb);
+        self.gc_run(budget)
+    }
+
+    /// 表的写屏障，向表中直接写入之后调用，使增量回收重新遍历这个表
+    pub fn table_barrier(&self, t: &Rc<RefCell<LuaTable>>) {
+        self.g.borrow_mut().heap.barrier_back(t);
+    }
+
+    /// 估计的内存使用量(字节)，包括被跟踪的对象和驻留的字符串
+    pub fn memory_used(&self) -> usize {
+        let g = self.g.borrow();
+        g.heap.memory() + g.strings.memory()
+    }
+
+    /// 停止或重新开始自动回收
+    pub fn set_gc_running(&mut self, running: bool) {
+        self.g.borrow_mut().heap.running = running;
+    }
+
+    pub fn is_gc_running(&self) -> bool {
+        self.g.borrow().heap.running
+    }
+
+    /// 设置回收间歇，返回之前的值
+    pub fn set_gc_pause(&mut self, pause: usize) -> usize {
+        mem::replace(&mut self.g.borrow_mut().heap.pause, pause)
+    }
+
+    /// 设置步进倍率，返回之前的值
+    pub fn set_gc_step_mul(&mut self, step_mul: usize) -> usize {
+        mem::replace(&mut self.g.borrow_mut().heap.step_mul, step_mul)
+    }
+
+    // 遍历线程持有的所有引用，用于垃圾回收
+    pub(crate) fn for_each_ref(&self, f: &mut dyn FnMut(Child)) {
+        self.stack.iter().for_each(|v| f(Child::Value(v)));
+        if let Ok(stack) = self.parked_stack.try_borrow() {
+            stack.iter().for_each(|v| f(Child::Value(v)));
+        }
+        for ci in &self.frames {
+            f(Child::Closure(&ci.closure));
+            ci.varargs.iter().for_each(|v| f(Child::Value(v)));
+        }
+        self.open_upvalues.values().for_each(|uv| f(Child::Upvalue(uv)));
+        self.native_calls.iter().for_each(|c| f(Child::Closure(c)));
+        self.handlers.iter().flatten().for_each(|h| f(Child::Value(h)));
+        if let Some(body) = &self.body {
+            f(Child::Value(body));
+        }
+    }
+
+    // 估计线程占用的字节数
+    pub(crate) fn memory(&self) -> usize {
+        let parked = self.parked_stack.try_borrow().map_or(0, |stack| stack.capacity());
+        mem::size_of::<RefCell<LuaState>>()
+            + (self.stack.capacity() + parked) * mem::size_of::<LuaValue>()
+            + self.frames.capacity() * mem::size_of::<CallInfo>()
+    }
+
+    // 线程不可达时由回收器调用，关闭所有upvalue并清空栈和调用帧
+    pub(crate) fn release(&mut self) {
+        self.unpark();
+        self.close_upvalues(0);
+        let stack = mem::take(&mut self.stack);
+        let frames = mem::take(&mut self.frames);
+        let native_calls = mem::take(&mut self.native_calls);
+        let body = self.body.take();
+        self.status = ThreadStatus::Dead;
+        self.park();
+        drop((stack, frames, native_calls, body));
+    }
+
+    /// 创建协程，主函数f在第一次恢复时被调用
+    pub fn new_thread(&mut self, f: LuaValue) -> LuaValue {
+        let thread = Rc::new_cyclic(|this| {
+            let mut thread = Self::with_global(self.g.clone(), ThreadStatus::Suspended);
+            thread.body = Some(f);
+            thread.this = this.clone();
+            RefCell::new(thread)
+        });
+        self.track(GcObject::Thread(Rc::downgrade(&thread)));
+        LuaValue::Thread(thread)
+    }
+
+    /// 当前正在运行的线程，以及它是否为主线程
+    pub fn running(&mut self) -> (LuaValue, bool) {
+        if let Some(this) = self.this.upgrade() {
+            return (LuaValue::Thread(this), false);
+        }
+        let g = self.g.clone();
+        let main = self
+            .main_thread
+            .get_or_insert_with(|| Rc::new(RefCell::new(Self::with_global(g, ThreadStatus::Main))));
+        (LuaValue::Thread(main.clone()), true)
+    }
+
+    /// 当前线程是否可以让出，只有协程中没有经过不能让出的Rust层调用时才可以
+    pub fn is_yieldable(&self) -> bool {
+        self.nny == 0
+    }
+
+    /// 对应coroutine.status，在当前线程中查看协程co的状态
+    pub fn thread_status(&self, co: &Rc<RefCell<LuaState>>) -> &'static str {
+        if std::ptr::eq(co.as_ptr(), self) {
+            return "running";
+        }
+        // 正在运行的协程已被可变借用，它是当前线程的某个调用者
+        let Ok(co) = co.try_borrow() else {
+            return "normal";
+        };
+        match co.status {
+            ThreadStatus::Main if self.status == ThreadStatus::Main => "running",
+            ThreadStatus::Main | ThreadStatus::Running => "normal",
+            ThreadStatus::Suspended => "suspended",
+            ThreadStatus::Dead => "dead",
+        }
+    }
+
+    /// 恢复协程co，返回协程让出或返回的值，协程中的错误原样返回
+    pub fn resume(
+        &mut self,
+        co: &Rc<RefCell<LuaState>>,
+        args: Vec<LuaValue>,
+    ) -> LuaResult<Vec<LuaValue>> {
+        let Ok(mut thread) = co.try_borrow_mut() else {
+            return Err(LuaError::runtime("cannot resume non-suspended coroutine"));
+        };
+        match thread.status {
+            ThreadStatus::Suspended => {}
+            ThreadStatus::Dead => return Err(LuaError::runtime("cannot resume dead coroutine")),
+            _ => return Err(LuaError::runtime("cannot resume non-suspended coroutine")),
+        }
+        if self.n_calls >= MAX_CALLS {
+            return Err(LuaError::runtime("C stack overflow"));
+        }
+        thread.n_calls = self.n_calls + 1;
+        // 协程运行期间，当前线程的栈放在共享的位置，协程中的闭包可以访问当前线程的open upvalue
+        self.park();
+        let result = thread.run(args);
+        self.unpark();
+        result
+    }
+
+    // 在协程中开始或者继续执行主函数
+    fn run(&mut self, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+        self.unpark();
+        self.status = ThreadStatus::Running;
+        let result = if let Some(f) = self.body.take() {
+            match &f {
+                LuaValue::Function(closure) if matches!(**closure, Closure::Lua(_)) => {
+                    let nargs = args.len();
+                    self.stack.push(f.clone());
+                    self.stack.extend(args);
+                    self.push_lua_frame(closure.clone(), 0, nargs, -1)
+                        .and_then(|_| self.execute(0))
+                }
+                _ => self.call_yieldable(f, args),
+            }
+        } else if let Some((func, nresults)) = self.resume_point.take() {
+            self.native_calls.pop();
+            self.place_results(func, args, nresults);
+            self.execute(0)
+        } else {
+            // 主函数是Rust函数并且已经让出，恢复的参数就是它的返回值
+            self.native_calls.pop();
+            Ok(args)
+        };
+        let result = match result {
+            Err(LuaError::Yield(values)) => {
+                self.status = ThreadStatus::Suspended;
+                Ok(values)
+            }
+            result => {
+                self.status = ThreadStatus::Dead;
+                self.close_upvalues(0);
+                result
+            }
+        };
+        self.park();
+        result
+    }
+
+    fn park(&mut self) {
+        mem::swap(&mut self.stack, &mut self.parked_stack.borrow_mut());
+    }
+
+    fn unpark(&mut self) {
+        mem::swap(&mut self.stack, &mut self.parked_stack.borrow_mut());
+    }
+
+    /// 正在执行的Rust函数的第idx个upvalue
+    pub fn native_upvalue(&self, idx: usize) -> LuaValue {
+        match self.native_calls.last().map(|c| &**c) {
+            Some(Closure::Native(f)) => f.upvalues.get(idx).cloned().unwrap_or_default(),
+            _ => LuaValue::Nil,
+        }
+    }
+
+    /// 调用函数，返回全部返回值，不是函数的值调用它的__call元方法
+    /// 被调用的函数不能让出
+    pub fn call(&mut self, func: LuaValue, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+        let n_native = self.native_calls.len();
+        self.nny += 1;
+        let result = self.call_yieldable(func, args);
+        self.nny -= 1;
+        if result.is_err() {
+            self.native_calls.truncate(n_native);
+        }
+        result
+    }
+
+    // 与call相同，但是不改变nny，Lua函数直接调用的Rust函数和协程的主函数可以让出
+    fn call_yieldable(
+        &mut self,
+        func: LuaValue,
+        mut args: Vec<LuaValue>,
+    ) -> LuaResult<Vec<LuaValue>> {
+        let LuaValue::Function(closure) = &func else {
+            // 与Lua 5.3相同，__call必须是函数，否则报告原来的值不能调用
+            let tm = self.metafield(&func, "__call");
+            if !matches!(tm, LuaValue::Function(_)) {
+                return Err(call_error(&func));
+            }
+            args.insert(0, func);
+            return self.call_yieldable(tm, args);
+        };
+        if self.n_calls >= MAX_CALLS {
+            return Err(LuaError::runtime("stack overflow"));
+        }
+
+        self.n_calls += 1;
+        let n_native = self.native_calls.len();
+        let result = match &**closure {
+            Closure::Native(f) => {
+                self.native_calls.push(closure.clone());
+                // Rust函数抛出的错误在它返回之前处理，位置是调用它的函数
+                let result = (f.func)(self, args).map_err(|err| self.handle_error(err, 1));
+                // 出错或让出的Rust函数留在调用栈中，协程因此结束或让出时可以回溯到它
+                // 捕获错误的调用和恢复协程时再把它弹出
+                if result.is_ok() {
+                    self.native_calls.pop();
+                }
+                result
+            }
+            Closure::Lua(_) => {
+                let func_idx = self.stack.len();
+                let depth = self.frames.len();
+                let nargs = args.len();
+                self.stack.push(func.clone());
+                self.stack.extend(args);
+                let result = self
+                    .push_lua_frame(closure.clone(), func_idx, nargs, -1)
+                    .and_then(|_| self.execute(depth));
+                if result.is_err() {
+                    // 出错时丢弃本次调用产生的所有调用帧
+                    self.close_upvalues(func_idx);
+                    self.frames.truncate(depth);
+                    self.native_calls.truncate(n_native);
+                    self.stack.truncate(func_idx);
+                }
+                result
+            }
+        };
+        self.n_calls -= 1;
+        result
+    }
+
+    /// 保护模式调用，出错时返回处理过的错误，调用栈恢复到调用之前
+    /// handler是消息处理函数，在出错的位置被调用，它的返回值作为错误对象
+    pub fn pcall(
+        &mut self,
+        func: LuaValue,
+        args: Vec<LuaValue>,
+        handler: Option<LuaValue>,
+    ) -> LuaResult<Vec<LuaValue>> {
+        self.handlers.push(handler);
+        let result = self.call(func, args).map_err(|err| self.handle_error(err, 0));
+        self.handlers.pop();
+        result
+    }
+
+    // 处理刚抛出的错误：字符串信息加上第level层函数的位置，再调用消息处理函数
+    // 已经处理过的错误和让出原样返回
+    fn handle_error(&mut self, err: LuaError, level: usize) -> LuaError {
+        let value = match err {
+            LuaError::Runtime(msg) => LuaValue::from(format!("{}{msg}", self.location(level))),
+            LuaError::Object(v) => v,
+            err => return err,
+        };
+        let Some(Some(handler)) = self.handlers.last().cloned() else {
+            return LuaError::Handled(value);
+        };
+        // 消息处理函数中的错误不再交给它处理
+        self.handlers.push(None);
+        let result = self.call(handler, vec![value]);
+        self.handlers.pop();
+        match result {
+            Ok(results) => LuaError::Handled(results.into_iter().next().unwrap_or_default()),
+            Err(_) => LuaError::Handled(LuaValue::from("error in error handling")),
+        }
+    }
+
+    // 调用栈中的各层，从正在运行的函数开始向下，Rust函数和Lua函数交错排列
+    fn levels(&self) -> Vec<Level> {
+        let mut levels = Vec::new();
+        let mut upper = self.native_calls.len();
+        for (i, ci) in self.frames.iter().enumerate().rev() {
+            levels.extend((ci.n_native..upper).rev().map(Level::Native));
+            levels.push(Level::Lua(i));
+            upper = ci.n_native;
+        }
+        levels.extend((0..upper).rev().map(Level::Native));
+        if self.status == ThreadStatus::Main && !levels.is_empty() {
+            levels.push(Level::Host);
+        }
+        levels
+    }
+
+    /// 第level层函数(0为正在运行的函数)当前执行的位置，形如"chunkname:line: "
+    /// 不是Lua函数或者没有行号信息时返回空字符串
+    pub fn location(&self, level: usize) -> String {
+        let Some(Level::Lua(i)) = self.levels().get(level).copied() else {
+            return String::new();
+        };
+        let ci = &self.frames[i];
+        match debug::line_at(&ci.proto, ci.pc.saturating_sub(1)) {
+            Some(line) => format!("{}:{line}: ", debug::chunk_id(&ci.proto)),
+            None => String::new(),
+        }
+    }
+
+    /// 从第level层开始的调用栈回溯，格式与Lua的luaL_traceback相同
+    /// 层数很多时只显示开始的10层和最后的11层
+    pub fn traceback(&self, level: usize) -> String {
+        let levels = self.levels();
+        let levels = levels.get(level..).unwrap_or_default();
+        let mut trace = String::from("stack traceback:");
+        for (n, &lv) in levels.iter().enumerate() {
+            if levels.len() > TRACEBACK_FIRST + TRACEBACK_LAST
+                && (TRACEBACK_FIRST..levels.len() - TRACEBACK_LAST).contains(&n)
+            {
+                if n == TRACEBACK_FIRST {
+                    trace.push_str("\n\t...");
+                }
+                continue;
+            }
+            match lv {
+                Level::Lua(i) => {
+                    let ci = &self.frames[i];
+                    trace.push_str(&format!("\n\t{}:", debug::chunk_id(&ci.proto)));
+                    if let Some(line) = debug::line_at(&ci.proto, ci.pc.saturating_sub(1)) {
+                        trace.push_str(&format!("{line}:"));
+                    }
+                }
+                Level::Native(_) | Level::Host => trace.push_str("\n\t[C]:"),
+            }
+            trace.push_str(" in ");
+            trace.push_str(&self.function_name(levels, n));
+            if let Level::Lua(i) = lv {
+                if self.frames[i].tail_call {
+                    trace.push_str("\n\t(...tail calls...)");
+                }
+            }
+        }
+        trace
+    }
+
+    // 回溯中第n层函数的描述，依次尝试全局函数名、调用指令推断的名字、主函数和定义位置
+    fn function_name(&self, levels: &[Level], n: usize) -> String {
+        let closure = match levels[n] {
+            Level::Lua(i) => &self.frames[i].closure,
+            Level::Native(i) => &self.native_calls[i],
+            Level::Host => return "?".to_string(),
+        };
+        if let Some(name) = self.global_name(closure) {
+            return format!("function '{name}'");
+        }
+        let tail_call = matches!(levels[n], Level::Lua(i) if self.frames[i].tail_call);
+        if let (false, Some(Level::Lua(caller))) = (tail_call, levels.get(n + 1)) {
+            let caller = &self.frames[*caller];
+            let pc = caller.pc.saturating_sub(1);
+            if let Some((what, name)) = debug::call_name(&caller.proto, pc) {
+                return format!("{what} '{name}'");
+            }
+        }
+        match levels[n] {
+            Level::Lua(i) if self.frames[i].proto.line_defined == 0 => "main chunk".to_string(),
+            Level::Lua(i) => {
+                let proto = &self.frames[i].proto;
+                format!("function <{}:{}>", debug::chunk_id(proto), proto.line_defined)
+            }
+            Level::Native(_) | Level::Host => "?".to_string(),
+        }
+    }
+
+    // 在全局变量和全局变量中的表(库)里查找函数的名字
+    fn global_name(&self, closure: &Rc<Closure>) -> Option<String> {
+        let LuaValue::Table(globals) = self.globals() else {
+            return None;
+        };
+        let is_closure = |v: &LuaValue| matches!(v, LuaValue::Function(f) if Rc::ptr_eq(f, closure));
+        let mut libs = Vec::new();
+        let mut key = LuaValue::Nil;
+        while let Ok(Some((k, v))) = globals.borrow().next(&key) {
+            if let LuaValue::Str(name) = &k {
+                if is_closure(&v) {
+                    return Some(name.to_str_lossy().into_owned());
+                }
+                if let LuaValue::Table(lib) = &v {
+                    if !Rc::ptr_eq(lib, &globals) {
+                        libs.push((name.clone(), lib.clone()));
+                    }
+                }
+            }
+            key = k;
+        }
+        for (lib_name, lib) in libs {
+            let mut key = LuaValue::Nil;
+            while let Ok(Some((k, v))) = lib.borrow().next(&key) {
+                if let (LuaValue::Str(name), true) = (&k, is_closure(&v)) {
+                    return Some(format!("{}.{}", lib_name.to_str_lossy(), name.to_str_lossy()));
+                }
+                key = k;
+            }
+        }
+        None
+    }
+
+    // 为Lua函数创建调用帧，函数和参数已经在栈上
+    fn push_lua_frame(
+        &mut self,
+        closure: Rc<Closure>,
+        func: usize,
+        nargs: usize,
+        nresults: isize,
+    ) -> LuaResult<()> {
+        let Closure::Lua(c) = &*closure else {
+            unreachable!()
+        };
+        let proto = c.proto.clone();
+        // 虚拟机只实现了5.3的指令集
+        if proto.version != LuaVersion::Lua53 {
+            return Err(LuaError::runtime(format!(
+                "cannot execute Lua {} bytecode",
+                proto.version
+            )));
+        }
+        let base = func + 1;
+        let nparams = proto.num_params as usize;
+        let frame_top = base + (proto.max_stack_size as usize).max(nparams);
+        if frame_top > MAX_STACK_SIZE {
+            return Err(LuaError::runtime("stack overflow"));
+        }
+
+        let varargs = if proto.is_vararg != 0 && nargs > nparams {
+            self.stack[base + nparams..base + nargs].to_vec()
+        } else {
+            Vec::new()
+        };
+        self.stack.truncate(base + nargs.min(nparams));
+        self.stack.resize(frame_top, LuaValue::Nil);
+
+        self.frames.push(CallInfo {
+            closure,
+            proto,
+            func,
+            base,
+            pc: 0,
+            nresults,
+            varargs,
+            n_native: self.native_calls.len(),
+            tail_call: false,
+        });
+        Ok(())
+    }
+
+    // 把返回值存放到dst开始的位置，nresults为-1时存放全部返回值并设置top
+    fn place_results(&mut self, dst: usize, mut results: Vec<LuaValue>, nresults: isize) {
+        if nresults >= 0 {
+            results.resize(nresults as usize, LuaValue::Nil);
+        } else {
+            self.top = dst + results.len();
+        }
+        if self.stack.len() < dst + results.len() {
+            self.stack.resize(dst + results.len(), LuaValue::Nil);
+        }
+        for (i, v) in results.into_iter().enumerate() {
+            self.stack[dst + i] = v;
+        }
+    }
+
+    // 找到引用栈中idx位置的open upvalue，不存在时创建一个
+    fn find_upvalue(&mut self, idx: usize) -> UpvalueRef {
+        if let Some(uv) = self.open_upvalues.get(&idx) {
+            return uv.clone();
+        }
+        let uv = self.new_upvalue(Upvalue::Open(self.parked_stack.clone(), idx));
+        self.open_upvalues.insert(idx, uv.clone());
+        uv
+    }
+
+    // 关闭所有引用栈中level及以上位置的upvalue
+    fn close_upvalues(&mut self, level: usize) {
+        let closing = self.open_upvalues.split_off(&level);
+        for (idx, uv) in closing {
+            *uv.borrow_mut() = Upvalue::Closed(self.stack[idx].clone());
+        }
+    }
+
+    fn get_upvalue(&self, idx: usize) -> LuaValue {
+        let uv = self.current_upvalue(idx);
+        let value = match &*uv.borrow() {
+            Upvalue::Open(stack, i) if Rc::ptr_eq(stack, &self.parked_stack) => {
+                self.stack[*i].clone()
+            }
+            // 其他线程的寄存器，该线程不在运行，栈已经被移到共享的位置
+            Upvalue::Open(stack, i) => stack.borrow()[*i].clone(),
+            Upvalue::Closed(v) => v.clone(),
+        };
+        value
+    }
+
+    fn set_upvalue(&mut self, idx: usize, value: LuaValue) {
+        let uv_ref = self.current_upvalue(idx);
+        let mut uv = uv_ref.borrow_mut();
+        match &mut *uv {
+            Upvalue::Open(stack, i) if Rc::ptr_eq(stack, &self.parked_stack) => {
+                self.stack[*i] = value
+            }
+            Upvalue::Open(stack, i) => stack.borrow_mut()[*i] = value,
+            Upvalue::Closed(v) => {
+                self.g.borrow_mut().heap.barrier(Child::Upvalue(&uv_ref), Child::Value(&value));
+                *v = value
+            }
+        }
+    }
+
+    fn current_upvalue(&self, idx: usize) -> UpvalueRef {
+        match &*self.frames.last().unwrap().closure {
+            Closure::Lua(c) => c.upvalues[idx].clone(),
+            Closure::Native(_) => unreachable!(),
+        }
+    }
+
+    // 从from到上一条指令设置的栈顶之间的值的个数，栈顶无效时报错而不是越界
+    fn count_to_top(&self, from: usize) -> LuaResult<usize> {
+        match self.top.checked_sub(from) {
+            Some(n) if self.top <= self.stack.len() => Ok(n),
+            _ => Err(LuaError::runtime("invalid stack top")),
+        }
+    }
+
+    // 获取RK操作数的值
+    fn rk(&self, proto: &FuncProto, base: usize, x: Rk) -> LuaValue {
+        match x {
+            Rk::Const(k) => proto.constants[k].clone(),
+            Rk::Reg(r) => self.stack[base + r].clone(),
+        }
+    }
+
+    /// t[k]，t不是表或者表中没有k时使用__index元方法
+    /// __index是函数时调用它，否则在__index的值中继续查找
+    pub fn index(&mut self, t: &LuaValue, k: &LuaValue) -> LuaResult<LuaValue> {
+        let mut t = t.clone();
+        for _ in 0..MAX_TAG_LOOP {
+            if let LuaValue::Table(tbl) = &t {
+                let v = tbl.borrow().get(k);
+                if !v.is_nil() {
+                    return Ok(v);
+                }
+            }
+            let tm = self.metafield(&t, "__index");
+            if tm.is_nil() {
+                return match t {
+                    LuaValue::Table(_) => Ok(LuaValue::Nil),
+                    _ => Err(index_error(&t)),
+                };
+            }
+            if let LuaValue::Function(_) = tm {
+                return self.call_tm(tm, vec![t, k.clone()]);
+            }
+            t = tm;
+        }
+        Err(LuaError::runtime("'__index' chain too long; possible loop"))
+    }
+
+    /// t[k] = v，t不是表或者表中没有k时使用__newindex元方法
+    pub fn set_index(&mut self, t: &LuaValue, k: LuaValue, v: LuaValue) -> LuaResult<()> {
+        let mut t = t.clone();
+        for _ in 0..MAX_TAG_LOOP {
+            let tm = match &t {
+                LuaValue::Table(tbl) => {
+                    let tm = if tbl.borrow().get(&k).is_nil() {
+                        self.metafield(&t, "__newindex")
+                    } else {
+                        LuaValue::Nil
+                    };
+                    if tm.is_nil() {
+                        tbl.borrow_mut().put(k, v).map_err(LuaError::runtime)?;
+                        self.table_barrier(tbl);
+                        return Ok(());
+                    }
+                    tm
+                }
+                _ => match self.metafield(&t, "__newindex") {
+                    LuaValue::Nil => return Err(index_error(&t)),
+                    tm => tm,
+                },
+            };
+            if let LuaValue::Function(_) = tm {
+                self.call(tm, vec![t, k, v])?;
+                return Ok(());
+            }
+            t = tm;
+        }
+        Err(LuaError::runtime("'__newindex' chain too long; possible loop"))
+    }
+
+    // 调用元方法，只保留第一个返回值
+    fn call_tm(&mut self, tm: LuaValue, args: Vec<LuaValue>) -> LuaResult<LuaValue> {
+        let results = self.call(tm, args)?;
+        Ok(results.into_iter().next().unwrap_or_default())
+    }
+
+    // 调用二元运算的元方法，先查找第一个操作数，再查找第二个操作数，都没有时返回None
+    fn call_bin_tm(&mut self, a: &LuaValue, b: &LuaValue, event: &str) -> LuaResult<Option<LuaValue>> {
+        let mut tm = self.metafield(a, event);
+        if tm.is_nil() {
+            tm = self.metafield(b, event);
+        }
+        if tm.is_nil() {
+            return Ok(None);
+        }
+        self.call_tm(tm, vec![a.clone(), b.clone()]).map(Some)
+    }
+
+    /// a == b，两个不同的表或两个不同的用户数据使用__eq元方法
+    pub fn equals(&mut self, a: &LuaValue, b: &LuaValue) -> LuaResult<bool> {
+        if a.raw_equals(b) {
+            return Ok(true);
+        }
+        match (a, b) {
+            (LuaValue::Table(_), LuaValue::Table(_)) | (LuaValue::UserData(_), LuaValue::UserData(_)) => {
+                Ok(self.call_bin_tm(a, b, "__eq")?.is_some_and(|v| v.to_boolean()))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// 对应Lua中的tostring函数，优先使用__tostring元方法
+    pub fn tostring(&mut self, v: &LuaValue) -> LuaResult<LuaValue> {
+        let tm = self.metafield(v, "__tostring");
+        if !tm.is_nil() {
+            return match self.call_tm(tm, vec![v.clone()])? {
+                s @ (LuaValue::Str(_) | LuaValue::Integer(_) | LuaValue::Number(_)) => Ok(s),
+                _ => Err(LuaError::runtime("'__tostring' must return a string")),
+            };
+        }
+        match v {
+            LuaValue::Str(_) => Ok(v.clone()),
+            _ => Ok(LuaValue::from(v.to_string())),
+        }
+    }
+
+    // 操作数不能转换为数字时使用元方法
+    fn arith(&mut self, op: ArithOp, a: &LuaValue, b: &LuaValue) -> LuaResult<LuaValue> {
+        if let Some(result) = arith::arith(op, a, b)? {
+            return Ok(result);
+        }
+        if let Some(result) = self.call_bin_tm(a, b, op.event())? {
+            return Ok(result);
+        }
+
+        if op.is_bitwise() {
+            if a.to_number().is_some() && b.to_number().is_some() {
+                return Err(LuaError::runtime("number has no integer representation"));
+            }
+            let bad = if a.to_number().is_none() { a } else { b };
+            Err(LuaError::runtime(format!(
+                "attempt to perform bitwise operation on a {} value",
+                bad.type_name()
+            )))
+        } else {
+            let bad = if a.to_arith().is_none() { a } else { b };
+            Err(LuaError::runtime(format!(
+                "attempt to perform arithmetic on a {} value",
+                bad.type_name()
+            )))
+        }
+    }
+
+    fn less_than(&mut self, a: &LuaValue, b: &LuaValue) -> LuaResult<bool> {
+        if let Some(result) = arith::less_than(a, b) {
+            return Ok(result);
+        }
+        match self.call_bin_tm(a, b, "__lt")? {
+            Some(result) => Ok(result.to_boolean()),
+            None => Err(compare_error(a, b)),
+        }
+    }
+
+    // 没有__le元方法时使用not (b < a)
+    fn less_equal(&mut self, a: &LuaValue, b: &LuaValue) -> LuaResult<bool> {
+        if let Some(result) = arith::less_equal(a, b) {
+            return Ok(result);
+        }
+        if let Some(result) = self.call_bin_tm(a, b, "__le")? {
+            return Ok(result.to_boolean());
+        }
+        match self.call_bin_tm(b, a, "__lt")? {
+            Some(result) => Ok(!result.to_boolean()),
+            None => Err(compare_error(a, b)),
+        }
+    }
+
+    // 字符串直接返回长度，表有__len元方法时调用它
+    fn len(&mut self, v: &LuaValue) -> LuaResult<LuaValue> {
+        if let LuaValue::Str(s) = v {
+            return Ok(LuaValue::Integer(s.len() as i64));
+        }
+        let tm = self.metafield(v, "__len");
+        if !tm.is_nil() {
+            return self.call_tm(tm, vec![v.clone(), v.clone()]);
+        }
+        match v {
+            LuaValue::Table(t) => Ok(LuaValue::Integer(t.borrow().len() as i64)),
+            _ => Err(LuaError::runtime(format!(
+                "attempt to get length of a {} value",
+                v.type_name()
+            ))),
+        }
+    }
+
+    // 与Lua相同从右向左连接，末尾连续的字符串和数字一次连接，其他值使用__concat元方法
+    fn concat(&mut self, values: &[LuaValue]) -> LuaResult<LuaValue> {
+        let is_str = |v: &LuaValue| {
+            matches!(v, LuaValue::Str(_) | LuaValue::Integer(_) | LuaValue::Number(_))
+        };
+        let mut values = values.to_vec();
+        while values.len() > 1 {
+            let n = values.len();
+            let (a, b) = (values[n - 2].clone(), values[n - 1].clone());
+            if !is_str(&a) || !is_str(&b) {
+                let Some(result) = self.call_bin_tm(&a, &b, "__concat")? else {
+                    let bad = if is_str(&a) { &b } else { &a };
+                    return Err(LuaError::runtime(format!(
+                        "attempt to concatenate a {} value",
+                        bad.type_name()
+                    )));
+                };
+                values.truncate(n - 2);
+                values.push(result);
+                continue;
+            }
+            let start = values.iter().rposition(|v| !is_str(v)).map_or(0, |i| i + 1);
+            let mut s = Vec::new();
+            for v in values.drain(start..) {
+                s.extend_from_slice(&v.to_str().unwrap());
+            }
+            let s = self.new_string(&s);
+            values.push(s);
+        }
+        Ok(values.pop().unwrap_or_default())
+    }
+
+    // 调用栈中func位置的函数，Lua函数会创建新的调用帧，由execute继续执行
+    // Rust函数直接执行，返回值存放到func开始的位置
+    // 不是函数的值调用它的__call元方法，原来的值作为第一个参数
+    fn precall(&mut self, func: usize, mut nargs: usize, nresults: isize) -> LuaResult<()> {
+        if !matches!(self.stack[func], LuaValue::Function(_)) {
+            let tm = self.metafield(&self.stack[func], "__call");
+            if !matches!(tm, LuaValue::Function(_)) {
+                return Err(call_error(&self.stack[func]));
+            }
+            self.stack.insert(func, tm);
+            nargs += 1;
+        }
+        let f = self.stack[func].clone();
+        let LuaValue::Function(closure) = &f else {
+            unreachable!()
+        };
+        match &**closure {
+            Closure::Lua(_) => self.push_lua_frame(closure.clone(), func, nargs, nresults),
+            Closure::Native(_) => {
+                let args = self.stack[func + 1..func + 1 + nargs].to_vec();
+                // 参数之上的寄存器已经无用，清除它们使其中的对象可以被回收
+                self.stack[func + 1 + nargs..].fill(LuaValue::Nil);
+                match self.call_yieldable(f.clone(), args) {
+                    Ok(results) => {
+                        self.place_results(func, results, nresults);
+                        Ok(())
+                    }
+                    Err(err) => {
+                        if let LuaError::Yield(_) = err {
+                            self.resume_point = Some((func, nresults));
+                        }
+                        Err(err)
+                    }
+                }
+            }
+        }
+    }
+
+    // 执行调用帧，直到depth层的调用帧返回，指令产生的错误在这里处理
+    fn execute(&mut self, depth: usize) -> LuaResult<Vec<LuaValue>> {
+        self.execute_frames(depth).map_err(|err| self.handle_error(err, 0))
+    }
+
+    fn execute_frames(&mut self, depth: usize) -> LuaResult<Vec<LuaValue>> {
+        loop {
+            let ci = self.frames.last_mut().unwrap();
+            let proto = ci.proto.clone();
+            let base = ci.base;
+            let pc = ci.pc;
+            ci.pc += 1;
+
+            let Some(&i) = proto.code.get(pc) else {
+                return Err(LuaError::runtime("program counter out of range"));
+            };
+            let Some(decoded) = Decoded::decode(i) else {
+                return Err(LuaError::runtime(format!("unknown opcode {}", i.op_code())));
+            };
+
+            match decoded {
+                Decoded::Move { a, b } => {
+                    self.stack[base + a] = self.stack[base + b].clone();
+                }
+                Decoded::LoadK { a, k } => {
+                    self.stack[base + a] = proto.constants[k].clone();
+                }
+                Decoded::LoadKX { a } => {
+                    let ax = proto.code[pc + 1].ax();
+                    self.frames.last_mut().unwrap().pc += 1;
+                    self.stack[base + a] = proto.constants[ax as usize].clone();
+                }
+                Decoded::LoadBool { a, value, skip } => {
+                    self.stack[base + a] = LuaValue::Boolean(value);
+                    if skip {
+                        self.frames.last_mut().unwrap().pc += 1;
+                    }
+                }
+                Decoded::LoadNil { a, n } => {
+                    for r in base + a..base + a + n {
+                        self.stack[r] = LuaValue::Nil;
+                    }
+                }
+                Decoded::GetUpval { a, upval } => {
+                    self.stack[base + a] = self.get_upvalue(upval);
+                }
+                Decoded::GetTabUp { a, upval, key } => {
+                    let t = self.get_upvalue(upval);
+                    let k = self.rk(&proto, base, key);
+                    self.stack[base + a] = self.index(&t, &k)?;
+                }
+                Decoded::GetTable { a, table, key } => {
+                    let t = self.stack[base + table].clone();
+                    let k = self.rk(&proto, base, key);
+                    self.stack[base + a] = self.index(&t, &k)?;
+                }
+                Decoded::SetTabUp { upval, key, value } => {
+                    let t = self.get_upvalue(upval);
+                    let k = self.rk(&proto, base, key);
+                    let v = self.rk(&proto, base, value);
+                    self.set_index(&t, k, v)?;
+                }
+                Decoded::SetUpval { a, upval } => {
+                    let v = self.stack[base + a].clone();
+                    self.set_upvalue(upval, v);
+                }
+                Decoded::SetTable { table, key, value } => {
+                    let t = self.stack[base + table].clone();
+                    let k = self.rk(&proto, base, key);
+                    let v = self.rk(&proto, base, value);
+                    self.set_index(&t, k, v)?;
+                }
+                Decoded::NewTable { a, array, hash } => {
+                    self.stack[base + a] = self.new_table(fb2int(array), fb2int(hash));
+                    self.check_gc()?;
+                }
+                Decoded::Self_ { a, table, key } => {
+                    let t = self.stack[base + table].clone();
+                    let k = self.rk(&proto, base, key);
+                    self.stack[base + a + 1] = t.clone();
+                    self.stack[base + a] = self.index(&t, &k)?;
+                }
+                Decoded::Arith { op, a, b, c } => {
+                    let x = self.rk(&proto, base, b);
+                    let y = self.rk(&proto, base, c);
+                    self.stack[base + a] = self.arith(op, &x, &y)?;
+                }
+                Decoded::Unary { op, a, b } => {
+                    let x = self.stack[base + b].clone();
+                    self.stack[base + a] = self.arith(op, &x, &x)?;
+                }
+                Decoded::Not { a, b } => {
+                    self.stack[base + a] = LuaValue::Boolean(!self.stack[base + b].to_boolean());
+                }
+                Decoded::Len { a, b } => {
+                    let x = self.stack[base + b].clone();
+                    self.stack[base + a] = self.len(&x)?;
+                }
+                Decoded::Concat { a, first, last } => {
+                    let values = self.stack[base + first..=base + last].to_vec();
+                    self.stack[base + a] = self.concat(&values)?;
+                }
+                Decoded::Jmp { close, offset } => {
+                    let ci = self.frames.last_mut().unwrap();
+                    ci.pc = (ci.pc as isize + offset) as usize;
+                    if let Some(close) = close {
+                        self.close_upvalues(base + close);
+                    }
+                }
+                Decoded::Eq { expect, b, c }
+                | Decoded::Lt { expect, b, c }
+                | Decoded::Le { expect, b, c } => {
+                    let x = self.rk(&proto, base, b);
+                    let y = self.rk(&proto, base, c);
+                    let result = match decoded {
+                        Decoded::Eq { .. } => self.equals(&x, &y)?,
+                        Decoded::Lt { .. } => self.less_than(&x, &y)?,
+                        _ => self.less_equal(&x, &y)?,
+                    };
+                    if result != expect {
+                        self.frames.last_mut().unwrap().pc += 1;
+                    }
+                }
+                Decoded::Test { a, expect } => {
+                    if self.stack[base + a].to_boolean() != expect {
+                        self.frames.last_mut().unwrap().pc += 1;
+                    }
+                }
+                Decoded::TestSet { a, b, expect } => {
+                    let v = self.stack[base + b].clone();
+                    if v.to_boolean() == expect {
+                        self.stack[base + a] = v;
+                    } else {
+                        self.frames.last_mut().unwrap().pc += 1;
+                    }
+                }
+                Decoded::Call { a, args, results } => {
+                    let ra = base + a;
+                    let nargs = match args {
+                        Some(n) => n,
+                        None => self.count_to_top(ra + 1)?,
+                    };
+                    self.precall(ra, nargs, results.map_or(-1, |n| n as isize))?;
+                }
+                Decoded::TailCall { a, args } => {
+                    let ra = base + a;
+                    let nargs = match args {
+                        Some(n) => n,
+                        None => self.count_to_top(ra + 1)?,
+                    };
+                    let f = self.stack[ra].clone();
+                    match &f {
+                        LuaValue::Function(closure) if matches!(**closure, Closure::Lua(_)) => {
+                            // 复用当前调用帧的位置
+                            self.close_upvalues(base);
+                            let ci = self.frames.pop().unwrap();
+                            for j in 0..=nargs {
+                                self.stack[ci.func + j] = self.stack[ra + j].clone();
+                            }
+                            self.push_lua_frame(closure.clone(), ci.func, nargs, ci.nresults)?;
+                            self.frames.last_mut().unwrap().tail_call = true;
+                        }
+                        // Rust函数按普通调用处理，返回值由随后的RETURN指令返回
+                        _ => self.precall(ra, nargs, -1)?,
+                    }
+                }
+                Decoded::Return { a, n } => {
+                    let ra = base + a;
+                    let n = match n {
+                        Some(n) => n,
+                        None => self.count_to_top(ra)?,
+                    };
+                    let results = self.stack[ra..ra + n].to_vec();
+                    self.close_upvalues(base);
+                    let ci = self.frames.pop().unwrap();
+                    if self.frames.len() == depth {
+                        self.stack.truncate(ci.func);
+                        return Ok(results);
+                    }
+
+                    let n = results.len();
+                    self.place_results(ci.func, results, ci.nresults);
+                    // 恢复调用者的栈空间
+                    let caller = self.frames.last().unwrap();
+                    let caller_top = caller.base + caller.proto.max_stack_size as usize;
+                    let end = if ci.nresults < 0 {
+                        ci.func + n
+                    } else {
+                        ci.func + ci.nresults as usize
+                    };
+                    let len = caller_top.max(end);
+                    self.stack.truncate(len);
+                    // 被调用函数留在调用者栈空间中的寄存器已经无用，清除它们使其中的对象可以被回收
+                    self.stack[end..].fill(LuaValue::Nil);
+                    self.stack.resize(len, LuaValue::Nil);
+                }
+                Decoded::ForLoop { a, offset } => {
+                    let ra = base + a;
+                    let next = match (&self.stack[ra], &self.stack[ra + 1], &self.stack[ra + 2]) {
+                        (LuaValue::Integer(idx), LuaValue::Integer(limit), LuaValue::Integer(step)) => {
+                            let idx = idx.wrapping_add(*step);
+                            let cont = if *step > 0 { idx <= *limit } else { *limit <= idx };
+                            cont.then_some(LuaValue::Integer(idx))
+                        }
+                        (idx, limit, step) => {
+                            let (Some(idx), Some(limit), Some(step)) =
+                                (idx.to_number(), limit.to_number(), step.to_number())
+                            else {
+                                return Err(LuaError::runtime("'for' values must be numbers"));
+                            };
+                            let idx = idx + step;
+                            let cont = if step > 0.0 { idx <= limit } else { limit <= idx };
+                            cont.then_some(LuaValue::Number(idx))
+                        }
+                    };
+                    if let Some(idx) = next {
+                        self.stack[ra] = idx.clone();
+                        self.stack[ra + 3] = idx;
+                        let ci = self.frames.last_mut().unwrap();
+                        ci.pc = (ci.pc as isize + offset) as usize;
+                    }
+                }
+                Decoded::ForPrep { a, offset } => {
+                    self.for_prep(base + a)?;
+                    let ci = self.frames.last_mut().unwrap();
+                    ci.pc = (ci.pc as isize + offset) as usize;
+                }
+                Decoded::TForCall { a, results } => {
+                    // 把迭代器函数和两个参数复制到R(A+3)处调用，返回值正好存放在R(A+3)开始的位置
+                    let ra = base + a;
+                    for i in 0..3 {
+                        self.stack[ra + 3 + i] = self.stack[ra + i].clone();
+                    }
+                    self.precall(ra + 3, 2, results as isize)?;
+                }
+                Decoded::TForLoop { a, offset } => {
+                    let ra = base + a;
+                    if !self.stack[ra + 1].is_nil() {
+                        self.stack[ra] = self.stack[ra + 1].clone();
+                        let ci = self.frames.last_mut().unwrap();
+                        ci.pc = (ci.pc as isize + offset) as usize;
+                    }
+                }
+                Decoded::SetList { a, n, block } => {
+                    let ra = base + a;
+                    let n = match n {
+                        Some(n) => n,
+                        None => self.count_to_top(ra + 1)?,
+                    };
+                    let block = match block {
+                        Some(block) => block,
+                        None => {
+                            let ax = proto.code[pc + 1].ax();
+                            self.frames.last_mut().unwrap().pc += 1;
+                            ax as usize
+                        }
+                    };
+                    let LuaValue::Table(t) = &self.stack[ra] else {
+                        return Err(LuaError::runtime(format!(
+                            "SETLIST target is a {} value, not a table",
+                            self.stack[ra].type_name()
+                        )));
+                    };
+                    let Some(offset) = block.checked_sub(1).map(|b| b * FIELDS_PER_FLUSH) else {
+                        return Err(LuaError::runtime("SETLIST block number must be positive"));
+                    };
+                    let mut tbl = t.borrow_mut();
+                    for j in 1..=n {
+                        let key = LuaValue::Integer((offset + j) as i64);
+                        tbl.put(key, self.stack[ra + j].clone()).unwrap();
+                    }
+                    drop(tbl);
+                    self.table_barrier(t);
+                }
+                Decoded::Closure { a, proto: bx } => {
+                    let sub_proto = proto.protos[bx].clone();
+                    let upvalues = sub_proto
+                        .upvalues
+                        .iter()
+                        .map(|uv| {
+                            if uv.instack != 0 {
+                                self.find_upvalue(base + uv.idx as usize)
+                            } else {
+                                self.current_upvalue(uv.idx as usize)
+                            }
+                        })
+                        .collect();
+                    self.stack[base + a] = self.new_lua_closure(sub_proto, upvalues);
+                    self.check_gc()?;
+                }
+                Decoded::VarArg { a, n } => {
+                    let varargs = self.frames.last().unwrap().varargs.clone();
+                    self.place_results(base + a, varargs, n.map_or(-1, |n| n as isize));
+                }
+                Decoded::ExtraArg { .. } => {
+                    return Err(LuaError::runtime("unexpected EXTRAARG"));
+                }
+            }
+        }
+    }
+
+    // 数值for循环的准备工作，初始值和步长都是整数时使用整数循环，否则使用浮点数循环
+    fn for_prep(&mut self, ra: usize) -> LuaResult<()> {
+        if let (LuaValue::Integer(init), LuaValue::Integer(step)) = (&self.stack[ra], &self.stack[ra + 2]) {
+            let (init, step) = (*init, *step);
+            let limit = self.stack[ra + 1].clone();
+            if let Some((limit, stop)) = for_limit(&limit, step) {
+                let init = if stop { 0 } else { init };
+                self.stack[ra] = LuaValue::Integer(init.wrapping_sub(step));
+                self.stack[ra + 1] = LuaValue::Integer(limit);
+                return Ok(());
+            }
+        }
+
+        let Some(limit) = self.stack[ra + 1].to_number() else {
+            return Err(LuaError::runtime("'for' limit must be a number"));
+        };
+        let Some(step) = self.stack[ra + 2].to_number() else {
+            return Err(LuaError::runtime("'for' step must be a number"));
+        };
+        let Some(init) = self.stack[ra].to_number() else {
+            return Err(LuaError::runtime("'for' initial value must be a number"));
+        };
+        self.stack[ra] = LuaValue::Number(init - step);
+        self.stack[ra + 1] = LuaValue::Number(limit);
+        self.stack[ra + 2] = LuaValue::Number(step);
+        Ok(())
+    }
+}
+
+// 把for循环的上限转换为整数，返回(上限, 是否跳过循环)，与Lua 5.3的forlimit相同
+fn for_limit(limit: &LuaValue, step: i64) -> Option<(i64, bool)> {
+    if let LuaValue::Integer(i) = limit {
+        return Some((*i, false));
+    }
+    let n = limit.to_number()?;
+    let n = if step < 0 { n.ceil() } else { n.floor() };
+    if let Some(i) = crate::vm::lua_value::float_to_integer(n) {
+        Some((i, false))
+    } else if n > 0.0 {
+        Some((i64::MAX, step < 0))
+    } else {
+        Some((i64::MIN, step > 0))
+    }
+}
+
+// NEWTABLE的B、C操作数使用"浮点字节"编码: eeeeexxx，值为(1xxx) * 2^(eeeee-1)
+fn fb2int(x: usize) -> usize {
+    if x < 8 {
+        x
+    } else {
+        ((x & 7) + 8) << ((x >> 3) - 1)
+    }
+}
+
+fn index_error(t: &LuaValue) -> LuaError {
+    LuaError::runtime(format!("attempt to index a {} value", t.type_name()))
+}
+
+fn call_error(f: &LuaValue) -> LuaError {
+    LuaError::runtime(format!("attempt to call a {} value", f.type_name()))
+}
+
+fn compare_error(a: &LuaValue, b: &LuaValue) -> LuaError {
+    let (t1, t2) = (a.type_name(), b.type_name());
+    if t1 == t2 {
+        LuaError::runtime(format!("attempt to compare two {t1} values"))
+    } else {
+        LuaError::runtime(format!("attempt to compare {t1} with {t2}"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::analysis::VerifyError;
+    use crate::binary::chunk::{Constant, LuaVersion, Prototype, UpValue};
+    use crate::compiler::compile;
+    use crate::stdlib;
+    use crate::vm::error::LuaResult;
+    use crate::vm::instruction::{Instruction, BIT_RK};
+    use crate::vm::lua_state::{for_limit, LuaState};
+    use crate::vm::lua_value::LuaValue;
+    use crate::vm::opcode::Op;
+
+    // RK操作数的常量标志
+    const K: isize = BIT_RK;
+
+    fn iabc(op: Op, a: isize, b: isize, c: isize) -> u32 {
+        u32::encode_abc(op, a, b, c).unwrap()
+    }
+
+    fn iabx(op: Op, a: isize, bx: isize) -> u32 {
+        u32::encode_abx(op, a, bx).unwrap()
+    }
+
+    fn iasbx(op: Op, a: isize, sbx: isize) -> u32 {
+        u32::encode_asbx(op, a, sbx).unwrap()
+    }
+
+    fn proto(code: Vec<u32>, constants: Vec<Constant>, max_stack_size: u8) -> Prototype {
+        Prototype {
+            version: LuaVersion::Lua53,
+            source: b"@test".to_vec(),
+            line_defined: 0,
+            last_line_defined: 0,
+            num_params: 0,
+            is_vararg: 1,
+            max_stack_size,
+            code,
+            constants,
+            upvalues: vec![UpValue { instack: 1, idx: 0, kind: 0 }],
+            protos: vec![],
+            line_info: vec![],
+            loc_vars: vec![],
+            upvalue_names: vec![],
+        }
+    }
+
+    fn run(proto: Prototype, args: Vec<LuaValue>) -> Vec<LuaValue> {
+        let mut state = LuaState::new();
+        stdlib::open_libs(&mut state);
+        let main = state.load(proto).unwrap();
+        state.call(main, args).unwrap()
+    }
+
+    #[test]
+    fn test_for_loop() {
+        // local s = 0; for i = 1, 100 do s = s + i end; return s
+        let code = vec![
+            iabx(Op::LoadK, 0, 0),
+            iabx(Op::LoadK, 1, 1),
+            iabx(Op::LoadK, 2, 2),
+            iabx(Op::LoadK, 3, 1),
+            iasbx(Op::ForPrep, 1, 1),
+            iabc(Op::Add, 0, 0, 4),
+            iasbx(Op::ForLoop, 1, -2),
+            iabc(Op::Return, 0, 2, 0),
+            iabc(Op::Return, 0, 1, 0),
+        ];
+        let constants = vec![Constant::Integer(0), Constant::Integer(1), Constant::Integer(100)];
+        let results = run(proto(code, constants, 5), vec![]);
+        assert_eq!(results, vec![LuaValue::Integer(5050)]);
+
+        // 浮点数上限按步长的方向取整，超出整数范围时按步长的符号决定是否跳过循环
+        assert_eq!(for_limit(&LuaValue::Number(2.5), 1), Some((2, false)));
+        assert_eq!(for_limit(&LuaValue::Number(2.5), -1), Some((3, false)));
+        assert_eq!(for_limit(&LuaValue::Number(1e300), -1), Some((i64::MAX, true)));
+        assert_eq!(for_limit(&LuaValue::Number(-1e300), 1), Some((i64::MIN, true)));
+        assert_eq!(for_limit(&LuaValue::Number(-1e300), 0), Some((i64::MIN, false)));
+    }
+
+    #[test]
+    fn test_closure_upvalues() {
+        // local n = 0
+        // local function inc() n = n + 1; return n end
+        // inc(); inc()
+        // return inc(), n, inc
+        let mut inc = proto(
+            vec![
+                iabc(Op::GetUpval, 0, 0, 0),
+                iabc(Op::Add, 0, 0, K),
+                iabc(Op::SetUpval, 0, 0, 0),
+                iabc(Op::GetUpval, 0, 0, 0),
+                iabc(Op::Return, 0, 2, 0),
+                iabc(Op::Return, 0, 1, 0),
+            ],
+            vec![Constant::Integer(1)],
+            2,
+        );
+        inc.is_vararg = 0;
+
+        let mut main = proto(
+            vec![
+                iabx(Op::LoadK, 0, 0),
+                iabx(Op::Closure, 1, 0),
+                iabc(Op::Move, 2, 1, 0),
+                iabc(Op::Call, 2, 1, 1),
+                iabc(Op::Move, 2, 1, 0),
+                iabc(Op::Call, 2, 1, 1),
+                iabc(Op::Move, 2, 1, 0),
+                iabc(Op::Call, 2, 1, 2),
+                iabc(Op::Move, 3, 0, 0),
+                iabc(Op::Move, 4, 1, 0),
+                iabc(Op::Return, 2, 4, 0),
+            ],
+            vec![Constant::Integer(0)],
+            5,
+        );
+        main.protos.push(Rc::new(inc));
+
+        let mut state = LuaState::new();
+        let f = state.load(main).unwrap();
+        let results = state.call(f, vec![]).unwrap();
+        assert_eq!(results[0], LuaValue::Integer(3));
+        assert_eq!(results[1], LuaValue::Integer(3));
+
+        // 主函数返回后upvalue已经关闭，闭包仍然可以修改它
+        let inc = results[2].clone();
+        assert_eq!(state.call(inc.clone(), vec![]).unwrap(), vec![LuaValue::Integer(4)]);
+        assert_eq!(state.call(inc, vec![]).unwrap(), vec![LuaValue::Integer(5)]);
+    }
+
+    #[test]
+    fn test_nested_upvalues() {
+        // local x = 1
+        // local f = function() return function() x = x + 1; return x end end
+        // local g = f(); g()
+        // return g(), x
+        let mut inner = proto(
+            vec![
+                iabc(Op::GetUpval, 0, 0, 0),
+                iabc(Op::Add, 0, 0, K),
+                iabc(Op::SetUpval, 0, 0, 0),
+                iabc(Op::GetUpval, 0, 0, 0),
+                iabc(Op::Return, 0, 2, 0),
+            ],
+            vec![Constant::Integer(1)],
+            1,
+        );
+        // 内层函数的upvalue来自外层函数的upvalue，而不是寄存器
+        inner.upvalues = vec![UpValue { instack: 0, idx: 0, kind: 0 }];
+        let mut f = proto(
+            vec![iabx(Op::Closure, 0, 0), iabc(Op::Return, 0, 2, 0)],
+            vec![],
+            1,
+        );
+        f.protos.push(Rc::new(inner));
+
+        let mut main = proto(
+            vec![
+                iabx(Op::LoadK, 0, 0),
+                iabx(Op::Closure, 1, 0),
+                iabc(Op::Move, 2, 1, 0),
+                iabc(Op::Call, 2, 1, 2),
+                iabc(Op::Move, 3, 2, 0),
+                iabc(Op::Call, 3, 1, 1),
+                iabc(Op::Move, 3, 2, 0),
+                iabc(Op::Call, 3, 1, 2),
+                iabc(Op::Move, 4, 0, 0),
+                iabc(Op::Return, 3, 3, 0),
+            ],
+            vec![Constant::Integer(1)],
+            5,
+        );
+        main.protos.push(Rc::new(f));
+        assert_eq!(run(main, vec![]), vec![LuaValue::Integer(3), LuaValue::Integer(3)]);
+    }
+
+    #[test]
+    fn test_jmp_close() {
+        // local i, fs = 0, {}
+        // repeat i = i + 1; local j = i; fs[i] = function() return j end until i >= 3
+        // return fs
+        let mut get = proto(
+            vec![iabc(Op::GetUpval, 0, 0, 0), iabc(Op::Return, 0, 2, 0)],
+            vec![],
+            1,
+        );
+        get.upvalues = vec![UpValue { instack: 1, idx: 2, kind: 0 }];
+
+        let mut main = proto(
+            vec![
+                iabx(Op::LoadK, 0, 0),
+                iabc(Op::NewTable, 1, 0, 0),
+                iabc(Op::Add, 0, 0, K + 1),
+                iabc(Op::Move, 2, 0, 0),
+                iabx(Op::Closure, 3, 0),
+                iabc(Op::SetTable, 1, 0, 3),
+                iabc(Op::Lt, 1, 0, K + 2),
+                // 每次回到循环开始前关闭j，下一次迭代的闭包捕获新的j
+                iasbx(Op::Jmp, 3, -6),
+                iasbx(Op::Jmp, 3, 0),
+                iabc(Op::Return, 1, 2, 0),
+            ],
+            vec![Constant::Integer(0), Constant::Integer(1), Constant::Integer(3)],
+            4,
+        );
+        main.protos.push(Rc::new(get));
+
+        let mut state = LuaState::new();
+        let f = state.load(main).unwrap();
+        let results = state.call(f, vec![]).unwrap();
+        let LuaValue::Table(fs) = &results[0] else {
+            panic!("expected a table, got {results:?}");
+        };
+        for i in 1..=3 {
+            let g = fs.borrow().get_int(i);
+            assert_eq!(state.call(g, vec![]).unwrap(), vec![LuaValue::Integer(i)]);
+        }
+    }
+
+    #[test]
+    fn test_table_vararg() {
+        // local t = {...}; return #t, t[2]
+        let code = vec![
+            iabc(Op::NewTable, 0, 0, 0),
+            iabc(Op::VarArg, 1, 0, 0),
+            iabc(Op::SetList, 0, 0, 1),
+            iabc(Op::Len, 1, 0, 0),
+            iabc(Op::GetTable, 2, 0, K),
+            iabc(Op::Return, 1, 3, 0),
+        ];
+        let args = vec![LuaValue::Integer(10), LuaValue::Integer(20), LuaValue::Integer(30)];
+        let results = run(proto(code, vec![Constant::Integer(2)], 3), args);
+        assert_eq!(results, vec![LuaValue::Integer(3), LuaValue::Integer(20)]);
+    }
+
+    #[test]
+    fn test_call_native() {
+        // return tostring(42) .. "!"
+        let code = vec![
+            iabc(Op::GetTabUp, 0, 0, K),
+            iabx(Op::LoadK, 1, 1),
+            iabc(Op::Call, 0, 2, 2),
+            iabx(Op::LoadK, 1, 2),
+            iabc(Op::Concat, 0, 0, 1),
+            iabc(Op::Return, 0, 2, 0),
+        ];
+        let constants = vec![
+            Constant::Str(b"tostring".to_vec()),
+            Constant::Integer(42),
+            Constant::Str(b"!".to_vec()),
+        ];
+        let results = run(proto(code, constants, 2), vec![]);
+        assert_eq!(results, vec![LuaValue::from("42!")]);
+    }
+
+    #[test]
+    fn test_interned_strings() {
+        // return "ab", "a" .. "b"
+        let code = vec![
+            iabx(Op::LoadK, 0, 0),
+            iabx(Op::LoadK, 1, 1),
+            iabx(Op::LoadK, 2, 2),
+            iabc(Op::Concat, 1, 1, 2),
+            iabc(Op::Return, 0, 3, 0),
+        ];
+        let constants = vec![
+            Constant::Str(b"ab".to_vec()),
+            Constant::Str(b"a".to_vec()),
+            Constant::Str(b"b".to_vec()),
+        ];
+        let results = run(proto(code, constants, 3), vec![]);
+        let (LuaValue::Str(x), LuaValue::Str(y)) = (&results[0], &results[1]) else {
+            panic!("expected strings, got {results:?}");
+        };
+        assert!(x.ptr_eq(y));
+    }
+
+    #[test]
+    fn test_runtime_error() {
+        // local a; return a + 1
+        let code = vec![
+            iabc(Op::LoadNil, 0, 0, 0),
+            iabc(Op::Add, 0, 0, K),
+            iabc(Op::Return, 0, 2, 0),
+        ];
+        let mut state = LuaState::new();
+        let f = state.load(proto(code, vec![Constant::Integer(1)], 1)).unwrap();
+        let err = state.call(f, vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to perform arithmetic on a nil value");
+        assert!(state.frames.is_empty());
+        assert!(state.stack.is_empty());
+    }
+
+    fn run_source(state: &mut LuaState, src: &str) -> LuaResult<Vec<LuaValue>> {
+        stdlib::open_libs(state);
+        let main = state.load(compile(src.as_bytes(), "=test").unwrap()).unwrap();
+        state.call(main, vec![])
+    }
+
+    #[test]
+    fn test_metamethods() {
+        let src = r#"
+            local V = {}
+            V.__index = V
+            V.__add = function(a, b) return V.new(a.x + b.x) end
+            V.__unm = function(a) return V.new(-a.x) end
+            V.__band = function(a, b) return "band" end
+            V.__eq = function(a, b) return a.x == b.x end
+            V.__lt = function(a, b) return a.x < b.x end
+            V.__len = function(a) return a.x end
+            V.__call = function(self, y) return self.x * y end
+            V.__tostring = function(a) return "V" .. a.x end
+            V.__concat = function(a, b) return tostring(a) .. tostring(b) end
+            function V.new(x) return setmetatable({x = x}, V) end
+            function V:get() return self.x end
+            local a, b = V.new(1), V.new(2)
+            return (a + b).x, (-b).x, a & 1, a == V.new(1), a <= b, b <= a,
+                #b, a(10), "<" .. a .. b .. 3, b:get()
+        "#;
+        let results = run_source(&mut LuaState::new(), src).unwrap();
+        let expected = vec![
+            LuaValue::Integer(3),
+            LuaValue::Integer(-2),
+            LuaValue::from("band"),
+            LuaValue::Boolean(true),
+            // 没有__le时使用not (b < a)
+            LuaValue::Boolean(true),
+            LuaValue::Boolean(false),
+            LuaValue::Integer(2),
+            LuaValue::Integer(10),
+            LuaValue::from("<V1V23"),
+            LuaValue::Integer(2),
+        ];
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn test_index_chain() {
+        let src = r#"
+            local log = {}
+            local base = {greet = "hi"}
+            local t = setmetatable({}, {__index = setmetatable({}, {__index = base})})
+            local p = setmetatable({}, {__newindex = function(t, k, v) log[#log + 1] = k; rawset(t, k, v) end})
+            p.x = 1; p.x = 2; p.y = 3
+            return t.greet, t.other, #log, log[2], p.x
+        "#;
+        let results = run_source(&mut LuaState::new(), src).unwrap();
+        let expected = vec![
+            LuaValue::from("hi"),
+            LuaValue::Nil,
+            LuaValue::Integer(2),
+            LuaValue::from("y"),
+            LuaValue::Integer(2),
+        ];
+        assert_eq!(results, expected);
+
+        let src = "local t = {}; setmetatable(t, {__index = t}); return t.x";
+        let err = run_source(&mut LuaState::new(), src).unwrap_err();
+        assert_eq!(err.to_string(), "test:1: '__index' chain too long; possible loop");
+        let src = "local t = setmetatable({}, {__metatable = false}); return getmetatable(t), setmetatable(t, {})";
+        let err = run_source(&mut LuaState::new(), src).unwrap_err();
+        assert_eq!(err.to_string(), "test:1: cannot change a protected metatable");
+    }
+
+    #[test]
+    fn test_metamethod_errors() {
+        // 没有元方法或元方法出错时的错误信息与Lua 5.3相同
+        let src = r#"
+            local cases = {
+                function() return {} < {} end,
+                function() return {} <= 1 end,
+                function() return {} .. "x" end,
+                function() return #nil end,
+                function() return -{} end,
+                function() return 1 & 1.5 end,
+                function() return "a" | 1 end,
+                function() return setmetatable({}, {__call = 1})() end,
+                function() local t = setmetatable({}, {__newindex = {}}) t[nil] = 1 end,
+                function() local t = setmetatable({}, {}) getmetatable(t).__newindex = t t.x = 1 end,
+                function() return setmetatable({}, {__index = function(_, k) error("no " .. k) end}).f end,
+                function() local t = setmetatable({}, {__lt = function() error("cmp") end}) return t < t end,
+            }
+            local results = {}
+            for i, f in ipairs(cases) do results[i] = select(2, pcall(f)) end
+            return results
+        "#;
+        let LuaValue::Table(t) = &run_source(&mut LuaState::new(), src).unwrap()[0] else {
+            panic!()
+        };
+        let results: Vec<String> =
+            (1..=12).map(|i| t.borrow().get(&LuaValue::Integer(i)).to_string()).collect();
+        let expected = [
+            "test:3: attempt to compare two table values",
+            "test:4: attempt to compare table with number",
+            "test:5: attempt to concatenate a table value",
+            "test:6: attempt to get length of a nil value",
+            "test:7: attempt to perform arithmetic on a table value",
+            "test:8: number has no integer representation",
+            "test:9: attempt to perform bitwise operation on a string value",
+            "test:10: attempt to call a table value",
+            "test:11: table index is nil",
+            "test:12: '__newindex' chain too long; possible loop",
+            "test:13: no f",
+            "test:14: cmp",
+        ];
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn test_finalizers() {
+        // 关闭时按标记的相反顺序调用__gc，设置元表之后才添加的__gc不会被调用
+        let src = r#"
+            order = {}
+            local mt = {__gc = function(o) order[#order + 1] = o.name end}
+            setmetatable({name = "a"}, mt)
+            setmetatable({name = "b"}, mt)
+            local late = {}
+            setmetatable({name = "c"}, late)
+            late.__gc = mt.__gc
+        "#;
+        let mut state = LuaState::new();
+        run_source(&mut state, src).unwrap();
+        state.close();
+        let LuaValue::Table(order) = state.get_global("order") else {
+            panic!("order is not a table");
+        };
+        let order = order.borrow();
+        assert_eq!(order.len(), 2);
+        assert_eq!((order.get_int(1), order.get_int(2)), (LuaValue::from("b"), LuaValue::from("a")));
+    }
+
+    #[test]
+    fn test_coroutines() {
+        let src = r#"
+            local log = {}
+            local co = coroutine.create(function(a, b)
+                local c = coroutine.yield(a + b)
+                local d, e = coroutine.yield(c * 2)
+                return d + e
+            end)
+            local _, x = coroutine.resume(co, 1, 2)
+            local _, y = coroutine.resume(co, 10)
+            local _, z = coroutine.resume(co, 3, 4)
+            local ok, err = coroutine.resume(co)
+
+            -- 在TFORCALL调用的迭代器中让出
+            local function iter(t, i)
+                i = i + 1
+                if t[i] then coroutine.yield("visit " .. t[i]) return i, t[i] end
+            end
+            local walk = coroutine.wrap(function()
+                for _, v in iter, {"a", "b"}, 0 do coroutine.yield("body " .. v) end
+                return "done"
+            end)
+            for step in walk do
+                log[#log + 1] = step
+                if step == "done" then break end
+            end
+
+            -- 协程中的闭包访问主线程的局部变量，主线程访问已让出的协程的局部变量
+            local count = 0
+            local getter
+            local counter = coroutine.wrap(function()
+                local n = 0
+                getter = function() return n end
+                while true do count = count + 1; n = n + 10; coroutine.yield() end
+            end)
+            counter(); counter()
+            local steps = log[1] .. "," .. log[2] .. "," .. log[5]
+            return x, y, z, ok, err, coroutine.status(co), steps, count, getter()
+        "#;
+        let results = run_source(&mut LuaState::new(), src).unwrap();
+        let expected = vec![
+            LuaValue::Integer(3),
+            LuaValue::Integer(20),
+            LuaValue::Integer(7),
+            LuaValue::Boolean(false),
+            LuaValue::from("cannot resume dead coroutine"),
+            LuaValue::from("dead"),
+            LuaValue::from("visit a,body a,done"),
+            LuaValue::Integer(2),
+            LuaValue::Integer(20),
+        ];
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn test_coroutine_errors() {
+        let src = r#"
+            local bad = coroutine.create(function() local t; return t.x end)
+            local ok, err = coroutine.resume(bad)
+            local mm = setmetatable({}, {__index = function() return coroutine.yield() end})
+            local _, across = coroutine.resume(coroutine.create(function() return mm.x end))
+            local _, self_resume = coroutine.resume(coroutine.create(function()
+                return coroutine.resume(coroutine.running())
+            end))
+            return ok, err, coroutine.status(bad), across, self_resume, coroutine.isyieldable()
+        "#;
+        let results = run_source(&mut LuaState::new(), src).unwrap();
+        let expected = vec![
+            LuaValue::Boolean(false),
+            LuaValue::from("test:2: attempt to index a nil value"),
+            LuaValue::from("dead"),
+            LuaValue::from("attempt to yield across a C-call boundary"),
+            LuaValue::Boolean(false),
+            LuaValue::Boolean(false),
+        ];
+        assert_eq!(results, expected);
+
+        let err = run_source(&mut LuaState::new(), "coroutine.yield(1)").unwrap_err();
+        assert_eq!(err.to_string(), "attempt to yield from outside a coroutine");
+    }
+
+    #[test]
+    fn test_error_handling() {
+        let src = r#"
+            local function g() error("boom", 2) end
+            local function h() g() end
+            local _, obj = pcall(function() error({code = 1}) end)
+            local _, lvl = pcall(h)
+            local _, plain = pcall(error, "x", 0)
+            local _, handled = xpcall(function() local t; return t.x end, function(m) return "H: " .. m end)
+            local _, nested = xpcall(function() error("a") end, function() error("b") end)
+            return obj.code, lvl, plain, handled, nested, pcall(function(a, b) return a + b end, 1, 2)
+        "#;
+        let results = run_source(&mut LuaState::new(), src).unwrap();
+        let expected = vec![
+            LuaValue::Integer(1),
+            LuaValue::from("test:3: boom"),
+            LuaValue::from("x"),
+            LuaValue::from("H: test:7: attempt to index a nil value"),
+            LuaValue::from("error in error handling"),
+            LuaValue::Boolean(true),
+            LuaValue::Integer(3),
+        ];
+        assert_eq!(results, expected);
+
+        let err = run_source(&mut LuaState::new(), "error(setmetatable({}, {}))").unwrap_err();
+        assert_eq!(err.to_string(), "(error object is a table value)");
+    }
+
+    #[test]
+    fn test_traceback() {
+        let src = r#"
+            local function f() error("e") end
+            return select(2, xpcall(f, debug.traceback))
+        "#;
+        let results = run_source(&mut LuaState::new(), src).unwrap();
+        let expected = "test:2: e\nstack traceback:\n\
+            \t[C]: in function 'error'\n\
+            \ttest:2: in function <test:2>\n\
+            \t[C]: in function 'xpcall'\n\
+            \ttest:3: in main chunk\n\
+            \t[C]: in ?";
+        assert_eq!(results, vec![LuaValue::from(expected)]);
+    }
+
+    #[test]
+    fn test_malformed_setlist() {
+        // 目标寄存器不是表，或者EXTRAARG给出的块号为0时报错而不是panic
+        let cases = [
+            (iabc(Op::LoadNil, 0, 0, 0), iabc(Op::SetList, 0, 2, 1), 0),
+            (iabc(Op::NewTable, 0, 2, 0), iabc(Op::SetList, 0, 2, 0), 0),
+        ];
+        let expected = [
+            "SETLIST target is a nil value, not a table",
+            "SETLIST block number must be positive",
+        ];
+        for ((first, setlist, ax), expected) in cases.into_iter().zip(expected) {
+            let code = vec![
+                first,
+                iabx(Op::LoadK, 1, 0),
+                iabx(Op::LoadK, 2, 0),
+                setlist,
+                u32::encode_ax(Op::ExtraArg, ax).unwrap(),
+                iabc(Op::Return, 0, 1, 0),
+            ];
+            let mut state = LuaState::new();
+            let main = state.load(proto(code, vec![Constant::Integer(1)], 3)).unwrap();
+            let err = state.call(main, vec![]).unwrap_err();
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_load_verifies() {
+        // B为0的SETLIST之前没有设置栈顶，load拒绝执行
+        let code = vec![
+            iabc(Op::NewTable, 0, 2, 0),
+            iabx(Op::LoadK, 1, 0),
+            iabc(Op::SetList, 0, 0, 1),
+            iabc(Op::Return, 0, 1, 0),
+        ];
+        let mut state = LuaState::new();
+        let result = state.load(proto(code, vec![Constant::Integer(1)], 2));
+        assert_eq!(result, Err(VerifyError::MissingOpenTop { pc: 2 }));
+    }
+}
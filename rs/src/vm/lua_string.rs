@@ -0,0 +1,210 @@
+use std::borrow::{Borrow, Cow};
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops::Deref;
+use std::rc::Rc;
+
+// 不超过这个长度的字符串会被驻留，与Lua的LUAI_MAXSHORTLEN保持一致
+pub const MAX_SHORT_LEN: usize = 40;
+
+/// Lua字符串，内容是任意字节序列，不要求是UTF-8
+/// 克隆只增加引用计数，驻留的短字符串通常只需比较指针
+#[derive(Clone)]
+pub struct LuaString(Rc<[u8]>);
+
+impl LuaString {
+    pub fn new(bytes: &[u8]) -> Self {
+        LuaString(Rc::from(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    // 内容是合法的UTF-8时返回&str
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    // 不合法的UTF-8替换为U+FFFD，只用于显示
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    pub fn ptr_eq(&self, other: &LuaString) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Deref for LuaString {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Borrow<[u8]> for LuaString {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl PartialEq for LuaString {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_eq(other) || self.0 == other.0
+    }
+}
+
+impl Eq for LuaString {}
+
+// 与[u8]的哈希一致，驻留池可以直接用字节查找
+impl Hash for LuaString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
+// 按字节比较，相当于C locale下的strcoll
+impl PartialOrd for LuaString {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for LuaString {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl From<&[u8]> for LuaString {
+    fn from(bytes: &[u8]) -> Self {
+        LuaString::new(bytes)
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    fn from(bytes: Vec<u8>) -> Self {
+        LuaString(Rc::from(bytes))
+    }
+}
+
+impl From<&str> for LuaString {
+    fn from(s: &str) -> Self {
+        LuaString::new(s.as_bytes())
+    }
+}
+
+impl From<String> for LuaString {
+    fn from(s: String) -> Self {
+        LuaString::from(s.into_bytes())
+    }
+}
+
+impl fmt::Display for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_str_lossy())
+    }
+}
+
+impl fmt::Debug for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self.0.escape_ascii())
+    }
+}
+
+/// 短字符串驻留池，内容相同的短字符串共享同一份内存
+/// 池中只被池本身引用的字符串在池增长到上次清理后的两倍时被清除
+pub struct StringPool {
+    strings: HashSet<LuaString>,
+    // 下次清理时池的大小
+    threshold: usize,
+}
+
+impl Default for StringPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StringPool {
+    pub fn new() -> Self {
+        Self {
+            strings: HashSet::new(),
+            threshold: 64,
+        }
+    }
+
+    // 返回内容为bytes的字符串，短字符串从池中取出或放入池中，长字符串直接创建
+    pub fn intern(&mut self, bytes: &[u8]) -> LuaString {
+        if bytes.len() > MAX_SHORT_LEN {
+            return LuaString::new(bytes);
+        }
+        if let Some(s) = self.strings.get(bytes) {
+            return s.clone();
+        }
+        if self.strings.len() >= self.threshold {
+            self.sweep();
+            self.threshold = (self.strings.len() * 2).max(64);
+        }
+        let s = LuaString::new(bytes);
+        self.strings.insert(s.clone());
+        s
+    }
+
+    // 清除不再被池外引用的字符串
+    pub fn sweep(&mut self) {
+        self.strings.retain(|s| Rc::strong_count(&s.0) > 1);
+    }
+
+    // 估计池中字符串占用的字节数
+    pub fn memory(&self) -> usize {
+        self.strings
+            .iter()
+            .map(|s| mem::size_of::<LuaString>() + 2 * mem::size_of::<usize>() + s.len())
+            .sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::vm::lua_string::{LuaString, StringPool, MAX_SHORT_LEN};
+
+    #[test]
+    fn test_intern() {
+        let mut pool = StringPool::new();
+        let a = pool.intern(b"hello");
+        let b = pool.intern(b"hello");
+        assert!(a.ptr_eq(&b));
+        assert_eq!(a, LuaString::from("hello"));
+
+        let long = vec![b'x'; MAX_SHORT_LEN + 1];
+        assert!(!pool.intern(&long).ptr_eq(&pool.intern(&long)));
+        assert_eq!(pool.len(), 1);
+
+        drop((a, b));
+        pool.sweep();
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_bytes() {
+        let s = LuaString::from(vec![0xff, 0x00, b'a']);
+        assert_eq!(s.len(), 3);
+        assert_eq!(s.to_str(), None);
+        assert_eq!(format!("{s:?}"), "\"\\xff\\x00a\"");
+        assert!(LuaString::from("a") < LuaString::from("b"));
+        assert!(LuaString::from("") < s);
+    }
+}
@@ -0,0 +1,507 @@
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::rc::Rc;
+
+use crate::vm::gc::Child;
+use crate::vm::lua_value::{float_to_integer, LuaValue};
+
+// 数组部分的最大长度为2^MAX_A_BITS，与Lua的MAXABITS保持一致
+const MAX_A_BITS: usize = 31;
+
+/// Lua表，与Lua的实现一样分为数组部分和哈希部分
+/// 数组部分存放key为1..=n的值，中间可以有nil；其余键值对存放在哈希部分
+/// 哈希部分的节点数是2的幂，与主位置冲突的key放在空闲节点中，用next连成链表
+/// 没有空闲节点时统计所有整数key重新计算两部分的大小(rehash)，数组部分的使用率总是超过一半
+#[derive(Debug, Default)]
+pub struct LuaTable {
+    arr: Vec<LuaValue>,
+    node: Vec<Node>,
+    // 从后往前寻找空闲节点的位置，之后的节点都已被使用过
+    last_free: usize,
+    metatable: Option<Rc<RefCell<LuaTable>>>,
+}
+
+/// 哈希部分的节点，key为nil的节点是空闲节点
+/// 值为nil的节点是被删除的key(dead key)，保留key使next可以从它继续遍历，rehash时才被清除
+#[derive(Debug, Default, Clone)]
+struct Node {
+    key: LuaValue,
+    val: LuaValue,
+    // 冲突链表中的下一个节点
+    next: Option<usize>,
+}
+
+impl LuaTable {
+    // n_arr、n_rec分别是数组部分和哈希部分的初始大小，即NEWTABLE的B、C操作数解码后的值
+    pub fn new(n_arr: usize, n_rec: usize) -> Self {
+        let mut t = Self::default();
+        t.resize(n_arr, n_rec);
+        t
+    }
+
+    pub fn metatable(&self) -> Option<Rc<RefCell<LuaTable>>> {
+        self.metatable.clone()
+    }
+
+    pub fn set_metatable(&mut self, mt: Option<Rc<RefCell<LuaTable>>>) {
+        self.metatable = mt;
+    }
+
+    pub fn get(&self, key: &LuaValue) -> LuaValue {
+        let key = normalize_key(key);
+        if let Some(i) = self.array_index(&key) {
+            return self.arr[i].clone();
+        }
+        match self.find_node(&key) {
+            Some(n) => self.node[n].val.clone(),
+            None => LuaValue::Nil,
+        }
+    }
+
+    pub fn get_int(&self, i: i64) -> LuaValue {
+        self.get(&LuaValue::Integer(i))
+    }
+
+    pub fn put(&mut self, key: LuaValue, val: LuaValue) -> Result<(), &'static str> {
+        let key = normalize_key(&key);
+        match key {
+            LuaValue::Nil => return Err("table index is nil"),
+            LuaValue::Number(n) if n.is_nan() => return Err("table index is NaN"),
+            _ => {}
+        }
+        self.set(key, val);
+        Ok(())
+    }
+
+    // key已经规范化，不是nil或NaN
+    // 修改或删除已有的key不会移动节点，所以不影响正在进行的遍历
+    fn set(&mut self, key: LuaValue, val: LuaValue) {
+        if let Some(i) = self.array_index(&key) {
+            self.arr[i] = val;
+            return;
+        }
+        if let Some(n) = self.find_node(&key) {
+            self.node[n].val = val;
+            return;
+        }
+        if val.is_nil() {
+            return;
+        }
+        if let Err((key, val)) = self.new_key(key, val) {
+            self.rehash(&key);
+            self.set(key, val);
+        }
+    }
+
+    // 整数key在数组部分中的下标
+    fn array_index(&self, key: &LuaValue) -> Option<usize> {
+        match key {
+            LuaValue::Integer(i) if *i >= 1 && *i as u64 <= self.arr.len() as u64 => {
+                Some(*i as usize - 1)
+            }
+            _ => None,
+        }
+    }
+
+    fn main_position(&self, key: &LuaValue) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize & (self.node.len() - 1)
+    }
+
+    // 沿冲突链表查找key所在的节点，包括被删除的key
+    fn find_node(&self, key: &LuaValue) -> Option<usize> {
+        if self.node.is_empty() {
+            return None;
+        }
+        let mut n = Some(self.main_position(key));
+        while let Some(i) = n {
+            if self.node[i].key == *key {
+                return Some(i);
+            }
+            n = self.node[i].next;
+        }
+        None
+    }
+
+    // 插入哈希部分中不存在的key(luaH_newkey)，没有空闲节点时原样返回键值对
+    fn new_key(&mut self, key: LuaValue, val: LuaValue) -> Result<(), (LuaValue, LuaValue)> {
+        if self.node.is_empty() {
+            return Err((key, val));
+        }
+        let mut mp = self.main_position(&key);
+        if !self.node[mp].val.is_nil() {
+            let Some(free) = self.free_position() else {
+                return Err((key, val));
+            };
+            let other = self.main_position(&self.node[mp].key);
+            if other != mp {
+                // 占据主位置的节点不在它自己的主位置上，把它移到空闲节点，新key使用主位置
+                let mut prev = other;
+                while self.node[prev].next != Some(mp) {
+                    prev = self.node[prev].next.unwrap();
+                }
+                self.node[prev].next = Some(free);
+                self.node[free] = mem::take(&mut self.node[mp]);
+            } else {
+                // 占据主位置的节点在它自己的主位置上，新key放在空闲节点，接在主位置之后
+                self.node[free].next = self.node[mp].next;
+                self.node[mp].next = Some(free);
+                mp = free;
+            }
+        }
+        self.node[mp].key = key;
+        self.node[mp].val = val;
+        Ok(())
+    }
+
+    fn free_position(&mut self) -> Option<usize> {
+        while self.last_free > 0 {
+            self.last_free -= 1;
+            if self.node[self.last_free].key.is_nil() {
+                return Some(self.last_free);
+            }
+        }
+        None
+    }
+
+    // 统计数组部分、哈希部分和将要插入的key中的整数key，重新计算两部分的大小
+    fn rehash(&mut self, extra: &LuaValue) {
+        let mut nums = [0; MAX_A_BITS + 1];
+        let mut n_int = 0;
+        let mut total = 1;
+        for (i, v) in self.arr.iter().enumerate() {
+            if !v.is_nil() {
+                count_int(i as i64 + 1, &mut nums);
+                n_int += 1;
+                total += 1;
+            }
+        }
+        for node in self.node.iter().filter(|node| !node.val.is_nil()) {
+            total += 1;
+            if let LuaValue::Integer(i) = node.key {
+                n_int += count_int(i, &mut nums) as usize;
+            }
+        }
+        if let LuaValue::Integer(i) = extra {
+            n_int += count_int(*i, &mut nums) as usize;
+        }
+        let (n_arr, n_arr_used) = compute_sizes(&nums, n_int);
+        self.resize(n_arr, total - n_arr_used);
+    }
+
+    // 调整数组部分和哈希部分的大小，哈希部分的大小向上取整到2的幂，被删除的key在这里被清除
+    fn resize(&mut self, n_arr: usize, n_hash: usize) {
+        let size = match n_hash {
+            0 => 0,
+            n => n.next_power_of_two(),
+        };
+        let old = mem::replace(&mut self.node, vec![Node::default(); size]);
+        self.last_free = size;
+        let moved = if n_arr < self.arr.len() {
+            self.arr.split_off(n_arr)
+        } else {
+            self.arr.resize(n_arr, LuaValue::Nil);
+            Vec::new()
+        };
+        for (i, val) in moved.into_iter().enumerate() {
+            if !val.is_nil() {
+                self.set(LuaValue::Integer((n_arr + i + 1) as i64), val);
+            }
+        }
+        for node in old.into_iter().filter(|node| !node.val.is_nil()) {
+            self.set(node.key, node.val);
+        }
+    }
+
+    // 长度操作符，返回一个边界n: t[n]不为nil且t[n+1]为nil，t[1]为nil时可以是0
+    pub fn len(&self) -> usize {
+        let j = self.arr.len();
+        if j > 0 && self.arr[j - 1].is_nil() {
+            // 边界在数组部分中，二分查找
+            let mut i = 0;
+            let mut j = j;
+            while j - i > 1 {
+                let m = (i + j) / 2;
+                if self.arr[m - 1].is_nil() {
+                    j = m;
+                } else {
+                    i = m;
+                }
+            }
+            return i;
+        }
+        if self.node.is_empty() {
+            return j;
+        }
+        self.unbound_search(j)
+    }
+
+    // t[j]不为nil(或j为0)，在哈希部分中倍增查找一个为nil的位置，再二分查找边界
+    fn unbound_search(&self, j: usize) -> usize {
+        let mut i = j;
+        let mut j = j + 1;
+        while !self.get_int(j as i64).is_nil() {
+            i = j;
+            if j > i64::MAX as usize / 2 {
+                // 恶意构造的表，线性查找
+                let mut i = 1;
+                while !self.get_int(i as i64).is_nil() {
+                    i += 1;
+                }
+                return i - 1;
+            }
+            j *= 2;
+        }
+        while j - i > 1 {
+            let m = (i + j) / 2;
+            if self.get_int(m as i64).is_nil() {
+                j = m;
+            } else {
+                i = m;
+            }
+        }
+        i
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.arr.iter().all(LuaValue::is_nil) && self.node.iter().all(|node| node.val.is_nil())
+    }
+
+    // 对应Lua中的next函数，返回key的下一个键值对，遍历结束时返回None
+    // 先按下标遍历数组部分，再按节点顺序遍历哈希部分
+    pub fn next(&self, key: &LuaValue) -> Result<Option<(LuaValue, LuaValue)>, &'static str> {
+        let key = normalize_key(key);
+        let start = if key.is_nil() {
+            0
+        } else if let Some(i) = self.array_index(&key) {
+            i + 1
+        } else {
+            match self.find_node(&key) {
+                Some(n) => self.arr.len() + n + 1,
+                None => return Err("invalid key to 'next'"),
+            }
+        };
+
+        for i in start..self.arr.len() {
+            if !self.arr[i].is_nil() {
+                return Ok(Some((LuaValue::Integer(i as i64 + 1), self.arr[i].clone())));
+            }
+        }
+        for node in self.node.iter().skip(start.saturating_sub(self.arr.len())) {
+            if !node.val.is_nil() {
+                return Ok(Some((node.key.clone(), node.val.clone())));
+            }
+        }
+        Ok(None)
+    }
+
+    // 遍历表持有的所有引用，包括被删除的key，用于垃圾回收时计算外部引用
+    pub(crate) fn for_each_ref(&self, f: &mut dyn FnMut(Child)) {
+        for v in &self.arr {
+            f(Child::Value(v));
+        }
+        for node in &self.node {
+            f(Child::Value(&node.key));
+            f(Child::Value(&node.val));
+        }
+        if let Some(mt) = &self.metatable {
+            f(Child::Table(mt));
+        }
+    }
+
+    // 遍历所有键值对，用于垃圾回收的标记
+    pub(crate) fn for_each_entry(&self, mut f: impl FnMut(&LuaValue, &LuaValue)) {
+        for (i, v) in self.arr.iter().enumerate() {
+            if !v.is_nil() {
+                f(&LuaValue::Integer(i as i64 + 1), v);
+            }
+        }
+        for node in &self.node {
+            if !node.val.is_nil() {
+                f(&node.key, &node.val);
+            }
+        }
+    }
+
+    // 被删除的项仍然保留的key，它们不会被标记，回收时被替换为NaN
+    pub(crate) fn dead_keys(&self) -> impl Iterator<Item = &LuaValue> {
+        self.node
+            .iter()
+            .filter(|node| node.val.is_nil() && !node.key.is_nil())
+            .map(|node| &node.key)
+    }
+
+    // 回收后清理表中引用的不可达对象：弱键或弱值不可达的项被删除，
+    // 不可达的被删除的key替换为不会与任何key相等的NaN，使key引用的对象可以被释放
+    pub(crate) fn clear_dead(
+        &mut self,
+        weak_keys: bool,
+        weak_values: bool,
+        is_alive: impl Fn(&LuaValue) -> bool,
+    ) {
+        if weak_values {
+            for v in &mut self.arr {
+                if !is_alive(v) {
+                    *v = LuaValue::Nil;
+                }
+            }
+        }
+        for node in &mut self.node {
+            if node.key.is_nil() {
+                continue;
+            }
+            if (weak_keys && !is_alive(&node.key)) || (weak_values && !is_alive(&node.val)) {
+                node.val = LuaValue::Nil;
+            }
+            if node.val.is_nil() && !is_alive(&node.key) {
+                node.key = LuaValue::Number(f64::NAN);
+            }
+        }
+    }
+
+    // 估计表占用的字节数
+    pub(crate) fn memory(&self) -> usize {
+        mem::size_of::<RefCell<LuaTable>>()
+            + self.arr.capacity() * mem::size_of::<LuaValue>()
+            + self.node.capacity() * mem::size_of::<Node>()
+    }
+}
+
+// 能无损转换为整数的浮点数key统一转换为整数
+fn normalize_key(key: &LuaValue) -> LuaValue {
+    if let LuaValue::Number(n) = key {
+        if let Some(i) = float_to_integer(*n) {
+            return LuaValue::Integer(i);
+        }
+    }
+    key.clone()
+}
+
+// 可以放在数组部分的整数key按所在区间计数，nums[i]是(2^(i-1), 2^i]中key的数量
+fn count_int(k: i64, nums: &mut [usize; MAX_A_BITS + 1]) -> bool {
+    if !(1..=1 << MAX_A_BITS).contains(&k) {
+        return false;
+    }
+    nums[(64 - (k as u64 - 1).leading_zeros()) as usize] += 1;
+    true
+}
+
+// 找到最大的2^i，使1..=2^i中的整数key超过一半，返回(数组部分大小, 放在数组部分中的key数量)
+fn compute_sizes(nums: &[usize; MAX_A_BITS + 1], n_int: usize) -> (usize, usize) {
+    let mut a = 0;
+    let mut n_arr_used = 0;
+    let mut optimal = 0;
+    let mut two_to_i = 1;
+    for &n in nums {
+        if n_int <= two_to_i / 2 {
+            break;
+        }
+        a += n;
+        if n > 0 && a > two_to_i / 2 {
+            optimal = two_to_i;
+            n_arr_used = a;
+        }
+        two_to_i *= 2;
+    }
+    (optimal, n_arr_used)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::vm::lua_table::LuaTable;
+    use crate::vm::lua_value::LuaValue;
+
+    fn int(i: i64) -> LuaValue {
+        LuaValue::Integer(i)
+    }
+
+    #[test]
+    fn test_parts() {
+        let mut t = LuaTable::new(3, 2);
+        assert_eq!((t.arr.len(), t.node.len()), (3, 2));
+        for i in 1..=3 {
+            t.put(int(i), int(i * 10)).unwrap();
+        }
+        t.put(LuaValue::from("x"), int(1)).unwrap();
+        assert_eq!((t.arr.len(), t.node.len()), (3, 2));
+
+        // 哈希部分放满后rehash，连续的整数key移到数组部分
+        for i in 4..=20 {
+            t.put(int(i), int(i * 10)).unwrap();
+        }
+        assert_eq!(t.arr.len(), 32);
+        assert_eq!(t.len(), 20);
+        assert_eq!(t.get(&LuaValue::Number(7.0)), int(70));
+        assert_eq!(t.get(&LuaValue::from("x")), int(1));
+
+        // 稀疏的整数key放在哈希部分
+        let mut t = LuaTable::new(0, 0);
+        for i in 0..10 {
+            t.put(int(1 << (i * 5)), int(i)).unwrap();
+        }
+        assert!(t.arr.len() <= 1);
+        assert_eq!(t.get_int(1 << 45), int(9));
+
+        assert_eq!(t.put(LuaValue::Nil, int(1)), Err("table index is nil"));
+        assert_eq!(t.put(LuaValue::Number(f64::NAN), int(1)), Err("table index is NaN"));
+        t.put(LuaValue::Number(2.0), int(2)).unwrap();
+        assert_eq!(t.get_int(2), int(2));
+        assert!(t.next(&LuaValue::Number(2.0)).is_ok());
+    }
+
+    #[test]
+    fn test_len() {
+        let mut t = LuaTable::new(4, 0);
+        assert_eq!(t.len(), 0);
+        t.put(int(1), int(1)).unwrap();
+        t.put(int(2), int(2)).unwrap();
+        assert_eq!(t.len(), 2);
+        // 数组部分已满，边界在哈希部分中
+        for i in 3..=4 {
+            t.put(int(i), int(i)).unwrap();
+        }
+        t.put(int(5), int(5)).unwrap();
+        assert_eq!(t.len(), 5);
+        t.put(int(5), LuaValue::Nil).unwrap();
+        t.put(int(4), LuaValue::Nil).unwrap();
+        assert_eq!(t.len(), 3);
+
+        // {1, nil, 3}的边界可以是1或3
+        let mut t = LuaTable::new(3, 0);
+        t.put(int(1), int(1)).unwrap();
+        t.put(int(3), int(3)).unwrap();
+        let n = t.len() as i64;
+        assert!(!t.get_int(n).is_nil() && t.get_int(n + 1).is_nil());
+    }
+
+    #[test]
+    fn test_next() {
+        let mut t = LuaTable::new(2, 0);
+        for i in 1..=2 {
+            t.put(int(i), int(i)).unwrap();
+        }
+        for s in ["a", "b", "c", "d", "e"] {
+            t.put(LuaValue::from(s), LuaValue::from(s)).unwrap();
+        }
+
+        // 遍历过程中修改和删除已有的key，每个key恰好被访问一次
+        let mut seen = Vec::new();
+        let mut key = LuaValue::Nil;
+        while let Some((k, v)) = t.next(&key).unwrap() {
+            assert!(!v.is_nil());
+            t.put(k.clone(), LuaValue::Nil).unwrap();
+            if let Some((next, _)) = t.next(&k).unwrap() {
+                t.put(next, int(0)).unwrap();
+            }
+            seen.push(k.to_string());
+            key = k;
+        }
+        seen.sort();
+        assert_eq!(seen, ["1", "2", "a", "b", "c", "d", "e"]);
+        assert!(t.is_empty());
+        assert_eq!(t.next(&LuaValue::from("z")), Err("invalid key to 'next'"));
+    }
+}
@@ -0,0 +1,443 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use crate::binary::chunk::Constant;
+use crate::vm::closure::Closure;
+use crate::vm::lua_state::LuaState;
+use crate::vm::lua_string::LuaString;
+use crate::vm::lua_table::LuaTable;
+use crate::vm::userdata::UserData;
+
+/// Lua运行时的值，数字分为整数和浮点数两种子类型
+/// 字符串、表、函数、用户数据和线程都是引用类型，克隆只增加引用计数
+#[derive(Clone, Default)]
+pub enum LuaValue {
+    #[default]
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    Str(LuaString),
+    Table(Rc<RefCell<LuaTable>>),
+    Function(Rc<Closure>),
+    UserData(Rc<UserData>),
+    // 线程有自己的栈和调用帧
+    Thread(Rc<RefCell<LuaState>>),
+}
+
+impl LuaValue {
+    // 创建的表和用户数据不被垃圾回收器跟踪，可能形成环时使用LuaState::new_table和new_userdata
+    pub fn new_table(n_arr: usize, n_rec: usize) -> LuaValue {
+        LuaValue::Table(Rc::new(RefCell::new(LuaTable::new(n_arr, n_rec))))
+    }
+
+    pub fn new_userdata<T: std::any::Any>(value: T) -> LuaValue {
+        LuaValue::UserData(Rc::new(UserData::new(value)))
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, LuaValue::Nil)
+    }
+
+    // 对应Lua中的type函数
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LuaValue::Nil => "nil",
+            LuaValue::Boolean(_) => "boolean",
+            LuaValue::Integer(_) | LuaValue::Number(_) => "number",
+            LuaValue::Str(_) => "string",
+            LuaValue::Table(_) => "table",
+            LuaValue::Function(_) => "function",
+            LuaValue::UserData(_) => "userdata",
+            LuaValue::Thread(_) => "thread",
+        }
+    }
+
+    // 只有nil和false为假
+    pub fn to_boolean(&self) -> bool {
+        !matches!(self, LuaValue::Nil | LuaValue::Boolean(false))
+    }
+
+    // 转换为浮点数，字符串会按Lua语法解析
+    pub fn to_number(&self) -> Option<f64> {
+        match self {
+            LuaValue::Integer(i) => Some(*i as f64),
+            LuaValue::Number(n) => Some(*n),
+            LuaValue::Str(s) => match s.to_str().and_then(parse_number) {
+                Some(LuaValue::Integer(i)) => Some(i as f64),
+                Some(LuaValue::Number(n)) => Some(n),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // 转换为整数，浮点数必须能无损转换
+    pub fn to_integer(&self) -> Option<i64> {
+        match self {
+            LuaValue::Integer(i) => Some(*i),
+            LuaValue::Number(n) => float_to_integer(*n),
+            LuaValue::Str(s) => match s.to_str().and_then(parse_number) {
+                Some(LuaValue::Integer(i)) => Some(i),
+                Some(LuaValue::Number(n)) => float_to_integer(n),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // 转换为数字(整数或浮点数)，用于算术运算
+    pub fn to_arith(&self) -> Option<LuaValue> {
+        match self {
+            LuaValue::Integer(_) | LuaValue::Number(_) => Some(self.clone()),
+            LuaValue::Str(s) => s.to_str().and_then(parse_number),
+            _ => None,
+        }
+    }
+
+    // 数字和字符串可以转换为字符串
+    pub fn to_str(&self) -> Option<LuaString> {
+        match self {
+            LuaValue::Str(s) => Some(s.clone()),
+            LuaValue::Integer(i) => Some(LuaString::from(i.to_string())),
+            LuaValue::Number(n) => Some(LuaString::from(number_to_string(*n))),
+            _ => None,
+        }
+    }
+
+    // 不考虑元方法的相等比较
+    pub fn raw_equals(&self, other: &LuaValue) -> bool {
+        match (self, other) {
+            (LuaValue::Nil, LuaValue::Nil) => true,
+            (LuaValue::Boolean(a), LuaValue::Boolean(b)) => a == b,
+            (LuaValue::Integer(a), LuaValue::Integer(b)) => a == b,
+            (LuaValue::Number(a), LuaValue::Number(b)) => a == b,
+            (LuaValue::Integer(a), LuaValue::Number(b)) => float_to_integer(*b) == Some(*a),
+            (LuaValue::Number(a), LuaValue::Integer(b)) => float_to_integer(*a) == Some(*b),
+            (LuaValue::Str(a), LuaValue::Str(b)) => a == b,
+            (LuaValue::Table(a), LuaValue::Table(b)) => Rc::ptr_eq(a, b),
+            (LuaValue::Function(a), LuaValue::Function(b)) => Rc::ptr_eq(a, b),
+            (LuaValue::UserData(a), LuaValue::UserData(b)) => Rc::ptr_eq(a, b),
+            (LuaValue::Thread(a), LuaValue::Thread(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl From<&Constant> for LuaValue {
+    fn from(constant: &Constant) -> Self {
+        match constant {
+            Constant::Nil => LuaValue::Nil,
+            Constant::Boolean(b) => LuaValue::Boolean(*b),
+            Constant::Integer(i) => LuaValue::Integer(*i),
+            Constant::Number(n) => LuaValue::Number(*n),
+            Constant::Str(s) => LuaValue::Str(LuaString::from(s.as_slice())),
+        }
+    }
+}
+
+impl From<&str> for LuaValue {
+    fn from(s: &str) -> Self {
+        LuaValue::Str(LuaString::from(s))
+    }
+}
+
+impl From<String> for LuaValue {
+    fn from(s: String) -> Self {
+        LuaValue::Str(LuaString::from(s))
+    }
+}
+
+impl From<&[u8]> for LuaValue {
+    fn from(bytes: &[u8]) -> Self {
+        LuaValue::Str(LuaString::from(bytes))
+    }
+}
+
+impl From<Vec<u8>> for LuaValue {
+    fn from(bytes: Vec<u8>) -> Self {
+        LuaValue::Str(LuaString::from(bytes))
+    }
+}
+
+impl From<LuaString> for LuaValue {
+    fn from(s: LuaString) -> Self {
+        LuaValue::Str(s)
+    }
+}
+
+// 作为表的key使用时，浮点数key已经在表中被规范化为整数，所以这里直接比较
+impl PartialEq for LuaValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (LuaValue::Number(a), LuaValue::Number(b)) => a.to_bits() == b.to_bits() || a == b,
+            _ => self.raw_equals(other),
+        }
+    }
+}
+
+impl Eq for LuaValue {}
+
+impl Hash for LuaValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            LuaValue::Nil => 0.hash(state),
+            LuaValue::Boolean(b) => b.hash(state),
+            LuaValue::Integer(i) => i.hash(state),
+            LuaValue::Number(n) => match float_to_integer(*n) {
+                Some(i) => i.hash(state),
+                // +0.0和-0.0已被规范化为整数0，其余浮点数的位模式与相等性一致
+                None => n.to_bits().hash(state),
+            },
+            LuaValue::Str(s) => s.hash(state),
+            LuaValue::Table(t) => Rc::as_ptr(t).hash(state),
+            LuaValue::Function(f) => Rc::as_ptr(f).hash(state),
+            LuaValue::UserData(u) => Rc::as_ptr(u).hash(state),
+            LuaValue::Thread(t) => Rc::as_ptr(t).hash(state),
+        }
+    }
+}
+
+impl fmt::Display for LuaValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LuaValue::Nil => write!(f, "nil"),
+            LuaValue::Boolean(b) => write!(f, "{b}"),
+            LuaValue::Integer(i) => write!(f, "{i}"),
+            LuaValue::Number(n) => write!(f, "{}", number_to_string(*n)),
+            LuaValue::Str(s) => write!(f, "{s}"),
+            LuaValue::Table(t) => write!(f, "table: {:p}", Rc::as_ptr(t)),
+            LuaValue::Function(c) => write!(f, "function: {:p}", Rc::as_ptr(c)),
+            LuaValue::UserData(u) => write!(f, "userdata: {:p}", Rc::as_ptr(u)),
+            LuaValue::Thread(t) => write!(f, "thread: {:p}", Rc::as_ptr(t)),
+        }
+    }
+}
+
+impl fmt::Debug for LuaValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LuaValue::Str(s) => write!(f, "{s:?}"),
+            _ => write!(f, "{self}"),
+        }
+    }
+}
+
+// 浮点数转换为整数，只有能无损转换时才成功
+pub fn float_to_integer(n: f64) -> Option<i64> {
+    // -2^63 <= n < 2^63
+    if n.floor() == n && (-9223372036854775808.0..9223372036854775808.0).contains(&n) {
+        Some(n as i64)
+    } else {
+        None
+    }
+}
+
+// 按照Lua的"%.14g"格式化浮点数，看起来像整数的浮点数会加上".0"
+pub fn number_to_string(n: f64) -> String {
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if n.is_infinite() {
+        return if n < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+
+    let s = format_g(n, 14);
+    if s.contains(['.', 'e']) {
+        s
+    } else {
+        s + ".0"
+    }
+}
+
+// C语言printf的"%.<precision>g"
+fn format_g(n: f64, precision: usize) -> String {
+    if n == 0.0 {
+        return if n.is_sign_negative() { "-0" } else { "0" }.to_string();
+    }
+
+    let sci = format!("{:.*e}", precision - 1, n);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    if exp < -4 || exp >= precision as i32 {
+        let mantissa = trim_zeros(mantissa);
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{mantissa}e{sign}{:02}", exp.abs())
+    } else {
+        let decimals = (precision as i32 - 1 - exp) as usize;
+        trim_zeros(&format!("{n:.decimals$}")).to_string()
+    }
+}
+
+fn trim_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+// 按照Lua语法把字符串解析为数字，支持十六进制整数和浮点数
+pub fn parse_number(s: &str) -> Option<LuaValue> {
+    let s = s.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (neg, body) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+
+    if let Some(hex) = body.strip_prefix("0x").or_else(|| body.strip_prefix("0X")) {
+        return parse_hex(hex, neg);
+    }
+
+    if body.is_empty() || !body.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    if body.bytes().all(|c| c.is_ascii_digit()) {
+        // 十进制整数溢出时转换为浮点数
+        let mut value: i64 = 0;
+        let mut overflow = false;
+        for c in body.bytes() {
+            match value.checked_mul(10).and_then(|v| v.checked_add((c - b'0') as i64)) {
+                Some(v) => value = v,
+                None => {
+                    overflow = true;
+                    break;
+                }
+            }
+        }
+        if !overflow {
+            return Some(LuaValue::Integer(if neg { value.wrapping_neg() } else { value }));
+        }
+    }
+
+    // Rust能解析"inf"、"nan"等字符串，Lua不支持
+    if !body.bytes().all(|c| c.is_ascii_digit() || matches!(c, b'.' | b'e' | b'E' | b'+' | b'-')) {
+        return None;
+    }
+    let n: f64 = body.parse().ok()?;
+    Some(LuaValue::Number(if neg { -n } else { n }))
+}
+
+// 十六进制整数超出范围时回绕，十六进制浮点数支持小数部分和p指数
+fn parse_hex(s: &str, neg: bool) -> Option<LuaValue> {
+    let (mantissa, exp) = match s.find(['p', 'P']) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let (int_part, frac_part) = match mantissa.split_once('.') {
+        Some((i, f)) => (i, Some(f)),
+        None => (mantissa, None),
+    };
+    if int_part.is_empty() && frac_part.is_none_or(|f| f.is_empty()) {
+        return None;
+    }
+    if !int_part.bytes().all(|c| c.is_ascii_hexdigit())
+        || !frac_part.is_none_or(|f| f.bytes().all(|c| c.is_ascii_hexdigit()))
+    {
+        return None;
+    }
+
+    if frac_part.is_none() && exp.is_none() {
+        let mut value: i64 = 0;
+        for c in int_part.bytes() {
+            let digit = (c as char).to_digit(16).unwrap() as i64;
+            value = value.wrapping_mul(16).wrapping_add(digit);
+        }
+        return Some(LuaValue::Integer(if neg { value.wrapping_neg() } else { value }));
+    }
+
+    let mut value = 0.0;
+    for c in int_part.bytes() {
+        value = value * 16.0 + (c as char).to_digit(16).unwrap() as f64;
+    }
+    let mut scale = 1.0 / 16.0;
+    for c in frac_part.unwrap_or("").bytes() {
+        value += (c as char).to_digit(16).unwrap() as f64 * scale;
+        scale /= 16.0;
+    }
+    if let Some(exp) = exp {
+        let exp: i32 = exp.parse().ok()?;
+        value *= 2f64.powi(exp);
+    }
+    Some(LuaValue::Number(if neg { -value } else { value }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::vm::lua_state::LuaState;
+    use crate::vm::lua_value::{number_to_string, parse_number, LuaValue};
+
+    #[test]
+    fn test_values() {
+        // 整数和浮点数是同一种类型，数学上相等时相等
+        assert_eq!(LuaValue::Integer(2).type_name(), "number");
+        assert!(LuaValue::Integer(2).raw_equals(&LuaValue::Number(2.0)));
+        assert!(!LuaValue::Integer(2).raw_equals(&LuaValue::Number(2.5)));
+        assert_eq!(LuaValue::from("0x10").to_integer(), Some(16));
+        assert_eq!(LuaValue::Number(3.0).to_integer(), Some(3));
+        assert_eq!(LuaValue::Number(3.5).to_integer(), None);
+        assert_eq!(LuaValue::from(&b"\xff"[..]).to_number(), None);
+        assert_eq!(LuaValue::Number(1.5).to_str().unwrap().as_bytes(), b"1.5");
+
+        // 字符串按内容比较，可以包含任意字节
+        let bytes = LuaValue::from(vec![0xff, 0x00]);
+        assert!(bytes.raw_equals(&LuaValue::from(&b"\xff\x00"[..])));
+        assert_eq!(format!("{bytes:?}"), "\"\\xff\\x00\"");
+
+        // 用户数据和线程按引用比较
+        let u = LuaValue::new_userdata(vec![1, 2, 3]);
+        assert_eq!(u.type_name(), "userdata");
+        assert!(u.raw_equals(&u.clone()));
+        assert!(!u.raw_equals(&LuaValue::new_userdata(vec![1, 2, 3])));
+        let LuaValue::UserData(data) = &u else {
+            unreachable!()
+        };
+        assert_eq!(data.borrow::<Vec<i32>>().unwrap().len(), 3);
+        assert!(data.borrow::<String>().is_none());
+        data.borrow_mut::<Vec<i32>>().unwrap().push(4);
+        assert_eq!(*data.borrow::<Vec<i32>>().unwrap(), vec![1, 2, 3, 4]);
+
+        let thread = LuaValue::Thread(Rc::new(RefCell::new(LuaState::new())));
+        assert_eq!(thread.type_name(), "thread");
+        assert!(thread.to_string().starts_with("thread: "));
+        assert!(thread.to_boolean());
+    }
+
+    #[test]
+    fn test_number_to_string() {
+        assert_eq!(number_to_string(3.0), "3.0");
+        assert_eq!(number_to_string(-0.5), "-0.5");
+        assert_eq!(number_to_string(0.1), "0.1");
+        assert_eq!(number_to_string(1e15), "1e+15");
+        assert_eq!(number_to_string(2f64.powi(53)), "9.007199254741e+15");
+        assert_eq!(number_to_string(1.0 / 3.0), "0.33333333333333");
+        assert_eq!(number_to_string(1e-5), "1e-05");
+        assert_eq!(number_to_string(100.25), "100.25");
+        assert_eq!(number_to_string(f64::INFINITY), "inf");
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("10"), Some(LuaValue::Integer(10)));
+        assert_eq!(parse_number("  -7 "), Some(LuaValue::Integer(-7)));
+        assert_eq!(parse_number("0x10"), Some(LuaValue::Integer(16)));
+        assert_eq!(parse_number("0xffffffffffffffff"), Some(LuaValue::Integer(-1)));
+        assert_eq!(parse_number("0x1p4"), Some(LuaValue::Number(16.0)));
+        assert_eq!(parse_number("0x.8"), Some(LuaValue::Number(0.5)));
+        assert_eq!(parse_number("1e2"), Some(LuaValue::Number(100.0)));
+        assert_eq!(parse_number(".5"), Some(LuaValue::Number(0.5)));
+        assert_eq!(
+            parse_number("9223372036854775808"),
+            Some(LuaValue::Number(9223372036854775808.0))
+        );
+        assert_eq!(parse_number("inf"), None);
+        assert_eq!(parse_number("1e"), None);
+        assert_eq!(parse_number(""), None);
+        assert_eq!(parse_number("0x"), None);
+    }
+}
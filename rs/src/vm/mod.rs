@@ -1,2 +1,13 @@
+pub mod arith;
+pub mod closure;
+pub mod debug;
+pub mod decoded;
+pub mod error;
+pub mod gc;
 pub mod instruction;
-pub mod opcode;
\ No newline at end of file
+pub mod lua_state;
+pub mod lua_string;
+pub mod lua_table;
+pub mod lua_value;
+pub mod opcode;
+pub mod userdata;
@@ -1,3 +1,7 @@
+pub mod lua51;
+pub mod lua52;
+pub mod lua54;
+
 /// Lua指令编码模式常量
 /// Lua指令模式可以分为4类
 /// I_ABC:  可以携带3个操作数A、B、C，分别占用8、9、9个bit
@@ -22,56 +26,187 @@ pub const OP_ARG_U: u8 = 1;
 pub const OP_ARG_R: u8 = 2;
 pub const OP_ARG_K: u8 = 3;
 
-/// Lua5.3中每条Lua虚拟机指令占4字节，共32个bit
-/// 低6位用于表示操作码(opcode)
-/// 高26位用于表示操作数
-// const OP_MOVE: u8 = 0;
-// const OP_LOADK: u8 = 1;
-// const OP_LOADKX: u8 = 2;
-// const OP_LOADBOOL: u8 = 3;
-// const OP_LOADNIL: u8 = 4;
-// const OP_GETUPVAL: u8 = 5;
-// const OP_GETTABUP: u8 = 6;
-// const OP_GETTABLE: u8 = 7;
-// const OP_SETTABUP: u8 = 8;
-// const OP_SETUPVAL: u8 = 9;
-// const OP_SETTABLE: u8 = 10;
-// const OP_NEWTABLE: u8 = 11;
-// const OP_SELF: u8 = 12;
-// const OP_ADD: u8 = 13;
-// const OP_SUB: u8 = 14;
-// const OP_MUL: u8 = 15;
-// const OP_MOD: u8 = 16;
-// const OP_POW: u8 = 17;
-// const OP_DIV: u8 = 18;
-// const OP_IDIV: u8 = 19;
-// const OP_BAND: u8 = 20;
-// const OP_BOR: u8 = 21;
-// const OP_BXOR: u8 = 22;
-// const OP_SHL: u8 = 23;
-// const OP_SHR: u8 = 24;
-// const OP_UNM: u8 = 25;
-// const OP_BNOT: u8 = 26;
-// const OP_NOT: u8 = 27;
-// const OP_LEN: u8 = 28;
-// const OP_CONCAT: u8 = 29;
-// const OP_JMP: u8 = 30;
-// const OP_EQ: u8 = 31;
-// const OP_LT: u8 = 32;
-// const OP_LE: u8 = 33;
-// const OP_TEST: u8 = 34;
-// const OP_TESTSET: u8 = 35;
-// const OP_CALL: u8 = 36;
-// const OP_TAILCALL: u8 = 37;
-// const OP_RETURN: u8 = 38;
-// const OP_FORLOOP: u8 = 39;
-// const OP_FORPREP: u8 = 40;
-// const OP_TFORCALL: u8 = 41;
-// const OP_TFORLOOP: u8 = 42;
-// const OP_SETLIST: u8 = 43;
-// const OP_CLOSURE: u8 = 44;
-// const OP_VARARG: u8 = 45;
-// const OP_EXTRAARG: u8 = 46;
+// Lua5.3中每条Lua虚拟机指令占4字节，共32个bit
+// 低6位用于表示操作码(opcode)
+// 高26位用于表示操作数
+pub const OP_MOVE: u8 = 0;
+pub const OP_LOADK: u8 = 1;
+pub const OP_LOADKX: u8 = 2;
+pub const OP_LOADBOOL: u8 = 3;
+pub const OP_LOADNIL: u8 = 4;
+pub const OP_GETUPVAL: u8 = 5;
+pub const OP_GETTABUP: u8 = 6;
+pub const OP_GETTABLE: u8 = 7;
+pub const OP_SETTABUP: u8 = 8;
+pub const OP_SETUPVAL: u8 = 9;
+pub const OP_SETTABLE: u8 = 10;
+pub const OP_NEWTABLE: u8 = 11;
+pub const OP_SELF: u8 = 12;
+pub const OP_ADD: u8 = 13;
+pub const OP_SUB: u8 = 14;
+pub const OP_MUL: u8 = 15;
+pub const OP_MOD: u8 = 16;
+pub const OP_POW: u8 = 17;
+pub const OP_DIV: u8 = 18;
+pub const OP_IDIV: u8 = 19;
+pub const OP_BAND: u8 = 20;
+pub const OP_BOR: u8 = 21;
+pub const OP_BXOR: u8 = 22;
+pub const OP_SHL: u8 = 23;
+pub const OP_SHR: u8 = 24;
+pub const OP_UNM: u8 = 25;
+pub const OP_BNOT: u8 = 26;
+pub const OP_NOT: u8 = 27;
+pub const OP_LEN: u8 = 28;
+pub const OP_CONCAT: u8 = 29;
+pub const OP_JMP: u8 = 30;
+pub const OP_EQ: u8 = 31;
+pub const OP_LT: u8 = 32;
+pub const OP_LE: u8 = 33;
+pub const OP_TEST: u8 = 34;
+pub const OP_TESTSET: u8 = 35;
+pub const OP_CALL: u8 = 36;
+pub const OP_TAILCALL: u8 = 37;
+pub const OP_RETURN: u8 = 38;
+pub const OP_FORLOOP: u8 = 39;
+pub const OP_FORPREP: u8 = 40;
+pub const OP_TFORCALL: u8 = 41;
+pub const OP_TFORLOOP: u8 = 42;
+pub const OP_SETLIST: u8 = 43;
+pub const OP_CLOSURE: u8 = 44;
+pub const OP_VARARG: u8 = 45;
+pub const OP_EXTRAARG: u8 = 46;
+
+/// 5.3的操作码，取值与OP_*常量相同
+/// 用类型代替u8，避免越界访问OP_CODES
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Op {
+    Move = OP_MOVE,
+    LoadK = OP_LOADK,
+    LoadKX = OP_LOADKX,
+    LoadBool = OP_LOADBOOL,
+    LoadNil = OP_LOADNIL,
+    GetUpval = OP_GETUPVAL,
+    GetTabUp = OP_GETTABUP,
+    GetTable = OP_GETTABLE,
+    SetTabUp = OP_SETTABUP,
+    SetUpval = OP_SETUPVAL,
+    SetTable = OP_SETTABLE,
+    NewTable = OP_NEWTABLE,
+    Self_ = OP_SELF,
+    Add = OP_ADD,
+    Sub = OP_SUB,
+    Mul = OP_MUL,
+    Mod = OP_MOD,
+    Pow = OP_POW,
+    Div = OP_DIV,
+    IDiv = OP_IDIV,
+    BAnd = OP_BAND,
+    BOr = OP_BOR,
+    BXor = OP_BXOR,
+    Shl = OP_SHL,
+    Shr = OP_SHR,
+    Unm = OP_UNM,
+    BNot = OP_BNOT,
+    Not = OP_NOT,
+    Len = OP_LEN,
+    Concat = OP_CONCAT,
+    Jmp = OP_JMP,
+    Eq = OP_EQ,
+    Lt = OP_LT,
+    Le = OP_LE,
+    Test = OP_TEST,
+    TestSet = OP_TESTSET,
+    Call = OP_CALL,
+    TailCall = OP_TAILCALL,
+    Return = OP_RETURN,
+    ForLoop = OP_FORLOOP,
+    ForPrep = OP_FORPREP,
+    TForCall = OP_TFORCALL,
+    TForLoop = OP_TFORLOOP,
+    SetList = OP_SETLIST,
+    Closure = OP_CLOSURE,
+    VarArg = OP_VARARG,
+    ExtraArg = OP_EXTRAARG,
+}
+
+
+impl Op {
+    // 按操作码顺序排列的所有操作码
+    pub const ALL: [Op; 47] = [
+        Op::Move,
+        Op::LoadK,
+        Op::LoadKX,
+        Op::LoadBool,
+        Op::LoadNil,
+        Op::GetUpval,
+        Op::GetTabUp,
+        Op::GetTable,
+        Op::SetTabUp,
+        Op::SetUpval,
+        Op::SetTable,
+        Op::NewTable,
+        Op::Self_,
+        Op::Add,
+        Op::Sub,
+        Op::Mul,
+        Op::Mod,
+        Op::Pow,
+        Op::Div,
+        Op::IDiv,
+        Op::BAnd,
+        Op::BOr,
+        Op::BXor,
+        Op::Shl,
+        Op::Shr,
+        Op::Unm,
+        Op::BNot,
+        Op::Not,
+        Op::Len,
+        Op::Concat,
+        Op::Jmp,
+        Op::Eq,
+        Op::Lt,
+        Op::Le,
+        Op::Test,
+        Op::TestSet,
+        Op::Call,
+        Op::TailCall,
+        Op::Return,
+        Op::ForLoop,
+        Op::ForPrep,
+        Op::TForCall,
+        Op::TForLoop,
+        Op::SetList,
+        Op::Closure,
+        Op::VarArg,
+        Op::ExtraArg,
+    ];
+
+    // 从操作码解码，未知的操作码返回None
+    pub fn from_u8(code: u8) -> Option<Op> {
+        Op::ALL.get(code as usize).copied()
+    }
+
+    // 操作码的参数模式和编码模式
+    pub fn info(self) -> &'static OpCode {
+        &OP_CODES[self as usize]
+    }
+
+    // 去掉对齐空格的操作码名字
+    pub fn name(self) -> &'static str {
+        self.info().name.trim_end()
+    }
+}
+
+impl TryFrom<u8> for Op {
+    type Error = u8;
+
+    fn try_from(code: u8) -> Result<Op, u8> {
+        Op::from_u8(code).ok_or(code)
+    }
+}
 
 pub struct OpCode {
     // OpCode是否是test，如果是test，那么下一个操作码必须是jump
@@ -87,7 +222,7 @@ pub struct OpCode {
     pub name: &'static str,
 }
 
-pub const OP_CODES: &'static [OpCode] = &[
+pub const OP_CODES: &[OpCode] = &[
     /*       B       C     mode    name    */
     opcode(0, 1, OP_ARG_R, OP_ARG_N, OP_MODE_ABC, "MOVE    "), // R(A) := R(B)
     opcode(0, 1, OP_ARG_K, OP_ARG_N, OP_MODE_ABX, "LOADK   "), // R(A) := Kst(Bx)
@@ -0,0 +1,89 @@
+// Lua5.2指令集
+// 指令布局与5.3相同，操作码占低6位
+// 5.2引入了_ENV upvalue，全局变量通过GETTABUP/SETTABUP访问
+
+use super::{opcode, OpCode, OP_ARG_K, OP_ARG_N, OP_ARG_R, OP_ARG_U, OP_MODE_ABC, OP_MODE_ABX, OP_MODE_ASBX, OP_MODE_AX};
+
+pub const OP_MOVE: u8 = 0;
+pub const OP_LOADK: u8 = 1;
+pub const OP_LOADKX: u8 = 2;
+pub const OP_LOADBOOL: u8 = 3;
+pub const OP_LOADNIL: u8 = 4;
+pub const OP_GETUPVAL: u8 = 5;
+pub const OP_GETTABUP: u8 = 6;
+pub const OP_GETTABLE: u8 = 7;
+pub const OP_SETTABUP: u8 = 8;
+pub const OP_SETUPVAL: u8 = 9;
+pub const OP_SETTABLE: u8 = 10;
+pub const OP_NEWTABLE: u8 = 11;
+pub const OP_SELF: u8 = 12;
+pub const OP_ADD: u8 = 13;
+pub const OP_SUB: u8 = 14;
+pub const OP_MUL: u8 = 15;
+pub const OP_DIV: u8 = 16;
+pub const OP_MOD: u8 = 17;
+pub const OP_POW: u8 = 18;
+pub const OP_UNM: u8 = 19;
+pub const OP_NOT: u8 = 20;
+pub const OP_LEN: u8 = 21;
+pub const OP_CONCAT: u8 = 22;
+pub const OP_JMP: u8 = 23;
+pub const OP_EQ: u8 = 24;
+pub const OP_LT: u8 = 25;
+pub const OP_LE: u8 = 26;
+pub const OP_TEST: u8 = 27;
+pub const OP_TESTSET: u8 = 28;
+pub const OP_CALL: u8 = 29;
+pub const OP_TAILCALL: u8 = 30;
+pub const OP_RETURN: u8 = 31;
+pub const OP_FORLOOP: u8 = 32;
+pub const OP_FORPREP: u8 = 33;
+pub const OP_TFORCALL: u8 = 34;
+pub const OP_TFORLOOP: u8 = 35;
+pub const OP_SETLIST: u8 = 36;
+pub const OP_CLOSURE: u8 = 37;
+pub const OP_VARARG: u8 = 38;
+pub const OP_EXTRAARG: u8 = 39;
+
+pub const OP_CODES: &[OpCode] = &[
+    opcode(0, 1, OP_ARG_R, OP_ARG_N, OP_MODE_ABC, "MOVE    "), // R(A) := R(B)
+    opcode(0, 1, OP_ARG_K, OP_ARG_N, OP_MODE_ABX, "LOADK   "), // R(A) := Kst(Bx)
+    opcode(0, 1, OP_ARG_N, OP_ARG_N, OP_MODE_ABX, "LOADKX  "), // R(A) := Kst(extra arg)
+    opcode(0, 1, OP_ARG_U, OP_ARG_U, OP_MODE_ABC, "LOADBOOL"), // R(A) := (Bool)B; if (C) pc++
+    opcode(0, 1, OP_ARG_U, OP_ARG_N, OP_MODE_ABC, "LOADNIL "), // R(A), R(A+1), ..., R(A+B) := nil
+    opcode(0, 1, OP_ARG_U, OP_ARG_N, OP_MODE_ABC, "GETUPVAL"), // R(A) := UpValue[B]
+    opcode(0, 1, OP_ARG_U, OP_ARG_K, OP_MODE_ABC, "GETTABUP"), // R(A) := UpValue[B][RK(C)]
+    opcode(0, 1, OP_ARG_R, OP_ARG_K, OP_MODE_ABC, "GETTABLE"), // R(A) := R(B)[RK(C)]
+    opcode(0, 0, OP_ARG_K, OP_ARG_K, OP_MODE_ABC, "SETTABUP"), // UpValue[A][RK(B)] := RK(C)
+    opcode(0, 0, OP_ARG_U, OP_ARG_N, OP_MODE_ABC, "SETUPVAL"), // UpValue[B] := R(A)
+    opcode(0, 0, OP_ARG_K, OP_ARG_K, OP_MODE_ABC, "SETTABLE"), // R(A)[RK(B)] := RK(C)
+    opcode(0, 1, OP_ARG_U, OP_ARG_U, OP_MODE_ABC, "NEWTABLE"), // R(A) := {} (size = B,C)
+    opcode(0, 1, OP_ARG_R, OP_ARG_K, OP_MODE_ABC, "SELF    "), // R(A+1) := R(B); R(A) := R(B)[RK(C)]
+    opcode(0, 1, OP_ARG_K, OP_ARG_K, OP_MODE_ABC, "ADD     "), // R(A) := RK(B) + RK(C)
+    opcode(0, 1, OP_ARG_K, OP_ARG_K, OP_MODE_ABC, "SUB     "), // R(A) := RK(B) - RK(C)
+    opcode(0, 1, OP_ARG_K, OP_ARG_K, OP_MODE_ABC, "MUL     "), // R(A) := RK(B) * RK(C)
+    opcode(0, 1, OP_ARG_K, OP_ARG_K, OP_MODE_ABC, "DIV     "), // R(A) := RK(B) / RK(C)
+    opcode(0, 1, OP_ARG_K, OP_ARG_K, OP_MODE_ABC, "MOD     "), // R(A) := RK(B) % RK(C)
+    opcode(0, 1, OP_ARG_K, OP_ARG_K, OP_MODE_ABC, "POW     "), // R(A) := RK(B) ^ RK(C)
+    opcode(0, 1, OP_ARG_R, OP_ARG_N, OP_MODE_ABC, "UNM     "), // R(A) := -R(B)
+    opcode(0, 1, OP_ARG_R, OP_ARG_N, OP_MODE_ABC, "NOT     "), // R(A) := not R(B)
+    opcode(0, 1, OP_ARG_R, OP_ARG_N, OP_MODE_ABC, "LEN     "), // R(A) := length of R(B)
+    opcode(0, 1, OP_ARG_R, OP_ARG_R, OP_MODE_ABC, "CONCAT  "), // R(A) := R(B).. ... ..R(C)
+    opcode(0, 0, OP_ARG_R, OP_ARG_N, OP_MODE_ASBX, "JMP     "), // pc+=sBx; if (A) close all upvalues >= R(A - 1)
+    opcode(1, 0, OP_ARG_K, OP_ARG_K, OP_MODE_ABC, "EQ      "), // if ((RK(B) == RK(C)) ~= A) then pc++
+    opcode(1, 0, OP_ARG_K, OP_ARG_K, OP_MODE_ABC, "LT      "), // if ((RK(B) <  RK(C)) ~= A) then pc++
+    opcode(1, 0, OP_ARG_K, OP_ARG_K, OP_MODE_ABC, "LE      "), // if ((RK(B) <= RK(C)) ~= A) then pc++
+    opcode(1, 0, OP_ARG_N, OP_ARG_U, OP_MODE_ABC, "TEST    "), // if not (R(A) <=> C) then pc++
+    opcode(1, 1, OP_ARG_R, OP_ARG_U, OP_MODE_ABC, "TESTSET "), // if (R(B) <=> C) then R(A) := R(B) else pc++
+    opcode(0, 1, OP_ARG_U, OP_ARG_U, OP_MODE_ABC, "CALL    "), // R(A), ... ,R(A+C-2) := R(A)(R(A+1), ... ,R(A+B-1))
+    opcode(0, 1, OP_ARG_U, OP_ARG_U, OP_MODE_ABC, "TAILCALL"), // return R(A)(R(A+1), ... ,R(A+B-1))
+    opcode(0, 0, OP_ARG_U, OP_ARG_N, OP_MODE_ABC, "RETURN  "), // return R(A), ... ,R(A+B-2)
+    opcode(0, 1, OP_ARG_R, OP_ARG_N, OP_MODE_ASBX, "FORLOOP "), // R(A)+=R(A+2); if R(A) <?= R(A+1) then { pc+=sBx; R(A+3)=R(A) }
+    opcode(0, 1, OP_ARG_R, OP_ARG_N, OP_MODE_ASBX, "FORPREP "), // R(A)-=R(A+2); pc+=sBx
+    opcode(0, 0, OP_ARG_N, OP_ARG_U, OP_MODE_ABC, "TFORCALL"), // R(A+3), ... ,R(A+2+C) := R(A)(R(A+1), R(A+2));
+    opcode(0, 1, OP_ARG_R, OP_ARG_N, OP_MODE_ASBX, "TFORLOOP"), // if R(A+1) ~= nil then { R(A)=R(A+1); pc += sBx }
+    opcode(0, 0, OP_ARG_U, OP_ARG_U, OP_MODE_ABC, "SETLIST "), // R(A)[(C-1)*FPF+i] := R(A+i), 1 <= i <= B
+    opcode(0, 1, OP_ARG_U, OP_ARG_N, OP_MODE_ABX, "CLOSURE "), // R(A) := closure(KPROTO[Bx])
+    opcode(0, 1, OP_ARG_U, OP_ARG_N, OP_MODE_ABC, "VARARG  "), // R(A), R(A+1), ..., R(A+B-2) = vararg
+    opcode(0, 0, OP_ARG_U, OP_ARG_U, OP_MODE_AX, "EXTRAARG"), // extra (larger) argument for previous opcode
+];
@@ -0,0 +1,221 @@
+// Lua5.4指令集
+// 5.4的指令同样占4字节，但操作码扩展为7位，并新增了k标志位和isJ模式
+// iABC:  C(8) | B(8) | k(1) | A(8) | Op(7)
+// iABx:  Bx(17) | A(8) | Op(7)
+// iAsBx: sBx(17) | A(8) | Op(7)
+// iAx:   Ax(25) | Op(7)
+// isJ:   sJ(25) | Op(7)
+
+use super::{OP_MODE_ABC, OP_MODE_ABX, OP_MODE_ASBX, OP_MODE_AX};
+
+/// isJ: 只携带一个有符号的跳转偏移sJ，占用25个bit
+pub const OP_MODE_SJ: u8 = 4;
+
+pub const OP_MOVE: u8 = 0;
+pub const OP_LOADI: u8 = 1;
+pub const OP_LOADF: u8 = 2;
+pub const OP_LOADK: u8 = 3;
+pub const OP_LOADKX: u8 = 4;
+pub const OP_LOADFALSE: u8 = 5;
+pub const OP_LFALSESKIP: u8 = 6;
+pub const OP_LOADTRUE: u8 = 7;
+pub const OP_LOADNIL: u8 = 8;
+pub const OP_GETUPVAL: u8 = 9;
+pub const OP_SETUPVAL: u8 = 10;
+pub const OP_GETTABUP: u8 = 11;
+pub const OP_GETTABLE: u8 = 12;
+pub const OP_GETI: u8 = 13;
+pub const OP_GETFIELD: u8 = 14;
+pub const OP_SETTABUP: u8 = 15;
+pub const OP_SETTABLE: u8 = 16;
+pub const OP_SETI: u8 = 17;
+pub const OP_SETFIELD: u8 = 18;
+pub const OP_NEWTABLE: u8 = 19;
+pub const OP_SELF: u8 = 20;
+pub const OP_ADDI: u8 = 21;
+pub const OP_ADDK: u8 = 22;
+pub const OP_SUBK: u8 = 23;
+pub const OP_MULK: u8 = 24;
+pub const OP_MODK: u8 = 25;
+pub const OP_POWK: u8 = 26;
+pub const OP_DIVK: u8 = 27;
+pub const OP_IDIVK: u8 = 28;
+pub const OP_BANDK: u8 = 29;
+pub const OP_BORK: u8 = 30;
+pub const OP_BXORK: u8 = 31;
+pub const OP_SHRI: u8 = 32;
+pub const OP_SHLI: u8 = 33;
+pub const OP_ADD: u8 = 34;
+pub const OP_SUB: u8 = 35;
+pub const OP_MUL: u8 = 36;
+pub const OP_MOD: u8 = 37;
+pub const OP_POW: u8 = 38;
+pub const OP_DIV: u8 = 39;
+pub const OP_IDIV: u8 = 40;
+pub const OP_BAND: u8 = 41;
+pub const OP_BOR: u8 = 42;
+pub const OP_BXOR: u8 = 43;
+pub const OP_SHL: u8 = 44;
+pub const OP_SHR: u8 = 45;
+pub const OP_MMBIN: u8 = 46;
+pub const OP_MMBINI: u8 = 47;
+pub const OP_MMBINK: u8 = 48;
+pub const OP_UNM: u8 = 49;
+pub const OP_BNOT: u8 = 50;
+pub const OP_NOT: u8 = 51;
+pub const OP_LEN: u8 = 52;
+pub const OP_CONCAT: u8 = 53;
+pub const OP_CLOSE: u8 = 54;
+pub const OP_TBC: u8 = 55;
+pub const OP_JMP: u8 = 56;
+pub const OP_EQ: u8 = 57;
+pub const OP_LT: u8 = 58;
+pub const OP_LE: u8 = 59;
+pub const OP_EQK: u8 = 60;
+pub const OP_EQI: u8 = 61;
+pub const OP_LTI: u8 = 62;
+pub const OP_LEI: u8 = 63;
+pub const OP_GTI: u8 = 64;
+pub const OP_GEI: u8 = 65;
+pub const OP_TEST: u8 = 66;
+pub const OP_TESTSET: u8 = 67;
+pub const OP_CALL: u8 = 68;
+pub const OP_TAILCALL: u8 = 69;
+pub const OP_RETURN: u8 = 70;
+pub const OP_RETURN0: u8 = 71;
+pub const OP_RETURN1: u8 = 72;
+pub const OP_FORLOOP: u8 = 73;
+pub const OP_FORPREP: u8 = 74;
+pub const OP_TFORPREP: u8 = 75;
+pub const OP_TFORCALL: u8 = 76;
+pub const OP_TFORLOOP: u8 = 77;
+pub const OP_SETLIST: u8 = 78;
+pub const OP_CLOSURE: u8 = 79;
+pub const OP_VARARG: u8 = 80;
+pub const OP_VARARGPREP: u8 = 81;
+pub const OP_EXTRAARG: u8 = 82;
+
+/// Lua5.4指令的属性
+#[derive(Debug)]
+pub struct OpCode {
+    // 指令调用元方法(MMBIN系列)
+    pub mm_flag: u8,
+    // 指令设置L->top，用于多返回值(CALL、VARARG等)
+    pub ot_flag: u8,
+    // 指令使用L->top设置的参数个数(CALL、RETURN等)
+    pub it_flag: u8,
+    // 指令是一个测试，下一条指令必须是跳转
+    pub test_flag: u8,
+    // 指令设置寄存器A
+    pub set_a_flag: u8,
+    // 指令编码模式
+    pub op_mode: u8,
+    // 指令名
+    pub name: &'static str,
+}
+
+pub const OP_CODES: &[OpCode] = &[
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "MOVE"), // R[A] := R[B]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ASBX, "LOADI"), // R[A] := sBx
+    opcode(0, 0, 0, 0, 1, OP_MODE_ASBX, "LOADF"), // R[A] := (lua_Number)sBx
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABX, "LOADK"), // R[A] := K[Bx]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABX, "LOADKX"), // R[A] := K[extra arg]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "LOADFALSE"), // R[A] := false
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "LFALSESKIP"), // R[A] := false; pc++
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "LOADTRUE"), // R[A] := true
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "LOADNIL"), // R[A], R[A+1], ..., R[A+B] := nil
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "GETUPVAL"), // R[A] := UpValue[B]
+    opcode(0, 0, 0, 0, 0, OP_MODE_ABC, "SETUPVAL"), // UpValue[B] := R[A]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "GETTABUP"), // R[A] := UpValue[B][K[C]:shortstring]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "GETTABLE"), // R[A] := R[B][R[C]]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "GETI"), // R[A] := R[B][C]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "GETFIELD"), // R[A] := R[B][K[C]:shortstring]
+    opcode(0, 0, 0, 0, 0, OP_MODE_ABC, "SETTABUP"), // UpValue[A][K[B]:shortstring] := RK(C)
+    opcode(0, 0, 0, 0, 0, OP_MODE_ABC, "SETTABLE"), // R[A][R[B]] := RK(C)
+    opcode(0, 0, 0, 0, 0, OP_MODE_ABC, "SETI"), // R[A][B] := RK(C)
+    opcode(0, 0, 0, 0, 0, OP_MODE_ABC, "SETFIELD"), // R[A][K[B]:shortstring] := RK(C)
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "NEWTABLE"), // R[A] := {}
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "SELF"), // R[A+1] := R[B]; R[A] := R[B][RK(C):string]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "ADDI"), // R[A] := R[B] + sC
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "ADDK"), // R[A] := R[B] + K[C]:number
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "SUBK"), // R[A] := R[B] - K[C]:number
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "MULK"), // R[A] := R[B] * K[C]:number
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "MODK"), // R[A] := R[B] % K[C]:number
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "POWK"), // R[A] := R[B] ^ K[C]:number
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "DIVK"), // R[A] := R[B] / K[C]:number
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "IDIVK"), // R[A] := R[B] // K[C]:number
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "BANDK"), // R[A] := R[B] & K[C]:integer
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "BORK"), // R[A] := R[B] | K[C]:integer
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "BXORK"), // R[A] := R[B] ~ K[C]:integer
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "SHRI"), // R[A] := R[B] >> sC
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "SHLI"), // R[A] := sC << R[B]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "ADD"), // R[A] := R[B] + R[C]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "SUB"), // R[A] := R[B] - R[C]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "MUL"), // R[A] := R[B] * R[C]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "MOD"), // R[A] := R[B] % R[C]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "POW"), // R[A] := R[B] ^ R[C]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "DIV"), // R[A] := R[B] / R[C]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "IDIV"), // R[A] := R[B] // R[C]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "BAND"), // R[A] := R[B] & R[C]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "BOR"), // R[A] := R[B] | R[C]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "BXOR"), // R[A] := R[B] ~ R[C]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "SHL"), // R[A] := R[B] << R[C]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "SHR"), // R[A] := R[B] >> R[C]
+    opcode(1, 0, 0, 0, 0, OP_MODE_ABC, "MMBIN"), // call C metamethod over R[A] and R[B]
+    opcode(1, 0, 0, 0, 0, OP_MODE_ABC, "MMBINI"), // call C metamethod over R[A] and sB
+    opcode(1, 0, 0, 0, 0, OP_MODE_ABC, "MMBINK"), // call C metamethod over R[A] and K[B]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "UNM"), // R[A] := -R[B]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "BNOT"), // R[A] := ~R[B]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "NOT"), // R[A] := not R[B]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "LEN"), // R[A] := #R[B] (length operator)
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABC, "CONCAT"), // R[A] := R[A].. ... ..R[A + B - 1]
+    opcode(0, 0, 0, 0, 0, OP_MODE_ABC, "CLOSE"), // close all upvalues >= R[A]
+    opcode(0, 0, 0, 0, 0, OP_MODE_ABC, "TBC"), // mark variable A "to be closed"
+    opcode(0, 0, 0, 0, 0, OP_MODE_SJ, "JMP"), // pc += sJ
+    opcode(0, 0, 0, 1, 0, OP_MODE_ABC, "EQ"), // if ((R[A] == R[B]) ~= k) then pc++
+    opcode(0, 0, 0, 1, 0, OP_MODE_ABC, "LT"), // if ((R[A] <  R[B]) ~= k) then pc++
+    opcode(0, 0, 0, 1, 0, OP_MODE_ABC, "LE"), // if ((R[A] <= R[B]) ~= k) then pc++
+    opcode(0, 0, 0, 1, 0, OP_MODE_ABC, "EQK"), // if ((R[A] == K[B]) ~= k) then pc++
+    opcode(0, 0, 0, 1, 0, OP_MODE_ABC, "EQI"), // if ((R[A] == sB) ~= k) then pc++
+    opcode(0, 0, 0, 1, 0, OP_MODE_ABC, "LTI"), // if ((R[A] < sB) ~= k) then pc++
+    opcode(0, 0, 0, 1, 0, OP_MODE_ABC, "LEI"), // if ((R[A] <= sB) ~= k) then pc++
+    opcode(0, 0, 0, 1, 0, OP_MODE_ABC, "GTI"), // if ((R[A] > sB) ~= k) then pc++
+    opcode(0, 0, 0, 1, 0, OP_MODE_ABC, "GEI"), // if ((R[A] >= sB) ~= k) then pc++
+    opcode(0, 0, 0, 1, 0, OP_MODE_ABC, "TEST"), // if (not R[A] == k) then pc++
+    opcode(0, 0, 0, 1, 1, OP_MODE_ABC, "TESTSET"), // if (not R[B] == k) then pc++ else R[A] := R[B]
+    opcode(0, 1, 1, 0, 1, OP_MODE_ABC, "CALL"), // R[A], ... ,R[A+C-2] := R[A](R[A+1], ... ,R[A+B-1])
+    opcode(0, 1, 1, 0, 1, OP_MODE_ABC, "TAILCALL"), // return R[A](R[A+1], ... ,R[A+B-1])
+    opcode(0, 0, 1, 0, 0, OP_MODE_ABC, "RETURN"), // return R[A], ... ,R[A+B-2]
+    opcode(0, 0, 0, 0, 0, OP_MODE_ABC, "RETURN0"), // return
+    opcode(0, 0, 0, 0, 0, OP_MODE_ABC, "RETURN1"), // return R[A]
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABX, "FORLOOP"), // update counters; if loop continues then pc-=Bx;
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABX, "FORPREP"), // <check values and prepare counters>; if not to run then pc+=Bx+1;
+    opcode(0, 0, 0, 0, 0, OP_MODE_ABX, "TFORPREP"), // create upvalue for R[A + 3]; pc+=Bx
+    opcode(0, 0, 0, 0, 0, OP_MODE_ABC, "TFORCALL"), // R[A+4], ... ,R[A+3+C] := R[A](R[A+1], R[A+2]);
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABX, "TFORLOOP"), // if R[A+2] ~= nil then { R[A]=R[A+2]; pc -= Bx }
+    opcode(0, 0, 1, 0, 0, OP_MODE_ABC, "SETLIST"), // R[A][C+i] := R[A+i], 1 <= i <= B
+    opcode(0, 0, 0, 0, 1, OP_MODE_ABX, "CLOSURE"), // R[A] := closure(KPROTO[Bx])
+    opcode(0, 1, 0, 0, 1, OP_MODE_ABC, "VARARG"), // R[A], R[A+1], ..., R[A+C-2] = vararg
+    opcode(0, 0, 1, 0, 1, OP_MODE_ABC, "VARARGPREP"), // (adjust vararg parameters)
+    opcode(0, 0, 0, 0, 0, OP_MODE_AX, "EXTRAARG"), // extra (larger) argument for previous opcode
+];
+
+const fn opcode(
+    mm_flag: u8,
+    ot_flag: u8,
+    it_flag: u8,
+    test_flag: u8,
+    set_a_flag: u8,
+    op_mode: u8,
+    name: &'static str,
+) -> OpCode {
+    OpCode {
+        mm_flag,
+        ot_flag,
+        it_flag,
+        test_flag,
+        set_a_flag,
+        op_mode,
+        name,
+    }
+}
@@ -0,0 +1,58 @@
+use std::any::Any;
+use std::cell::{Ref, RefCell, RefMut};
+use std::mem;
+use std::rc::Rc;
+
+use crate::vm::gc::Child;
+use crate::vm::lua_table::LuaTable;
+
+/// 完全用户数据，保存宿主程序的任意Rust值，Lua代码只能通过宿主提供的函数操作它
+pub struct UserData {
+    data: RefCell<Box<dyn Any>>,
+    metatable: RefCell<Option<Rc<RefCell<LuaTable>>>>,
+}
+
+impl UserData {
+    pub fn new<T: Any>(value: T) -> Self {
+        Self {
+            data: RefCell::new(Box::new(value)),
+            metatable: RefCell::new(None),
+        }
+    }
+
+    pub fn metatable(&self) -> Option<Rc<RefCell<LuaTable>>> {
+        self.metatable.borrow().clone()
+    }
+
+    pub fn set_metatable(&self, mt: Option<Rc<RefCell<LuaTable>>>) {
+        *self.metatable.borrow_mut() = mt;
+    }
+
+    // 用户数据持有的引用只有元表，用于垃圾回收
+    pub(crate) fn for_each_ref(&self, f: &mut dyn FnMut(Child)) {
+        if let Ok(mt) = self.metatable.try_borrow() {
+            if let Some(mt) = &*mt {
+                f(Child::Table(mt));
+            }
+        }
+    }
+
+    // 估计用户数据占用的字节数
+    pub(crate) fn memory(&self) -> usize {
+        let data = self.data.try_borrow().map_or(0, |data| mem::size_of_val(&**data));
+        mem::size_of::<UserData>() + data
+    }
+
+    pub fn is<T: Any>(&self) -> bool {
+        self.data.borrow().is::<T>()
+    }
+
+    // 类型不是T时返回None
+    pub fn borrow<T: Any>(&self) -> Option<Ref<'_, T>> {
+        Ref::filter_map(self.data.borrow(), |data| data.downcast_ref::<T>()).ok()
+    }
+
+    pub fn borrow_mut<T: Any>(&self) -> Option<RefMut<'_, T>> {
+        RefMut::filter_map(self.data.borrow_mut(), |data| data.downcast_mut::<T>()).ok()
+    }
+}